@@ -0,0 +1,443 @@
+//! Benchmarks for the graph and cache, run with `cargo bench`.
+
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use futures::executor::block_on;
+use lru::LruCache;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+use twilight_gateway::Event;
+use twilight_http::Client;
+use twilight_model::gateway::payload::incoming::{GuildCreate, MemberAdd};
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use discograph::cache::Cache;
+use discograph::snowflake::{BuildSnowflakeHasher, IdPair};
+use discograph::social::graph::{Relationship, SocialGraph, UserPair, UserRelationshipGraphMap};
+use discograph::social::inference::{Interaction, InteractionType};
+
+/// How many ordinary guilds the interactions are spread across.
+const GUILDS: u64 = 1000;
+
+/// Users talking in each of the ordinary guilds.
+const GUILD_USERS: u64 = 50;
+
+/// The one huge guild that is built over and over while everything else is going on.
+const HUGE_GUILD_USERS: u64 = 5000;
+const HUGE_GUILD_EDGES: usize = 200_000;
+
+const INTERACTIONS_PER_THREAD: usize = 2000;
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+fn message(guild_id: Id<GuildMarker>, rng: &mut StdRng, when: u64) -> Interaction {
+    let source = rng.gen_range(1..=GUILD_USERS);
+    let target = rng.gen_range(1..=GUILD_USERS);
+
+    Interaction {
+        what: InteractionType::Message,
+        when,
+        guild: guild_id,
+        channel: Id::new(guild_id.get() * 10 + rng.gen_range(0..4)),
+        thread: None,
+        message: Some(Id::new(rng.gen_range(1..u64::MAX))),
+        source: Id::new(source),
+        source_is_bot: false,
+        target: (source != target).then(|| Id::new(target)),
+        other_targets: Vec::new(),
+        reply_to: None,
+        source_roles: Vec::new(),
+        mention_roles: Vec::new(),
+        mention_everyone: false,
+    }
+}
+
+/// Process messages across many guilds from every thread, while another thread keeps building
+/// the huge guild's graph. With `single_lock` every call is serialised behind one lock, the same
+/// as when the whole `SocialGraph` was behind a single mutex.
+fn bench_concurrent_guilds(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    name: &str,
+    threads: usize,
+    single_lock: bool,
+) {
+    let social = SocialGraph::new(None);
+    let lock = Mutex::new(());
+    let with_lock = |f: &mut dyn FnMut()| {
+        let _guard = single_lock.then(|| lock.lock());
+        f()
+    };
+
+    let huge_guild_id = Id::new(GUILDS + 1);
+    let mut rng = StdRng::seed_from_u64(0);
+    social.with_graph(huge_guild_id, Id::new(1), |graph| {
+        while graph.len() < HUGE_GUILD_EDGES {
+            let source = Id::new(rng.gen_range(1..=HUGE_GUILD_USERS));
+            let target = Id::new(rng.gen_range(1..=HUGE_GUILD_USERS));
+
+            graph.insert(
+                IdPair(source, target),
+                Relationship {
+                    strength: 1.0,
+                    updated: 0,
+                },
+            );
+        }
+    });
+
+    let mut round = 0;
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            round += 1;
+
+            let done = AtomicBool::new(false);
+
+            std::thread::scope(|scope| {
+                let builder = scope.spawn(|| {
+                    let mut builds = 0;
+
+                    while !done.load(Ordering::Relaxed) {
+                        with_lock(&mut || {
+                            social.build_guild_graph(huge_guild_id, DAY);
+                        });
+
+                        builds += 1;
+                    }
+
+                    builds
+                });
+
+                let workers: Vec<_> = (0..threads)
+                    .map(|thread| {
+                        let social = &social;
+                        let with_lock = &with_lock;
+
+                        scope.spawn(move || {
+                            let seed = (round * threads + thread) as u64;
+                            let mut rng = StdRng::seed_from_u64(seed);
+
+                            for when in 0..INTERACTIONS_PER_THREAD as u64 {
+                                let guild_id = Id::new(rng.gen_range(1..=GUILDS));
+                                let interaction = message(guild_id, &mut rng, when * 1000);
+
+                                with_lock(&mut || {
+                                    social.infer_and_apply(&interaction, interaction.when);
+                                });
+                            }
+                        })
+                    })
+                    .collect();
+
+                for worker in workers {
+                    worker.join().unwrap();
+                }
+
+                done.store(true, Ordering::Relaxed);
+
+                builder.join().unwrap()
+            })
+        })
+    });
+}
+
+fn concurrent_guilds(c: &mut Criterion) {
+    let threads = std::thread::available_parallelism().map_or(4, |threads| threads.get());
+
+    let mut group = c.benchmark_group("concurrent guilds");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(
+        (threads * INTERACTIONS_PER_THREAD) as u64,
+    ));
+
+    bench_concurrent_guilds(&mut group, "single lock", threads, true);
+    bench_concurrent_guilds(&mut group, "per-guild locks", threads, false);
+
+    group.finish();
+}
+
+fn random_graph(rng: &mut StdRng, users: u64, edges: usize) -> UserRelationshipGraphMap {
+    let mut graph = UserRelationshipGraphMap::new();
+
+    while graph.len() < edges {
+        graph.insert(
+            IdPair(random_user(rng, users), random_user(rng, users)),
+            Relationship {
+                strength: rng.gen_range(0.5..10.0),
+                updated: rng.gen_range(0..DAY),
+            },
+        );
+    }
+
+    graph
+}
+
+/// Real snowflakes, rather than small sequential numbers, so that the hashing is representative.
+fn random_user(rng: &mut StdRng, users: u64) -> Id<UserMarker> {
+    Id::new(snowflake(rng.gen_range(0..users)))
+}
+
+fn snowflake(index: u64) -> u64 {
+    const DISCORD_EPOCH_OFFSET: u64 = 1_000_000_000_000;
+
+    ((DISCORD_EPOCH_OFFSET + index * 7919) << 22) | (index % 31) << 17 | (index & 0xFFF)
+}
+
+fn half_life() -> Duration {
+    Duration::from_millis(14 * DAY)
+}
+
+/// Looks up every edge in a map of the huge guild's edges hashed with `hasher`, the same thing
+/// decay does.
+fn bench_pair_lookups<S: BuildHasher>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    name: &str,
+    graph: &UserRelationshipGraphMap,
+    hasher: S,
+) {
+    let mut map = HashMap::with_capacity_and_hasher(graph.len(), hasher);
+    map.extend(graph.keys().map(|&key| (key, 1.0)));
+
+    group.bench_function(name, |b| {
+        b.iter(|| graph.keys().map(|key| map[key]).sum::<f64>())
+    });
+}
+
+/// A copy of the edges in a graph, with the SipHash the graph used to be keyed with.
+type SipHashGraph = HashMap<UserPair, Relationship, RandomState>;
+
+fn siphash_graph(graph: &UserRelationshipGraphMap) -> SipHashGraph {
+    graph.iter().map(|(&key, &value)| (key, value)).collect()
+}
+
+/// The same as `UserRelationshipGraphMap::merge`, for a baseline with SipHash.
+fn siphash_merge<'a>(
+    graphs: impl IntoIterator<Item = &'a SipHashGraph>,
+    now: u64,
+    half_life: Duration,
+) -> SipHashGraph {
+    let mut merged = SipHashGraph::default();
+    for graph in graphs {
+        for (&source_target, relationship) in graph.iter() {
+            let merged_relationship = merged.entry(source_target).or_default();
+
+            merged_relationship.strength += relationship.decayed_strength(now, half_life);
+            merged_relationship.updated = now;
+        }
+    }
+
+    merged
+}
+
+fn graph_operations(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let graph = random_graph(&mut rng, HUGE_GUILD_USERS, HUGE_GUILD_EDGES);
+
+    let mut group = c.benchmark_group("pair lookups");
+    group.throughput(Throughput::Elements(graph.len() as u64));
+    bench_pair_lookups(&mut group, "siphash", &graph, RandomState::new());
+    bench_pair_lookups(
+        &mut group,
+        "snowflake",
+        &graph,
+        BuildSnowflakeHasher::default(),
+    );
+    group.finish();
+
+    let mut group = c.benchmark_group("decay");
+    group.throughput(Throughput::Elements(graph.len() as u64));
+    let siphash = siphash_graph(&graph);
+    group.bench_function("siphash", |b| {
+        b.iter(|| siphash_merge([&siphash], 2 * DAY, half_life()))
+    });
+    group.bench_function("snowflake", |b| {
+        b.iter(|| UserRelationshipGraphMap::merge([&graph], 2 * DAY, half_life()))
+    });
+    group.finish();
+
+    let keys: Vec<_> = graph.keys().copied().collect();
+    let mut reinforced = UserRelationshipGraphMap::new();
+    c.bench_function("reinforce", |b| {
+        b.iter(|| {
+            let key = keys[rng.gen_range(0..keys.len())];
+            reinforced.reinforce(key, 1.0, DAY, half_life());
+        })
+    });
+
+    let social = SocialGraph::new(None);
+    let guild_id = Id::new(snowflake(1));
+    let mut siphash_channels = Vec::new();
+    for channel in 0..10 {
+        let channel_graph = random_graph(&mut rng, HUGE_GUILD_USERS, HUGE_GUILD_EDGES / 10);
+        siphash_channels.push(siphash_graph(&channel_graph));
+        social.with_graph(guild_id, Id::new(snowflake(channel)), |graph| {
+            *graph = channel_graph;
+        });
+    }
+
+    // The SipHash baseline only merges the channels, without the locking around it.
+    let mut group = c.benchmark_group("build_guild_graph, 10 channels");
+    group.bench_function("siphash", |b| {
+        b.iter(|| siphash_merge(&siphash_channels, 2 * DAY, half_life()))
+    });
+    group.bench_function("snowflake", |b| {
+        b.iter(|| social.build_guild_graph(guild_id, 2 * DAY))
+    });
+    group.finish();
+}
+
+/// A cache with `guilds` guilds and `users` users spread between them, without touching the API.
+fn populated_cache(guilds: u64, users: u64) -> Cache {
+    let cache = Cache::new(Arc::new(Client::new(String::new())));
+
+    for guild in 0..guilds {
+        let guild = serde_json::from_value(json!({
+            "afk_channel_id": null,
+            "afk_timeout": 300,
+            "application_id": null,
+            "banner": null,
+            "default_message_notifications": 0,
+            "description": null,
+            "discovery_splash": null,
+            "emojis": [],
+            "explicit_content_filter": 0,
+            "features": [],
+            "icon": null,
+            "id": snowflake(guild).to_string(),
+            "large": false,
+            "mfa_level": 0,
+            "name": format!("guild {}", guild),
+            "nsfw_level": 0,
+            "owner_id": snowflake(0).to_string(),
+            "preferred_locale": "en-US",
+            "premium_progress_bar_enabled": false,
+            "public_updates_channel_id": null,
+            "roles": [],
+            "rules_channel_id": null,
+            "splash": null,
+            "system_channel_flags": 0,
+            "system_channel_id": null,
+            "vanity_url_code": null,
+            "verification_level": 0,
+        }))
+        .unwrap();
+
+        cache.update(&Event::GuildCreate(Box::new(GuildCreate(guild))));
+    }
+
+    for user in 0..users {
+        let member: MemberAdd = serde_json::from_value(json!({
+            "guild_id": snowflake(user % guilds).to_string(),
+            "deaf": false,
+            "mute": false,
+            "flags": 0,
+            "joined_at": "2020-01-01T00:00:00.000000+00:00",
+            "roles": [],
+            "user": {
+                "id": snowflake(user).to_string(),
+                "username": format!("user {}", user),
+                "discriminator": "0001",
+                "avatar": null,
+            },
+        }))
+        .unwrap();
+
+        cache.update(&Event::MemberAdd(Box::new(member)));
+    }
+
+    cache
+}
+
+/// The cache always uses the snowflake hasher, so for a SipHash baseline these are the same kinds
+/// of maps the cache keeps guilds and users in, with the same locking and cloning on lookup.
+struct CacheMaps<S> {
+    guilds: Mutex<HashMap<Id<GuildMarker>, Arc<Mutex<String>>, S>>,
+    users: Mutex<LruCache<Id<UserMarker>, String, S>>,
+}
+
+impl<S: BuildHasher + Clone> CacheMaps<S> {
+    fn new(guilds: u64, users: u64, hasher: S) -> Self {
+        let mut guild_map = HashMap::with_hasher(hasher.clone());
+        for guild in 0..guilds {
+            let name = Arc::new(Mutex::new(format!("guild {}", guild)));
+            guild_map.insert(Id::new(snowflake(guild)), name);
+        }
+
+        let limit = NonZeroUsize::new(users as usize).unwrap();
+        let mut user_cache = LruCache::with_hasher(limit, hasher);
+        for user in 0..users {
+            user_cache.put(Id::new(snowflake(user)), format!("user {}", user));
+        }
+
+        CacheMaps {
+            guilds: Mutex::new(guild_map),
+            users: Mutex::new(user_cache),
+        }
+    }
+
+    fn get_guild(&self, guild_id: Id<GuildMarker>) -> Option<String> {
+        let guilds = self.guilds.lock();
+        guilds.get(&guild_id).map(|guild| guild.lock().clone())
+    }
+
+    fn get_user(&self, user_id: Id<UserMarker>) -> Option<String> {
+        self.users.lock().get(&user_id).cloned()
+    }
+}
+
+fn cache_lookups(c: &mut Criterion) {
+    let siphash = CacheMaps::new(1000, 5000, RandomState::new());
+    let snowflake_maps = CacheMaps::new(1000, 5000, BuildSnowflakeHasher::default());
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let mut group = c.benchmark_group("guild map lookups");
+    group.bench_function("siphash", |b| {
+        b.iter(|| siphash.get_guild(Id::new(snowflake(rng.gen_range(0..1000)))))
+    });
+    group.bench_function("snowflake", |b| {
+        b.iter(|| snowflake_maps.get_guild(Id::new(snowflake(rng.gen_range(0..1000)))))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("user lru lookups");
+    group.bench_function("siphash", |b| {
+        b.iter(|| siphash.get_user(Id::new(snowflake(rng.gen_range(0..5000)))))
+    });
+    group.bench_function("snowflake", |b| {
+        b.iter(|| snowflake_maps.get_user(Id::new(snowflake(rng.gen_range(0..5000)))))
+    });
+    group.finish();
+
+    let cache = populated_cache(1000, 5000);
+
+    let mut group = c.benchmark_group("cache guild lookups");
+    group.bench_function("snowflake", |b| {
+        b.iter(|| {
+            let guild_id = Id::new(snowflake(rng.gen_range(0..1000)));
+            block_on(cache.get_guild(guild_id)).unwrap()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("cache user lookups");
+    group.bench_function("snowflake", |b| {
+        b.iter(|| {
+            let user_id = Id::new(snowflake(rng.gen_range(0..5000)));
+            block_on(cache.get_user(user_id)).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_guilds, graph_operations, cache_lookups);
+criterion_main!(benches);
@@ -0,0 +1,457 @@
+use anyhow::{Context as AnyhowContext, Result};
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use tracing::{debug, warn};
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+use twilight_model::util::ImageHash;
+
+use std::collections::HashMap;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::cache::{CachedMember, CachedUser};
+use crate::social::graph::DisplayGraph;
+
+/// Avatars are drawn at 48pt, this gives us enough pixels for the 2x PNG output.
+const AVATAR_SIZE: u16 = 128;
+
+const CDN_BASE_URL: &str = "https://cdn.discordapp.com";
+
+/// How many avatars to fetch at once for a single graph.
+const CONCURRENT_FETCHES: usize = 8;
+
+/// How big the avatar cache can get before the oldest avatars are removed, in bytes.
+pub const DEFAULT_CACHE_SIZE_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// How many avatars are written to the cache between checking its size.
+const CACHE_SWEEP_INTERVAL: usize = 100;
+
+/// The avatar image a user has in a particular guild.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Avatar {
+    /// A guild-specific avatar set on the member.
+    Guild {
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        hash: ImageHash,
+    },
+    User {
+        user_id: Id<UserMarker>,
+        hash: ImageHash,
+    },
+    /// One of the built-in avatars, for users that haven't set their own.
+    Default { index: u64 },
+}
+
+impl Avatar {
+    pub fn for_user(
+        guild_id: Id<GuildMarker>,
+        user: &CachedUser,
+        member: Option<&CachedMember>,
+    ) -> Self {
+        if let Some(hash) = member.and_then(|member| member.avatar) {
+            return Avatar::Guild {
+                guild_id,
+                user_id: user.id,
+                hash,
+            };
+        }
+
+        if let Some(hash) = user.avatar {
+            return Avatar::User {
+                user_id: user.id,
+                hash,
+            };
+        }
+
+        // Users that have migrated to the new username system have a discriminator of 0.
+        let index = if user.discriminator == 0 {
+            (user.id.get() >> 22) % 6
+        } else {
+            u64::from(user.discriminator) % 5
+        };
+
+        Avatar::Default { index }
+    }
+
+    /// Animated avatars are requested as PNG too, which gets us the first frame.
+    pub fn url(&self) -> String {
+        match self {
+            Avatar::Guild {
+                guild_id,
+                user_id,
+                hash,
+            } => format!(
+                "{}/guilds/{}/users/{}/avatars/{}.png?size={}",
+                CDN_BASE_URL, guild_id, user_id, hash, AVATAR_SIZE,
+            ),
+            Avatar::User { user_id, hash } => format!(
+                "{}/avatars/{}/{}.png?size={}",
+                CDN_BASE_URL, user_id, hash, AVATAR_SIZE,
+            ),
+            Avatar::Default { index } => format!("{}/embed/avatars/{}.png", CDN_BASE_URL, index),
+        }
+    }
+
+    /// Avatar hashes change whenever the image does, so this never needs invalidating.
+    fn cache_file_name(&self) -> String {
+        match self {
+            Avatar::Guild {
+                guild_id,
+                user_id,
+                hash,
+            } => format!("{}_{}_{}.png", guild_id, user_id, hash),
+            Avatar::User { user_id, hash } => format!("{}_{}.png", user_id, hash),
+            Avatar::Default { index } => format!("default_{}.png", index),
+        }
+    }
+}
+
+/// Somewhere to get avatar images from.
+pub trait AvatarSource: Send + Sync {
+    fn fetch<'a>(&'a self, avatar: &'a Avatar) -> BoxFuture<'a, Result<Vec<u8>>>;
+}
+
+/// Fetches avatars from Discord's CDN.
+pub struct CdnAvatarSource {
+    client: reqwest::Client,
+}
+
+impl CdnAvatarSource {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("failed to create http client")?;
+
+        Ok(CdnAvatarSource { client })
+    }
+}
+
+impl AvatarSource for CdnAvatarSource {
+    fn fetch<'a>(&'a self, avatar: &'a Avatar) -> BoxFuture<'a, Result<Vec<u8>>> {
+        async move {
+            let url = avatar.url();
+
+            debug!(?url, "fetching avatar");
+
+            let response = self
+                .client
+                .get(&url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("failed to fetch {}", url))?;
+
+            let bytes = response
+                .bytes()
+                .await
+                .with_context(|| format!("failed to read {}", url))?;
+
+            Ok(bytes.to_vec())
+        }
+        .boxed()
+    }
+}
+
+/// Wraps another source, keeping a copy of everything it fetches on disk.
+///
+/// Avatars that have been replaced are never asked for again, so once the cache is over its size
+/// limit the ones that were fetched longest ago are removed.
+pub struct DiskCachedAvatarSource<S> {
+    inner: S,
+    directory: PathBuf,
+    size_limit: u64,
+    /// How many avatars have been written, which also keeps temporary file names unique.
+    writes: AtomicUsize,
+}
+
+impl<S> DiskCachedAvatarSource<S> {
+    pub fn new(inner: S, directory: PathBuf, size_limit: u64) -> Self {
+        DiskCachedAvatarSource {
+            inner,
+            directory,
+            size_limit,
+            writes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Write to a temporary file first, so that nothing ever reads a partly written avatar.
+    async fn write(&self, path: &Path, bytes: &[u8], write: usize) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+
+        let temp_path = path.with_extension(format!("{}.tmp", write));
+        tokio::fs::write(&temp_path, bytes).await?;
+
+        let result = tokio::fs::rename(&temp_path, path).await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+
+        result
+    }
+
+    /// Remove the oldest files until the cache is back under its size limit.
+    async fn evict(&self) -> std::io::Result<()> {
+        let mut files = Vec::new();
+        let mut total = 0;
+
+        let mut entries = tokio::fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+
+            total += metadata.len();
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+
+        if total <= self.size_limit {
+            return Ok(());
+        }
+
+        files.sort_unstable();
+
+        let mut removed = 0;
+        for (_, size, path) in files {
+            if total <= self.size_limit {
+                break;
+            }
+
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(error) if error.kind() == IoErrorKind::NotFound => {}
+                Err(error) => return Err(error),
+            }
+
+            total -= size;
+            removed += 1;
+        }
+
+        debug!(removed, "evicted avatars from the cache");
+
+        Ok(())
+    }
+}
+
+impl<S: AvatarSource> AvatarSource for DiskCachedAvatarSource<S> {
+    fn fetch<'a>(&'a self, avatar: &'a Avatar) -> BoxFuture<'a, Result<Vec<u8>>> {
+        async move {
+            let path = self.directory.join(avatar.cache_file_name());
+
+            match tokio::fs::read(&path).await {
+                Ok(bytes) => return Ok(bytes),
+                Err(error) if error.kind() == IoErrorKind::NotFound => {}
+                Err(error) => warn!(?path, ?error, "failed to read cached avatar"),
+            }
+
+            let bytes = self.inner.fetch(avatar).await?;
+
+            // Failing to cache isn't fatal, we've still got the image.
+            let write = self.writes.fetch_add(1, Ordering::Relaxed);
+            if let Err(error) = self.write(&path, &bytes, write).await {
+                warn!(?path, ?error, "failed to cache avatar");
+            }
+
+            if write.is_multiple_of(CACHE_SWEEP_INTERVAL) {
+                if let Err(error) = self.evict().await {
+                    warn!(directory = ?self.directory, ?error, "failed to evict cached avatars");
+                }
+            }
+
+            Ok(bytes)
+        }
+        .boxed()
+    }
+}
+
+/// Fetch the avatar for every node in the graph, leaving out any that fail.
+pub async fn fetch_avatars(
+    source: &dyn AvatarSource,
+    graph: &DisplayGraph,
+) -> HashMap<Id<UserMarker>, Vec<u8>> {
+    // Collected up front, as the stream can't otherwise be shown to be Send.
+    let fetches: Vec<_> = graph
+        .nodes
+        .iter()
+        .map(|(&user_id, node)| async move {
+            match source.fetch(&node.avatar).await {
+                Ok(bytes) => Some((user_id, bytes)),
+                Err(error) => {
+                    warn!(?user_id, ?error, "failed to fetch avatar");
+
+                    None
+                }
+            }
+        })
+        .collect();
+
+    futures::stream::iter(fetches)
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+    use twilight_model::id::Id;
+    use twilight_model::util::ImageHash;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Poll;
+
+    use super::{
+        fetch_avatars, Avatar, AvatarSource, DiskCachedAvatarSource, CACHE_SWEEP_INTERVAL,
+        CONCURRENT_FETCHES,
+    };
+    use crate::cache::{CachedMember, CachedUser};
+    use crate::social::graph::{DisplayGraph, DisplayNode};
+
+    /// Hands out each avatar's index as its image, keeping track of how many are being fetched.
+    #[derive(Default)]
+    struct TestAvatarSource {
+        fetching: AtomicUsize,
+        most_fetching: AtomicUsize,
+        fetched: AtomicUsize,
+    }
+
+    impl AvatarSource for TestAvatarSource {
+        fn fetch<'a>(&'a self, avatar: &'a Avatar) -> BoxFuture<'a, Result<Vec<u8>>> {
+            async move {
+                let fetching = self.fetching.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most_fetching.fetch_max(fetching, Ordering::SeqCst);
+
+                // Give everything else a chance to start before finishing.
+                let mut yielded = false;
+                futures::future::poll_fn(|context| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+
+                    yielded = true;
+                    context.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+
+                self.fetching.fetch_sub(1, Ordering::SeqCst);
+                self.fetched.fetch_add(1, Ordering::SeqCst);
+
+                match avatar {
+                    Avatar::Default { index } => Ok(vec![*index as u8]),
+                    _ => anyhow::bail!("not a default avatar"),
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[test]
+    fn test_fetch_avatars() {
+        let nodes = (1..=20)
+            .map(|i| {
+                let node = DisplayNode {
+                    name: i.to_string(),
+                    color: None,
+                    is_member: true,
+                    avatar: Avatar::Default { index: i },
+                    weight: 1.0,
+                };
+
+                (Id::new(i), node)
+            })
+            .collect();
+
+        let graph = DisplayGraph {
+            nodes,
+            edges: vec![],
+        };
+
+        let source = TestAvatarSource::default();
+        let avatars = futures::executor::block_on(fetch_avatars(&source, &graph));
+
+        assert_eq!(avatars.len(), 20);
+        assert_eq!(avatars[&Id::new(7)], vec![7]);
+        assert_eq!(
+            source.most_fetching.load(Ordering::SeqCst),
+            CONCURRENT_FETCHES
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let directory =
+            std::env::temp_dir().join(format!("discograph-avatars-{}", std::process::id()));
+        let cache = DiskCachedAvatarSource::new(TestAvatarSource::default(), directory.clone(), 50);
+
+        let avatar = Avatar::Default { index: 0 };
+        assert_eq!(cache.fetch(&avatar).await.unwrap(), vec![0]);
+        assert_eq!(cache.fetch(&avatar).await.unwrap(), vec![0]);
+        assert_eq!(cache.inner.fetched.load(Ordering::SeqCst), 1);
+
+        // The cache is cut back down to size every so often.
+        for index in 1..=CACHE_SWEEP_INTERVAL as u64 {
+            cache.fetch(&Avatar::Default { index }).await.unwrap();
+        }
+
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 50);
+        assert!(files.iter().all(|path| path.extension().unwrap() == "png"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_avatar_for_user() {
+        let guild_id = Id::new(1);
+        let hash = ImageHash::parse(b"a_1269e74af4df7417b13759eae50c83dc").unwrap();
+
+        let mut user = CachedUser {
+            id: Id::new(80351110224678912),
+            name: "Nelly".to_owned(),
+            discriminator: 1337,
+            avatar: None,
+            bot: false,
+        };
+
+        let mut member = CachedMember {
+            nick: None,
+            avatar: None,
+            roles: vec![],
+        };
+
+        assert_eq!(
+            Avatar::for_user(guild_id, &user, Some(&member)),
+            Avatar::Default { index: 2 },
+        );
+
+        user.discriminator = 0;
+        assert_eq!(
+            Avatar::for_user(guild_id, &user, None).url(),
+            "https://cdn.discordapp.com/embed/avatars/5.png",
+        );
+
+        user.avatar = Some(hash);
+        assert_eq!(
+            Avatar::for_user(guild_id, &user, Some(&member)).url(),
+            "https://cdn.discordapp.com/avatars/80351110224678912/a_1269e74af4df7417b13759eae50c83dc.png?size=128",
+        );
+
+        member.avatar = Some(hash);
+        assert_eq!(
+            Avatar::for_user(guild_id, &user, Some(&member)).url(),
+            "https://cdn.discordapp.com/guilds/1/users/80351110224678912/avatars/a_1269e74af4df7417b13759eae50c83dc.png?size=128",
+        );
+    }
+}
@@ -25,6 +25,8 @@ use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::snowflake::{BuildSnowflakeHasher, SnowflakeMap};
+
 #[derive(Debug, Clone)]
 pub struct CachedUser {
     pub id: Id<UserMarker>,
@@ -154,6 +156,21 @@ pub struct CachedChannel {
     pub id: Id<ChannelMarker>,
     pub name: String,
     pub kind: ChannelType,
+    /// The category for channels, or the channel for threads.
+    pub parent_id: Option<Id<ChannelMarker>>,
+    /// The user that started a thread.
+    pub owner_id: Option<Id<UserMarker>>,
+}
+
+/// A guild's channels along with its active threads, which are only sent separately when it becomes
+/// available.
+fn guild_channels(guild: &Guild) -> HashMap<Id<ChannelMarker>, CachedChannel> {
+    guild
+        .channels
+        .iter()
+        .chain(&guild.threads)
+        .map(|channel| (channel.id, CachedChannel::from(channel)))
+        .collect()
 }
 
 impl From<&Channel> for CachedChannel {
@@ -165,6 +182,8 @@ impl From<&Channel> for CachedChannel {
                 |name| name.clone(),
             ),
             kind: channel.kind,
+            parent_id: channel.parent_id,
+            owner_id: channel.owner_id,
         }
     }
 }
@@ -192,7 +211,7 @@ impl From<&Message> for CachedMessage {
 struct GuildCache {
     guild: Mutex<CachedGuild>,
     roles: Mutex<HashMap<Id<RoleMarker>, CachedRole>>,
-    members: Mutex<LruCache<Id<UserMarker>, CachedMember>>,
+    members: Mutex<LruCache<Id<UserMarker>, CachedMember, BuildSnowflakeHasher>>,
     channels: Mutex<HashMap<Id<ChannelMarker>, CachedChannel>>,
     /// Used to lookup the author of messages being reacted to.
     messages: Mutex<LruCache<Id<MessageMarker>, CachedMessage>>,
@@ -201,8 +220,8 @@ struct GuildCache {
 pub struct Cache {
     http: Arc<Client>,
     pending_guild_members: Mutex<HashMap<String, mpsc::Sender<Vec<Id<UserMarker>>>>>,
-    users: Mutex<LruCache<Id<UserMarker>, CachedUser>>,
-    guilds: Mutex<HashMap<Id<GuildMarker>, Arc<GuildCache>>>,
+    users: Mutex<LruCache<Id<UserMarker>, CachedUser, BuildSnowflakeHasher>>,
+    guilds: Mutex<SnowflakeMap<Id<GuildMarker>, Arc<GuildCache>>>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -217,13 +236,13 @@ pub struct CacheStats {
 }
 
 // TODO: Consider being more selective about who gets cached.
-const USERS_LRU_CACHE_LIMIT: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(5000) };
+const USERS_LRU_CACHE_LIMIT: NonZeroUsize = NonZeroUsize::new(5000).unwrap();
 
 // TODO: Consider being more selective about who gets cached.
-const MEMBERS_LRU_CACHE_LIMIT: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(500) };
+const MEMBERS_LRU_CACHE_LIMIT: NonZeroUsize = NonZeroUsize::new(500).unwrap();
 
 // TODO: Consider splitting off a separate one for recent messages and looked up messages.
-const MESSAGES_LRU_CACHE_LIMIT: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(100) };
+const MESSAGES_LRU_CACHE_LIMIT: NonZeroUsize = NonZeroUsize::new(100).unwrap();
 
 // The `get_*` functions in here release the lock while processing in order to support async in
 // the future, and a potential switch to RwLock if we move away from LruCache.
@@ -232,8 +251,11 @@ impl Cache {
         Cache {
             http,
             pending_guild_members: Mutex::new(HashMap::new()),
-            users: Mutex::new(LruCache::new(USERS_LRU_CACHE_LIMIT)),
-            guilds: Mutex::new(HashMap::new()),
+            users: Mutex::new(LruCache::with_hasher(
+                USERS_LRU_CACHE_LIMIT,
+                BuildSnowflakeHasher::default(),
+            )),
+            guilds: Mutex::new(SnowflakeMap::default()),
         }
     }
 
@@ -293,6 +315,12 @@ impl Cache {
             }
             Event::ThreadCreate(thread) => self.put_channel(thread),
             Event::ThreadUpdate(thread) => self.put_channel(thread),
+            // Sent when we gain access to a channel, with the threads in it that are active.
+            Event::ThreadListSync(sync) => {
+                for thread in &sync.threads {
+                    self.put_channel(thread);
+                }
+            }
             Event::ThreadDelete(thread) => {
                 self.guilds
                     .lock()
@@ -507,7 +535,7 @@ impl Cache {
                 Ok(Some(results)) => {
                     // Empty results are used to reset our timeout.
                     if !results.is_empty() {
-                        not_found.extend(results.into_iter());
+                        not_found.extend(results);
                     }
                 }
                 Err(_) => {
@@ -590,7 +618,10 @@ impl Cache {
                             .map(|role| (role.id, CachedRole::from(role)))
                             .collect(),
                     ),
-                    members: Mutex::new(LruCache::new(MEMBERS_LRU_CACHE_LIMIT)),
+                    members: Mutex::new(LruCache::with_hasher(
+                        MEMBERS_LRU_CACHE_LIMIT,
+                        BuildSnowflakeHasher::default(),
+                    )),
                     channels: Mutex::new(HashMap::new()),
                     messages: Mutex::new(LruCache::new(MESSAGES_LRU_CACHE_LIMIT)),
                 })
@@ -618,11 +649,7 @@ impl Cache {
 
                 {
                     let mut old_channels = cache.channels.lock();
-                    *old_channels = guild
-                        .channels
-                        .iter()
-                        .map(|channel| (channel.id, CachedChannel::from(channel)))
-                        .collect();
+                    *old_channels = guild_channels(guild);
                 }
             })
             .or_insert_with(|| {
@@ -635,14 +662,11 @@ impl Cache {
                             .map(|role| (role.id, CachedRole::from(role)))
                             .collect(),
                     ),
-                    members: Mutex::new(LruCache::new(MEMBERS_LRU_CACHE_LIMIT)),
-                    channels: Mutex::new(
-                        guild
-                            .channels
-                            .iter()
-                            .map(|channel| (channel.id, CachedChannel::from(channel)))
-                            .collect(),
-                    ),
+                    members: Mutex::new(LruCache::with_hasher(
+                        MEMBERS_LRU_CACHE_LIMIT,
+                        BuildSnowflakeHasher::default(),
+                    )),
+                    channels: Mutex::new(guild_channels(guild)),
                     messages: Mutex::new(LruCache::new(MESSAGES_LRU_CACHE_LIMIT)),
                 })
             });
@@ -798,14 +822,7 @@ impl Cache {
         guild_id: Id<GuildMarker>,
         channel_id: Id<ChannelMarker>,
     ) -> Result<CachedChannel> {
-        let cached_channel = {
-            self.guilds
-                .lock()
-                .get_mut(&guild_id)
-                .and_then(|guild| guild.channels.lock().get(&channel_id).cloned())
-        };
-
-        match cached_channel {
+        match self.get_cached_channel(guild_id, channel_id) {
             Some(cached_channel) => Ok(cached_channel),
             None => {
                 info!("channel {} not in cache, fetching", channel_id);
@@ -827,6 +844,42 @@ impl Cache {
         }
     }
 
+    /// Get a channel only if it is already cached, for when fetching it would take too long.
+    pub fn get_cached_channel(
+        &self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Option<CachedChannel> {
+        self.guilds
+            .lock()
+            .get(&guild_id)
+            .and_then(|guild| guild.channels.lock().get(&channel_id).cloned())
+    }
+
+    /// Get all the cached channels whose parent is `parent_id`.
+    ///
+    /// Unlike the other getters this doesn't fall back to fetching, we get the full channel list for
+    /// each guild when it becomes available, so the cache should always be complete.
+    pub fn get_child_channels(
+        &self,
+        guild_id: Id<GuildMarker>,
+        parent_id: Id<ChannelMarker>,
+    ) -> Vec<CachedChannel> {
+        self.guilds
+            .lock()
+            .get(&guild_id)
+            .map(|guild| {
+                guild
+                    .channels
+                    .lock()
+                    .values()
+                    .filter(|channel| channel.parent_id == Some(parent_id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     fn put_message(&self, message: &Message) {
         self.put_user(&message.author);
 
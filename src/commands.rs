@@ -1,11 +1,12 @@
-use std::process::Stdio;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use futures::future::join_all;
 use futures::FutureExt;
-use tokio::io::AsyncWriteExt;
-use tokio::process;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
 use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 use twilight_command_parser::{Arguments, CommandParserConfig, Parser};
@@ -20,26 +21,37 @@ use twilight_model::application::interaction::application_command::{
 };
 use twilight_model::application::interaction::InteractionData::ApplicationCommand;
 use twilight_model::channel::message::embed::{Embed, EmbedField};
-use twilight_model::channel::message::{AllowedMentions, MentionType};
+use twilight_model::channel::message::{AllowedMentions, MentionType, MessageFlags};
 use twilight_model::channel::Message;
 use twilight_model::gateway::event::Event;
 use twilight_model::gateway::event::Event::{
     GuildCreate, GuildDelete, InteractionCreate, MessageCreate,
 };
 use twilight_model::gateway::CloseFrame;
+use twilight_model::guild::Permissions;
 use twilight_model::http::attachment::Attachment;
-use twilight_model::http::interaction::{InteractionResponse, InteractionResponseType};
-use twilight_model::id::marker::GuildMarker;
+use twilight_model::http::interaction::{
+    InteractionResponse, InteractionResponseData, InteractionResponseType,
+};
+use twilight_model::id::marker::{ChannelMarker, GenericMarker, GuildMarker, UserMarker};
 use twilight_model::id::Id;
 use twilight_model::user::User;
 
+use crate::avatar;
+use crate::cache::CachedMember;
 use crate::context::Context;
-use crate::social::graph::{ColorScheme, ToDotError};
+use crate::render::{self, EdgeStyle, NodeStyle, RenderOptions};
+use crate::social::analysis::{self, Communities, GraphDiff, UserMetrics};
+use crate::social::export::ExportFormat;
+use crate::social::graph::{ColorScheme, DisplayGraph, ToDotError, UserRelationshipGraphMap};
+use crate::social::history::{self, FrameReplay, GuildRebuilder};
 use crate::stats;
 
 struct CommandContext {
     guild_id: Option<Id<GuildMarker>>,
     author: User,
+    /// The author's permissions in the channel, only known for slash commands.
+    permissions: Option<Permissions>,
 }
 
 #[derive(Debug)]
@@ -161,6 +173,10 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<bool> {
                     .as_ref()
                     .unwrap_or_else(|| interaction.member.as_ref().unwrap().user.as_ref().unwrap())
                     .clone(),
+                permissions: interaction
+                    .member
+                    .as_ref()
+                    .and_then(|member| member.permissions),
             };
 
             let result_future = match command_data.name.as_str() {
@@ -169,6 +185,27 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<bool> {
                     command_graph_from_interaction(context, &command_context, &command_data.options)
                         .boxed()
                 }
+                "Show connections" => {
+                    command_show_connections(context, &command_context, command_data.target_id)
+                        .boxed()
+                }
+                "insights" => command_insights(context, &command_context).boxed(),
+                "diff" => {
+                    command_diff_from_interaction(context, &command_context, &command_data.options)
+                        .boxed()
+                }
+                "timelapse" => command_timelapse_from_interaction(
+                    context,
+                    &command_context,
+                    &command_data.options,
+                )
+                .boxed(),
+                "export" => command_export_from_interaction(
+                    context,
+                    &command_context,
+                    &command_data.options,
+                )
+                .boxed(),
                 "stats" => command_stats(context).boxed(),
                 "dump" => {
                     command_dump_from_interaction(context, &command_context, &command_data.options)
@@ -177,6 +214,12 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<bool> {
                 _ => return Ok(false),
             };
 
+            // Exports can contain things about members that shouldn't be posted for everyone.
+            let data = (command_data.name == "export").then(|| InteractionResponseData {
+                flags: Some(MessageFlags::EPHEMERAL),
+                ..Default::default()
+            });
+
             context
                 .http
                 .interaction(interaction.application_id)
@@ -185,7 +228,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<bool> {
                     &interaction.token,
                     &InteractionResponse {
                         kind: InteractionResponseType::DeferredChannelMessageWithSource,
-                        data: None,
+                        data,
                     },
                 )
                 .await?;
@@ -258,10 +301,14 @@ async fn handle_message(context: &Context, message: &Message) -> Result<bool> {
     config.add_command("help", false);
     config.add_command("invite", false);
     config.add_command("graph", false);
+    config.add_command("insights", false);
     config.add_command("stats", false);
     config.add_command("dump", false);
     config.add_command("bounce", false);
     config.add_command("debug", false);
+    config.add_command("engines", false);
+    config.add_command("halflife", false);
+    config.add_command("rebuild", false);
 
     let parser = Parser::new(config);
     let command = match parser.parse(&message.content) {
@@ -274,11 +321,13 @@ async fn handle_message(context: &Context, message: &Message) -> Result<bool> {
     let command_context = CommandContext {
         guild_id: message.guild_id,
         author: message.author.clone(),
+        permissions: None,
     };
 
     let result = match command.name {
         "help" | "invite" => command_help(context).await,
         "graph" => command_graph_from_message(context, &command_context, command.arguments).await,
+        "insights" => command_insights(context, &command_context).await,
         "stats" => command_stats(context).await,
         "dump" => command_dump_from_message(context, &command_context, command.arguments).await,
         "bounce" => {
@@ -325,6 +374,9 @@ async fn handle_message(context: &Context, message: &Message) -> Result<bool> {
                 })
             }
         }
+        "engines" => command_engines(context, &command_context, command.arguments).await,
+        "halflife" => command_half_life(context, &command_context, command.arguments).await,
+        "rebuild" => command_rebuild(context, &command_context, command.arguments).await,
         _ => Err(anyhow!("unknown command")),
     };
 
@@ -444,9 +496,13 @@ async fn command_help(context: &Context) -> Result<CommandResponse> {
     let commands_field = EmbedField {
         inline: false,
         name: "Commands".to_string(),
-        value: vec![
+        value: [
             "` help               `\u{2000}This message.",
             "` graph [light|dark] `\u{2000}Get a preview-quality graph image.",
+            "` insights           `\u{2000}See who holds the server together.",
+            "` timelapse          `\u{2000}Watch the server's graph change over time.",
+            "` diff               `\u{2000}See what changed in the last month.",
+            "` export             `\u{2000}Download the graph for Gephi, networkx, or pandas.",
         ]
         .join("\n"),
     };
@@ -500,6 +556,66 @@ async fn command_help(context: &Context) -> Result<CommandResponse> {
     })
 }
 
+/// What to draw for a graph command.
+struct GraphOptions {
+    color_scheme: ColorScheme,
+    transparent: bool,
+    node_style: NodeStyle,
+    /// Color nodes by detected community rather than by role, and list the communities.
+    communities: bool,
+    /// Only draw this user and their connections, out to `depth` hops away.
+    user: Option<Id<UserMarker>>,
+    depth: usize,
+    scope: GraphScope,
+    /// Draw the graph from the last snapshot taken on or before this day, rather than as it is now.
+    at: Option<Date>,
+}
+
+/// Which of the guild's channels to build a graph from.
+#[derive(Debug, Clone, Copy)]
+enum GraphScope {
+    Guild,
+    Channel(Id<ChannelMarker>),
+    /// All the channels in a category.
+    Category(Id<ChannelMarker>),
+}
+
+/// How far from the user an ego graph reaches, graphs get too big to be useful past this.
+const DEFAULT_EGO_DEPTH: usize = 1;
+pub const MAX_EGO_DEPTH: i64 = 3;
+
+const SNAPSHOT_DATE_FORMAT: &[FormatItem] = format_description!("[year]-[month]-[day]");
+
+const MAX_FIELD_LENGTH: usize = 1024;
+
+const DEFAULT_DIFF_DAYS: u64 = 30;
+pub const MAX_DIFF_DAYS: i64 = 365;
+
+const MAX_TIMELAPSE_FRAMES: u64 = 52;
+
+/// In hundredths of a second.
+const TIMELAPSE_FRAME_DELAY: u16 = 30;
+
+/// How much time each frame of a timelapse moves forward by.
+#[derive(Debug, Clone, Copy)]
+enum TimelapsePeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl TimelapsePeriod {
+    fn as_millis(&self) -> u64 {
+        const DAY: u64 = 24 * 60 * 60 * 1000;
+
+        match self {
+            TimelapsePeriod::Day => DAY,
+            TimelapsePeriod::Week => 7 * DAY,
+            TimelapsePeriod::Month => 30 * DAY,
+        }
+    }
+}
+
 async fn command_graph_from_message(
     context: &Context,
     command: &CommandContext,
@@ -515,7 +631,28 @@ async fn command_graph_from_message(
         None => ColorScheme::Dark,
     };
 
-    let transparent = matches!(arguments.next(), Some("transparent"));
+    let mut options = GraphOptions {
+        color_scheme,
+        transparent: false,
+        node_style: NodeStyle::Names,
+        communities: false,
+        user: None,
+        depth: DEFAULT_EGO_DEPTH,
+        scope: GraphScope::Guild,
+        at: None,
+    };
+
+    for argument in arguments {
+        match argument {
+            "transparent" => options.transparent = true,
+            "avatars" => options.node_style = NodeStyle::Avatars,
+            "communities" => options.communities = true,
+            value => anyhow::bail!(
+                "{} is not a recognized option, expected \"transparent\", \"avatars\", or \"communities\"",
+                value,
+            ),
+        }
+    }
 
     if let Some(guild_id) = command.guild_id {
         if let Err(error) =
@@ -525,7 +662,7 @@ async fn command_graph_from_message(
         }
     }
 
-    command_graph(context, command, color_scheme, transparent).await
+    command_graph(context, command, options).await
 }
 
 async fn command_graph_from_interaction(
@@ -549,6 +686,117 @@ async fn command_graph_from_interaction(
         (ColorScheme::Dark, false)
     };
 
+    let node_style = if let Some(CommandDataOption {
+        value: CommandOptionValue::String(nodes),
+        ..
+    }) = options.iter().find(|i| i.name == "nodes")
+    {
+        match nodes.as_str() {
+            "names" => NodeStyle::Names,
+            "avatars" => NodeStyle::Avatars,
+            _ => anyhow::bail!("{} is not a recognized node style", nodes),
+        }
+    } else {
+        NodeStyle::Names
+    };
+
+    let communities = options
+        .iter()
+        .find_map(|option| match option {
+            CommandDataOption {
+                name,
+                value: CommandOptionValue::Boolean(communities),
+            } if name == "communities" => Some(*communities),
+            _ => None,
+        })
+        .unwrap_or(false);
+
+    if let Some(guild_id) = command.guild_id {
+        if let Err(error) =
+            stats::record_graph_command(context, guild_id, stats::CommandType::Slash).await
+        {
+            warn!(?error, "failed to record graph request");
+        }
+    }
+
+    let user = options.iter().find_map(|option| match option {
+        CommandDataOption {
+            name,
+            value: CommandOptionValue::User(user_id),
+        } if name == "user" => Some(*user_id),
+        _ => None,
+    });
+
+    let depth = options
+        .iter()
+        .find_map(|option| match option {
+            CommandDataOption {
+                name,
+                value: CommandOptionValue::Integer(depth),
+            } if name == "depth" => Some((*depth).clamp(1, MAX_EGO_DEPTH) as usize),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_EGO_DEPTH);
+
+    let channel = options.iter().find_map(|option| match option {
+        CommandDataOption {
+            name,
+            value: CommandOptionValue::Channel(channel_id),
+        } if name == "channel" => Some(*channel_id),
+        _ => None,
+    });
+
+    let category = options.iter().find_map(|option| match option {
+        CommandDataOption {
+            name,
+            value: CommandOptionValue::Channel(category_id),
+        } if name == "category" => Some(*category_id),
+        _ => None,
+    });
+
+    let at = options
+        .iter()
+        .find_map(|option| match option {
+            CommandDataOption {
+                name,
+                value: CommandOptionValue::String(at),
+            } if name == "at" => Some(at),
+            _ => None,
+        })
+        .map(|at| {
+            Date::parse(at.trim(), SNAPSHOT_DATE_FORMAT)
+                .map_err(|_| anyhow!("{} is not a valid date, use YYYY-MM-DD", at))
+        })
+        .transpose()?;
+
+    let scope = match (channel, category) {
+        (Some(_), Some(_)) => anyhow::bail!("only one of channel or category can be used"),
+        (Some(channel_id), None) => GraphScope::Channel(channel_id),
+        (None, Some(category_id)) => GraphScope::Category(category_id),
+        (None, None) => GraphScope::Guild,
+    };
+
+    let options = GraphOptions {
+        color_scheme,
+        transparent,
+        node_style,
+        communities,
+        user,
+        depth,
+        scope,
+        at,
+    };
+
+    command_graph(context, command, options).await
+}
+
+async fn command_show_connections(
+    context: &Context,
+    command: &CommandContext,
+    target_id: Option<Id<GenericMarker>>,
+) -> Result<CommandResponse> {
+    let user_id = target_id.context("no target user")?.cast();
+
     if let Some(guild_id) = command.guild_id {
         if let Err(error) =
             stats::record_graph_command(context, guild_id, stats::CommandType::Slash).await
@@ -557,74 +805,248 @@ async fn command_graph_from_interaction(
         }
     }
 
-    command_graph(context, command, color_scheme, transparent).await
+    let options = GraphOptions {
+        color_scheme: ColorScheme::Dark,
+        transparent: false,
+        node_style: NodeStyle::Names,
+        communities: false,
+        user: Some(user_id),
+        depth: DEFAULT_EGO_DEPTH,
+        scope: GraphScope::Guild,
+        at: None,
+    };
+
+    command_graph(context, command, options).await
 }
 
 async fn command_graph(
     context: &Context,
     command: &CommandContext,
-    color_scheme: ColorScheme,
-    transparent: bool,
+    options: GraphOptions,
 ) -> Result<CommandResponse> {
     let guild_id = command.guild_id.context("message not to guild")?;
     let guild_name = context.cache.get_guild(guild_id).await?.name;
-    let attachment_base_name = sanitize_name_for_attachment(&guild_name);
+    let mut attachment_base_name = sanitize_name_for_attachment(&guild_name);
 
-    let graph = {
-        let social = context.social.lock();
+    let (channel_ids, scope_name) = match options.scope {
+        GraphScope::Guild => (None, None),
+        GraphScope::Channel(channel_id) => {
+            let channel = context.cache.get_channel(guild_id, channel_id).await?;
 
-        social
-            .build_guild_graph(guild_id)
-            .context("no graph for guild")?
+            // Threads are part of their parent channel's graph, but used to have their own.
+            let mut channel_ids: HashSet<_> = context
+                .cache
+                .get_child_channels(guild_id, channel_id)
+                .into_iter()
+                .map(|thread| thread.id)
+                .collect();
+
+            channel_ids.insert(channel_id);
+
+            (Some(channel_ids), Some(format!("#{}", channel.name)))
+        }
+        GraphScope::Category(category_id) => {
+            let category = context.cache.get_channel(guild_id, category_id).await?;
+
+            let channels = context.cache.get_child_channels(guild_id, category_id);
+
+            let threads = channels
+                .iter()
+                .flat_map(|channel| context.cache.get_child_channels(guild_id, channel.id));
+
+            let channel_ids = channels
+                .iter()
+                .cloned()
+                .chain(threads)
+                .map(|channel| channel.id)
+                .collect();
+
+            (Some(channel_ids), Some(category.name))
+        }
     };
 
-    let dot_result = graph
-        .to_dot(
-            context,
-            guild_id,
-            Some(&command.author),
-            color_scheme,
-            transparent,
-            &context.font_name,
-        )
-        .await;
+    let (graph, snapshot_timestamp) = match options.at {
+        Some(at) => {
+            let snapshots = context
+                .snapshots
+                .clone()
+                .context("graph history is not enabled")?;
+
+            // Use the last snapshot taken before the end of the requested day.
+            let at = (at.next_day().context("date out of range")?)
+                .midnight()
+                .assume_utc()
+                .unix_timestamp();
+            let at = (at.max(0) as u64 * 1000).saturating_sub(1);
+
+            let snapshot = tokio::task::spawn_blocking(move || {
+                snapshots
+                    .find(guild_id, at)?
+                    .map(|timestamp| snapshots.load(guild_id, timestamp))
+                    .transpose()
+            })
+            .await??;
+
+            let snapshot = match snapshot {
+                Some(snapshot) => snapshot,
+                None => {
+                    return Ok(CommandResponse {
+                        content: Some(
+                            "I don't have any history for this server from that far back.".into(),
+                        ),
+                        attachments: vec![],
+                        embeds: vec![],
+                    })
+                }
+            };
+
+            (
+                snapshot.build_graph(channel_ids.as_ref()),
+                Some(snapshot.timestamp),
+            )
+        }
+        None => {
+            let now = context.clock.now();
+
+            let graph = match &channel_ids {
+                Some(channel_ids) => {
+                    context
+                        .social
+                        .build_channels_graph(guild_id, channel_ids, now)
+                }
+                None => context.social.build_guild_graph(guild_id, now),
+            }
+            .context("no graph for guild")?;
 
-    let dot = match dot_result {
-        Ok(dot) => dot,
+            (graph, None)
+        }
+    };
+
+    let snapshot_date = snapshot_timestamp
+        .map(|timestamp| -> Result<String> {
+            let date = OffsetDateTime::from_unix_timestamp((timestamp / 1000) as i64)?.date();
+
+            Ok(date.format(SNAPSHOT_DATE_FORMAT)?)
+        })
+        .transpose()?;
+
+    let mut display_graph = match graph.to_display_graph(context, guild_id).await {
+        Ok(display_graph) => display_graph,
         Err(error) => {
-            return match error.downcast_ref::<ToDotError>() {
-                Some(ToDotError::NoUsers) => Ok(CommandResponse {
-                    content: Some(
-                        "Hi there, welcome to DiscoGraph!\n\n\
-                        I don't have enough data to display a graph for this server yet, please try again in a couple of days.".into()),
+            // The usual welcome message doesn't make sense when they've asked for part of the guild.
+            if let (Some(scope_name), Some(ToDotError::NoUsers | ToDotError::NotEnoughUsers)) =
+                (&scope_name, error.downcast_ref::<ToDotError>())
+            {
+                return Ok(CommandResponse {
+                    content: Some(format!(
+                        "I don't have enough data to display a graph for {} yet, please try again later.",
+                        scope_name,
+                    )),
                     attachments: vec![],
                     embeds: vec![],
-                }),
-                Some(ToDotError::NotEnoughUsers) => Ok(CommandResponse {
-                    content: Some(format!(
-                        "Hi there, welcome to DiscoGraph!\n\n\
-                        I'm still learning about the conversations that happen in this server, please try again in a couple more days.\n\
-                        If you want to see what I've got so far anyway, please check out <https://discograph.gg/server/{}>.", guild_id.get())),
+                });
+            }
+
+            return display_graph_error_response(error, guild_id);
+        }
+    };
+
+    if let Some(scope_name) = &scope_name {
+        attachment_base_name.push('_');
+        attachment_base_name.push_str(&sanitize_name_for_attachment(scope_name));
+    }
+
+    let mut subject = None;
+    let mut highlight = command.author.id;
+
+    if let Some(user_id) = options.user {
+        display_graph = match display_graph.ego_graph(user_id, options.depth) {
+            Some(ego_graph) => ego_graph,
+            None => {
+                return Ok(CommandResponse {
+                    content: Some(
+                        "I haven't seen that user interact with anyone enough to show their connections yet.".into(),
+                    ),
                     attachments: vec![],
                     embeds: vec![],
-                }),
-                None => Err(error.context("Internal error while creating graph, please try again later")),
+                })
             }
+        };
+
+        subject = Some(display_graph.nodes[&user_id].name.clone());
+        highlight = user_id;
+    }
+
+    if let Some(subject) = &subject {
+        attachment_base_name.push('_');
+        attachment_base_name.push_str(&sanitize_name_for_attachment(subject));
+    }
+
+    if let Some(snapshot_date) = &snapshot_date {
+        attachment_base_name.push('_');
+        attachment_base_name.push_str(snapshot_date);
+    }
+
+    let mut heading = match (&subject, &scope_name) {
+        (Some(subject), Some(scope_name)) => {
+            format!("Connections of {} in {}", subject, scope_name)
         }
+        (Some(subject), None) => format!("Connections of {}", subject),
+        (None, Some(scope_name)) => scope_name.clone(),
+        (None, None) => String::new(),
     };
 
-    let png = render_dot(&dot).await?;
+    if let Some(snapshot_date) = &snapshot_date {
+        if heading.is_empty() {
+            heading = format!("As of {}", snapshot_date);
+        } else {
+            heading.push_str(&format!(" as of {}", snapshot_date));
+        }
+    }
 
-    let png = if transparent {
-        add_png_shadow(&png, color_scheme).await?
+    let heading = if heading.is_empty() {
+        None
     } else {
-        png
+        Some(heading)
     };
 
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    let mut embeds = vec![];
+
+    if options.communities {
+        let edges = display_graph.edges.clone();
+        let communities =
+            tokio::task::spawn_blocking(move || analysis::detect_communities(&edges)).await?;
+
+        for (user_id, node) in display_graph.nodes.iter_mut() {
+            node.color = Some(render::community_color(communities.membership[user_id]));
+        }
+
+        embeds.push(communities_embed(&display_graph, &communities));
+    }
+
+    let title = graph_title(context, guild_id, &command.author, heading.as_deref()).await?;
+
+    let (_, png) = render_graph(
+        context,
+        &display_graph,
+        RenderOptions {
+            color_scheme: options.color_scheme,
+            node_style: options.node_style,
+            transparent: options.transparent,
+            font_family: context.fonts.family(),
+            title: Some(title),
+            highlight: Some(highlight),
+            edge_labels: options.user.is_some(),
+            bounds: None,
+            edge_styles: None,
+        },
+    )
+    .await?;
+
+    let timestamp = match snapshot_timestamp {
+        Some(snapshot_timestamp) => snapshot_timestamp / 1000,
+        None => context.clock.now() / 1000,
+    };
 
     Ok(CommandResponse {
         content: Some(format!(
@@ -637,6 +1059,662 @@ async fn command_graph(
             png,
             0,
         )],
+        embeds,
+    })
+}
+
+/// List the members of each community, in the same colors used in the graph.
+fn communities_embed(display_graph: &DisplayGraph, communities: &Communities) -> Embed {
+    const MAX_GROUPS: usize = 10;
+
+    let fields = communities
+        .groups
+        .iter()
+        .take(MAX_GROUPS)
+        .enumerate()
+        .map(|(index, group)| {
+            let names: Vec<_> = group
+                .iter()
+                .map(|user_id| escape_markdown(&display_graph.nodes[user_id].name))
+                .collect();
+
+            EmbedField {
+                inline: false,
+                name: format!(
+                    "Group {} \u{00b7} #{:06X} \u{00b7} {} members",
+                    index + 1,
+                    render::community_color(index),
+                    group.len(),
+                ),
+                value: join_with_limit(&names, MAX_FIELD_LENGTH),
+            }
+        })
+        .collect();
+
+    let mut description = format!(
+        "Found {} groups of users that mostly talk among themselves (modularity {:.2}).",
+        communities.groups.len(),
+        communities.modularity,
+    );
+
+    if communities.groups.len() > MAX_GROUPS {
+        description.push_str(&format!(" Only the largest {} are listed.", MAX_GROUPS));
+    }
+
+    Embed {
+        author: None,
+        color: None,
+        description: Some(description),
+        fields,
+        footer: None,
+        image: None,
+        kind: "rich".to_string(),
+        provider: None,
+        thumbnail: None,
+        timestamp: None,
+        title: Some("Communities".to_string()),
+        url: None,
+        video: None,
+    }
+}
+
+/// Join `items` into a comma-separated list no longer than `max_length`, ending with how many
+/// didn't fit.
+fn join_with_limit(items: &[String], max_length: usize) -> String {
+    let mut value = String::new();
+
+    for (shown, item) in items.iter().enumerate() {
+        let remaining = items.len() - shown;
+        let more = format!(" and {} more", remaining);
+
+        // Always leave room to say how many didn't fit.
+        if value.len() + item.len() + 2 + more.len() > max_length {
+            value.push_str(&more);
+            break;
+        }
+
+        if !value.is_empty() {
+            value.push_str(", ");
+        }
+
+        value.push_str(item);
+    }
+
+    value
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '[' | ']') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+async fn command_timelapse_from_interaction(
+    context: &Context,
+    command: &CommandContext,
+    options: &[CommandDataOption],
+) -> Result<CommandResponse> {
+    let period = if let Some(CommandDataOption {
+        value: CommandOptionValue::String(period),
+        ..
+    }) = options.iter().find(|i| i.name == "period")
+    {
+        match period.as_str() {
+            "day" => TimelapsePeriod::Day,
+            "week" => TimelapsePeriod::Week,
+            "month" => TimelapsePeriod::Month,
+            _ => anyhow::bail!("{} is not a recognized timelapse period", period),
+        }
+    } else {
+        TimelapsePeriod::Week
+    };
+
+    command_timelapse(context, command, period).await
+}
+
+/// Replay the guild's history from the events table, drawing a frame every `period`.
+async fn command_timelapse(
+    context: &Context,
+    command: &CommandContext,
+    period: TimelapsePeriod,
+) -> Result<CommandResponse> {
+    let guild_id = command.guild_id.context("message not to guild")?;
+    let guild_name = context.cache.get_guild(guild_id).await?.name;
+
+    let pool = context
+        .pool
+        .as_ref()
+        .context("graph history is not enabled")?;
+
+    let half_life = context.social.get_effective_half_life(guild_id);
+
+    let now = context.clock.now();
+
+    let period_millis = period.as_millis();
+    let first_frame = now.saturating_sub((MAX_TIMELAPSE_FRAMES - 1) * period_millis);
+    let frames: Vec<_> = (0..MAX_TIMELAPSE_FRAMES)
+        .map(|index| first_frame + (index * period_millis))
+        .collect();
+
+    // Start a few half-lives early so the first frame includes relationships that already existed.
+    let since = first_frame.saturating_sub(4 * half_life.as_millis() as u64);
+    let (replay, _) = history::process_guild_events(
+        pool,
+        guild_id,
+        since,
+        FrameReplay::new(frames.clone(), half_life),
+        FrameReplay::add,
+    )
+    .await?;
+    let graphs = replay.finish();
+
+    // Lay out everyone that appears at any point, so that nodes don't move between frames.
+    let combined_graph = UserRelationshipGraphMap::strongest_of(&graphs);
+
+    let display_graph = match combined_graph.to_display_graph(context, guild_id).await {
+        Ok(display_graph) => display_graph,
+        Err(error) => return display_graph_error_response(error, guild_id),
+    };
+
+    let positions = display_graph.layout(NodeStyle::Names).await?;
+    let bounds = render::graph_bounds(&display_graph, &positions, NodeStyle::Names);
+
+    let title = graph_title(context, guild_id, &command.author, None).await?;
+
+    let mut svgs = vec![];
+    for (frame, graph) in frames.into_iter().zip(graphs) {
+        let frame_graph = display_graph.with_edges(graph.visible_edges());
+
+        // Skip over the time before we knew anything about the server.
+        if frame_graph.nodes.is_empty() && svgs.is_empty() {
+            continue;
+        }
+
+        let date = OffsetDateTime::from_unix_timestamp((frame / 1000) as i64)?
+            .date()
+            .format(SNAPSHOT_DATE_FORMAT)?;
+
+        svgs.push(render::render_svg(
+            &frame_graph,
+            &positions,
+            &HashMap::new(),
+            &RenderOptions {
+                color_scheme: ColorScheme::Dark,
+                node_style: NodeStyle::Names,
+                transparent: false,
+                font_family: context.fonts.family(),
+                title: Some(format!("{} \u{00b7} {}", date, title)),
+                highlight: Some(command.author.id),
+                edge_labels: false,
+                bounds: Some(bounds),
+                edge_styles: None,
+            },
+        ));
+    }
+
+    if svgs.is_empty() {
+        return Ok(CommandResponse {
+            content: Some(
+                "I don't have any history to replay for this server yet, please try again in a couple of days.".into(),
+            ),
+            attachments: vec![],
+            embeds: vec![],
+        });
+    }
+
+    let gif = render::render_gif(context.fonts.clone(), svgs, TIMELAPSE_FRAME_DELAY).await?;
+
+    Ok(CommandResponse {
+        content: None,
+        attachments: vec![Attachment::from_bytes(
+            sanitize_name_for_attachment(&guild_name) + "_timelapse.gif",
+            gif,
+            0,
+        )],
+        embeds: vec![],
+    })
+}
+
+async fn command_diff_from_interaction(
+    context: &Context,
+    command: &CommandContext,
+    options: &[CommandDataOption],
+) -> Result<CommandResponse> {
+    let days = options
+        .iter()
+        .find_map(|option| match option {
+            CommandDataOption {
+                name,
+                value: CommandOptionValue::Integer(days),
+            } if name == "days" => Some((*days).clamp(1, MAX_DIFF_DAYS) as u64),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_DIFF_DAYS);
+
+    command_diff(context, command, days).await
+}
+
+/// Draw the current graph with what changed over the last `days` highlighted.
+async fn command_diff(
+    context: &Context,
+    command: &CommandContext,
+    days: u64,
+) -> Result<CommandResponse> {
+    let guild_id = command.guild_id.context("message not to guild")?;
+    let guild_name = context.cache.get_guild(guild_id).await?.name;
+
+    let now = context.clock.now();
+
+    let then = now.saturating_sub(days * 24 * 60 * 60 * 1000);
+
+    let (before, after) = match load_graphs_to_compare(context, guild_id, then, now).await? {
+        Some(graphs) => graphs,
+        None => {
+            return Ok(CommandResponse {
+                content: Some(
+                    "I don't have any history for this server from that far back.".into(),
+                ),
+                attachments: vec![],
+                embeds: vec![],
+            })
+        }
+    };
+
+    // Look up everyone that was in either graph.
+    let combined_graph = UserRelationshipGraphMap::strongest_of([&before, &after]);
+    let display_graph = match combined_graph.to_display_graph(context, guild_id).await {
+        Ok(display_graph) => display_graph,
+        Err(error) => return display_graph_error_response(error, guild_id),
+    };
+
+    // Only compare the users we're able to display.
+    let displayable_edges = |graph: &UserRelationshipGraphMap| -> Vec<_> {
+        graph
+            .visible_edges()
+            .into_iter()
+            .filter(|([source, target], _)| {
+                display_graph.nodes.contains_key(source) && display_graph.nodes.contains_key(target)
+            })
+            .collect()
+    };
+
+    let after_edges = displayable_edges(&after);
+    let diff = analysis::diff_graphs(&displayable_edges(&before), &after_edges);
+
+    let date = OffsetDateTime::from_unix_timestamp((then / 1000) as i64)?
+        .date()
+        .format(SNAPSHOT_DATE_FORMAT)?;
+
+    if diff.is_empty() {
+        return Ok(CommandResponse {
+            content: Some(format!("Nothing much has changed since {}.", date)),
+            attachments: vec![],
+            embeds: vec![],
+        });
+    }
+
+    // Draw the graph as it is now, plus anything that has faded away since.
+    let mut edges = after_edges;
+    edges.extend(diff.removed.iter().cloned());
+    edges.sort_unstable_by_key(|(key, _)| *key);
+
+    let display_graph = display_graph.with_edges(edges);
+
+    let edge_styles: HashMap<_, _> = diff
+        .added
+        .iter()
+        .map(|(key, _)| (*key, EdgeStyle::Added))
+        .chain(
+            diff.removed
+                .iter()
+                .map(|(key, _)| (*key, EdgeStyle::Removed)),
+        )
+        .collect();
+
+    let heading = format!("Changes since {}", date);
+    let title = graph_title(context, guild_id, &command.author, Some(&heading)).await?;
+
+    let (_, png) = render_graph(
+        context,
+        &display_graph,
+        RenderOptions {
+            color_scheme: ColorScheme::Dark,
+            node_style: NodeStyle::Names,
+            transparent: false,
+            font_family: context.fonts.family(),
+            title: Some(title),
+            highlight: Some(command.author.id),
+            edge_labels: false,
+            bounds: None,
+            edge_styles: Some(&edge_styles),
+        },
+    )
+    .await?;
+
+    Ok(CommandResponse {
+        content: None,
+        attachments: vec![Attachment::from_bytes(
+            format!(
+                "{}_since_{}.png",
+                sanitize_name_for_attachment(&guild_name),
+                date
+            ),
+            png,
+            0,
+        )],
+        embeds: vec![diff_embed(&display_graph, &diff, &heading)],
+    })
+}
+
+/// Get the guild's graph at `then` and `now`.
+///
+/// Snapshots are compared against the live graph if there is one old enough, otherwise both are
+/// rebuilt from the events table so that they're weighted the same way.
+async fn load_graphs_to_compare(
+    context: &Context,
+    guild_id: Id<GuildMarker>,
+    then: u64,
+    now: u64,
+) -> Result<Option<(UserRelationshipGraphMap, UserRelationshipGraphMap)>> {
+    if let Some(snapshots) = context.snapshots.clone() {
+        let snapshot = tokio::task::spawn_blocking(move || {
+            snapshots
+                .find(guild_id, then)?
+                .map(|timestamp| snapshots.load(guild_id, timestamp))
+                .transpose()
+        })
+        .await??;
+
+        if let Some(snapshot) = snapshot {
+            let live_graph = context
+                .social
+                .build_guild_graph(guild_id, now)
+                .context("no graph for guild")?;
+
+            return Ok(Some((snapshot.build_graph(None), live_graph)));
+        }
+    }
+
+    let pool = match &context.pool {
+        Some(pool) => pool,
+        None if context.snapshots.is_some() => return Ok(None),
+        None => anyhow::bail!("graph history is not enabled"),
+    };
+
+    let half_life = context.social.get_effective_half_life(guild_id);
+
+    // Start a few half-lives early so the older graph includes relationships that already existed.
+    let since = then.saturating_sub(4 * half_life.as_millis() as u64);
+    let (replay, _) = history::process_guild_events(
+        pool,
+        guild_id,
+        since,
+        FrameReplay::new(vec![then, now], half_life),
+        FrameReplay::add,
+    )
+    .await?;
+    let mut graphs = replay.finish();
+
+    let after = graphs.pop().context("missing replayed graph")?;
+    let before = graphs.pop().context("missing replayed graph")?;
+
+    Ok(Some((before, after)))
+}
+
+/// Summarise a diff, listing the biggest changes in each category.
+fn diff_embed(display_graph: &DisplayGraph, diff: &GraphDiff, title: &str) -> Embed {
+    let name = |user_id: &Id<UserMarker>| escape_markdown(&display_graph.nodes[user_id].name);
+    let pair =
+        |[source, target]: &[Id<UserMarker>; 2]| format!("{} & {}", name(source), name(target));
+
+    let lists = [
+        (
+            "New connections",
+            diff.added
+                .iter()
+                .map(|(key, _)| pair(key))
+                .collect::<Vec<_>>(),
+        ),
+        (
+            "Getting closer",
+            diff.strengthened
+                .iter()
+                .map(|(key, _, _)| pair(key))
+                .collect(),
+        ),
+        (
+            "Drifting apart",
+            diff.weakened.iter().map(|(key, _, _)| pair(key)).collect(),
+        ),
+        (
+            "Faded away",
+            diff.removed.iter().map(|(key, _)| pair(key)).collect(),
+        ),
+        ("New faces", diff.users_added.iter().map(name).collect()),
+        (
+            "No longer around",
+            diff.users_removed.iter().map(name).collect(),
+        ),
+    ];
+
+    let fields = lists
+        .into_iter()
+        .filter(|(_, items)| !items.is_empty())
+        .map(|(title, items)| EmbedField {
+            inline: false,
+            name: format!("{} \u{00b7} {}", title, items.len()),
+            value: join_with_limit(&items, MAX_FIELD_LENGTH),
+        })
+        .collect();
+
+    Embed {
+        author: None,
+        color: None,
+        description: Some(
+            "New connections are drawn in green, and ones that have faded away are dashed red."
+                .to_string(),
+        ),
+        fields,
+        footer: None,
+        image: None,
+        kind: "rich".to_string(),
+        provider: None,
+        thumbnail: None,
+        timestamp: None,
+        title: Some(title.to_string()),
+        url: None,
+        video: None,
+    }
+}
+
+async fn command_insights(context: &Context, command: &CommandContext) -> Result<CommandResponse> {
+    const TOP_USERS: usize = 5;
+
+    let guild_id = command.guild_id.context("message not to guild")?;
+    let guild_name = context.cache.get_guild(guild_id).await?.name;
+
+    let graph = context
+        .social
+        .build_guild_graph(guild_id, context.clock.now())
+        .context("no graph for guild")?;
+
+    let display_graph = match graph.to_display_graph(context, guild_id).await {
+        Ok(display_graph) => display_graph,
+        Err(error) => return display_graph_error_response(error, guild_id),
+    };
+
+    let edges = display_graph.edges.clone();
+    let (metrics, communities) = tokio::task::spawn_blocking(move || {
+        (
+            analysis::compute_metrics(&edges),
+            analysis::detect_communities(&edges),
+        )
+    })
+    .await?;
+
+    let list_users = |users: Vec<(Id<UserMarker>, UserMetrics)>,
+                      describe: &dyn Fn(&UserMetrics) -> String| {
+        users
+            .iter()
+            .enumerate()
+            .map(|(index, (user_id, user_metrics))| {
+                format!(
+                    "**{}.** {} \u{00b7} {}",
+                    index + 1,
+                    escape_markdown(&display_graph.nodes[user_id].name),
+                    describe(user_metrics),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    let connectors_field = EmbedField {
+        inline: false,
+        name: "Top connectors".to_string(),
+        value: list_users(metrics.top_by(TOP_USERS, |user| user.pagerank), &|user| {
+            format!("{:.1}% of the server's PageRank", user.pagerank * 100.0)
+        }),
+    };
+
+    let bridges_field = EmbedField {
+        inline: false,
+        name: "Bridges".to_string(),
+        value: list_users(
+            metrics.top_by(TOP_USERS, |user| user.betweenness),
+            &|user| {
+                format!(
+                    "on {:.0}% of the shortest paths between others",
+                    user.betweenness * 100.0
+                )
+            },
+        ),
+    };
+
+    let server_field = EmbedField {
+        inline: false,
+        name: "Server".to_string(),
+        value: [
+            format!(
+                "{} users with {} connections between them",
+                metrics.users.len(),
+                metrics.edges,
+            ),
+            format!(
+                "{:.1}% of possible connections exist",
+                metrics.density * 100.0
+            ),
+            format!(
+                "{:.0}% of a user's connections know each other on average",
+                metrics.average_clustering * 100.0,
+            ),
+            format!(
+                "{} communities, modularity {:.2}",
+                communities.groups.len(),
+                communities.modularity,
+            ),
+        ]
+        .join("\n"),
+    };
+
+    let embed = Embed {
+        author: None,
+        color: None,
+        description: None,
+        fields: vec![connectors_field, bridges_field, server_field],
+        footer: None,
+        image: None,
+        kind: "rich".to_string(),
+        provider: None,
+        thumbnail: None,
+        timestamp: None,
+        title: Some(format!("Insights for {}", guild_name)),
+        url: None,
+        video: None,
+    };
+
+    Ok(CommandResponse {
+        content: None,
+        attachments: vec![],
+        embeds: vec![embed],
+    })
+}
+
+async fn command_export_from_interaction(
+    context: &Context,
+    command: &CommandContext,
+    options: &[CommandDataOption],
+) -> Result<CommandResponse> {
+    let format = match options.iter().find(|i| i.name == "format") {
+        Some(CommandDataOption {
+            value: CommandOptionValue::String(format),
+            ..
+        }) => ExportFormat::from_name(format)
+            .with_context(|| format!("{} is not a recognized export format", format))?,
+        _ => anyhow::bail!("missing export format"),
+    };
+
+    command_export(context, command, format).await
+}
+
+/// Attach the guild's graph in a format that can be loaded into other tools.
+async fn command_export(
+    context: &Context,
+    command: &CommandContext,
+    format: ExportFormat,
+) -> Result<CommandResponse> {
+    let guild_id = command.guild_id.context("message not to guild")?;
+
+    // Discord hides the command from anyone else by default, but server admins can change that.
+    let can_manage_guild = command
+        .permissions
+        .is_some_and(|permissions| permissions.contains(Permissions::MANAGE_GUILD));
+
+    if !can_manage_guild {
+        return Ok(CommandResponse {
+            content: Some("You need the Manage Server permission to export the graph.".into()),
+            attachments: vec![],
+            embeds: vec![],
+        });
+    }
+
+    let guild_name = context.cache.get_guild(guild_id).await?.name;
+
+    let graph = context
+        .social
+        .build_guild_graph(guild_id, context.clock.now())
+        .context("no graph for guild")?;
+
+    let display_graph = match graph.to_display_graph(context, guild_id).await {
+        Ok(display_graph) => display_graph,
+        Err(error) => return display_graph_error_response(error, guild_id),
+    };
+
+    let data = format.export(&display_graph);
+
+    Ok(CommandResponse {
+        content: Some(format!(
+            "{} users and {} relationships",
+            display_graph.nodes.len(),
+            display_graph.edges.len(),
+        )),
+        attachments: vec![Attachment::from_bytes(
+            format!(
+                "{}.{}",
+                sanitize_name_for_attachment(&guild_name),
+                format.extension(),
+            ),
+            data.into_bytes(),
+            0,
+        )],
         embeds: vec![],
     })
 }
@@ -718,31 +1796,34 @@ async fn command_dump_with_guild(
     let guild_name = context.cache.get_guild(guild_id).await?.name;
     let attachment_base_name = sanitize_name_for_attachment(&guild_name);
 
-    let graph = {
-        let social = context.social.lock();
-
-        social
-            .build_guild_graph(guild_id)
-            .context("no graph for guild")?
-    };
-
-    let dot = graph
-        .to_dot(
-            context,
-            guild_id,
-            None,
-            ColorScheme::Light,
-            false,
-            &context.font_name,
-        )
-        .await?;
-
-    let png = render_dot(&dot).await?;
+    let graph = context
+        .social
+        .build_guild_graph(guild_id, context.clock.now())
+        .context("no graph for guild")?;
+
+    let display_graph = graph.to_display_graph(context, guild_id).await?;
+
+    let (svg, png) = render_graph(
+        context,
+        &display_graph,
+        RenderOptions {
+            color_scheme: ColorScheme::Light,
+            node_style: NodeStyle::Names,
+            transparent: false,
+            font_family: context.fonts.family(),
+            title: None,
+            highlight: None,
+            edge_labels: false,
+            bounds: None,
+            edge_styles: None,
+        },
+    )
+    .await?;
 
     Ok(CommandResponse {
         content: None,
         attachments: vec![
-            Attachment::from_bytes(attachment_base_name.clone() + ".dot", dot.into_bytes(), 0),
+            Attachment::from_bytes(attachment_base_name.clone() + ".svg", svg.into_bytes(), 0),
             Attachment::from_bytes(attachment_base_name + ".png", png, 1),
         ],
         embeds: vec![],
@@ -750,10 +1831,7 @@ async fn command_dump_with_guild(
 }
 
 async fn command_dump_without_guild(context: &Context) -> Result<CommandResponse> {
-    let guild_ids = {
-        let social = context.social.lock();
-        social.get_all_guild_ids()
-    };
+    let guild_ids = context.social.get_all_guild_ids();
 
     let guild_futures = guild_ids
         .into_iter()
@@ -777,6 +1855,205 @@ async fn command_dump_without_guild(context: &Context) -> Result<CommandResponse
     })
 }
 
+async fn command_engines(
+    context: &Context,
+    command: &CommandContext,
+    mut arguments: Arguments<'_>,
+) -> Result<CommandResponse> {
+    if !context.owners.contains(&command.author.id) {
+        info!(
+            "{} tried to run engines command but isn't an owner",
+            command.author.id,
+        );
+
+        return Ok(CommandResponse {
+            content: None,
+            attachments: vec![],
+            embeds: vec![],
+        });
+    }
+
+    let guild_id = command.guild_id.context("message not to guild")?;
+
+    if let Some(name) = arguments.next() {
+        let enabled = match arguments.next() {
+            Some("on") => true,
+            Some("off") => false,
+            Some(value) => anyhow::bail!(
+                "{} is not a recognized engine state, expected \"on\" or \"off\"",
+                value,
+            ),
+            None => anyhow::bail!("missing engine state, expected \"on\" or \"off\""),
+        };
+
+        if !context.social.set_engine_enabled(guild_id, name, enabled) {
+            anyhow::bail!("{} is not a recognized inference engine", name);
+        }
+    }
+
+    let engines = context.social.get_engines(guild_id);
+
+    let lines: Vec<_> = engines
+        .into_iter()
+        .map(|(name, enabled)| format!("`{}` {}", name, if enabled { "on" } else { "off" }))
+        .collect();
+
+    Ok(CommandResponse {
+        content: Some(format!("Inference engines:\n{}", lines.join("\n"))),
+        attachments: vec![],
+        embeds: vec![],
+    })
+}
+
+async fn command_half_life(
+    context: &Context,
+    command: &CommandContext,
+    mut arguments: Arguments<'_>,
+) -> Result<CommandResponse> {
+    if !context.owners.contains(&command.author.id) {
+        info!(
+            "{} tried to run halflife command but isn't an owner",
+            command.author.id,
+        );
+
+        return Ok(CommandResponse {
+            content: None,
+            attachments: vec![],
+            embeds: vec![],
+        });
+    }
+
+    let guild_id = command.guild_id.context("message not to guild")?;
+
+    match arguments.next() {
+        Some("default") => context.social.set_half_life(guild_id, None),
+        Some(days) => {
+            let days: f64 = days.parse()?;
+            if !days.is_finite() || days <= 0.0 {
+                anyhow::bail!("half-life must be greater than 0 days");
+            }
+
+            let half_life = Duration::from_secs_f64(days * 24.0 * 60.0 * 60.0);
+            context.social.set_half_life(guild_id, Some(half_life));
+        }
+        None => (),
+    }
+
+    let days = context.social.get_half_life(guild_id).as_secs_f64() / (24.0 * 60.0 * 60.0);
+    let effective_days = context
+        .social
+        .get_effective_half_life(guild_id)
+        .as_secs_f64()
+        / (24.0 * 60.0 * 60.0);
+
+    let mut content = format!(
+        "Relationship half-life is {:.1} days ({:.1} days after adapting to guild size)",
+        days, effective_days,
+    );
+
+    if let Some(activity) = context.social.get_activity(guild_id) {
+        let now = context.clock.now();
+
+        content.push_str(&format!(
+            "\n{} users, {} relationships, {:.1} messages per hour, reinforcement scaled by {:.2}",
+            activity.nodes(),
+            activity.edges(),
+            activity.message_rate(now),
+            activity.reinforcement_scale(now),
+        ));
+    }
+
+    Ok(CommandResponse {
+        content: Some(content),
+        attachments: vec![],
+        embeds: vec![],
+    })
+}
+
+async fn command_rebuild(
+    context: &Context,
+    command: &CommandContext,
+    mut arguments: Arguments<'_>,
+) -> Result<CommandResponse> {
+    if !context.owners.contains(&command.author.id) {
+        info!(
+            "{} tried to run rebuild command but isn't an owner",
+            command.author.id,
+        );
+
+        return Ok(CommandResponse {
+            content: None,
+            attachments: vec![],
+            embeds: vec![],
+        });
+    }
+
+    let pool = context
+        .pool
+        .as_ref()
+        .context("graph history is not enabled")?;
+
+    let guild_ids = match arguments.next() {
+        Some("all") => history::load_event_guild_ids(pool).await?,
+        Some(guild_id) => {
+            let guild_id: u64 = guild_id.parse()?;
+            vec![Id::new_checked(guild_id).context("invalid guild id")?]
+        }
+        None => vec![command.guild_id.context("message not to guild")?],
+    };
+
+    let mut total_events = 0;
+    let mut total_channels = 0;
+
+    for &guild_id in &guild_ids {
+        // This has to start before the events are loaded, so nothing is missed in between.
+        let rebuild = context
+            .social
+            .begin_rebuild(guild_id)
+            .with_context(|| format!("{} is already being rebuilt", guild_id))?;
+
+        let half_life = context.social.get_half_life(guild_id);
+
+        let ((rebuilder, loaded), events) = history::process_guild_events(
+            pool,
+            guild_id,
+            0,
+            (GuildRebuilder::new(half_life), rebuild.start_loading()),
+            |(rebuilder, loaded), events| {
+                rebuilder.add(events);
+                loaded.add(events);
+            },
+        )
+        .await?;
+        total_events += events;
+
+        let now = context.clock.now();
+
+        let (graphs, activity) = rebuilder.finish(now);
+
+        total_channels += graphs.len();
+
+        info!(
+            "rebuilt {} channel graphs for {} from history",
+            graphs.len(),
+            guild_id,
+        );
+
+        rebuild.finish(graphs, activity, loaded);
+    }
+
+    Ok(CommandResponse {
+        content: Some(format!(
+            "Rebuilt {} channel graphs in {} guilds from {} events",
+            total_channels,
+            guild_ids.len(),
+            total_events,
+        )),
+        attachments: vec![],
+        embeds: vec![],
+    })
+}
+
 fn sanitize_name_for_attachment(name: &str) -> String {
     let mut string = String::with_capacity(name.len());
     let mut prev_escaped = false;
@@ -794,74 +2071,86 @@ fn sanitize_name_for_attachment(name: &str) -> String {
     string
 }
 
-async fn render_dot(dot: &str) -> Result<Vec<u8>> {
-    let mut graphviz = process::Command::new("dot")
-        .arg("-v")
-        .arg("-Tpng")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    {
-        let stdin = graphviz.stdin.as_mut().unwrap();
-        stdin.write_all(dot.as_bytes()).await?;
-    }
-
-    let output = graphviz.wait_with_output().await?;
-
-    if !output.status.success() {
-        anyhow::bail!("graphviz failed");
+/// Turn a failure to build a display graph into a friendly response if it was due to a lack of data.
+fn display_graph_error_response(
+    error: anyhow::Error,
+    guild_id: Id<GuildMarker>,
+) -> Result<CommandResponse> {
+    match error.downcast_ref::<ToDotError>() {
+        Some(ToDotError::NoUsers) => Ok(CommandResponse {
+            content: Some(
+                "Hi there, welcome to DiscoGraph!\n\n\
+                I don't have enough data to display a graph for this server yet, please try again in a couple of days.".into()),
+            attachments: vec![],
+            embeds: vec![],
+        }),
+        Some(ToDotError::NotEnoughUsers) => Ok(CommandResponse {
+            content: Some(format!(
+                "Hi there, welcome to DiscoGraph!\n\n\
+                I'm still learning about the conversations that happen in this server, please try again in a couple more days.\n\
+                If you want to see what I've got so far anyway, please check out <https://discograph.gg/server/{}>.", guild_id.get())),
+            attachments: vec![],
+            embeds: vec![],
+        }),
+        None => Err(error.context("Internal error while creating graph, please try again later")),
     }
-
-    Ok(output.stdout)
 }
 
-async fn add_png_shadow(input: &[u8], color_scheme: ColorScheme) -> Result<Vec<u8>> {
-    let background_color = match color_scheme {
-        ColorScheme::Light => 0xFFFFFF,
-        ColorScheme::Dark => 0x313338,
+/// Lay out and draw a graph, returning both the SVG and the rendered PNG.
+async fn render_graph(
+    context: &Context,
+    display_graph: &DisplayGraph,
+    options: RenderOptions<'_>,
+) -> Result<(String, Vec<u8>)> {
+    let positions = display_graph.layout(options.node_style).await?;
+
+    let avatars = match options.node_style {
+        NodeStyle::Names => HashMap::new(),
+        NodeStyle::Avatars => avatar::fetch_avatars(&*context.avatars, display_graph).await,
     };
 
-    let mut convert = process::Command::new("convert")
-        .arg("png:-")
-        .arg("-background")
-        .arg("none")
-        .arg("(")
-        .arg("+clone")
-        .arg("-background")
-        .arg(format!("#{:06X}", background_color))
-        .arg("-shadow")
-        .arg("200x2+0+0")
-        .arg(")")
-        .arg("-background")
-        .arg("none")
-        .arg("-compose")
-        .arg("DstOver")
-        .arg("-flatten")
-        .arg("png:-")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+    let svg = render::render_svg(display_graph, &positions, &avatars, &options);
+    let png = render::render_png(context.fonts.clone(), svg.clone()).await?;
 
-    {
-        let stdin = convert.stdin.as_mut().unwrap();
-        stdin.write_all(input).await?;
-    }
+    Ok((svg, png))
+}
 
-    let output = convert.wait_with_output().await?;
+async fn graph_title(
+    context: &Context,
+    guild_id: Id<GuildMarker>,
+    user: &User,
+    heading: Option<&str>,
+) -> Result<String> {
+    let guild = context.cache.get_guild(guild_id).await?;
+
+    let member = context
+        .cache
+        .get_member(guild_id, context.user.id)
+        .await
+        .ok();
 
-    if !output.status.success() {
-        anyhow::bail!("convert failed");
-    }
+    let nickname = match &member {
+        Some(CachedMember {
+            nick: Some(nick), ..
+        }) => nick,
+        _ => &context.user.name,
+    };
+
+    // TODO: Add a timestamp.
+    let title = format!(
+        "Generated for {}#{:04} by {} in {}",
+        user.name, user.discriminator, nickname, guild.name,
+    );
 
-    Ok(output.stdout)
+    Ok(match heading {
+        Some(heading) => format!("{} \u{00b7} {}", heading, title),
+        None => title,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::sanitize_name_for_attachment;
+    use super::{escape_markdown, sanitize_name_for_attachment};
 
     #[test]
     fn test_sanitize_name_for_attachment() {
@@ -870,4 +2159,10 @@ mod tests {
             "Name_With_Spaces"
         );
     }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!(escape_markdown("plain name"), "plain name");
+        assert_eq!(escape_markdown("*bold_ish*"), "\\*bold\\_ish\\*");
+    }
 }
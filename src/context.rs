@@ -10,8 +10,14 @@ use twilight_model::user::CurrentUser;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+use crate::avatar::AvatarSource;
 use crate::cache::Cache;
+use crate::render::Fonts;
 use crate::social::graph::SocialGraph;
+use crate::social::inference::Clock;
+use crate::social::replay::InteractionRecorder;
+use crate::social::snapshot::SnapshotStore;
+use crate::social::EventWrites;
 
 #[derive(Clone)]
 pub struct Context {
@@ -22,9 +28,14 @@ pub struct Context {
     pub management_guild: Option<Id<GuildMarker>>,
     pub http: Arc<Client>,
     pub cache: Arc<Cache>,
-    pub social: Arc<Mutex<SocialGraph>>,
+    pub social: Arc<SocialGraph>,
+    pub clock: Arc<dyn Clock>,
+    pub recorder: Option<Arc<InteractionRecorder>>,
+    pub event_writes: Arc<EventWrites>,
     pub pool: Option<MySqlPool>,
-    pub font_name: String,
+    pub fonts: Arc<Fonts>,
+    pub avatars: Arc<dyn AvatarSource>,
+    pub snapshots: Option<Arc<SnapshotStore>>,
     pub guilds_with_broken_commands: Arc<Mutex<HashMap<Id<GuildMarker>, Option<Instant>>>>,
     pub channels_with_debug_enabled: Arc<Mutex<HashSet<Id<ChannelMarker>>>>,
 }
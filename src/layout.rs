@@ -0,0 +1,253 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LayoutNode {
+    /// Approximate size of the node once drawn, used to stop nodes overlapping.
+    pub radius: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LayoutEdge {
+    pub source: usize,
+    pub target: usize,
+    pub weight: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayoutConfig {
+    pub iterations: usize,
+    /// The distance we'd like connected nodes to settle at.
+    pub ideal_edge_length: f32,
+    /// Pulls everything towards the center, stops disconnected groups drifting apart.
+    pub gravity: f32,
+    /// Extra space to leave between nodes when removing overlaps.
+    pub overlap_padding: f32,
+    /// The layout returns whatever it has so far once this has elapsed.
+    pub timeout: Duration,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        LayoutConfig {
+            iterations: 300,
+            ideal_edge_length: 120.0,
+            gravity: 0.05,
+            overlap_padding: 8.0,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+const OVERLAP_REMOVAL_PASSES: usize = 100;
+
+/// Fruchterman-Reingold style force-directed layout.
+///
+/// The initial positions are derived from node order, so the result is deterministic as long as
+/// the nodes are passed in a stable order and the timeout isn't hit.
+pub fn layout(nodes: &[LayoutNode], edges: &[LayoutEdge], config: &LayoutConfig) -> Vec<Point> {
+    let deadline = Instant::now() + config.timeout;
+    let count = nodes.len();
+    let k = config.ideal_edge_length;
+
+    let mut positions = initial_positions(count, k);
+    let mut displacements = vec![Point::default(); count];
+
+    let mut temperature = k * (count as f32).sqrt();
+    let cooling = temperature / (config.iterations + 1) as f32;
+
+    for _ in 0..config.iterations {
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        displacements.fill(Point::default());
+
+        // Every node repels every other node.
+        for i in 0..count {
+            for j in (i + 1)..count {
+                let (dx, dy, distance) = offset(positions[i], positions[j]);
+                let force = (k * k) / distance;
+
+                displacements[i].x += (dx / distance) * force;
+                displacements[i].y += (dy / distance) * force;
+                displacements[j].x -= (dx / distance) * force;
+                displacements[j].y -= (dy / distance) * force;
+            }
+        }
+
+        // Connected nodes attract, more strongly the heavier the edge.
+        for edge in edges {
+            let (dx, dy, distance) = offset(positions[edge.source], positions[edge.target]);
+            let force = ((distance * distance) / k) * edge_strength(edge.weight);
+
+            displacements[edge.source].x -= (dx / distance) * force;
+            displacements[edge.source].y -= (dy / distance) * force;
+            displacements[edge.target].x += (dx / distance) * force;
+            displacements[edge.target].y += (dy / distance) * force;
+        }
+
+        for (position, displacement) in positions.iter_mut().zip(displacements.iter_mut()) {
+            displacement.x -= position.x * config.gravity;
+            displacement.y -= position.y * config.gravity;
+
+            // Limit how far anything can move by the current temperature.
+            let length = (displacement.x * displacement.x + displacement.y * displacement.y).sqrt();
+            if length > 0.0 {
+                let limited = length.min(temperature);
+                position.x += (displacement.x / length) * limited;
+                position.y += (displacement.y / length) * limited;
+            }
+        }
+
+        temperature = (temperature - cooling).max(0.0);
+    }
+
+    remove_overlaps(&mut positions, nodes, config.overlap_padding, deadline);
+
+    positions
+}
+
+/// Spread the nodes out on a sunflower spiral, which avoids any two starting in the same place.
+fn initial_positions(count: usize, spacing: f32) -> Vec<Point> {
+    let golden_angle = std::f32::consts::PI * (3.0 - 5.0f32.sqrt());
+
+    (0..count)
+        .map(|i| {
+            let radius = spacing * 0.5 * (i as f32 + 0.5).sqrt();
+            let angle = i as f32 * golden_angle;
+
+            Point {
+                x: radius * angle.cos(),
+                y: radius * angle.sin(),
+            }
+        })
+        .collect()
+}
+
+/// Vector from `b` to `a`, and its length (never zero, to avoid dividing by it).
+fn offset(a: Point, b: Point) -> (f32, f32, f32) {
+    let dx = a.x - b.x;
+    let dy = a.y - b.y;
+    let distance = (dx * dx + dy * dy).sqrt();
+
+    if distance < 0.01 {
+        // Nudge coincident nodes apart in a fixed direction to stay deterministic.
+        (0.01, 0.0, 0.01)
+    } else {
+        (dx, dy, distance)
+    }
+}
+
+fn edge_strength(weight: f32) -> f32 {
+    1.0 + weight.max(1.0).log10()
+}
+
+/// Push apart any nodes that would be drawn on top of each other.
+fn remove_overlaps(positions: &mut [Point], nodes: &[LayoutNode], padding: f32, deadline: Instant) {
+    for _ in 0..OVERLAP_REMOVAL_PASSES {
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        let mut moved = false;
+
+        for i in 0..positions.len() {
+            for j in (i + 1)..positions.len() {
+                let minimum = nodes[i].radius + nodes[j].radius + padding;
+                let (dx, dy, distance) = offset(positions[i], positions[j]);
+
+                if distance >= minimum {
+                    continue;
+                }
+
+                let push = (minimum - distance) / 2.0;
+                positions[i].x += (dx / distance) * push;
+                positions[i].y += (dy / distance) * push;
+                positions[j].x -= (dx / distance) * push;
+                positions[j].y -= (dy / distance) * push;
+
+                moved = true;
+            }
+        }
+
+        if !moved {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{layout, LayoutConfig, LayoutEdge, LayoutNode};
+
+    fn distance(a: super::Point, b: super::Point) -> f32 {
+        ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let nodes = vec![LayoutNode { radius: 20.0 }; 10];
+        let edges: Vec<_> = (1..10)
+            .map(|i| LayoutEdge {
+                source: 0,
+                target: i,
+                weight: i as f32,
+            })
+            .collect();
+
+        let config = LayoutConfig::default();
+        assert_eq!(
+            layout(&nodes, &edges, &config),
+            layout(&nodes, &edges, &config)
+        );
+    }
+
+    #[test]
+    fn test_no_overlaps() {
+        let nodes = vec![LayoutNode { radius: 50.0 }; 20];
+        let edges: Vec<_> = (0..20)
+            .flat_map(|i| {
+                (0..i).map(move |j| LayoutEdge {
+                    source: i,
+                    target: j,
+                    weight: 10.0,
+                })
+            })
+            .collect();
+
+        let config = LayoutConfig::default();
+        let positions = layout(&nodes, &edges, &config);
+
+        for i in 0..positions.len() {
+            for j in (i + 1)..positions.len() {
+                // Allow a little slack as overlap removal works pair by pair.
+                assert!(distance(positions[i], positions[j]) > 95.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_connected_nodes_are_closer() {
+        // Two triangles joined by a single edge.
+        let nodes = vec![LayoutNode { radius: 10.0 }; 6];
+        let edges: Vec<_> = [(0, 1), (1, 2), (2, 0), (3, 4), (4, 5), (5, 3), (0, 3)]
+            .into_iter()
+            .map(|(source, target)| LayoutEdge {
+                source,
+                target,
+                weight: 5.0,
+            })
+            .collect();
+
+        let positions = layout(&nodes, &edges, &LayoutConfig::default());
+
+        assert!(distance(positions[1], positions[2]) < distance(positions[1], positions[4]));
+        assert!(distance(positions[4], positions[5]) < distance(positions[2], positions[5]));
+    }
+}
@@ -0,0 +1,9 @@
+pub mod avatar;
+pub mod cache;
+pub mod commands;
+pub mod context;
+pub mod layout;
+pub mod render;
+pub mod snowflake;
+pub mod social;
+pub mod stats;
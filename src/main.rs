@@ -1,9 +1,3 @@
-mod cache;
-mod commands;
-mod context;
-mod social;
-mod stats;
-
 use anyhow::{Context as AnyhowContext, Result};
 use futures::StreamExt;
 use parking_lot::Mutex;
@@ -15,11 +9,13 @@ use twilight_gateway::{stream, Config, Event, MessageSender};
 use twilight_http::{Client as HttpClient, Client};
 use twilight_model::application::command::{
     Command, CommandOption, CommandOptionChoice, CommandOptionChoiceValue, CommandOptionType,
-    CommandType,
+    CommandOptionValue, CommandType,
 };
+use twilight_model::channel::ChannelType;
 use twilight_model::gateway::payload::outgoing::update_presence::UpdatePresencePayload;
 use twilight_model::gateway::presence::{Activity, ActivityType, MinimalActivity, Status};
 use twilight_model::gateway::{CloseFrame, Intents};
+use twilight_model::guild::Permissions;
 use twilight_model::id::marker::{ApplicationMarker, GuildMarker, UserMarker};
 use twilight_model::id::Id;
 use twilight_model::oauth::team::TeamMembershipState;
@@ -31,9 +27,17 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::cache::Cache;
-use crate::context::Context;
-use crate::social::graph::SocialGraph;
+use discograph::avatar::{self, AvatarSource, CdnAvatarSource, DiskCachedAvatarSource};
+use discograph::cache::Cache;
+use discograph::context::Context;
+use discograph::render::Fonts;
+use discograph::social::graph::SocialGraph;
+use discograph::social::inference::{Clock, SystemClock};
+use discograph::social::replay::InteractionRecorder;
+use discograph::social::snapshot::{self, RetentionPolicy, SnapshotStore};
+use discograph::social::store::{GraphFlusher, SharedGraphStore, DEFAULT_FLUSH_INTERVAL};
+use discograph::social::EventWrites;
+use discograph::{commands, social, stats};
 
 fn get_optional_env(key: &str) -> Option<String> {
     match env::var(key) {
@@ -48,6 +52,16 @@ async fn main() -> Result<()> {
     // Initialize the tracing subscriber.
     tracing_subscriber::fmt::init();
 
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("replay") => return social::replay::run(args, &mut std::io::stdout()),
+        Some("migrate") => {
+            let data_dir = get_optional_env("DATA_DIR").context("missing DATA_DIR")?;
+            return social::store::run_migration(&PathBuf::from(data_dir));
+        }
+        _ => (),
+    }
+
     let pool = if let Some(url) = get_optional_env("DATABASE_URL") {
         debug!("DATABASE_URL set, connecting to database");
 
@@ -98,9 +112,55 @@ async fn main() -> Result<()> {
     let cache = Arc::new(Cache::new(http.clone()));
 
     let data_dir = get_optional_env("DATA_DIR").map(PathBuf::from);
-    let social = Arc::new(Mutex::new(SocialGraph::new(data_dir)));
+    let store = match &data_dir {
+        Some(data_dir) => {
+            let store = social::store::open_store(data_dir)?;
+            Some(Arc::new(Mutex::new(store)) as SharedGraphStore)
+        }
+        None => None,
+    };
+
+    let social = Arc::new(SocialGraph::new(store.clone()));
+
+    let flusher = store.map(|store| Arc::new(GraphFlusher::new(social.clone(), store)));
+
+    let flush_interval =
+        get_optional_env("GRAPH_FLUSH_INTERVAL").map_or(DEFAULT_FLUSH_INTERVAL, |value| {
+            let seconds = value.parse().expect("Invalid GRAPH_FLUSH_INTERVAL value");
+            Duration::from_secs(seconds)
+        });
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
+    let event_writes = Arc::new(EventWrites::default());
+
+    let recorder = match get_optional_env("INTERACTION_RECORDING") {
+        Some(path) => Some(Arc::new(
+            InteractionRecorder::new(&PathBuf::from(path)).await?,
+        )),
+        None => None,
+    };
+
+    let snapshots = data_dir.as_ref().map(|data_dir| {
+        Arc::new(SnapshotStore::new(
+            data_dir.join("snapshots"),
+            RetentionPolicy::default(),
+        ))
+    });
+
+    let avatars: Arc<dyn AvatarSource> = match data_dir {
+        Some(data_dir) => Arc::new(DiskCachedAvatarSource::new(
+            CdnAvatarSource::new()?,
+            data_dir.join("avatars"),
+            avatar::DEFAULT_CACHE_SIZE_LIMIT,
+        )),
+        None => Arc::new(CdnAvatarSource::new()?),
+    };
 
     let font_name = get_optional_env("FONT_NAME").unwrap_or("sans-serif".into());
+    let fonts = Arc::new(Fonts::load(&font_name));
+
+    info!("using {} for graph labels", fonts.family());
 
     let management_guild = get_optional_env("MANAGEMENT_GUILD").map(|value| {
         use std::str::FromStr;
@@ -115,6 +175,8 @@ async fn main() -> Result<()> {
 
     let mut intents = Intents::GUILDS | Intents::GUILD_MESSAGES | Intents::GUILD_MESSAGE_REACTIONS;
 
+    // Privileged, and also needed for Discord to tell us about users joining threads, so thread
+    // joins only count towards the graph when this is enabled.
     if let Some("1") = get_optional_env("DISCOGRAPH_SERVER_MEMBERS").as_deref() {
         intents |= Intents::GUILD_MEMBERS;
     }
@@ -123,6 +185,10 @@ async fn main() -> Result<()> {
         intents |= Intents::MESSAGE_CONTENT;
     }
 
+    if let Some("1") = get_optional_env("DISCOGRAPH_VOICE_STATES").as_deref() {
+        intents |= Intents::GUILD_VOICE_STATES;
+    }
+
     let presence = UpdatePresencePayload::new(
         vec![Activity::from(MinimalActivity {
             kind: ActivityType::Watching,
@@ -156,13 +222,36 @@ async fn main() -> Result<()> {
             use hyper::service::{make_service_fn, service_fn};
             use hyper::{Body, Request, Response, Server};
 
+            async fn handle_snapshots_request(
+                request: Request<Body>,
+                snapshots: Option<Arc<SnapshotStore>>,
+            ) -> Result<Response<Body>> {
+                let snapshots = snapshots.context("snapshots not configured")?;
+
+                let body = body::to_bytes(request).await?;
+                let body = String::from_utf8(body.into())?;
+
+                let guild_id: Id<GuildMarker> =
+                    body.trim().parse().context("failed to parse guild id")?;
+
+                let timestamps =
+                    tokio::task::spawn_blocking(move || snapshots.list(guild_id)).await??;
+
+                Ok(Response::new(serde_json::to_string(&timestamps)?.into()))
+            }
+
             async fn handle_request(
                 request: Request<Body>,
                 cache: Arc<Cache>,
                 pool: Option<MySqlPool>,
+                snapshots: Option<Arc<SnapshotStore>>,
                 senders: HashMap<u64, MessageSender>,
                 total_shards: u64,
             ) -> Result<Response<Body>> {
+                if request.uri().path() == "/api/snapshots" {
+                    return handle_snapshots_request(request, snapshots).await;
+                }
+
                 if request.uri().path() != "/api/members" {
                     anyhow::bail!("unknown api call");
                 }
@@ -211,12 +300,13 @@ async fn main() -> Result<()> {
                 request: Request<Body>,
                 cache: Arc<Cache>,
                 pool: Option<MySqlPool>,
+                snapshots: Option<Arc<SnapshotStore>>,
                 senders: HashMap<u64, MessageSender>,
                 total_shards: u64,
             ) -> Result<Response<Body>> {
                 info!(?request);
 
-                match handle_request(request, cache, pool, senders, total_shards).await {
+                match handle_request(request, cache, pool, snapshots, senders, total_shards).await {
                     Ok(response) => {
                         info!(?response);
                         Ok(response)
@@ -231,11 +321,13 @@ async fn main() -> Result<()> {
 
             let cache = cache.clone();
             let pool = pool.clone();
+            let snapshots = snapshots.clone();
             let shard_senders = shard_senders.clone();
 
             let service = make_service_fn(move |_conn| {
                 let cache = cache.clone();
                 let pool = pool.clone();
+                let snapshots = snapshots.clone();
                 let shard_senders = shard_senders.clone();
 
                 async move {
@@ -244,6 +336,7 @@ async fn main() -> Result<()> {
                             request,
                             cache.clone(),
                             pool.clone(),
+                            snapshots.clone(),
                             shard_senders.clone(),
                             total_shards,
                         )
@@ -286,6 +379,23 @@ async fn main() -> Result<()> {
         debug!("top.gg stats posting not configured");
     }
 
+    if let Some(snapshots) = &snapshots {
+        tokio::spawn(snapshot::start_taking_snapshots(
+            social.clone(),
+            snapshots.clone(),
+            clock.clone(),
+        ));
+    } else {
+        debug!("graph snapshots not configured");
+    }
+
+    if let Some(flusher) = &flusher {
+        tokio::spawn(social::store::start_flushing(
+            flusher.clone(),
+            flush_interval,
+        ));
+    }
+
     let mut stream = ShardEventStream::new(shards.iter_mut());
 
     while let Some((shard, event)) = stream.next().await {
@@ -332,8 +442,13 @@ async fn main() -> Result<()> {
             http: http.clone(),
             cache: cache.clone(),
             social: social.clone(),
+            clock: clock.clone(),
+            recorder: recorder.clone(),
+            event_writes: event_writes.clone(),
             pool: pool.clone(),
-            font_name: font_name.clone(),
+            fonts: fonts.clone(),
+            avatars: avatars.clone(),
+            snapshots: snapshots.clone(),
             guilds_with_broken_commands: guilds_with_broken_commands.clone(),
             channels_with_debug_enabled: channels_with_debug_enabled.clone(),
         };
@@ -352,6 +467,17 @@ async fn main() -> Result<()> {
 
     info!("event stream ended, exiting");
 
+    // Make sure nothing changed since the last flush is lost, including the recent interactions
+    // that can still be taken back.
+    social.save_recent_interactions();
+
+    if let Some(flusher) = flusher {
+        match tokio::task::spawn_blocking(move || flusher.flush()).await {
+            Ok(written) => info!("wrote {} changed graphs", written),
+            Err(error) => error!(?error, "failed to flush graphs"),
+        }
+    }
+
     if let Some(pool) = &pool {
         if let Err(error) = stats::set_offline(pool).await {
             warn!(?error, "failed to set guilds offline");
@@ -427,42 +553,334 @@ async fn setup_global_commands(http: Arc<Client>, application_id: Id<Application
                 name: "graph".to_string(),
                 name_localizations: None,
                 nsfw: None,
+                options: vec![
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: None,
+                        choices: Some(vec![
+                            CommandOptionChoice {
+                                name: "Light".to_string(),
+                                name_localizations: None,
+                                value: CommandOptionChoiceValue::String("light".into()),
+                            },
+                            CommandOptionChoice {
+                                name: "Dark".to_string(),
+                                name_localizations: None,
+                                value: CommandOptionChoiceValue::String("dark".into()),
+                            },
+                            CommandOptionChoice {
+                                name: "Transparent Light".to_string(),
+                                name_localizations: None,
+                                value: CommandOptionChoiceValue::String("transparent light".into()),
+                            },
+                            CommandOptionChoice {
+                                name: "Transparent Dark".to_string(),
+                                name_localizations: None,
+                                value: CommandOptionChoiceValue::String("transparent dark".into()),
+                            },
+                        ]),
+                        description: "Style of graph to render.".to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::String,
+                        max_length: None,
+                        max_value: None,
+                        min_length: None,
+                        min_value: None,
+                        name: "style".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: None,
+                        choices: Some(vec![
+                            CommandOptionChoice {
+                                name: "Names".to_string(),
+                                name_localizations: None,
+                                value: CommandOptionChoiceValue::String("names".into()),
+                            },
+                            CommandOptionChoice {
+                                name: "Avatars".to_string(),
+                                name_localizations: None,
+                                value: CommandOptionChoiceValue::String("avatars".into()),
+                            },
+                        ]),
+                        description: "What to show for each user.".to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::String,
+                        max_length: None,
+                        max_value: None,
+                        min_length: None,
+                        min_value: None,
+                        name: "nodes".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: None,
+                        choices: None,
+                        description: "Color users by the community they belong to.".to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::Boolean,
+                        max_length: None,
+                        max_value: None,
+                        min_length: None,
+                        min_value: None,
+                        name: "communities".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: None,
+                        choices: None,
+                        description: "Only show this user and their connections.".to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::User,
+                        max_length: None,
+                        max_value: None,
+                        min_length: None,
+                        min_value: None,
+                        name: "user".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: None,
+                        choices: None,
+                        description: "How many steps away from the user to show, defaults to 1."
+                            .to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::Integer,
+                        max_length: None,
+                        max_value: Some(CommandOptionValue::Integer(commands::MAX_EGO_DEPTH)),
+                        min_length: None,
+                        min_value: Some(CommandOptionValue::Integer(1)),
+                        name: "depth".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: Some(vec![
+                            ChannelType::GuildText,
+                            ChannelType::GuildAnnouncement,
+                            ChannelType::GuildForum,
+                            ChannelType::GuildVoice,
+                        ]),
+                        choices: None,
+                        description: "Only include conversations in this channel.".to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::Channel,
+                        max_length: None,
+                        max_value: None,
+                        min_length: None,
+                        min_value: None,
+                        name: "channel".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: Some(vec![ChannelType::GuildCategory]),
+                        choices: None,
+                        description: "Only include conversations in this category's channels."
+                            .to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::Channel,
+                        max_length: None,
+                        max_value: None,
+                        min_length: None,
+                        min_value: None,
+                        name: "category".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                    CommandOption {
+                        autocomplete: None,
+                        channel_types: None,
+                        choices: None,
+                        description: "Show the graph as it was on this date (YYYY-MM-DD)."
+                            .to_string(),
+                        description_localizations: None,
+                        kind: CommandOptionType::String,
+                        max_length: Some(10),
+                        max_value: None,
+                        min_length: Some(10),
+                        min_value: None,
+                        name: "at".to_string(),
+                        name_localizations: None,
+                        options: None,
+                        required: Some(false),
+                    },
+                ],
+                version: Id::new(1),
+            },
+            Command {
+                application_id: None,
+                default_member_permissions: None,
+                dm_permission: Some(false),
+                description: String::new(),
+                description_localizations: None,
+                guild_id: None,
+                id: None,
+                kind: CommandType::User,
+                name: "Show connections".to_string(),
+                name_localizations: None,
+                nsfw: None,
+                options: Vec::new(),
+                version: Id::new(1),
+            },
+            Command {
+                application_id: None,
+                default_member_permissions: None,
+                dm_permission: Some(false),
+                description: "Show the most connected users and other server insights.".to_string(),
+                description_localizations: None,
+                guild_id: None,
+                id: None,
+                kind: CommandType::ChatInput,
+                name: "insights".to_string(),
+                name_localizations: None,
+                nsfw: None,
+                options: Vec::new(),
+                version: Id::new(1),
+            },
+            Command {
+                application_id: None,
+                default_member_permissions: None,
+                dm_permission: Some(false),
+                description: "See how the server's graph has changed recently.".to_string(),
+                description_localizations: None,
+                guild_id: None,
+                id: None,
+                kind: CommandType::ChatInput,
+                name: "diff".to_string(),
+                name_localizations: None,
+                nsfw: None,
+                options: vec![CommandOption {
+                    autocomplete: None,
+                    channel_types: None,
+                    choices: None,
+                    description: "How many days back to compare against, defaults to 30."
+                        .to_string(),
+                    description_localizations: None,
+                    kind: CommandOptionType::Integer,
+                    max_length: None,
+                    max_value: Some(CommandOptionValue::Integer(commands::MAX_DIFF_DAYS)),
+                    min_length: None,
+                    min_value: Some(CommandOptionValue::Integer(1)),
+                    name: "days".to_string(),
+                    name_localizations: None,
+                    options: None,
+                    required: Some(false),
+                }],
+                version: Id::new(1),
+            },
+            Command {
+                application_id: None,
+                default_member_permissions: None,
+                dm_permission: Some(false),
+                description: "Watch how the server's graph has changed over time.".to_string(),
+                description_localizations: None,
+                guild_id: None,
+                id: None,
+                kind: CommandType::ChatInput,
+                name: "timelapse".to_string(),
+                name_localizations: None,
+                nsfw: None,
+                options: vec![CommandOption {
+                    autocomplete: None,
+                    channel_types: None,
+                    choices: Some(vec![
+                        CommandOptionChoice {
+                            name: "Day".to_string(),
+                            name_localizations: None,
+                            value: CommandOptionChoiceValue::String("day".into()),
+                        },
+                        CommandOptionChoice {
+                            name: "Week".to_string(),
+                            name_localizations: None,
+                            value: CommandOptionChoiceValue::String("week".into()),
+                        },
+                        CommandOptionChoice {
+                            name: "Month".to_string(),
+                            name_localizations: None,
+                            value: CommandOptionChoiceValue::String("month".into()),
+                        },
+                    ]),
+                    description: "How much time passes between each frame, defaults to a week."
+                        .to_string(),
+                    description_localizations: None,
+                    kind: CommandOptionType::String,
+                    max_length: None,
+                    max_value: None,
+                    min_length: None,
+                    min_value: None,
+                    name: "period".to_string(),
+                    name_localizations: None,
+                    options: None,
+                    required: Some(false),
+                }],
+                version: Id::new(1),
+            },
+            Command {
+                application_id: None,
+                default_member_permissions: Some(Permissions::MANAGE_GUILD),
+                dm_permission: Some(false),
+                description: "Download the server's graph to explore in other tools.".to_string(),
+                description_localizations: None,
+                guild_id: None,
+                id: None,
+                kind: CommandType::ChatInput,
+                name: "export".to_string(),
+                name_localizations: None,
+                nsfw: None,
                 options: vec![CommandOption {
                     autocomplete: None,
                     channel_types: None,
                     choices: Some(vec![
                         CommandOptionChoice {
-                            name: "Light".to_string(),
+                            name: "GEXF (Gephi)".to_string(),
                             name_localizations: None,
-                            value: CommandOptionChoiceValue::String("light".into()),
+                            value: CommandOptionChoiceValue::String("gexf".into()),
                         },
                         CommandOptionChoice {
-                            name: "Dark".to_string(),
+                            name: "GraphML".to_string(),
                             name_localizations: None,
-                            value: CommandOptionChoiceValue::String("dark".into()),
+                            value: CommandOptionChoiceValue::String("graphml".into()),
                         },
                         CommandOptionChoice {
-                            name: "Transparent Light".to_string(),
+                            name: "JSON (node-link)".to_string(),
                             name_localizations: None,
-                            value: CommandOptionChoiceValue::String("transparent light".into()),
+                            value: CommandOptionChoiceValue::String("json".into()),
                         },
                         CommandOptionChoice {
-                            name: "Transparent Dark".to_string(),
+                            name: "CSV (edge list)".to_string(),
                             name_localizations: None,
-                            value: CommandOptionChoiceValue::String("transparent dark".into()),
+                            value: CommandOptionChoiceValue::String("csv".into()),
                         },
                     ]),
-                    description: "Style of graph to render.".to_string(),
+                    description: "File format to export the graph in.".to_string(),
                     description_localizations: None,
                     kind: CommandOptionType::String,
                     max_length: None,
                     max_value: None,
                     min_length: None,
                     min_value: None,
-                    name: "style".to_string(),
+                    name: "format".to_string(),
                     name_localizations: None,
                     options: None,
-                    required: Some(false),
+                    required: Some(true),
                 }],
                 version: Id::new(1),
             },
@@ -0,0 +1,946 @@
+use anyhow::{Context as AnyhowContext, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::fontdb::Database;
+use resvg::usvg::{Options, Tree};
+use tracing::warn;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+use unicode_segmentation::UnicodeSegmentation;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::layout::Point;
+use crate::social::graph::{ColorScheme, DisplayGraph};
+use crate::social::inference::RelationshipStrength;
+
+const BG_LIGHT: u32 = 0xFFFFFFFF;
+const FG_LIGHT: u32 = 0x060607FF;
+const BG_DARK: u32 = 0x313338FF;
+const FG_DARK: u32 = 0xF2F3F5FF;
+
+const FONT_SIZE: f32 = 14.0;
+const EDGE_LABEL_FONT_SIZE: f32 = 11.0;
+
+const ADDED_EDGE_COLOR: u32 = 0x2ECC71FF;
+const REMOVED_EDGE_COLOR: u32 = 0xE74C3CFF;
+
+/// Distinct colors for communities, cycled through if there are more communities than colors.
+const COMMUNITY_COLORS: &[u32] = &[
+    0xE74C3C, 0x3498DB, 0x2ECC71, 0xF1C40F, 0x9B59B6, 0xE67E22, 0x1ABC9C, 0xE91E63, 0x95A5A6,
+    0x206694, 0xA84300, 0x11806A,
+];
+
+const AVATAR_RADIUS: f32 = 24.0;
+const AVATAR_RING_WIDTH: f32 = 3.0;
+
+/// Space around the edge of the image, in points.
+const PADDING: f32 = 21.6;
+
+/// The output is drawn at 144 DPI, where the SVG coordinates are in points.
+const PNG_SCALE: f32 = 2.0;
+
+/// How hard to work on picking GIF palettes, from 1 (best quality) to 30 (fastest).
+const GIF_QUANTIZE_SPEED: i32 = 10;
+
+/// Families to try (in order) when FONT_NAME is one of the generic CSS families.
+const SANS_SERIF_FAMILIES: &[&str] = &[
+    "DejaVu Sans",
+    "Noto Sans",
+    "Liberation Sans",
+    "Arial",
+    "Helvetica",
+];
+const SERIF_FAMILIES: &[&str] = &[
+    "DejaVu Serif",
+    "Noto Serif",
+    "Liberation Serif",
+    "Times New Roman",
+];
+const MONOSPACE_FAMILIES: &[&str] = &[
+    "DejaVu Sans Mono",
+    "Noto Sans Mono",
+    "Liberation Mono",
+    "Courier New",
+];
+
+/// The font database used for rendering, and the family we resolved FONT_NAME to.
+pub struct Fonts {
+    database: Arc<Database>,
+    family: String,
+}
+
+impl Fonts {
+    /// Load the system fonts, plus FONT_NAME itself if it is a path to a font file.
+    ///
+    /// If FONT_NAME can't be found we fall back to whatever is installed, rather than refusing to
+    /// start over it.
+    pub fn load(font_name: &str) -> Self {
+        let mut database = Database::new();
+        database.load_system_fonts();
+
+        let family = match find_family(&mut database, font_name) {
+            Ok(family) => family,
+            Err(error) => {
+                let fallback = fallback_family(&database);
+                warn!(?error, ?fallback, "failed to find font {}", font_name);
+
+                // With nothing installed the text just won't be drawn.
+                fallback.unwrap_or_else(|| "sans-serif".to_owned())
+            }
+        };
+
+        // Anything that falls back to the generic families should end up with our font too.
+        database.set_sans_serif_family(family.clone());
+        database.set_serif_family(family.clone());
+        database.set_monospace_family(family.clone());
+
+        Fonts {
+            database: Arc::new(database),
+            family,
+        }
+    }
+
+    pub fn family(&self) -> &str {
+        &self.family
+    }
+}
+
+fn has_family(database: &Database, name: &str) -> bool {
+    database.faces().any(|face| {
+        face.families
+            .iter()
+            .any(|(family, _)| family.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Resolve FONT_NAME to an installed family, loading it first if it is a path to a font file.
+fn find_family(database: &mut Database, font_name: &str) -> Result<String> {
+    let path = Path::new(font_name);
+    if path.is_file() {
+        let ids = database.load_font_source(resvg::usvg::fontdb::Source::File(path.into()));
+        let id = ids
+            .first()
+            .with_context(|| format!("{} does not contain any fonts", font_name))?;
+
+        let face = database.face(*id).unwrap();
+        return face
+            .families
+            .first()
+            .map(|(family, _)| family.clone())
+            .with_context(|| format!("{} does not have a font family name", font_name));
+    }
+
+    let candidates = match font_name {
+        "sans-serif" => SANS_SERIF_FAMILIES,
+        "serif" => SERIF_FAMILIES,
+        "monospace" => MONOSPACE_FAMILIES,
+        name => &[name][..],
+    };
+
+    candidates
+        .iter()
+        .find(|&&candidate| has_family(database, candidate))
+        .map(|family| family.to_string())
+        .with_context(|| format!("no installed font found for {}", font_name))
+}
+
+/// One of the usual sans-serif families if we have any of them, otherwise any installed family.
+fn fallback_family(database: &Database) -> Option<String> {
+    SANS_SERIF_FAMILIES
+        .iter()
+        .find(|&&candidate| has_family(database, candidate))
+        .map(|family| family.to_string())
+        .or_else(|| {
+            database
+                .faces()
+                .find_map(|face| face.families.first())
+                .map(|(family, _)| family.clone())
+        })
+}
+
+/// How each user is drawn in the graph.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NodeStyle {
+    /// The user's name inside an ellipse.
+    Names,
+    /// The user's avatar clipped to a circle, with a ring in their role color and their name below.
+    Avatars,
+}
+
+/// Highlights for edges when showing what changed between two graphs.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EdgeStyle {
+    /// Drawn in green.
+    Added,
+    /// Drawn dashed in red.
+    Removed,
+}
+
+pub struct RenderOptions<'a> {
+    pub color_scheme: ColorScheme,
+    pub node_style: NodeStyle,
+    /// Draw without a background, with a glow in the background color to keep it readable.
+    pub transparent: bool,
+    pub font_family: &'a str,
+    /// Shown in the bottom left corner.
+    pub title: Option<String>,
+    /// This user's node is drawn filled in and bold.
+    pub highlight: Option<Id<UserMarker>>,
+    /// Write the weight of each edge next to it, only readable for small graphs.
+    pub edge_labels: bool,
+    /// The area to draw, instead of fitting the image to the graph.
+    pub bounds: Option<[Point; 2]>,
+    /// Any edges that aren't listed are drawn normally.
+    pub edge_styles: Option<&'a HashMap<[Id<UserMarker>; 2], EdgeStyle>>,
+}
+
+// TODO: This doesn't handle counting wide characters very well,
+//       Probably want to pull in the unicode-width crate for that.
+fn get_label(mut name: String) -> String {
+    let label_length = 9;
+    let ellipsis = "...";
+    let ellipsis_length = ellipsis.len();
+
+    let mut name_iter = name.grapheme_indices(true);
+    let key_graphemes = (
+        name_iter.nth(label_length - ellipsis_length),
+        name_iter.nth(ellipsis_length - 1),
+    );
+
+    match key_graphemes {
+        (_, None) => name,
+        (Some((pos, _)), Some(_)) => {
+            name.truncate(pos);
+            name.push_str(ellipsis);
+            name
+        }
+        (None, Some(_)) => unreachable!(),
+    }
+}
+
+fn calculate_luma(color: u32) -> f32 {
+    // TODO: Consider alpha blending with a background color.
+    let r = ((color >> 24) & 0xFF) as f32;
+    let g = ((color >> 16) & 0xFF) as f32;
+    let b = ((color >> 8) & 0xFF) as f32;
+
+    (r * 0.299) + (g * 0.587) + (b * 0.114)
+}
+
+/// RGB color used for a community, matching the format of role colors.
+pub fn community_color(index: usize) -> u32 {
+    COMMUNITY_COLORS[index % COMMUNITY_COLORS.len()]
+}
+
+fn pen_width(weight: RelationshipStrength) -> f32 {
+    1.0 + weight.log10()
+}
+
+fn label_width(name: &str) -> f32 {
+    // Labels are at most 9 characters, so this is a reasonable estimate without measuring.
+    get_label(name.to_owned()).graphemes(true).count() as f32 * FONT_SIZE * 0.6
+}
+
+/// Width and height of the ellipse drawn for a node with the names style, in points.
+fn ellipse_size(name: &str) -> (f32, f32) {
+    ((label_width(name) + 18.0).max(54.0), 36.0)
+}
+
+/// The radius of a circle that contains the whole node, including its outline.
+pub fn node_radius(node_style: NodeStyle, name: &str, weight: RelationshipStrength) -> f32 {
+    match node_style {
+        NodeStyle::Names => {
+            let (width, height) = ellipse_size(name);
+
+            (width.max(height) / 2.0) + pen_width(weight) + 4.0
+        }
+        NodeStyle::Avatars => {
+            // The label hangs below the avatar, so it is the furthest point from the center.
+            let below = AVATAR_RADIUS + AVATAR_RING_WIDTH + 4.0 + (FONT_SIZE * 1.5);
+
+            (label_width(name) / 2.0).max(below)
+        }
+    }
+}
+
+/// Work out the MIME type of an avatar image from its magic bytes.
+fn image_mime_type(image: &[u8]) -> Option<&'static str> {
+    if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if image.starts_with(b"\xFF\xD8\xFF") {
+        Some("image/jpeg")
+    } else if image.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else if image.len() >= 12 && &image[0..4] == b"RIFF" && &image[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn format_weight(weight: RelationshipStrength) -> String {
+    if weight >= 10.0 {
+        format!("{:.0}", weight)
+    } else {
+        format!("{:.1}", weight)
+    }
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Format an RRGGBBAA color as an SVG paint and matching opacity attribute.
+fn paint(attribute: &str, color: u32) -> String {
+    format!(
+        "{attribute}=\"#{:06X}\" {attribute}-opacity=\"{:.3}\"",
+        color >> 8,
+        (color & 0xFF) as f32 / 255.0,
+    )
+}
+
+/// Work out how much space the graph needs, as the top left and bottom right corners.
+pub fn graph_bounds(
+    graph: &DisplayGraph,
+    positions: &HashMap<Id<UserMarker>, Point>,
+    node_style: NodeStyle,
+) -> [Point; 2] {
+    let mut min = Point {
+        x: f32::MAX,
+        y: f32::MAX,
+    };
+    let mut max = Point {
+        x: f32::MIN,
+        y: f32::MIN,
+    };
+
+    for (user_id, node) in &graph.nodes {
+        let position = positions[user_id];
+        let radius = node_radius(node_style, &node.name, node.weight);
+
+        min.x = min.x.min(position.x - radius);
+        min.y = min.y.min(position.y - radius);
+        max.x = max.x.max(position.x + radius);
+        max.y = max.y.max(position.y + radius);
+    }
+
+    [min, max]
+}
+
+/// Draw the graph as an SVG, in points. `avatars` is only used with `NodeStyle::Avatars`, and any
+/// missing ones are drawn as an empty circle.
+pub fn render_svg(
+    graph: &DisplayGraph,
+    positions: &HashMap<Id<UserMarker>, Point>,
+    avatars: &HashMap<Id<UserMarker>, Vec<u8>>,
+    options: &RenderOptions,
+) -> String {
+    let (bg_color, fg_color) = match options.color_scheme {
+        ColorScheme::Light => (BG_LIGHT, FG_LIGHT),
+        ColorScheme::Dark => (BG_DARK, FG_DARK),
+    };
+
+    let [min, max] = options
+        .bounds
+        .unwrap_or_else(|| graph_bounds(graph, positions, options.node_style));
+
+    let title_height = if options.title.is_some() {
+        FONT_SIZE * 2.0
+    } else {
+        0.0
+    };
+
+    let width = (max.x - min.x) + (PADDING * 2.0);
+    let height = (max.y - min.y) + (PADDING * 2.0) + title_height;
+
+    let mut svg = String::new();
+
+    // Writing to a String can't fail, so the results are ignored throughout.
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{:.2}\" height=\"{:.2}\" viewBox=\"0 0 {:.2} {:.2}\" font-family=\"{}\" font-size=\"{}\">",
+        width,
+        height,
+        width,
+        height,
+        escape_xml(options.font_family),
+        FONT_SIZE,
+    );
+
+    if options.transparent {
+        // A strong glow in the background color behind everything, so the graph is readable
+        // regardless of what it ends up displayed on top of.
+        let _ = writeln!(
+            svg,
+            "<filter id=\"shadow\" filterUnits=\"userSpaceOnUse\" x=\"0\" y=\"0\" width=\"{:.2}\" height=\"{:.2}\">\
+            <feGaussianBlur in=\"SourceAlpha\" stdDeviation=\"2\" result=\"blur\"/>\
+            <feFlood flood-color=\"#{:06X}\"/>\
+            <feComposite in2=\"blur\" operator=\"in\" result=\"glow\"/>\
+            <feComponentTransfer in=\"glow\" result=\"shadow\"><feFuncA type=\"linear\" slope=\"2\"/></feComponentTransfer>\
+            <feMerge><feMergeNode in=\"shadow\"/><feMergeNode in=\"SourceGraphic\"/></feMerge>\
+            </filter>",
+            width,
+            height,
+            bg_color >> 8,
+        );
+
+        svg.push_str("<g filter=\"url(#shadow)\">\n");
+    } else {
+        let _ = writeln!(
+            svg,
+            "<rect width=\"100%\" height=\"100%\" {}/>",
+            paint("fill", bg_color),
+        );
+
+        svg.push_str("<g>\n");
+    }
+
+    if options.node_style == NodeStyle::Avatars {
+        svg.push_str("<clipPath id=\"avatar\" clipPathUnits=\"objectBoundingBox\"><circle cx=\"0.5\" cy=\"0.5\" r=\"0.5\"/></clipPath>\n");
+    }
+
+    let _ = writeln!(
+        svg,
+        "<g transform=\"translate({:.2} {:.2})\">",
+        PADDING - min.x,
+        PADDING - min.y,
+    );
+
+    // Edges are drawn first so they go underneath the nodes.
+    for &([source, target], weight) in &graph.edges {
+        let mut color = fg_color;
+        let mut dash = "";
+
+        match options
+            .edge_styles
+            .and_then(|edge_styles| edge_styles.get(&[source, target]))
+        {
+            Some(EdgeStyle::Added) => color = ADDED_EDGE_COLOR,
+            Some(EdgeStyle::Removed) => {
+                color = REMOVED_EDGE_COLOR;
+                dash = " stroke-dasharray=\"6 4\"";
+            }
+            None => {}
+        }
+
+        if !graph.nodes[&source].is_member || !graph.nodes[&target].is_member {
+            color -= 200;
+        }
+
+        let from = positions[&source];
+        let to = positions[&target];
+
+        let _ = writeln!(
+            svg,
+            "<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" stroke-width=\"{:.2}\"{} {}/>",
+            from.x,
+            from.y,
+            to.x,
+            to.y,
+            pen_width(weight),
+            dash,
+            paint("stroke", color),
+        );
+    }
+
+    for user_id in graph.sorted_user_ids() {
+        let node = &graph.nodes[&user_id];
+        let position = positions[&user_id];
+
+        let mut color = fg_color;
+        let mut fillcolor = bg_color;
+        let mut fontcolor = fg_color;
+        let mut highlighted = false;
+
+        if let Some(role_color) = node.color {
+            color = (role_color << 8) | 0xFF;
+        }
+
+        if !node.is_member {
+            color -= 200;
+            fontcolor -= 200;
+        }
+
+        // Labels drawn outside the node don't get inverted.
+        let outside_fontcolor = fontcolor;
+
+        // Invert the colors if it is the requesting user.
+        if options.highlight == Some(user_id) {
+            highlighted = true;
+
+            fillcolor = color;
+
+            // Select text color based on fill contrast.
+            fontcolor = if calculate_luma(fillcolor) > 186.0 {
+                FG_LIGHT
+            } else {
+                FG_DARK
+            };
+        }
+
+        let node_svg = NodeSvg {
+            position,
+            label: &get_label(node.name.clone()),
+            stroke_width: pen_width(node.weight),
+            color,
+            fillcolor,
+            fontcolor,
+            highlighted,
+        };
+
+        match options.node_style {
+            NodeStyle::Names => node_svg.write_ellipse(&mut svg),
+            NodeStyle::Avatars => {
+                let node_svg = NodeSvg {
+                    fontcolor: outside_fontcolor,
+                    ..node_svg
+                };
+
+                node_svg.write_avatar(&mut svg, avatars.get(&user_id), bg_color, node.is_member)
+            }
+        }
+    }
+
+    // Drawn last so that nothing covers them, with an outline to separate them from the edges.
+    if options.edge_labels {
+        for &([source, target], weight) in &graph.edges {
+            let from = positions[&source];
+            let to = positions[&target];
+
+            let _ = writeln!(
+                svg,
+                "<text x=\"{:.2}\" y=\"{:.2}\" text-anchor=\"middle\" font-size=\"{}\" stroke-width=\"3\" stroke-linejoin=\"round\" paint-order=\"stroke\" {} {}>{}</text>",
+                (from.x + to.x) / 2.0,
+                ((from.y + to.y) / 2.0) + (EDGE_LABEL_FONT_SIZE * 0.35),
+                EDGE_LABEL_FONT_SIZE,
+                paint("fill", fg_color),
+                paint("stroke", bg_color),
+                format_weight(weight),
+            );
+        }
+    }
+
+    svg.push_str("</g>\n");
+
+    if let Some(title) = &options.title {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.2}\" y=\"{:.2}\" {}>{}</text>",
+            PADDING,
+            height - PADDING,
+            paint("fill", fg_color),
+            escape_xml(title),
+        );
+    }
+
+    svg.push_str("</g>\n</svg>\n");
+
+    svg
+}
+
+/// Everything needed to draw a single node.
+struct NodeSvg<'a> {
+    position: Point,
+    label: &'a str,
+    stroke_width: f32,
+    color: u32,
+    fillcolor: u32,
+    fontcolor: u32,
+    highlighted: bool,
+}
+
+impl NodeSvg<'_> {
+    fn write_ellipse(&self, svg: &mut String) {
+        let (width, height) = ellipse_size(self.label);
+
+        if self.highlighted {
+            // A second outline around the node, like graphviz's peripheries.
+            let _ = writeln!(
+                svg,
+                "<ellipse cx=\"{:.2}\" cy=\"{:.2}\" rx=\"{:.2}\" ry=\"{:.2}\" fill=\"none\" stroke-width=\"{:.2}\" {}/>",
+                self.position.x,
+                self.position.y,
+                width / 2.0 + 4.0,
+                height / 2.0 + 4.0,
+                self.stroke_width,
+                paint("stroke", self.color),
+            );
+        }
+
+        let _ = writeln!(
+            svg,
+            "<ellipse cx=\"{:.2}\" cy=\"{:.2}\" rx=\"{:.2}\" ry=\"{:.2}\" stroke-width=\"{:.2}\" {} {}/>",
+            self.position.x,
+            self.position.y,
+            width / 2.0,
+            height / 2.0,
+            self.stroke_width,
+            paint("fill", self.fillcolor),
+            paint("stroke", self.color),
+        );
+
+        self.write_label(svg, self.position.y + (FONT_SIZE * 0.35));
+    }
+
+    fn write_avatar(
+        &self,
+        svg: &mut String,
+        image: Option<&Vec<u8>>,
+        bg_color: u32,
+        is_member: bool,
+    ) {
+        let Point { x, y } = self.position;
+
+        // Something solid behind the image, for transparent avatars or if it is missing.
+        let _ = writeln!(
+            svg,
+            "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" {}/>",
+            x,
+            y,
+            AVATAR_RADIUS,
+            paint("fill", bg_color),
+        );
+
+        if let Some((image, mime_type)) =
+            image.and_then(|image| Some((image, image_mime_type(image)?)))
+        {
+            let _ = writeln!(
+                svg,
+                "<image x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" clip-path=\"url(#avatar)\"{} xlink:href=\"data:{};base64,{}\"/>",
+                x - AVATAR_RADIUS,
+                y - AVATAR_RADIUS,
+                AVATAR_RADIUS * 2.0,
+                AVATAR_RADIUS * 2.0,
+                if is_member { "" } else { " opacity=\"0.5\"" },
+                mime_type,
+                BASE64.encode(image),
+            );
+        }
+
+        let ring_radius = AVATAR_RADIUS + (AVATAR_RING_WIDTH / 2.0);
+
+        let _ = writeln!(
+            svg,
+            "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" fill=\"none\" stroke-width=\"{:.2}\" {}/>",
+            x,
+            y,
+            ring_radius,
+            AVATAR_RING_WIDTH,
+            paint("stroke", self.color),
+        );
+
+        if self.highlighted {
+            let _ = writeln!(
+                svg,
+                "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" fill=\"none\" stroke-width=\"{:.2}\" {}/>",
+                x,
+                y,
+                ring_radius + 4.0,
+                self.stroke_width,
+                paint("stroke", self.color),
+            );
+        }
+
+        self.write_label(svg, y + AVATAR_RADIUS + AVATAR_RING_WIDTH + 4.0 + FONT_SIZE);
+    }
+
+    fn write_label(&self, svg: &mut String, baseline: f32) {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.2}\" y=\"{:.2}\" text-anchor=\"middle\"{} {}>{}</text>",
+            self.position.x,
+            baseline,
+            if self.highlighted {
+                " font-weight=\"bold\""
+            } else {
+                ""
+            },
+            paint("fill", self.fontcolor),
+            escape_xml(self.label),
+        );
+    }
+}
+
+/// Rasterize an SVG to PNG on a blocking thread.
+pub async fn render_png(fonts: Arc<Fonts>, svg: String) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || render_png_blocking(&fonts, &svg))
+        .await
+        .context("png rendering task failed")?
+}
+
+fn render_png_blocking(fonts: &Fonts, svg: &str) -> Result<Vec<u8>> {
+    let pixmap = rasterize(fonts, svg, PNG_SCALE)?;
+
+    pixmap.encode_png().context("failed to encode png")
+}
+
+/// Rasterize a series of same-sized SVGs into a looping animated GIF on a blocking thread.
+///
+/// `frame_delay` is in hundredths of a second, the last frame is held for longer.
+pub async fn render_gif(
+    fonts: Arc<Fonts>,
+    frames: Vec<String>,
+    frame_delay: u16,
+) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || render_gif_blocking(&fonts, &frames, frame_delay))
+        .await
+        .context("gif rendering task failed")?
+}
+
+fn render_gif_blocking(fonts: &Fonts, frames: &[String], frame_delay: u16) -> Result<Vec<u8>> {
+    // GIFs get large quickly, so these are drawn at 1x.
+    let mut pixmaps = frames.iter().map(|svg| rasterize(fonts, svg, 1.0));
+
+    let first = pixmaps.next().context("no frames to render")??;
+    let width = u16::try_from(first.width()).context("timelapse is too large to render")?;
+    let height = u16::try_from(first.height()).context("timelapse is too large to render")?;
+
+    let mut gif = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut gif, width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+
+    for (index, pixmap) in std::iter::once(Ok(first)).chain(pixmaps).enumerate() {
+        let pixmap = pixmap?;
+
+        anyhow::ensure!(
+            pixmap.width() == u32::from(width) && pixmap.height() == u32::from(height),
+            "timelapse frames must all be the same size",
+        );
+
+        // Everything we draw has an opaque background, so there is no need to demultiply alpha.
+        let mut pixels = pixmap.take();
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, GIF_QUANTIZE_SPEED);
+
+        frame.delay = if index == frames.len() - 1 {
+            frame_delay * 10
+        } else {
+            frame_delay
+        };
+
+        encoder.write_frame(&frame)?;
+    }
+
+    drop(encoder);
+
+    Ok(gif)
+}
+
+fn rasterize(fonts: &Fonts, svg: &str, scale: f32) -> Result<Pixmap> {
+    let options = Options {
+        font_family: fonts.family.clone(),
+        fontdb: fonts.database.clone(),
+        ..Default::default()
+    };
+
+    let tree = Tree::from_str(svg, &options).context("failed to parse generated svg")?;
+
+    let size = tree
+        .size()
+        .to_int_size()
+        .scale_by(scale)
+        .context("graph image is too large to render")?;
+
+    let mut pixmap = Pixmap::new(size.width(), size.height()).with_context(|| {
+        format!(
+            "failed to allocate {}x{} image",
+            size.width(),
+            size.height()
+        )
+    })?;
+
+    resvg::render(
+        &tree,
+        Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    Ok(pixmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use resvg::tiny_skia::{Color, Pixmap};
+    use resvg::usvg::fontdb::Database;
+    use twilight_model::id::marker::UserMarker;
+    use twilight_model::id::Id;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use super::{
+        fallback_family, find_family, get_label, graph_bounds, has_family, render_gif_blocking,
+        render_png_blocking, render_svg, EdgeStyle, Fonts, NodeStyle, RenderOptions,
+    };
+    use crate::avatar::Avatar;
+    use crate::layout::Point;
+    use crate::social::graph::{ColorScheme, DisplayGraph, DisplayNode};
+
+    fn test_graph() -> (DisplayGraph, HashMap<Id<UserMarker>, Point>) {
+        let mut nodes = HashMap::new();
+        let mut positions = HashMap::new();
+
+        for (i, name) in ["alice", "<bob & \"carol\">", "a very long name"]
+            .into_iter()
+            .enumerate()
+        {
+            let user_id = Id::new(i as u64 + 1);
+
+            nodes.insert(
+                user_id,
+                DisplayNode {
+                    name: name.to_owned(),
+                    color: if i == 0 { Some(0xE91E63) } else { None },
+                    is_member: i != 2,
+                    avatar: Avatar::Default { index: i as u64 },
+                    weight: 5.0,
+                },
+            );
+
+            positions.insert(
+                user_id,
+                Point {
+                    x: i as f32 * 100.0,
+                    y: i as f32 * -50.0,
+                },
+            );
+        }
+
+        let edges = vec![
+            ([Id::new(1), Id::new(2)], 2.0),
+            ([Id::new(2), Id::new(3)], 3.0),
+        ];
+
+        (DisplayGraph { nodes, edges }, positions)
+    }
+
+    #[test]
+    fn test_get_label() {
+        assert_eq!(get_label("alice".to_owned()), "alice");
+        assert_eq!(get_label("123456789".to_owned()), "123456789");
+        assert_eq!(get_label("1234567890".to_owned()), "123456...");
+    }
+
+    #[test]
+    fn test_font_fallback() {
+        let mut database = Database::new();
+        assert!(find_family(&mut database, "sans-serif").is_err());
+        assert!(fallback_family(&database).is_none());
+
+        // A missing font isn't fatal, something installed is used instead.
+        let fonts = Fonts::load("DiscoGraph Missing Font");
+        if fonts.database.is_empty() {
+            assert_eq!(fonts.family(), "sans-serif");
+        } else {
+            assert!(has_family(&fonts.database, fonts.family()));
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let (graph, positions) = test_graph();
+
+        // No fonts are needed to check the output is valid, the text just won't be drawn.
+        let fonts = Fonts {
+            database: Arc::new(Database::new()),
+            family: "sans-serif".to_owned(),
+        };
+
+        let mut avatar = Pixmap::new(16, 16).unwrap();
+        avatar.fill(Color::from_rgba8(0x58, 0x65, 0xF2, 0xFF));
+
+        // The third user's avatar is missing, which should still render.
+        let avatars = HashMap::from([
+            (Id::new(1), avatar.encode_png().unwrap()),
+            (Id::new(2), avatar.encode_png().unwrap()),
+        ]);
+
+        let edge_styles = HashMap::from([([Id::new(1), Id::new(2)], EdgeStyle::Removed)]);
+
+        for (node_style, transparent) in [
+            (NodeStyle::Names, false),
+            (NodeStyle::Names, true),
+            (NodeStyle::Avatars, false),
+            (NodeStyle::Avatars, true),
+        ] {
+            let svg = render_svg(
+                &graph,
+                &positions,
+                &avatars,
+                &RenderOptions {
+                    color_scheme: ColorScheme::Dark,
+                    node_style,
+                    transparent,
+                    font_family: fonts.family(),
+                    title: Some("Generated for <someone>".to_owned()),
+                    highlight: Some(Id::new(1)),
+                    edge_labels: transparent,
+                    bounds: None,
+                    edge_styles: transparent.then_some(&edge_styles),
+                },
+            );
+
+            assert!(svg.contains("&lt;bob &amp;..."));
+            assert_eq!(svg.contains("stroke-dasharray"), transparent);
+            assert_eq!(
+                svg.matches("data:image/png;base64,").count(),
+                if node_style == NodeStyle::Avatars {
+                    2
+                } else {
+                    0
+                },
+            );
+
+            let png = render_png_blocking(&fonts, &svg).unwrap();
+            assert!(png.starts_with(b"\x89PNG"));
+        }
+    }
+
+    #[test]
+    fn test_render_gif() {
+        let (graph, positions) = test_graph();
+
+        let fonts = Fonts {
+            database: Arc::new(Database::new()),
+            family: "sans-serif".to_owned(),
+        };
+
+        let bounds = graph_bounds(&graph, &positions, NodeStyle::Names);
+
+        // The frames have different nodes, but the fixed bounds keep them the same size.
+        let frames: Vec<_> = [graph.with_edges(vec![]), graph.clone()]
+            .iter()
+            .map(|graph| {
+                render_svg(
+                    graph,
+                    &positions,
+                    &HashMap::new(),
+                    &RenderOptions {
+                        color_scheme: ColorScheme::Light,
+                        node_style: NodeStyle::Names,
+                        transparent: false,
+                        font_family: fonts.family(),
+                        title: Some("Timelapse".to_owned()),
+                        highlight: None,
+                        edge_labels: false,
+                        bounds: Some(bounds),
+                        edge_styles: None,
+                    },
+                )
+            })
+            .collect();
+
+        let gif = render_gif_blocking(&fonts, &frames, 10).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+
+        assert!(render_gif_blocking(&fonts, &[], 10).is_err());
+    }
+}
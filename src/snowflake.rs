@@ -0,0 +1,146 @@
+use twilight_model::id::Id;
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+
+/// A cheap hasher for maps keyed by Discord snowflakes.
+///
+/// Snowflakes are handed out by Discord rather than chosen by users, so there's nothing to gain
+/// from the default hasher's protection against crafted collisions, and a lot of speed to lose.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnowflakeHasher(u64);
+
+/// An odd constant with its bits well spread out, the same one FxHash uses.
+const MULTIPLIER: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl Hasher for SnowflakeHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        // IDs are written as whole integers, this is only for anything else that ends up in here.
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = (self.0.rotate_left(5) ^ value).wrapping_mul(MULTIPLIER);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        // Multiplying only carries changes upwards, but the bucket is picked with the low bits,
+        // which would otherwise only depend on the increment at the bottom of each snowflake.
+        self.0 ^ (self.0 >> 32)
+    }
+}
+
+pub type BuildSnowflakeHasher = BuildHasherDefault<SnowflakeHasher>;
+
+pub type SnowflakeMap<K, V> = HashMap<K, V, BuildSnowflakeHasher>;
+pub type SnowflakeSet<K> = HashSet<K, BuildSnowflakeHasher>;
+
+/// An ordered pair of IDs, such as the source and target of an edge, that hashes as one value.
+pub struct IdPair<T>(pub Id<T>, pub Id<T>);
+
+// These are all implemented by hand, as deriving them would require the marker types to
+// implement them as well.
+
+impl<T> Clone for IdPair<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for IdPair<T> {}
+
+impl<T> PartialEq for IdPair<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0 && self.1 == other.1
+    }
+}
+
+impl<T> Eq for IdPair<T> {}
+
+impl<T> PartialOrd for IdPair<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for IdPair<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0, self.1).cmp(&(other.0, other.1))
+    }
+}
+
+impl<T> Hash for IdPair<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Swapping the halves of the first ID keeps the timestamps, which most pairs share the
+        // top of, from cancelling each other out.
+        state.write_u64(self.0.get().rotate_left(32) ^ self.1.get());
+    }
+}
+
+impl<T> Debug for IdPair<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IdPair")
+            .field(&self.0)
+            .field(&self.1)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdPair, SnowflakeMap, SnowflakeSet};
+    use twilight_model::id::marker::UserMarker;
+    use twilight_model::id::Id;
+
+    /// Sequential snowflakes from one worker, which only differ in their lowest bits.
+    fn snowflakes(count: u64) -> impl Iterator<Item = Id<UserMarker>> {
+        (0..count).map(|increment| Id::new((1_100_000_000_000 << 22) | increment))
+    }
+
+    #[test]
+    fn test_pairs() {
+        let a = Id::new(1);
+        let b = Id::new(2);
+
+        let mut map = SnowflakeMap::default();
+        map.insert(IdPair::<UserMarker>(a, b), 1);
+        map.insert(IdPair(b, a), 2);
+
+        assert_eq!(map[&IdPair(a, b)], 1);
+        assert_eq!(map[&IdPair(b, a)], 2);
+        assert!(IdPair(a, b) < IdPair(b, a));
+    }
+
+    #[test]
+    fn test_low_bits_spread() {
+        use std::hash::BuildHasher;
+
+        let map: SnowflakeSet<Id<UserMarker>> = snowflakes(1024).collect();
+        assert_eq!(map.len(), 1024);
+
+        // Lots of IDs that only differ in their increment should still land in lots of buckets.
+        let hasher = map.hasher();
+        let buckets: SnowflakeSet<u64> = snowflakes(1024)
+            .map(|id| hasher.hash_one(id) & 1023)
+            .collect();
+        assert!(buckets.len() > 512);
+
+        let pairs: SnowflakeSet<u64> = snowflakes(32)
+            .flat_map(|source| snowflakes(32).map(move |target| IdPair(source, target)))
+            .map(|pair| hasher.hash_one(pair) & 1023)
+            .collect();
+        assert!(pairs.len() > 512);
+    }
+}
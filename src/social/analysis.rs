@@ -0,0 +1,787 @@
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+
+use super::inference::RelationshipStrength;
+
+/// Limits on the Louvain iterations, real graphs settle well before these.
+const MAX_LEVELS: usize = 32;
+const MAX_PASSES_PER_LEVEL: usize = 100;
+
+/// Moves have to beat the current community by at least this much, to avoid nodes bouncing
+/// between equally good communities due to rounding.
+const MIN_GAIN: f64 = 1e-9;
+
+/// Edges have to get this many times stronger or weaker to be counted as changed in a diff.
+const MIN_CHANGE_RATIO: f32 = 1.5;
+
+/// Groups of users that are more connected to each other than to the rest of the guild.
+#[derive(Debug, Clone)]
+pub struct Communities {
+    /// Index into `groups` for each user.
+    pub membership: HashMap<Id<UserMarker>, usize>,
+    /// Members of each community, largest community first and most connected member first.
+    pub groups: Vec<Vec<Id<UserMarker>>>,
+    /// How much more clustered the graph is than chance, from -0.5 to 1.
+    pub modularity: f64,
+}
+
+/// Undirected weighted graph over dense node indexes.
+///
+/// Every edge appears in the adjacency list of both ends, apart from self-loops, which appear once
+/// with the full weight of the edges they replaced (counted in both directions).
+#[derive(Debug)]
+struct WeightedGraph {
+    adjacency: Vec<Vec<(usize, f64)>>,
+    degrees: Vec<f64>,
+    total_weight: f64,
+}
+
+impl WeightedGraph {
+    fn new(adjacency: Vec<Vec<(usize, f64)>>) -> Self {
+        let degrees: Vec<f64> = adjacency
+            .iter()
+            .map(|neighbours| neighbours.iter().map(|(_, weight)| weight).sum())
+            .collect();
+
+        let total_weight = degrees.iter().sum();
+
+        WeightedGraph {
+            adjacency,
+            degrees,
+            total_weight,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.adjacency.len()
+    }
+
+    /// Move nodes between communities until no move improves modularity.
+    /// Returns true if anything moved.
+    fn local_moves(&self, community: &mut [usize]) -> bool {
+        let mut totals = vec![0.0; self.len()];
+        for (node, &degree) in self.degrees.iter().enumerate() {
+            totals[community[node]] += degree;
+        }
+
+        let mut improved = false;
+
+        for _ in 0..MAX_PASSES_PER_LEVEL {
+            let mut moved = false;
+
+            for node in 0..self.len() {
+                let current = community[node];
+                let degree = self.degrees[node];
+
+                // BTreeMap so ties are always broken the same way.
+                let mut neighbour_weights: BTreeMap<usize, f64> = BTreeMap::new();
+                for &(neighbour, weight) in &self.adjacency[node] {
+                    if neighbour != node {
+                        *neighbour_weights.entry(community[neighbour]).or_default() += weight;
+                    }
+                }
+
+                totals[current] -= degree;
+
+                let gain = |community: usize, weight: f64| {
+                    weight - (totals[community] * degree / self.total_weight)
+                };
+
+                let mut best = current;
+                let mut best_gain = gain(
+                    current,
+                    neighbour_weights.get(&current).cloned().unwrap_or(0.0),
+                );
+
+                for (&candidate, &weight) in &neighbour_weights {
+                    let candidate_gain = gain(candidate, weight);
+                    if candidate_gain > best_gain + MIN_GAIN {
+                        best = candidate;
+                        best_gain = candidate_gain;
+                    }
+                }
+
+                totals[best] += degree;
+                community[node] = best;
+
+                if best != current {
+                    moved = true;
+                    improved = true;
+                }
+            }
+
+            if !moved {
+                break;
+            }
+        }
+
+        improved
+    }
+
+    /// Build a graph with one node per community.
+    fn aggregate(&self, community: &[usize], count: usize) -> Self {
+        let mut weights: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); count];
+
+        for (node, neighbours) in self.adjacency.iter().enumerate() {
+            for &(neighbour, weight) in neighbours {
+                *weights[community[node]]
+                    .entry(community[neighbour])
+                    .or_default() += weight;
+            }
+        }
+
+        let adjacency = weights
+            .into_iter()
+            .map(|neighbours| neighbours.into_iter().collect())
+            .collect();
+
+        WeightedGraph::new(adjacency)
+    }
+
+    fn modularity(&self, community: &[usize]) -> f64 {
+        if self.total_weight <= 0.0 {
+            return 0.0;
+        }
+
+        let mut internal = 0.0;
+        let mut totals: HashMap<usize, f64> = HashMap::new();
+
+        for (node, neighbours) in self.adjacency.iter().enumerate() {
+            for &(neighbour, weight) in neighbours {
+                if community[node] == community[neighbour] {
+                    internal += weight;
+                }
+            }
+
+            *totals.entry(community[node]).or_default() += self.degrees[node];
+        }
+
+        let expected: f64 = totals
+            .values()
+            .map(|total| (total / self.total_weight).powi(2))
+            .sum();
+
+        (internal / self.total_weight) - expected
+    }
+}
+
+/// Renumber communities to be contiguous from zero, in order of first appearance.
+fn renumber(community: &mut [usize]) -> usize {
+    let mut mapping = HashMap::new();
+
+    for value in community.iter_mut() {
+        let next = mapping.len();
+        *value = *mapping.entry(*value).or_insert(next);
+    }
+
+    mapping.len()
+}
+
+/// Build a dense graph from undirected edges, returning the user ID for each node index.
+///
+/// Nodes are in user ID order, so anything iterating over them is stable for the same input.
+fn build_graph(
+    edges: &[([Id<UserMarker>; 2], RelationshipStrength)],
+) -> (Vec<Id<UserMarker>>, WeightedGraph) {
+    let mut user_ids: Vec<_> = edges.iter().flat_map(|(key, _)| *key).collect();
+    user_ids.sort_unstable();
+    user_ids.dedup();
+
+    let indexes: HashMap<_, _> = user_ids
+        .iter()
+        .enumerate()
+        .map(|(index, user_id)| (*user_id, index))
+        .collect();
+
+    let mut adjacency = vec![Vec::new(); user_ids.len()];
+    for &([source, target], weight) in edges {
+        if source == target {
+            continue;
+        }
+
+        let (source, target) = (indexes[&source], indexes[&target]);
+        adjacency[source].push((target, weight as f64));
+        adjacency[target].push((source, weight as f64));
+    }
+
+    (user_ids, WeightedGraph::new(adjacency))
+}
+
+/// Find communities using the Louvain method, maximising modularity.
+pub fn detect_communities(edges: &[([Id<UserMarker>; 2], RelationshipStrength)]) -> Communities {
+    let (user_ids, original) = build_graph(edges);
+
+    // Which community each original node is in, updated as levels are aggregated.
+    let mut membership: Vec<usize> = (0..original.len()).collect();
+
+    let mut aggregated: Option<WeightedGraph> = None;
+    for _ in 0..MAX_LEVELS {
+        let graph = aggregated.as_ref().unwrap_or(&original);
+
+        let mut community: Vec<usize> = (0..graph.len()).collect();
+        if !graph.local_moves(&mut community) {
+            break;
+        }
+
+        let count = renumber(&mut community);
+
+        for value in membership.iter_mut() {
+            *value = community[*value];
+        }
+
+        aggregated = Some(graph.aggregate(&community, count));
+    }
+
+    let modularity = original.modularity(&membership);
+
+    // Order the groups and their members so the biggest and most central come first.
+    let count = renumber(&mut membership);
+    let mut groups: Vec<Vec<usize>> = vec![Vec::new(); count];
+    for (node, &community) in membership.iter().enumerate() {
+        groups[community].push(node);
+    }
+
+    for group in &mut groups {
+        group.sort_by(|a, b| original.degrees[*b].total_cmp(&original.degrees[*a]));
+    }
+
+    let group_weight =
+        |group: &Vec<usize>| -> f64 { group.iter().map(|&node| original.degrees[node]).sum() };
+
+    groups.sort_by(|a, b| {
+        b.len()
+            .cmp(&a.len())
+            .then_with(|| group_weight(b).total_cmp(&group_weight(a)))
+    });
+
+    let groups: Vec<Vec<_>> = groups
+        .into_iter()
+        .map(|group| group.into_iter().map(|node| user_ids[node]).collect())
+        .collect();
+
+    let membership = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(index, group)| group.iter().map(move |user_id| (*user_id, index)))
+        .collect();
+
+    Communities {
+        membership,
+        groups,
+        modularity,
+    }
+}
+
+/// Centrality measures for a single user.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UserMetrics {
+    pub neighbours: usize,
+    /// Sum of the weights of all the user's edges.
+    pub weighted_degree: f64,
+    /// Fraction of the shortest paths between other users that pass through this user.
+    pub betweenness: f64,
+    /// Weighted PageRank, summing to 1 across the graph.
+    pub pagerank: f64,
+    /// Fraction of the user's neighbours that are also connected to each other.
+    pub clustering: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphMetrics {
+    pub users: HashMap<Id<UserMarker>, UserMetrics>,
+    pub edges: usize,
+    /// Fraction of all possible edges that exist.
+    pub density: f64,
+    pub average_clustering: f64,
+}
+
+impl GraphMetrics {
+    /// The `count` users with the highest value for `key`, ties broken by user ID.
+    pub fn top_by(
+        &self,
+        count: usize,
+        key: impl Fn(&UserMetrics) -> f64,
+    ) -> Vec<(Id<UserMarker>, UserMetrics)> {
+        let mut users: Vec<_> = self
+            .users
+            .iter()
+            .map(|(user_id, metrics)| (*user_id, *metrics))
+            .collect();
+
+        users.sort_by(|(a_id, a), (b_id, b)| key(b).total_cmp(&key(a)).then(a_id.cmp(b_id)));
+        users.truncate(count);
+
+        users
+    }
+}
+
+const PAGERANK_DAMPING: f64 = 0.85;
+const PAGERANK_MAX_ITERATIONS: usize = 100;
+const PAGERANK_TOLERANCE: f64 = 1e-9;
+
+/// Compute per-user centrality and whole-graph metrics.
+///
+/// Betweenness treats stronger relationships as shorter paths, which makes this O(nm log n), so
+/// it should be run on a blocking thread for large guilds.
+pub fn compute_metrics(edges: &[([Id<UserMarker>; 2], RelationshipStrength)]) -> GraphMetrics {
+    let (user_ids, graph) = build_graph(edges);
+    let count = graph.len();
+
+    let betweenness = betweenness(&graph);
+    let pagerank = pagerank(&graph);
+    let clustering = clustering(&graph);
+
+    let users = user_ids
+        .iter()
+        .enumerate()
+        .map(|(node, user_id)| {
+            let metrics = UserMetrics {
+                neighbours: graph.adjacency[node].len(),
+                weighted_degree: graph.degrees[node],
+                betweenness: betweenness[node],
+                pagerank: pagerank[node],
+                clustering: clustering[node],
+            };
+
+            (*user_id, metrics)
+        })
+        .collect();
+
+    let edges = graph.adjacency.iter().map(Vec::len).sum::<usize>() / 2;
+
+    let density = if count > 1 {
+        edges as f64 / ((count * (count - 1)) as f64 / 2.0)
+    } else {
+        0.0
+    };
+
+    let average_clustering = if count > 0 {
+        clustering.iter().sum::<f64>() / count as f64
+    } else {
+        0.0
+    };
+
+    GraphMetrics {
+        users,
+        edges,
+        density,
+        average_clustering,
+    }
+}
+
+/// Distance entry for Dijkstra's priority queue, ordered so the nearest is popped first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f64,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance
+            .total_cmp(&self.distance)
+            .then(other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Brandes' algorithm with edge lengths of 1 / weight, normalised to 0..1.
+fn betweenness(graph: &WeightedGraph) -> Vec<f64> {
+    let count = graph.len();
+    let mut centrality = vec![0.0; count];
+
+    let mut distances = vec![f64::INFINITY; count];
+    let mut paths = vec![0.0; count];
+    let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); count];
+    let mut dependencies = vec![0.0; count];
+
+    for source in 0..count {
+        distances.fill(f64::INFINITY);
+        paths.fill(0.0);
+        dependencies.fill(0.0);
+        predecessors.iter_mut().for_each(Vec::clear);
+
+        let mut visited = Vec::with_capacity(count);
+        let mut queue = BinaryHeap::new();
+
+        distances[source] = 0.0;
+        paths[source] = 1.0;
+        queue.push(Candidate {
+            distance: 0.0,
+            node: source,
+        });
+
+        while let Some(Candidate { distance, node }) = queue.pop() {
+            // Skip stale queue entries.
+            if distance > distances[node] {
+                continue;
+            }
+
+            visited.push(node);
+
+            for &(neighbour, weight) in &graph.adjacency[node] {
+                let candidate = distance + (1.0 / weight);
+
+                if candidate < distances[neighbour] - MIN_GAIN {
+                    distances[neighbour] = candidate;
+                    paths[neighbour] = paths[node];
+                    predecessors[neighbour].clear();
+                    predecessors[neighbour].push(node);
+
+                    queue.push(Candidate {
+                        distance: candidate,
+                        node: neighbour,
+                    });
+                } else if (candidate - distances[neighbour]).abs() <= MIN_GAIN {
+                    paths[neighbour] += paths[node];
+                    predecessors[neighbour].push(node);
+                }
+            }
+        }
+
+        // Nodes can be pushed more than once, only the first visit counts.
+        let mut seen = vec![false; count];
+        visited.retain(|&node| !std::mem::replace(&mut seen[node], true));
+
+        for &node in visited.iter().rev() {
+            for &predecessor in &predecessors[node] {
+                dependencies[predecessor] +=
+                    (paths[predecessor] / paths[node]) * (1.0 + dependencies[node]);
+            }
+
+            if node != source {
+                centrality[node] += dependencies[node];
+            }
+        }
+    }
+
+    // Every pair was counted from both ends.
+    if count > 2 {
+        let pairs = ((count - 1) * (count - 2)) as f64;
+        for value in &mut centrality {
+            *value /= pairs;
+        }
+    }
+
+    centrality
+}
+
+fn pagerank(graph: &WeightedGraph) -> Vec<f64> {
+    let count = graph.len();
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let base = (1.0 - PAGERANK_DAMPING) / count as f64;
+    let mut ranks = vec![1.0 / count as f64; count];
+    let mut next = vec![0.0; count];
+
+    for _ in 0..PAGERANK_MAX_ITERATIONS {
+        next.fill(base);
+
+        for (node, neighbours) in graph.adjacency.iter().enumerate() {
+            let degree = graph.degrees[node];
+            if degree <= 0.0 {
+                continue;
+            }
+
+            for &(neighbour, weight) in neighbours {
+                next[neighbour] += PAGERANK_DAMPING * ranks[node] * (weight / degree);
+            }
+        }
+
+        let change: f64 = ranks.iter().zip(&next).map(|(a, b)| (a - b).abs()).sum();
+
+        std::mem::swap(&mut ranks, &mut next);
+
+        if change < PAGERANK_TOLERANCE {
+            break;
+        }
+    }
+
+    ranks
+}
+
+fn clustering(graph: &WeightedGraph) -> Vec<f64> {
+    let neighbours: Vec<HashSet<usize>> = graph
+        .adjacency
+        .iter()
+        .enumerate()
+        .map(|(node, neighbours)| {
+            neighbours
+                .iter()
+                .map(|(neighbour, _)| *neighbour)
+                .filter(|neighbour| *neighbour != node)
+                .collect()
+        })
+        .collect();
+
+    neighbours
+        .iter()
+        .map(|node_neighbours| {
+            let degree = node_neighbours.len();
+            if degree < 2 {
+                return 0.0;
+            }
+
+            let links = node_neighbours
+                .iter()
+                .map(|neighbour| neighbours[*neighbour].intersection(node_neighbours).count())
+                .sum::<usize>()
+                / 2;
+
+            links as f64 / ((degree * (degree - 1)) as f64 / 2.0)
+        })
+        .collect()
+}
+
+/// What changed between two versions of a graph.
+#[derive(Debug, Clone, Default)]
+pub struct GraphDiff {
+    /// Edges only in the newer graph, strongest first.
+    pub added: Vec<([Id<UserMarker>; 2], RelationshipStrength)>,
+    /// Edges only in the older graph with their old weight, strongest first.
+    pub removed: Vec<([Id<UserMarker>; 2], RelationshipStrength)>,
+    /// Edges in both graphs with their old and new weights, biggest change first.
+    pub strengthened: Vec<(
+        [Id<UserMarker>; 2],
+        RelationshipStrength,
+        RelationshipStrength,
+    )>,
+    pub weakened: Vec<(
+        [Id<UserMarker>; 2],
+        RelationshipStrength,
+        RelationshipStrength,
+    )>,
+    /// Users only in the newer graph.
+    pub users_added: Vec<Id<UserMarker>>,
+    /// Users only in the older graph.
+    pub users_removed: Vec<Id<UserMarker>>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.strengthened.is_empty()
+            && self.weakened.is_empty()
+    }
+}
+
+/// Compare the edges of two versions of a graph.
+pub fn diff_graphs(
+    before: &[([Id<UserMarker>; 2], RelationshipStrength)],
+    after: &[([Id<UserMarker>; 2], RelationshipStrength)],
+) -> GraphDiff {
+    let before_weights: HashMap<_, _> = before.iter().cloned().collect();
+    let after_weights: HashMap<_, _> = after.iter().cloned().collect();
+
+    let mut diff = GraphDiff::default();
+
+    for &(key, after_weight) in after {
+        match before_weights.get(&key) {
+            None => diff.added.push((key, after_weight)),
+            Some(&before_weight) if after_weight >= before_weight * MIN_CHANGE_RATIO => {
+                diff.strengthened.push((key, before_weight, after_weight))
+            }
+            Some(&before_weight) if before_weight >= after_weight * MIN_CHANGE_RATIO => {
+                diff.weakened.push((key, before_weight, after_weight))
+            }
+            Some(_) => {}
+        }
+    }
+
+    for &(key, before_weight) in before {
+        if !after_weights.contains_key(&key) {
+            diff.removed.push((key, before_weight));
+        }
+    }
+
+    // Sort by key first so that ties come out in a stable order.
+    let by_weight = |a: &([Id<UserMarker>; 2], RelationshipStrength),
+                     b: &([Id<UserMarker>; 2], RelationshipStrength)| {
+        b.1.total_cmp(&a.1).then(a.0.cmp(&b.0))
+    };
+
+    diff.added.sort_by(by_weight);
+    diff.removed.sort_by(by_weight);
+
+    diff.strengthened
+        .sort_by(|a, b| (b.2 / b.1).total_cmp(&(a.2 / a.1)).then(a.0.cmp(&b.0)));
+    diff.weakened
+        .sort_by(|a, b| (b.1 / b.2).total_cmp(&(a.1 / a.2)).then(a.0.cmp(&b.0)));
+
+    let users = |edges: &[([Id<UserMarker>; 2], RelationshipStrength)]| -> HashSet<Id<UserMarker>> {
+        edges.iter().flat_map(|(key, _)| *key).collect()
+    };
+
+    let before_users = users(before);
+    let after_users = users(after);
+
+    diff.users_added = after_users.difference(&before_users).cloned().collect();
+    diff.users_added.sort_unstable();
+
+    diff.users_removed = before_users.difference(&after_users).cloned().collect();
+    diff.users_removed.sort_unstable();
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::id::marker::UserMarker;
+    use twilight_model::id::Id;
+
+    use super::{compute_metrics, detect_communities, diff_graphs};
+
+    fn clique(user_ids: &[u64], weight: f32) -> Vec<([Id<UserMarker>; 2], f32)> {
+        let mut edges = Vec::new();
+
+        for (i, &source) in user_ids.iter().enumerate() {
+            for &target in &user_ids[(i + 1)..] {
+                edges.push(([Id::new(source), Id::new(target)], weight));
+            }
+        }
+
+        edges
+    }
+
+    #[test]
+    fn test_empty() {
+        let communities = detect_communities(&[]);
+
+        assert!(communities.groups.is_empty());
+        assert_eq!(communities.modularity, 0.0);
+    }
+
+    #[test]
+    fn test_two_cliques() {
+        let mut edges = clique(&[1, 2, 3, 4, 5], 10.0);
+        edges.extend(clique(&[6, 7, 8, 9], 10.0));
+
+        // A weak link between the two groups.
+        edges.push(([Id::new(5), Id::new(6)], 1.0));
+
+        let communities = detect_communities(&edges);
+
+        assert_eq!(
+            communities.groups,
+            vec![
+                vec![Id::new(5), Id::new(1), Id::new(2), Id::new(3), Id::new(4)],
+                vec![Id::new(6), Id::new(7), Id::new(8), Id::new(9)],
+            ],
+        );
+
+        assert_eq!(communities.membership[&Id::new(3)], 0);
+        assert_eq!(communities.membership[&Id::new(9)], 1);
+        assert!(communities.modularity > 0.4, "{}", communities.modularity);
+    }
+
+    #[test]
+    fn test_ring_of_cliques() {
+        // The classic case for Louvain, each clique should be found on its own.
+        let mut edges = Vec::new();
+        for i in 0..6 {
+            let base = i * 4 + 1;
+            edges.extend(clique(&[base, base + 1, base + 2, base + 3], 1.0));
+            edges.push(([Id::new(base + 3), Id::new(((i + 1) % 6) * 4 + 1)], 1.0));
+        }
+
+        let communities = detect_communities(&edges);
+
+        assert_eq!(communities.groups.len(), 6);
+        for group in &communities.groups {
+            assert_eq!(group.len(), 4);
+
+            let first = (group[0].get() - 1) / 4;
+            assert!(group.iter().all(|user_id| (user_id.get() - 1) / 4 == first));
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        // A star around 1, plus a triangle hanging off of 2.
+        let edges = vec![
+            ([Id::new(1), Id::new(2)], 1.0),
+            ([Id::new(1), Id::new(3)], 1.0),
+            ([Id::new(1), Id::new(4)], 1.0),
+            ([Id::new(2), Id::new(5)], 1.0),
+            ([Id::new(2), Id::new(6)], 1.0),
+            ([Id::new(5), Id::new(6)], 1.0),
+        ];
+
+        let metrics = compute_metrics(&edges);
+        let user = |id: u64| metrics.users[&Id::new(id)];
+
+        assert_eq!(metrics.edges, 6);
+        assert!((metrics.density - 0.4).abs() < 1e-9);
+
+        // 1 and 2 sit between everything, the leaves don't.
+        assert_eq!(user(3).betweenness, 0.0);
+
+        // Out of the 10 pairs of other users, 1 is on the paths between 3 or 4 and everyone else,
+        // and 2 is on the paths between 5 or 6 and 1, 3, or 4.
+        assert!((user(1).betweenness - 0.7).abs() < 1e-9, "{:?}", user(1));
+        assert!((user(2).betweenness - 0.6).abs() < 1e-9, "{:?}", user(2));
+
+        assert_eq!(user(5).clustering, 1.0);
+        assert!((user(2).clustering - (1.0 / 3.0)).abs() < 1e-9);
+        assert_eq!(user(1).clustering, 0.0);
+
+        let total: f64 = metrics.users.values().map(|user| user.pagerank).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+
+        let top = metrics.top_by(2, |user| user.pagerank);
+        assert_eq!(top.len(), 2);
+        assert!(top.iter().all(|(user_id, _)| user_id.get() <= 2));
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = vec![
+            ([Id::new(1), Id::new(2)], 2.0),
+            ([Id::new(1), Id::new(3)], 2.0),
+            ([Id::new(2), Id::new(3)], 4.0),
+            ([Id::new(3), Id::new(4)], 1.0),
+        ];
+
+        let after = vec![
+            ([Id::new(1), Id::new(2)], 2.5),
+            ([Id::new(1), Id::new(3)], 6.0),
+            ([Id::new(2), Id::new(3)], 1.0),
+            ([Id::new(2), Id::new(5)], 1.0),
+            ([Id::new(1), Id::new(5)], 3.0),
+        ];
+
+        let diff = diff_graphs(&before, &after);
+
+        assert_eq!(
+            diff.added,
+            vec![
+                ([Id::new(1), Id::new(5)], 3.0),
+                ([Id::new(2), Id::new(5)], 1.0),
+            ],
+        );
+        assert_eq!(diff.removed, vec![([Id::new(3), Id::new(4)], 1.0)]);
+        assert_eq!(
+            diff.strengthened,
+            vec![([Id::new(1), Id::new(3)], 2.0, 6.0)]
+        );
+        assert_eq!(diff.weakened, vec![([Id::new(2), Id::new(3)], 4.0, 1.0)]);
+        assert_eq!(diff.users_added, vec![Id::new(5)]);
+        assert_eq!(diff.users_removed, vec![Id::new(4)]);
+
+        assert!(diff_graphs(&after, &after).is_empty());
+    }
+}
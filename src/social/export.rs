@@ -0,0 +1,332 @@
+use serde_json::json;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+
+use std::fmt::Write;
+
+use super::graph::{DisplayGraph, DisplayNode};
+use crate::render::escape_xml;
+
+/// File formats a graph can be exported in, for loading into other tools.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// Gephi's native format.
+    Gexf,
+    GraphMl,
+    /// The node-link layout used by networkx and d3.
+    Json,
+    /// One row per edge, with the details of both users.
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "gexf" => Self::Gexf,
+            "graphml" => Self::GraphMl,
+            "json" => Self::Json,
+            "csv" => Self::Csv,
+            _ => return None,
+        })
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Gexf => "gexf",
+            Self::GraphMl => "graphml",
+            Self::Json => "json",
+            Self::Csv => "csv",
+        }
+    }
+
+    pub fn export(&self, graph: &DisplayGraph) -> String {
+        match self {
+            Self::Gexf => export_gexf(graph),
+            Self::GraphMl => export_graphml(graph),
+            Self::Json => export_json(graph),
+            Self::Csv => export_csv(graph),
+        }
+    }
+}
+
+/// Role colors as `#RRGGBB`, empty for users without a colored role.
+fn format_color(color: Option<u32>) -> String {
+    color.map_or_else(String::new, |color| format!("#{:06X}", color))
+}
+
+// Writing to a String can't fail, so the results are ignored throughout.
+
+fn export_gexf(graph: &DisplayGraph) -> String {
+    let mut gexf = String::new();
+
+    gexf.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gexf.push_str("<gexf xmlns=\"http://gexf.net/1.3\" xmlns:viz=\"http://gexf.net/1.3/viz\" version=\"1.3\">\n");
+    gexf.push_str("<graph defaultedgetype=\"undirected\">\n");
+    gexf.push_str("<attributes class=\"node\">\n");
+    gexf.push_str("<attribute id=\"color\" title=\"color\" type=\"string\"/>\n");
+    gexf.push_str("<attribute id=\"is_member\" title=\"is_member\" type=\"boolean\"/>\n");
+    gexf.push_str("<attribute id=\"weight\" title=\"weight\" type=\"double\"/>\n");
+    gexf.push_str("</attributes>\n");
+
+    gexf.push_str("<nodes>\n");
+    for user_id in graph.sorted_user_ids() {
+        let node = &graph.nodes[&user_id];
+
+        let _ = writeln!(
+            gexf,
+            "<node id=\"{}\" label=\"{}\"><attvalues>\
+            <attvalue for=\"color\" value=\"{}\"/>\
+            <attvalue for=\"is_member\" value=\"{}\"/>\
+            <attvalue for=\"weight\" value=\"{}\"/>\
+            </attvalues>",
+            user_id,
+            escape_xml(&node.name),
+            format_color(node.color),
+            node.is_member,
+            node.weight,
+        );
+
+        if let Some(color) = node.color {
+            let _ = writeln!(
+                gexf,
+                "<viz:color r=\"{}\" g=\"{}\" b=\"{}\"/>",
+                (color >> 16) & 0xFF,
+                (color >> 8) & 0xFF,
+                color & 0xFF,
+            );
+        }
+
+        gexf.push_str("</node>\n");
+    }
+    gexf.push_str("</nodes>\n");
+
+    gexf.push_str("<edges>\n");
+    for (id, ([source, target], weight)) in graph.edges.iter().enumerate() {
+        let _ = writeln!(
+            gexf,
+            "<edge id=\"{}\" source=\"{}\" target=\"{}\" weight=\"{}\"/>",
+            id, source, target, weight,
+        );
+    }
+    gexf.push_str("</edges>\n");
+
+    gexf.push_str("</graph>\n</gexf>\n");
+
+    gexf
+}
+
+fn export_graphml(graph: &DisplayGraph) -> String {
+    let mut graphml = String::new();
+
+    graphml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    graphml.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+    graphml.push_str("<key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n");
+    graphml.push_str("<key id=\"color\" for=\"node\" attr.name=\"color\" attr.type=\"string\"/>\n");
+    graphml.push_str(
+        "<key id=\"is_member\" for=\"node\" attr.name=\"is_member\" attr.type=\"boolean\"/>\n",
+    );
+    graphml.push_str(
+        "<key id=\"node_weight\" for=\"node\" attr.name=\"weight\" attr.type=\"double\"/>\n",
+    );
+    graphml.push_str(
+        "<key id=\"edge_weight\" for=\"edge\" attr.name=\"weight\" attr.type=\"double\"/>\n",
+    );
+    graphml.push_str("<graph edgedefault=\"undirected\">\n");
+
+    for user_id in graph.sorted_user_ids() {
+        let node = &graph.nodes[&user_id];
+
+        let _ = writeln!(
+            graphml,
+            "<node id=\"{}\">\
+            <data key=\"name\">{}</data>\
+            <data key=\"color\">{}</data>\
+            <data key=\"is_member\">{}</data>\
+            <data key=\"node_weight\">{}</data>\
+            </node>",
+            user_id,
+            escape_xml(&node.name),
+            format_color(node.color),
+            node.is_member,
+            node.weight,
+        );
+    }
+
+    for ([source, target], weight) in &graph.edges {
+        let _ = writeln!(
+            graphml,
+            "<edge source=\"{}\" target=\"{}\"><data key=\"edge_weight\">{}</data></edge>",
+            source, target, weight,
+        );
+    }
+
+    graphml.push_str("</graph>\n</graphml>\n");
+
+    graphml
+}
+
+fn export_json(graph: &DisplayGraph) -> String {
+    // IDs are strings as they're too big to survive being parsed as a double.
+    let nodes: Vec<_> = graph
+        .sorted_user_ids()
+        .into_iter()
+        .map(|user_id| {
+            let node = &graph.nodes[&user_id];
+
+            json!({
+                "id": user_id.to_string(),
+                "name": node.name,
+                "color": node.color.map(|color| format_color(Some(color))),
+                "is_member": node.is_member,
+                "weight": node.weight,
+            })
+        })
+        .collect();
+
+    let links: Vec<_> = graph
+        .edges
+        .iter()
+        .map(|([source, target], weight)| {
+            json!({
+                "source": source.to_string(),
+                "target": target.to_string(),
+                "weight": weight,
+            })
+        })
+        .collect();
+
+    let json = json!({
+        "directed": false,
+        "multigraph": false,
+        "graph": {},
+        "nodes": nodes,
+        "links": links,
+    });
+
+    serde_json::to_string_pretty(&json).unwrap()
+}
+
+/// Quote a CSV field if it needs it.
+///
+/// Names are chosen by users, so any that a spreadsheet would take as a formula are prefixed with
+/// a `'` to keep them as text.
+fn escape_csv(text: &str) -> String {
+    let text = if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", text)
+    } else {
+        text.to_owned()
+    };
+
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn export_csv(graph: &DisplayGraph) -> String {
+    let mut csv = String::from(
+        "source,source_name,source_color,source_is_member,\
+        target,target_name,target_color,target_is_member,weight\n",
+    );
+
+    let user_columns = |user_id: &Id<UserMarker>, node: &DisplayNode| {
+        format!(
+            "{},{},{},{}",
+            user_id,
+            escape_csv(&node.name),
+            format_color(node.color),
+            node.is_member,
+        )
+    };
+
+    for ([source, target], weight) in &graph.edges {
+        let _ = writeln!(
+            csv,
+            "{},{},{}",
+            user_columns(source, &graph.nodes[source]),
+            user_columns(target, &graph.nodes[target]),
+            weight,
+        );
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExportFormat;
+    use crate::avatar::Avatar;
+    use crate::social::graph::{DisplayGraph, DisplayNode};
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use twilight_model::id::Id;
+
+    fn graph() -> DisplayGraph {
+        let node = |name: &str, color, is_member| DisplayNode {
+            name: name.to_owned(),
+            color,
+            is_member,
+            avatar: Avatar::Default { index: 0 },
+            weight: 3.0,
+        };
+
+        DisplayGraph {
+            nodes: HashMap::from([
+                (Id::new(1), node("alice", Some(0xE91E63), true)),
+                (Id::new(2), node("<bob, \"the builder\">", None, false)),
+                (Id::new(3), node("carol", None, true)),
+                (Id::new(4), node("=HYPERLINK(\"x\")", None, true)),
+            ]),
+            edges: vec![
+                ([Id::new(1), Id::new(2)], 1.5),
+                ([Id::new(1), Id::new(3)], 1.5),
+                ([Id::new(3), Id::new(4)], 1.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let graph = graph();
+
+        let gexf = ExportFormat::Gexf.export(&graph);
+        assert!(gexf.contains("label=\"&lt;bob, &quot;the builder&quot;&gt;\""));
+        assert!(gexf.contains("<viz:color r=\"233\" g=\"30\" b=\"99\"/>"));
+        assert_eq!(gexf.matches("<edge ").count(), 3);
+
+        let graphml = ExportFormat::GraphMl.export(&graph);
+        assert!(graphml.contains("<data key=\"color\">#E91E63</data>"));
+        assert!(graphml.contains("<data key=\"is_member\">false</data>"));
+        assert_eq!(graphml.matches("<node ").count(), 4);
+
+        let json: Value = serde_json::from_str(&ExportFormat::Json.export(&graph)).unwrap();
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 4);
+        assert_eq!(json["nodes"][0]["id"], "1");
+        assert_eq!(json["nodes"][0]["color"], "#E91E63");
+        assert_eq!(json["links"][1]["target"], "3");
+        assert_eq!(json["links"][1]["weight"], 1.5);
+
+        let csv = ExportFormat::Csv.export(&graph);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[1],
+            "1,alice,#E91E63,true,2,\"<bob, \"\"the builder\"\">\",,false,1.5"
+        );
+        assert_eq!(
+            lines[3],
+            "3,carol,,true,4,\"'=HYPERLINK(\"\"x\"\")\",,true,1"
+        );
+    }
+
+    #[test]
+    fn test_format_names() {
+        for name in ["gexf", "graphml", "json", "csv"] {
+            let format = ExportFormat::from_name(name).unwrap();
+            assert_eq!(format.extension(), name);
+        }
+
+        assert_eq!(ExportFormat::from_name("dot"), None);
+    }
+}
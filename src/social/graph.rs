@@ -1,71 +1,74 @@
-use anyhow::Result as AnyhowResult;
+use anyhow::{Context as AnyhowContext, Result as AnyhowResult};
 use futures::future::join_all;
-use serde::de::{Deserialize, Deserializer, Error as DeserializerError, MapAccess, Visitor};
-use serde::ser::{Serialize, SerializeMap, Serializer};
+use lru::LruCache;
+use parking_lot::{Mutex, RwLock};
+use serde::de::{
+    Deserialize, Deserializer, Error as DeserializerError, IgnoredAny, MapAccess, SeqAccess,
+    Visitor,
+};
+use serde::ser::{Serialize, SerializeMap, SerializeTuple, Serializer};
+use serde_json::{json, Map as JsonMap, Value};
 use tracing::{error, info, warn};
-use twilight_model::id::marker::{ChannelMarker, GuildMarker, UserMarker};
+use twilight_model::channel::message::ReactionType;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
 use twilight_model::id::Id;
-use twilight_model::user::User;
-use unicode_segmentation::UnicodeSegmentation;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
-use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::io::{Read, Write};
 use std::num::ParseIntError;
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use super::history::StoredEvent;
 use super::inference::{
-    InferenceState, Interaction, RelationshipChange, RelationshipStrength, RELATIONSHIP_DECAY,
+    InferenceEngineRegistry, InferenceState, Interaction, RelationshipChange,
+    RelationshipChangeReason, RelationshipStrength, RELATIONSHIP_HALF_LIFE,
+    RELATIONSHIP_PRUNE_THRESHOLD,
 };
+use super::replay::{interaction_from_json, interaction_to_json};
+use super::store::{PendingWrite, SharedGraphStore};
+use super::weighting::GuildActivity;
+use crate::avatar::Avatar;
 use crate::cache::CachedMember;
 use crate::context::Context;
-use crate::social::inference::{InteractionType, RELATIONSHIP_DECAY_GLOBAL};
-
-// TODO: This doesn't handle counting wide characters very well,
-//       Probably want to pull in the unicode-width crate for that.
-fn get_label(mut name: String) -> String {
-    let label_length = 9;
-    let ellipsis = "...";
-    let ellipsis_length = ellipsis.len();
+use crate::layout::{self, LayoutConfig, LayoutEdge, LayoutNode, Point};
+use crate::render::{self, NodeStyle};
+use crate::snowflake::{IdPair, SnowflakeMap};
+use crate::social::inference::InteractionType;
 
-    let mut name_iter = name.grapheme_indices(true);
-    let key_graphemes = (
-        name_iter.nth(label_length - ellipsis_length),
-        name_iter.nth(ellipsis_length - 1),
-    );
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
 
-    match key_graphemes {
-        (_, None) => name,
-        (Some((pos, _)), Some(_)) => {
-            name.truncate(pos);
-            name.push_str(ellipsis);
-            name
-        }
-        (None, Some(_)) => unreachable!(),
-    }
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Relationship {
+    pub strength: RelationshipStrength,
+    /// Unix timestamp (in milliseconds) that `strength` was last brought up to date at.
+    pub updated: u64,
 }
 
-fn calculate_luma(color: u32) -> f32 {
-    // TODO: Consider alpha blending with a background color.
-    let r = ((color >> 24) & 0xFF) as f32;
-    let g = ((color >> 16) & 0xFF) as f32;
-    let b = ((color >> 8) & 0xFF) as f32;
+impl Relationship {
+    /// Calculate the strength of the relationship at `now`, after exponential decay.
+    pub fn decayed_strength(&self, now: u64, half_life: Duration) -> RelationshipStrength {
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        let half_lives = elapsed / half_life.as_millis() as f64;
 
-    (r * 0.299) + (g * 0.587) + (b * 0.114)
+        (self.strength as f64 * 0.5f64.powf(half_lives)) as RelationshipStrength
+    }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub enum ColorScheme {
-    Light,
-    Dark,
-}
+/// A directed edge between two users, as `IdPair(source, target)`.
+pub type UserPair = IdPair<UserMarker>;
 
-#[derive(Clone, Debug)]
-pub struct UserRelationshipGraphMap(
-    HashMap<(Id<UserMarker>, Id<UserMarker>), RelationshipStrength>,
-);
+#[derive(Clone, Debug, Default)]
+pub struct UserRelationshipGraphMap(SnowflakeMap<UserPair, Relationship>);
 
 #[derive(Debug)]
 pub enum ToDotError {
@@ -87,11 +90,11 @@ impl Display for ToDotError {
 impl std::error::Error for ToDotError {}
 
 impl UserRelationshipGraphMap {
-    fn new() -> Self {
-        UserRelationshipGraphMap(HashMap::new())
+    pub fn new() -> Self {
+        UserRelationshipGraphMap(SnowflakeMap::default())
     }
 
-    fn new_from_path(path: &Path) -> std::io::Result<Self> {
+    pub(crate) fn new_from_path(path: &Path) -> std::io::Result<Self> {
         let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
@@ -99,7 +102,7 @@ impl UserRelationshipGraphMap {
         Ok(serde_json::from_str(&contents)?)
     }
 
-    fn save_to_path(&self, path: &Path) -> std::io::Result<()> {
+    pub(crate) fn save_to_path(&self, path: &Path) -> std::io::Result<()> {
         if self.0.is_empty() {
             return Ok(());
         }
@@ -110,48 +113,141 @@ impl UserRelationshipGraphMap {
         file.write_all(contents.as_bytes())
     }
 
-    fn decay(&mut self, amount: RelationshipStrength) {
-        let mut edges_to_remove = Vec::new();
+    /// Decay an edge up to `now`, then add `amount` to it.
+    pub fn reinforce(
+        &mut self,
+        source_target: UserPair,
+        amount: RelationshipStrength,
+        now: u64,
+        half_life: Duration,
+    ) {
+        let relationship = self.entry(source_target).or_default();
+
+        relationship.strength = relationship.decayed_strength(now, half_life) + amount;
+        relationship.updated = now;
+    }
+
+    /// Like `reinforce`, but for an `amount` that should have been added at `at` and has been
+    /// decaying since. A negative `amount` takes away something that was added, and the strength
+    /// never drops below zero.
+    fn adjust(
+        &mut self,
+        source_target: UserPair,
+        amount: RelationshipStrength,
+        at: u64,
+        now: u64,
+        half_life: Duration,
+    ) {
+        let decayed_amount = Relationship {
+            strength: amount,
+            updated: at,
+        }
+        .decayed_strength(now, half_life);
+
+        let relationship = self.entry(source_target).or_default();
+
+        relationship.strength =
+            (relationship.decayed_strength(now, half_life) + decayed_amount).max(0.0);
+        relationship.updated = now;
+    }
 
-        for (&source_target, relationship) in self.iter_mut() {
-            *relationship += amount;
+    /// Remove any edges that would have decayed below the threshold by `now`, returning them.
+    ///
+    /// The remaining edges are left untouched, decay is only applied to them when next reinforced.
+    pub(crate) fn prune(&mut self, now: u64, half_life: Duration) -> Vec<UserPair> {
+        let mut pruned = Vec::new();
 
-            if *relationship <= 0.0 {
-                edges_to_remove.push(source_target);
+        self.retain(|&source_target, relationship| {
+            let keep =
+                relationship.decayed_strength(now, half_life) >= RELATIONSHIP_PRUNE_THRESHOLD;
+
+            if !keep {
+                pruned.push(source_target);
+            }
+
+            keep
+        });
+
+        pruned
+    }
+
+    /// Sum a set of graphs into one, with all the decay applied up until `now`.
+    pub fn merge<'a>(
+        graphs: impl IntoIterator<Item = &'a UserRelationshipGraphMap>,
+        now: u64,
+        half_life: Duration,
+    ) -> Self {
+        let mut merged = UserRelationshipGraphMap::new();
+        for graph in graphs {
+            for (&source_target, relationship) in graph.iter() {
+                let merged_relationship = merged.entry(source_target).or_default();
+
+                merged_relationship.strength += relationship.decayed_strength(now, half_life);
+                merged_relationship.updated = now;
             }
         }
 
-        for source_target in edges_to_remove {
-            self.remove(&source_target);
+        merged
+    }
+
+    /// Combine graphs by taking each relationship from whichever graph it is strongest in.
+    pub(crate) fn strongest_of<'a>(
+        graphs: impl IntoIterator<Item = &'a UserRelationshipGraphMap>,
+    ) -> Self {
+        let mut combined = UserRelationshipGraphMap::new();
+        for graph in graphs {
+            for (&source_target, relationship) in graph.iter() {
+                let combined_relationship = combined.entry(source_target).or_default();
+                if relationship.strength > combined_relationship.strength {
+                    *combined_relationship = *relationship;
+                }
+            }
         }
+
+        combined
     }
 
-    pub async fn to_dot(
-        &self,
-        context: &Context,
-        guild_id: Id<GuildMarker>,
-        requesting_user: Option<&User>,
-        color_scheme: ColorScheme,
-        transparent: bool,
-        font_name: &str,
-    ) -> AnyhowResult<String> {
-        // Gather all undirected edges.
+    /// The undirected edges that are strong enough to be drawn, sorted by user IDs.
+    pub fn visible_edges(&self) -> Vec<([Id<UserMarker>; 2], RelationshipStrength)> {
+        let mut edges: Vec<_> = self
+            .undirected_edges()
+            .into_iter()
+            .filter(|(_, weight)| *weight >= 1.0)
+            .collect();
+
+        edges.sort_unstable_by_key(|(key, _)| *key);
+
+        edges
+    }
+
+    /// Collapse directed edges into undirected ones keyed by the sorted user IDs, summing their
+    /// strengths. Self-connected edges are ignored.
+    pub fn undirected_edges(&self) -> HashMap<[Id<UserMarker>; 2], RelationshipStrength> {
         let mut undirected_edges = HashMap::new();
-        for (&(source, target), new_weight) in &self.0 {
-            // Ignore self-connected edges.
+        for (&IdPair(source, target), relationship) in &self.0 {
             if source == target {
                 continue;
             }
 
-            // Sort the key to make it direction-independent.
             let mut key = [source, target];
             key.sort();
 
-            // As we're collapsing directed edges, we need to sum the weights.
             let weight: &mut RelationshipStrength = undirected_edges.entry(key).or_default();
-            *weight += new_weight;
+            *weight += relationship.strength;
         }
 
+        undirected_edges
+    }
+
+    /// Collapse the graph into weighted undirected edges between users, filtering out anything
+    /// too weak to display and anyone we can't look up, and attach their display info.
+    pub async fn to_display_graph(
+        &self,
+        context: &Context,
+        guild_id: Id<GuildMarker>,
+    ) -> AnyhowResult<DisplayGraph> {
+        let mut undirected_edges = self.undirected_edges();
+
         // Remove any edges that have a weight under the threshold and build a list of unique user IDs.
         let mut user_ids = HashSet::new();
         undirected_edges.retain(|&[source, target], weight| {
@@ -257,6 +353,8 @@ impl UserRelationshipGraphMap {
                     })
                 });
 
+                let avatar = Avatar::for_user(guild_id, &user, member.as_ref());
+
                 let name = if let Some(CachedMember {
                     nick: Some(nick), ..
                 }) = member
@@ -266,7 +364,7 @@ impl UserRelationshipGraphMap {
                     user.name
                 };
 
-                Some((user_id, (name, color, is_member)))
+                Some((user_id, (name, color, is_member, avatar)))
             });
 
             join_all(futures).await.into_iter().flatten().collect()
@@ -297,153 +395,161 @@ impl UserRelationshipGraphMap {
             return Err(anyhow::Error::new(ToDotError::NotEnoughUsers));
         }
 
-        const BG_LIGHT: u32 = 0xFFFFFFFF;
-        const FG_LIGHT: u32 = 0x060607FF;
-        const BG_DARK: u32 = 0x313338FF;
-        const FG_DARK: u32 = 0xF2F3F5FF;
+        let nodes = user_weights
+            .into_iter()
+            .map(|(user_id, weight)| {
+                let (name, color, is_member, avatar) =
+                    names_and_colors.get(&user_id).unwrap().clone();
+
+                let node = DisplayNode {
+                    name,
+                    color,
+                    is_member,
+                    avatar,
+                    weight,
+                };
 
-        let (bg_color, fg_color) = match color_scheme {
-            ColorScheme::Light => (BG_LIGHT, FG_LIGHT),
-            ColorScheme::Dark => (BG_DARK, FG_DARK),
-        };
+                (user_id, node)
+            })
+            .collect();
 
-        let mut lines = Vec::with_capacity(17 + user_weights.len() + undirected_edges.len() + 1);
-
-        lines.push(String::from("graph {"));
-        lines.push(String::from("    dpi = \"144\""));
-        lines.push(String::from("    pad = \"0.3\""));
-        lines.push(String::from("    layout = \"fdp\""));
-        lines.push(String::from("    K = \"0.1\""));
-        lines.push(String::from("    splines = \"true\""));
-        lines.push(String::from("    overlap = \"30:true\""));
-        lines.push(String::from("    outputorder = \"edgesfirst\""));
-        lines.push(String::from("    truecolor = \"true\""));
-        lines.push(format!("    color = \"#{:08X}\"", fg_color));
-        lines.push(format!("    fontcolor = \"#{:08X}\"", fg_color));
-
-        if transparent {
-            lines.push(String::from("    bgcolor = \"transparent\""));
-        } else {
-            lines.push(format!("    bgcolor = \"#{:08X}\"", bg_color));
-        }
+        let mut edges: Vec<_> = undirected_edges.into_iter().collect();
+        edges.sort_unstable_by_key(|(key, _)| *key);
 
-        if let Some(user) = requesting_user {
-            let guild = context.cache.get_guild(guild_id).await?;
+        Ok(DisplayGraph { nodes, edges })
+    }
+}
 
-            let member = context
-                .cache
-                .get_member(guild_id, context.user.id)
-                .await
-                .ok();
+#[derive(Debug, Clone)]
+pub struct DisplayNode {
+    pub name: String,
+    pub color: Option<u32>,
+    pub is_member: bool,
+    pub avatar: Avatar,
+    /// Sum of the weights of all the node's edges.
+    pub weight: RelationshipStrength,
+}
 
-            let nickname = match &member {
-                Some(CachedMember {
-                    nick: Some(nick), ..
-                }) => nick,
-                _ => &context.user.name,
-            };
+/// A guild graph that has been filtered and annotated ready to be displayed.
+#[derive(Debug, Clone)]
+pub struct DisplayGraph {
+    pub nodes: HashMap<Id<UserMarker>, DisplayNode>,
+    /// Undirected edges, sorted by user IDs.
+    pub edges: Vec<([Id<UserMarker>; 2], RelationshipStrength)>,
+}
 
-            let safe_name = user.name.replace('\\', "\\\\").replace('"', "\\\"");
-            let safe_nickname = nickname.replace('\\', "\\\\").replace('"', "\\\"");
-            let safe_guild_name = guild.name.replace('\\', "\\\\").replace('"', "\\\"");
+impl DisplayGraph {
+    /// Node IDs in a stable order, so that layouts are reproducible.
+    pub fn sorted_user_ids(&self) -> Vec<Id<UserMarker>> {
+        let mut user_ids: Vec<_> = self.nodes.keys().cloned().collect();
+        user_ids.sort_unstable();
+        user_ids
+    }
 
-            // TODO: Add a timestamp.
-            let label = format!(
-                "Generated for {}#{:04} by {} in {}",
-                safe_name, user.discriminator, safe_nickname, safe_guild_name,
-            );
+    /// The part of the graph within `depth` hops of `user_id`, or `None` if they aren't in it.
+    pub fn ego_graph(&self, user_id: Id<UserMarker>, depth: usize) -> Option<DisplayGraph> {
+        if !self.nodes.contains_key(&user_id) {
+            return None;
+        }
 
-            lines.push(format!("    label = \"{}\"", label));
-            lines.push(String::from("    labelloc = \"bottom\""));
-            lines.push(String::from("    labeljust = \"left\""));
-            lines.push(format!("    fontname = \"{}\"", font_name));
+        let mut neighbours: HashMap<Id<UserMarker>, Vec<Id<UserMarker>>> = HashMap::new();
+        for ([source, target], _) in &self.edges {
+            neighbours.entry(*source).or_default().push(*target);
+            neighbours.entry(*target).or_default().push(*source);
         }
 
-        lines.push(format!("    node [ fontname = \"{}\" ]", font_name));
+        let mut included = HashSet::from([user_id]);
+        let mut frontier = vec![user_id];
 
-        for (user_id, weight) in &user_weights {
-            let (name, role_color, is_member) = names_and_colors.get(user_id).unwrap().clone();
-            let width = 1.0 + weight.log10();
+        for _ in 0..depth {
+            frontier = frontier
+                .iter()
+                .flat_map(|user_id| &neighbours[user_id])
+                .filter(|neighbour| included.insert(**neighbour))
+                .cloned()
+                .collect();
+        }
+
+        let edges: Vec<_> = self
+            .edges
+            .iter()
+            .filter(|([source, target], _)| included.contains(source) && included.contains(target))
+            .cloned()
+            .collect();
 
-            // TODO: This could be a lot more efficient.
-            let mut label = get_label(name.to_owned())
-                .replace('&', "&amp;")
-                .replace('"', "&quot;")
-                .replace('\'', "&#x27;")
-                .replace('<', "&lt;")
-                .replace('>', "&gt;")
-                .replace('\\', "\\\\");
+        Some(self.with_edges(edges))
+    }
 
-            let mut peripheries = 1;
-            let mut color = fg_color;
-            let mut fillcolor = bg_color;
-            let mut fontcolor = fg_color;
+    /// A graph of just the given edges, between users that are already in this one, and only
+    /// including the users they connect.
+    pub fn with_edges(&self, edges: Vec<([Id<UserMarker>; 2], RelationshipStrength)>) -> Self {
+        let edges: Vec<_> = edges
+            .into_iter()
+            .filter(|([source, target], _)| {
+                self.nodes.contains_key(source) && self.nodes.contains_key(target)
+            })
+            .collect();
 
-            if let Some(role_color) = role_color {
-                color = (role_color << 8) | 0xFF;
-            }
+        // Node weights are only used for sizing, so keep them relative to the visible edges.
+        let mut nodes = HashMap::new();
+        for ([source, target], weight) in &edges {
+            for user_id in [source, target] {
+                let node = nodes.entry(*user_id).or_insert_with(|| DisplayNode {
+                    weight: 0.0,
+                    ..self.nodes[user_id].clone()
+                });
 
-            if !is_member {
-                color -= 200;
-                fontcolor -= 200;
+                node.weight += weight;
             }
+        }
 
-            if let Some(user) = requesting_user {
-                // Invert the colors if it is the requesting user.
-                if *user_id == user.id {
-                    // Make the text bold.
-                    label = format!("<B>{}</B>", label);
+        DisplayGraph { nodes, edges }
+    }
 
-                    peripheries = 2;
+    /// Run the force-directed layout on a blocking thread, returning positions in points.
+    pub async fn layout(
+        &self,
+        node_style: NodeStyle,
+    ) -> AnyhowResult<HashMap<Id<UserMarker>, Point>> {
+        let user_ids = self.sorted_user_ids();
+        let indexes: HashMap<_, _> = user_ids
+            .iter()
+            .enumerate()
+            .map(|(index, user_id)| (*user_id, index))
+            .collect();
 
-                    fillcolor = color;
+        let nodes: Vec<_> = user_ids
+            .iter()
+            .map(|user_id| {
+                let node = &self.nodes[user_id];
 
-                    // Select text color based on fill contrast.
-                    fontcolor = if calculate_luma(fillcolor) > 186.0 {
-                        FG_LIGHT
-                    } else {
-                        FG_DARK
-                    };
+                LayoutNode {
+                    radius: render::node_radius(node_style, &node.name, node.weight),
                 }
-            }
-
-            lines.push(format!(
-                "    {} [ label = <{}>, penwidth = \"{}\", style = \"filled\", peripheries = \"{}\", color = \"#{:08X}\", fillcolor = \"#{:08X}\", fontcolor = \"#{:08X}\" ]",
-                user_id,
-                label,
-                width,
-                peripheries,
-                color,
-                fillcolor,
-                fontcolor,
-            ));
-        }
-
-        for ([source, target], weight) in undirected_edges {
-            let width = 1.0 + weight.log10();
-            let mut color = fg_color;
-
-            // TODO: We could store this in undirected_edges.
-            let (_, _, source_is_member) = names_and_colors.get(&source).unwrap();
-            let (_, _, target_is_member) = names_and_colors.get(&target).unwrap();
-            if !source_is_member || !target_is_member {
-                color -= 200;
-            }
+            })
+            .collect();
 
-            lines.push(format!(
-                "    {} -- {} [ weight = \"{}\", penwidth = \"{}\", color = \"#{:08X}\" ]",
-                source, target, weight, width, color,
-            ));
-        }
+        let edges: Vec<_> = self
+            .edges
+            .iter()
+            .map(|([source, target], weight)| LayoutEdge {
+                source: indexes[source],
+                target: indexes[target],
+                weight: *weight,
+            })
+            .collect();
 
-        lines.push(String::from("}"));
+        let positions = tokio::task::spawn_blocking(move || {
+            layout::layout(&nodes, &edges, &LayoutConfig::default())
+        })
+        .await?;
 
-        Ok(lines.join("\n"))
+        Ok(user_ids.into_iter().zip(positions).collect())
     }
 }
 
 impl std::ops::Deref for UserRelationshipGraphMap {
-    type Target = HashMap<(Id<UserMarker>, Id<UserMarker>), RelationshipStrength>;
+    type Target = SnowflakeMap<UserPair, Relationship>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -494,11 +600,20 @@ impl<'de> Visitor<'de> for UserRelationshipGraphMapVisitor {
     where
         M: MapAccess<'de>,
     {
-        let mut map =
-            UserRelationshipGraphMap(HashMap::with_capacity(access.size_hint().unwrap_or(0)));
+        let mut map = UserRelationshipGraphMap(SnowflakeMap::with_capacity_and_hasher(
+            access.size_hint().unwrap_or(0),
+            Default::default(),
+        ));
+
+        // Graphs saved before we tracked decay over time don't have timestamps, so treat those
+        // relationships as being up to date as of when they were loaded.
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
 
         // While there are entries remaining in the input, add them into our map.
-        while let Some((key, value)) = access.next_entry::<&str, RelationshipStrength>()? {
+        while let Some((key, mut value)) = access.next_entry::<&str, Relationship>()? {
             let err = "expected exactly 2 numbers separated by :";
 
             let mut iter = key.split(':');
@@ -514,7 +629,11 @@ impl<'de> Visitor<'de> for UserRelationshipGraphMapVisitor {
                 return Err(M::Error::custom(err));
             }
 
-            map.insert((Id::new(k1), Id::new(k2)), value);
+            if value.updated == 0 {
+                value.updated = now;
+            }
+
+            map.insert(IdPair(Id::new(k1), Id::new(k2)), value);
         }
 
         Ok(map)
@@ -533,165 +652,2383 @@ impl<'de> Deserialize<'de> for UserRelationshipGraphMap {
     }
 }
 
-// TODO: Just keeping this note here, but it is a rather general thing - we've got a lot of HashMap
-//       objects around using Discord snowflakes as keys, which are out of user control and thus do
-//       not need secure, anti-DoS hashing. We could probably increase HashMap performance a tonne
-//       by avoiding the default secure hashing and just truncating them to fit / XOR for tuples.
-#[derive(Debug)]
-pub struct SocialGraph {
-    data_dir: Option<PathBuf>,
-    graph: HashMap<Id<GuildMarker>, HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>>,
-    state: HashMap<(Id<GuildMarker>, Id<ChannelMarker>), InferenceState>,
+// Relationships are stored as a `[strength, updated]` pair to keep the files small.
+impl Serialize for Relationship {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.strength)?;
+        tuple.serialize_element(&self.updated)?;
+        tuple.end()
+    }
 }
 
-impl SocialGraph {
-    pub fn new(data_dir: Option<PathBuf>) -> Self {
-        SocialGraph {
-            data_dir,
-            graph: HashMap::new(),
-            state: HashMap::new(),
-        }
+struct RelationshipVisitor();
+
+impl<'de> Visitor<'de> for RelationshipVisitor {
+    type Value = Relationship;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a strength and timestamp pair, or a bare strength")
     }
 
-    /// Helper function to run inference with the right state.
-    pub fn infer(&mut self, interaction: &Interaction) -> Vec<RelationshipChange> {
-        let mut changes = Vec::new();
+    // Older files only stored the strength, these get an `updated` of 0.
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+    where
+        E: DeserializerError,
+    {
+        Ok(Relationship {
+            strength: value as RelationshipStrength,
+            updated: 0,
+        })
+    }
 
-        self.state
-            .entry((interaction.guild, interaction.channel))
-            .or_insert_with(InferenceState::new)
-            .infer(&mut changes, interaction);
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: DeserializerError,
+    {
+        self.visit_f64(value as f64)
+    }
 
-        changes
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: DeserializerError,
+    {
+        self.visit_f64(value as f64)
     }
 
-    /// Apply a set of relationship changes to the graph.
-    pub fn apply(&mut self, interaction: &Interaction, changes: &[RelationshipChange]) {
-        let data_dir = self.data_dir.clone();
-        let guild_id = interaction.guild;
-        let channel_id = interaction.channel;
+    fn visit_seq<A>(self, mut access: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let err = "expected a strength and timestamp";
 
-        // Decay all of the guild channel's graphs a tiny bit.
-        if interaction.what == InteractionType::Message && !interaction.source_is_bot {
-            if let Some(guild_graphs) = self.graph.get_mut(&guild_id) {
-                for graph in guild_graphs.values_mut() {
-                    graph.decay(RELATIONSHIP_DECAY_GLOBAL);
-                }
-            }
+        let strength = access
+            .next_element()?
+            .ok_or_else(|| A::Error::custom(err))?;
+        let updated = access
+            .next_element()?
+            .ok_or_else(|| A::Error::custom(err))?;
+
+        if access.next_element::<IgnoredAny>()?.is_some() {
+            return Err(A::Error::custom(err));
         }
 
-        let graph = self.get_graph(guild_id, channel_id);
+        Ok(Relationship { strength, updated })
+    }
+}
+
+impl<'de> Deserialize<'de> for Relationship {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(RelationshipVisitor())
+    }
+}
+
+/// Reinforce one of a guild's channel graphs with a set of changes from a single interaction,
+/// scaled by how busy the guild is.
+///
+/// This is the whole weighting and decay model, shared between live updates and rebuilding graphs
+/// from history so that they always agree. Returns how much the changes were scaled by, and every
+/// edge that was changed or pruned.
+pub(crate) fn reinforce_guild(
+    guild_graphs: &mut HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+    activity: &mut GuildActivity,
+    base_half_life: Duration,
+    channel_id: Id<ChannelMarker>,
+    is_human_message: bool,
+    changes: impl IntoIterator<Item = (UserPair, RelationshipStrength)>,
+    timestamp: u64,
+) -> (RelationshipStrength, Vec<UserPair>) {
+    if is_human_message {
+        activity.record_message(timestamp);
+    }
 
-        graph.decay(RELATIONSHIP_DECAY);
+    if activity.needs_recount(timestamp) {
+        activity.recount(guild_graphs.values(), timestamp);
+    }
 
-        for change in changes {
-            let weight = graph.entry((change.source, change.target)).or_default();
+    let scale = activity.reinforcement_scale(timestamp);
+    let half_life = base_half_life.mul_f64(activity.half_life_scale());
 
-            *weight += change.reason.get_change_strength();
-        }
+    let graph = guild_graphs.entry(channel_id).or_default();
 
-        if let Some(data_dir) = data_dir {
-            // TODO: Do we want to be writing this every update?
-            // TODO: Maybe we should use a proper database for the backing store? for all of this?
-            let data_path = Self::graph_data_file_name(data_dir, guild_id, channel_id);
-            if let Err(err) = graph.save_to_path(&data_path) {
-                error!(
-                    "failed to store on-disk data for ({}, {}): {}",
-                    interaction.guild, interaction.channel, err,
-                );
-            }
-        }
+    let mut changed = Vec::new();
+    for (source_target, strength) in changes {
+        graph.reinforce(source_target, strength * scale, timestamp, half_life);
+
+        changed.push(source_target);
     }
 
-    // TODO: Do we want to do this on the client-side instead? Probably.
-    pub fn build_guild_graph(&self, guild_id: Id<GuildMarker>) -> Option<UserRelationshipGraphMap> {
-        let guild = self.graph.get(&guild_id)?;
+    // Drop anything that has faded away, which the store needs to remove as well.
+    changed.extend(graph.prune(timestamp, half_life));
 
-        let mut guild_graph = UserRelationshipGraphMap::new();
-        for channel_graph in guild.values() {
-            for (&source_target, weight) in channel_graph.iter() {
-                let guild_weight = guild_graph.entry(source_target).or_default();
+    (scale, changed)
+}
 
-                *guild_weight += weight;
-            }
-        }
+/// How many recent messages in each guild to remember the changes of, so they can be undone if the
+/// message is edited or deleted.
+const TRACKED_MESSAGES_LIMIT: usize = 2000;
+
+/// How many recent messages in each guild to remember the reactions to, so that each user's
+/// reactions to a message only count once and can be taken back when they're removed.
+const TRACKED_REACTION_MESSAGES_LIMIT: usize = 2000;
+
+/// Only this many reactions from one user to another's messages count within the window, so that
+/// reacting to everything someone says doesn't outweigh actually talking to them.
+const REACTION_RATE_LIMIT: usize = 5;
+const REACTION_RATE_WINDOW: u64 = 60 * 60 * 1000;
+const TRACKED_REACTION_RATES_LIMIT: usize = 2000;
+
+/// Each event is handled separately, so a message or reaction can be removed before we've finished
+/// looking up what it was in reply to. Removals of things we haven't seen are remembered for this
+/// long in case they turn up afterwards.
+const EARLY_REMOVAL_WINDOW: u64 = 60 * 1000;
+const TRACKED_EARLY_REMOVALS_LIMIT: usize = 2000;
+
+/// Something done to a guild's graphs while they were being rebuilt from history, to be done again
+/// on top of the rebuilt graphs if history doesn't have it yet.
+#[derive(Debug)]
+enum LoggedChanges {
+    Applied {
+        interaction: Interaction,
+        changes: Vec<RelationshipChange>,
+        timestamp: u64,
+    },
+    Adjusted {
+        interaction: Interaction,
+        changes: Vec<(RelationshipChange, RelationshipStrength)>,
+        now: u64,
+    },
+}
+
+/// A change as it's identified in the `events` table, see `StoredEvent`.
+type HistoryKey = (u64, Id<ChannelMarker>, UserPair, RelationshipChangeReason);
 
-        Some(guild_graph)
+fn history_key(interaction: &Interaction, change: &RelationshipChange) -> HistoryKey {
+    (
+        interaction.when,
+        interaction.channel,
+        IdPair(change.source, change.target),
+        change.reason,
+    )
+}
+
+impl LoggedChanges {
+    fn history_keys(&self) -> Vec<HistoryKey> {
+        match self {
+            Self::Applied {
+                interaction,
+                changes,
+                ..
+            } => changes
+                .iter()
+                .map(|change| history_key(interaction, change))
+                .collect(),
+            Self::Adjusted {
+                interaction,
+                changes,
+                ..
+            } => changes
+                .iter()
+                .map(|(change, _)| history_key(interaction, change))
+                .collect(),
+        }
     }
+}
 
-    // TODO: Temporary hack for debug command.
-    pub fn get_all_guild_ids(&self) -> Vec<(Id<GuildMarker>, usize)> {
-        let mut guilds: Vec<_> = self
-            .graph
+/// The changes an interaction made to the graph.
+#[derive(Debug)]
+struct InteractionChanges {
+    interaction: Interaction,
+    /// How much the changes were scaled by when they were applied.
+    scale: RelationshipStrength,
+    changes: Vec<RelationshipChange>,
+}
+
+impl InteractionChanges {
+    /// `{"interaction":<interaction>,"scale":<scale>,"changes":[[source,target,reason,count],...]}`
+    fn to_json(&self) -> Value {
+        let changes: Vec<_> = self
+            .changes
             .iter()
-            .map(|(guild_id, channels)| {
-                (*guild_id, channels.values().map(|graph| graph.len()).sum())
+            .map(|change| {
+                json!([
+                    change.source,
+                    change.target,
+                    change.reason as u8,
+                    change.count
+                ])
             })
             .collect();
 
-        guilds.sort_by_key(|(_, len)| *len);
-        guilds.reverse();
+        json!({
+            "interaction": interaction_to_json(&self.interaction),
+            "scale": self.scale,
+            "changes": changes,
+        })
+    }
 
-        guilds
+    fn from_json(value: &Value) -> AnyhowResult<Self> {
+        let changes = value["changes"]
+            .as_array()
+            .context("missing changes")?
+            .iter()
+            .map(|change| {
+                let reason = change[2].as_u64().context("invalid reason")?;
+
+                Ok(RelationshipChange {
+                    source: Deserialize::deserialize(&change[0]).context("invalid source")?,
+                    target: Deserialize::deserialize(&change[1]).context("invalid target")?,
+                    reason: u8::try_from(reason)?.try_into()?,
+                    // Changes were only ever made once before this was added.
+                    count: match &change[3] {
+                        Value::Null => 1,
+                        count => Deserialize::deserialize(count).context("invalid count")?,
+                    },
+                })
+            })
+            .collect::<AnyhowResult<_>>()?;
+
+        Ok(InteractionChanges {
+            interaction: interaction_from_json(&value["interaction"])?,
+            scale: value["scale"].as_f64().context("invalid scale")? as RelationshipStrength,
+            changes,
+        })
     }
+}
 
-    pub(crate) fn get_graph(
-        &mut self,
+/// How the changes made by a message were updated after it was edited.
+#[derive(Debug)]
+pub struct MessageEdit {
+    /// The message's interaction, updated for the edit.
+    pub interaction: Interaction,
+    pub retracted: Vec<RelationshipChange>,
+    pub added: Vec<RelationshipChange>,
+}
+
+/// A user's reactions to a message.
+#[derive(Debug, Default)]
+struct UserReactions {
+    emojis: HashSet<String>,
+    /// The changes made by the first reaction, if it counted.
+    changes: Option<InteractionChanges>,
+}
+
+/// A removal of reactions to a message that we hadn't seen being added, for either one user or
+/// everyone, and either one emoji or all of them.
+#[derive(Debug)]
+struct RemovedReaction {
+    user: Option<Id<UserMarker>>,
+    emoji: Option<String>,
+    when: u64,
+}
+
+/// How a reaction's emoji is told apart from others on the same message, and recorded.
+pub fn reaction_emoji_key(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Custom { id, .. } => id.to_string(),
+        ReactionType::Unicode { name } => name.clone(),
+    }
+}
+
+/// What has changed in a channel's graph since it was last written to the store.
+#[derive(Debug)]
+enum DirtyGraph {
+    Edges(HashSet<UserPair>),
+    /// The whole graph was swapped out, so needs writing in full.
+    Replaced,
+}
+
+/// Where channel graphs are loaded from, and where writes wait for channels that were unloaded
+/// before they were flushed.
+#[derive(Debug)]
+struct GraphStorage {
+    /// Graphs are loaded from here, but written back by a `GraphFlusher`.
+    store: Option<SharedGraphStore>,
+    pending: Mutex<Vec<PendingWrite>>,
+}
+
+impl GraphStorage {
+    /// Load a channel's graph. This waits for any flush that is in progress, so must not be
+    /// called with the guild locked.
+    fn load(
+        &self,
         guild_id: Id<GuildMarker>,
         channel_id: Id<ChannelMarker>,
-    ) -> &mut UserRelationshipGraphMap {
-        let data_dir = self.data_dir.clone();
-
-        self.graph
-            .entry(guild_id)
-            .or_insert_with(HashMap::new)
-            .entry(channel_id)
-            .or_insert_with(|| {
-                let existing_graph = data_dir.and_then(|data_dir| {
-                    let data_path = Self::graph_data_file_name(data_dir, guild_id, channel_id);
-                    match UserRelationshipGraphMap::new_from_path(&data_path) {
-                        Ok(graph) => Some(graph),
-                        Err(err) if err.kind() == IoErrorKind::NotFound => None,
-                        Err(err) => {
-                            error!(
-                                "failed to load on-disk data for ({}, {}): {}",
-                                guild_id, channel_id, err,
-                            );
+    ) -> UserRelationshipGraphMap {
+        // Holding the store until the pending writes have been applied means a flush can't take
+        // them in between, which would leave us with neither the stored nor the pending copy.
+        let mut store = self.store.as_ref().map(|store| store.lock());
+
+        let existing_graph =
+            store
+                .as_mut()
+                .and_then(|store| match store.load(guild_id, channel_id) {
+                    Ok(graph) => graph,
+                    Err(error) => {
+                        error!(
+                            ?error,
+                            "failed to load graph for ({}, {})", guild_id, channel_id,
+                        );
 
-                            None
-                        }
+                        None
                     }
                 });
 
-                existing_graph.unwrap_or_else(UserRelationshipGraphMap::new)
-            })
+        let mut graph = existing_graph.unwrap_or_default();
+
+        // The store won't have any changes made since the channel was last unloaded yet.
+        for write in self.pending.lock().iter() {
+            if write.channel() == Some((guild_id, channel_id)) {
+                write.apply_to(&mut graph);
+            }
+        }
+
+        graph
     }
 
-    pub fn remove_guild(&mut self, guild_id: Id<GuildMarker>) {
-        let channels = self.graph.remove(&guild_id);
+    /// Load the interactions a guild was keeping track of before it was last unloaded. Like
+    /// `load`, this must not be called with the guild locked.
+    fn load_recent_interactions(&self, guild_id: Id<GuildMarker>) -> RecentInteractions {
+        let mut store = match &self.store {
+            Some(store) => store.lock(),
+            None => return RecentInteractions::default(),
+        };
+
+        // Anything waiting to be written is newer than what was stored.
+        let pending = self
+            .pending
+            .lock()
+            .iter()
+            .rev()
+            .find_map(|write| match write {
+                PendingWrite::RecentInteractions {
+                    guild_id: pending_guild_id,
+                    recent,
+                } if *pending_guild_id == guild_id => Some(recent.clone()),
+                _ => None,
+            });
+
+        let recent = match pending {
+            Some(recent) => Ok(Some(recent)),
+            None => store.load_recent_interactions(guild_id),
+        };
+
+        let recent = match recent {
+            Ok(Some(recent)) => RecentInteractions::from_json(&recent),
+            Ok(None) => Ok(RecentInteractions::default()),
+            Err(error) => Err(error),
+        };
+
+        match recent {
+            Ok(recent) => recent,
+            Err(error) => {
+                error!(?error, ?guild_id, "failed to load recent interactions");
 
-        if let Some(channels) = channels {
-            for &channel_id in channels.keys() {
-                self.state.remove(&(guild_id, channel_id));
+                RecentInteractions::default()
             }
         }
     }
+}
 
-    pub fn remove_channel(&mut self, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) {
-        self.state.remove(&(guild_id, channel_id));
+/// Everything that only concerns a single guild, which is locked separately from other guilds.
+#[derive(Debug)]
+struct GuildGraph {
+    guild_id: Id<GuildMarker>,
+    channels: HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+    /// Inference state for each conversation, either a channel or a thread.
+    state: SnowflakeMap<Id<ChannelMarker>, InferenceState>,
+    half_life: Option<Duration>,
+    activity: Option<GuildActivity>,
+    /// If the guild's messages from before we were last restarted have been counted towards its
+    /// activity, or are being counted.
+    activity_counted: bool,
+    /// The voice channel each user is currently in, so we know which one they left.
+    voice_channels: SnowflakeMap<Id<UserMarker>, Id<ChannelMarker>>,
+    dirty: HashMap<Id<ChannelMarker>, DirtyGraph>,
+    recent: RecentInteractions,
+    /// If the recent interactions from before we were last restarted have been loaded.
+    recent_loaded: bool,
+    /// Everything changed while the guild is being rebuilt from history, see `GuildRebuild`.
+    rebuild_log: Option<Vec<LoggedChanges>>,
+    /// How many channels have been unloaded, so a load done without the lock can tell if it
+    /// might have missed some pending writes.
+    unloads: u64,
+}
 
-        if let Some(channels) = self.graph.get_mut(&guild_id) {
-            channels.remove(&channel_id);
+impl GuildGraph {
+    fn new(guild_id: Id<GuildMarker>) -> Self {
+        GuildGraph {
+            guild_id,
+            channels: HashMap::new(),
+            state: SnowflakeMap::default(),
+            half_life: None,
+            activity: None,
+            activity_counted: false,
+            voice_channels: SnowflakeMap::default(),
+            dirty: HashMap::new(),
+            recent: RecentInteractions::default(),
+            recent_loaded: false,
+            rebuild_log: None,
+            unloads: 0,
         }
     }
 
-    fn graph_data_file_name(
-        data_dir: PathBuf,
-        guild_id: Id<GuildMarker>,
-        channel_id: Id<ChannelMarker>,
-    ) -> PathBuf {
-        let mut file_name = data_dir;
-        file_name.push(format!("{}_{}.json", guild_id, channel_id));
-        file_name
+    fn half_life(&self) -> Duration {
+        self.half_life.unwrap_or(RELATIONSHIP_HALF_LIFE)
+    }
+
+    /// If there's nothing in the guild's entry that would be missed if it was dropped.
+    fn is_unused(&self) -> bool {
+        self.half_life.is_none()
+            && self.rebuild_log.is_none()
+            && self.channels.is_empty()
+            && self.state.is_empty()
+            && self.voice_channels.is_empty()
+            && self.activity.is_none()
+            && !self.activity_counted
+            && !self.recent_loaded
+    }
+
+    fn effective_half_life(&self) -> Duration {
+        let half_life = self.half_life();
+
+        match &self.activity {
+            Some(activity) => half_life.mul_f64(activity.half_life_scale()),
+            None => half_life,
+        }
+    }
+
+    /// A channel's graph, which should already have been loaded by `SocialGraph::with_channel`.
+    fn graph(&mut self, channel_id: Id<ChannelMarker>) -> &mut UserRelationshipGraphMap {
+        self.channels.entry(channel_id).or_default()
+    }
+
+    fn infer(
+        &mut self,
+        engines: &RwLock<InferenceEngineRegistry>,
+        interaction: &Interaction,
+    ) -> Vec<RelationshipChange> {
+        let mut changes = Vec::new();
+        let guild_id = self.guild_id;
+
+        self.state
+            .entry(interaction.conversation())
+            .or_insert_with(|| engines.read().create_state(guild_id))
+            .infer(&mut changes, interaction);
+
+        changes
+    }
+
+    /// Returns how much the changes were scaled by.
+    fn apply(
+        &mut self,
+        storage: &GraphStorage,
+        interaction: &Interaction,
+        changes: &[RelationshipChange],
+        timestamp: u64,
+    ) -> RelationshipStrength {
+        let channel_id = interaction.channel;
+
+        // Make sure the graph is there before we count it.
+        self.graph(channel_id);
+
+        let base_half_life = self.half_life();
+
+        let (scale, changed) = reinforce_guild(
+            &mut self.channels,
+            self.activity.get_or_insert_with(GuildActivity::default),
+            base_half_life,
+            channel_id,
+            interaction.what == InteractionType::Message && !interaction.source_is_bot,
+            changes
+                .iter()
+                .map(|change| (IdPair(change.source, change.target), change.strength())),
+            timestamp,
+        );
+
+        self.store_graph(storage, channel_id, &changed);
+
+        if let Some(log) = &mut self.rebuild_log {
+            log.push(LoggedChanges::Applied {
+                interaction: interaction.clone(),
+                changes: changes.to_vec(),
+                timestamp,
+            });
+        }
+
+        scale
+    }
+
+    /// Add or take away changes that were made at the time of `interaction`, with each scaled by
+    /// the given amount.
+    fn adjust_interaction<'a>(
+        &mut self,
+        storage: &GraphStorage,
+        interaction: &Interaction,
+        changes: impl Iterator<Item = (&'a RelationshipChange, RelationshipStrength)>,
+        now: u64,
+    ) {
+        let channel_id = interaction.channel;
+
+        let changes: Vec<_> = changes.collect();
+
+        if let Some(log) = &mut self.rebuild_log {
+            log.push(LoggedChanges::Adjusted {
+                interaction: interaction.clone(),
+                changes: changes
+                    .iter()
+                    .map(|&(change, scale)| (change.clone(), scale))
+                    .collect(),
+                now,
+            });
+        }
+
+        let half_life = self.effective_half_life();
+        let graph = self.graph(channel_id);
+
+        let mut changed = Vec::new();
+        for (change, scale) in changes {
+            graph.adjust(
+                IdPair(change.source, change.target),
+                change.strength() * scale,
+                interaction.when,
+                now,
+                half_life,
+            );
+
+            changed.push(IdPair(change.source, change.target));
+        }
+
+        changed.extend(graph.prune(now, half_life));
+
+        self.store_graph(storage, channel_id, &changed);
+    }
+
+    /// Swap in a complete new set of graphs, clearing any channels missing from `graphs`.
+    fn replace(
+        &mut self,
+        storage: &GraphStorage,
+        mut graphs: HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+        activity: GuildActivity,
+    ) {
+        for &channel_id in self.channels.keys() {
+            graphs.entry(channel_id).or_default();
+        }
+
+        if storage.store.is_some() {
+            for &channel_id in graphs.keys() {
+                self.dirty.insert(channel_id, DirtyGraph::Replaced);
+            }
+        }
+
+        self.channels = graphs;
+        self.activity = Some(activity);
+    }
+
+    /// Mark the `changed` edges of a channel's graph as needing to be written to the store.
+    fn store_graph(
+        &mut self,
+        storage: &GraphStorage,
+        channel_id: Id<ChannelMarker>,
+        changed: &[UserPair],
+    ) {
+        if storage.store.is_none() || changed.is_empty() {
+            return;
+        }
+
+        let dirty = self
+            .dirty
+            .entry(channel_id)
+            .or_insert_with(|| DirtyGraph::Edges(HashSet::new()));
+
+        if let DirtyGraph::Edges(edges) = dirty {
+            edges.extend(changed.iter().copied());
+        }
+    }
+
+    /// Build the write that brings the store up to date with a channel's graph.
+    fn take_dirty_graph(&mut self, channel_id: Id<ChannelMarker>) -> Option<PendingWrite> {
+        let dirty = self.dirty.remove(&channel_id)?;
+        let graph = self.channels.get(&channel_id);
+
+        Some(match dirty {
+            DirtyGraph::Edges(edges) => PendingWrite::Update {
+                guild_id: self.guild_id,
+                channel_id,
+                edges: edges
+                    .into_iter()
+                    .map(|key| (key, graph.and_then(|graph| graph.get(&key)).copied()))
+                    .collect(),
+            },
+            DirtyGraph::Replaced => PendingWrite::Replace {
+                guild_id: self.guild_id,
+                channel_id,
+                graph: graph.cloned().unwrap_or_else(UserRelationshipGraphMap::new),
+            },
+        })
+    }
+
+    /// Drop a channel's graph from memory, holding on to any changes that haven't been written.
+    fn unload_channel(&mut self, storage: &GraphStorage, channel_id: Id<ChannelMarker>) {
+        if let Some(write) = self.take_dirty_graph(channel_id) {
+            storage.pending.lock().push(write);
+        }
+
+        self.channels.remove(&channel_id);
+        self.unloads += 1;
+    }
+
+    /// Queue the guild's recent interactions to be written to the store.
+    fn save_recent_interactions(&self, storage: &GraphStorage) {
+        // Until they've been loaded, writing ours would replace the ones from before.
+        if storage.store.is_none() || !self.recent_loaded {
+            return;
+        }
+
+        storage
+            .pending
+            .lock()
+            .push(PendingWrite::RecentInteractions {
+                guild_id: self.guild_id,
+                recent: self.recent.to_json(),
+            });
+    }
+}
+
+/// A guild's recent interactions that might still be taken back.
+///
+/// These are written to the store when the guild goes away and when we shut down, so that messages
+/// from before a restart can still be retracted.
+#[derive(Debug)]
+struct RecentInteractions {
+    messages: LruCache<Id<MessageMarker>, InteractionChanges>,
+    reactions: LruCache<Id<MessageMarker>, HashMap<Id<UserMarker>, UserReactions>>,
+    /// When each user's recent counted reactions to each other user happened, oldest first.
+    reaction_times: LruCache<UserPair, VecDeque<u64>>,
+    /// When messages we hadn't seen were deleted, see `EARLY_REMOVAL_WINDOW`.
+    deleted_messages: LruCache<Id<MessageMarker>, u64>,
+    /// Reactions we hadn't seen that were removed, see `EARLY_REMOVAL_WINDOW`.
+    removed_reactions: LruCache<Id<MessageMarker>, Vec<RemovedReaction>>,
+}
+
+impl Default for RecentInteractions {
+    fn default() -> Self {
+        // Every guild has these, so they start empty and have their limits applied by `trim`
+        // rather than allocating space for the limit up front.
+        RecentInteractions {
+            messages: LruCache::unbounded(),
+            reactions: LruCache::unbounded(),
+            reaction_times: LruCache::unbounded(),
+            deleted_messages: LruCache::unbounded(),
+            removed_reactions: LruCache::unbounded(),
+        }
+    }
+}
+
+impl RecentInteractions {
+    /// Drop the least recently used entries over each limit.
+    fn trim(&mut self) {
+        while self.messages.len() > TRACKED_MESSAGES_LIMIT {
+            self.messages.pop_lru();
+        }
+
+        while self.reactions.len() > TRACKED_REACTION_MESSAGES_LIMIT {
+            self.reactions.pop_lru();
+        }
+
+        while self.reaction_times.len() > TRACKED_REACTION_RATES_LIMIT {
+            self.reaction_times.pop_lru();
+        }
+
+        while self.deleted_messages.len() > TRACKED_EARLY_REMOVALS_LIMIT {
+            self.deleted_messages.pop_lru();
+        }
+
+        while self.removed_reactions.len() > TRACKED_EARLY_REMOVALS_LIMIT {
+            self.removed_reactions.pop_lru();
+        }
+    }
+
+    /// Remember that a message we haven't seen was deleted, in case it's still on its way.
+    fn message_deleted(&mut self, message_id: Id<MessageMarker>, now: u64) {
+        self.deleted_messages.put(message_id, now);
+
+        self.trim();
+    }
+
+    /// Returns whether an interaction is for a message that was deleted before it got here.
+    fn take_deleted_message(&mut self, interaction: &Interaction) -> bool {
+        let message_id = match (interaction.what, interaction.message) {
+            (InteractionType::Message, Some(message_id)) => message_id,
+            _ => return false,
+        };
+
+        self.deleted_messages
+            .pop(&message_id)
+            .is_some_and(|deleted| interaction.when.saturating_sub(deleted) < EARLY_REMOVAL_WINDOW)
+    }
+
+    /// Returns whether a reaction was removed before it got here, forgetting the removal if it
+    /// was only of this reaction.
+    fn take_removed_reaction(
+        &mut self,
+        message_id: Id<MessageMarker>,
+        interaction: &Interaction,
+        emoji: &str,
+    ) -> bool {
+        let removed = match self.removed_reactions.get_mut(&message_id) {
+            Some(removed) => removed,
+            None => return false,
+        };
+
+        removed
+            .retain(|removal| interaction.when.saturating_sub(removal.when) < EARLY_REMOVAL_WINDOW);
+
+        let position = removed.iter().position(|removal| {
+            removal
+                .user
+                .is_none_or(|user_id| user_id == interaction.source)
+                && removal
+                    .emoji
+                    .as_deref()
+                    .is_none_or(|removed| removed == emoji)
+        });
+
+        // Removing everyone's reactions might have beaten more than one of them here.
+        if let Some(position) = position {
+            if removed[position].user.is_some() {
+                removed.remove(position);
+            }
+        }
+
+        if removed.is_empty() {
+            self.removed_reactions.pop(&message_id);
+        }
+
+        position.is_some()
+    }
+
+    /// Remember the changes an interaction made, so they can be undone later.
+    fn track_changes(
+        &mut self,
+        interaction: &Interaction,
+        changes: &[RelationshipChange],
+        scale: RelationshipStrength,
+    ) {
+        let record = InteractionChanges {
+            interaction: interaction.clone(),
+            scale,
+            changes: changes.to_vec(),
+        };
+
+        match (interaction.what, interaction.message) {
+            (InteractionType::Message, Some(message_id)) => {
+                self.messages.put(message_id, record);
+            }
+            (InteractionType::Reaction, Some(message_id)) => {
+                let user = self
+                    .reactions
+                    .get_mut(&message_id)
+                    .and_then(|users| users.get_mut(&interaction.source));
+
+                if let Some(user) = user {
+                    user.changes = Some(record);
+                }
+            }
+            _ => (),
+        }
+
+        self.trim();
+    }
+
+    /// Keep track of a new reaction, returning whether it should count towards the graph.
+    fn add_reaction(&mut self, interaction: &Interaction, emoji: &str) -> bool {
+        let message_id = match interaction.message {
+            Some(message_id) => message_id,
+            None => return true,
+        };
+
+        if self.take_removed_reaction(message_id, interaction, emoji) {
+            return false;
+        }
+
+        let user = self
+            .reactions
+            .get_or_insert_mut(message_id, HashMap::new)
+            .entry(interaction.source)
+            .or_default();
+
+        let is_first = user.emojis.is_empty();
+        user.emojis.insert(emoji.to_owned());
+
+        if !is_first {
+            return false;
+        }
+
+        let target = match interaction.target {
+            Some(target) => target,
+            None => return true,
+        };
+
+        let times = self
+            .reaction_times
+            .get_or_insert_mut(IdPair(interaction.source, target), VecDeque::new);
+
+        while let Some(&oldest) = times.front() {
+            if interaction.when.saturating_sub(oldest) < REACTION_RATE_WINDOW {
+                break;
+            }
+
+            times.pop_front();
+        }
+
+        if times.len() >= REACTION_RATE_LIMIT {
+            return false;
+        }
+
+        times.push_back(interaction.when);
+
+        self.trim();
+
+        true
+    }
+
+    /// Forget reactions to a message, returning the changes of any users that no longer have any
+    /// reactions left on it.
+    ///
+    /// If none of the reactions were ones we'd seen, the removal is remembered in case they're
+    /// still on their way.
+    fn remove_reactions(
+        &mut self,
+        message_id: Id<MessageMarker>,
+        user_id: Option<Id<UserMarker>>,
+        emoji: Option<&str>,
+        now: u64,
+    ) -> Vec<InteractionChanges> {
+        let mut removed = Vec::new();
+        let mut found = false;
+
+        if let Some(users) = self.reactions.get_mut(&message_id) {
+            users.retain(|&reactor_id, user| {
+                if user_id.is_some_and(|user_id| user_id != reactor_id) {
+                    return true;
+                }
+
+                found |= match emoji {
+                    Some(emoji) => user.emojis.remove(emoji),
+                    None => !std::mem::take(&mut user.emojis).is_empty(),
+                };
+
+                if !user.emojis.is_empty() {
+                    return true;
+                }
+
+                removed.extend(user.changes.take());
+
+                false
+            });
+        }
+
+        if !found {
+            self.removed_reactions
+                .get_or_insert_mut(message_id, Vec::new)
+                .push(RemovedReaction {
+                    user: user_id,
+                    emoji: emoji.map(str::to_owned),
+                    when: now,
+                });
+
+            self.trim();
+        }
+
+        removed.retain(|record| !record.changes.is_empty());
+
+        removed
+    }
+
+    /// Add in interactions tracked before these ones, which are treated as being less recently
+    /// used than any of ours.
+    fn merge_older(&mut self, mut older: RecentInteractions) {
+        while let Some((message_id, record)) = self.messages.pop_lru() {
+            older.messages.put(message_id, record);
+        }
+
+        while let Some((message_id, users)) = self.reactions.pop_lru() {
+            older.reactions.put(message_id, users);
+        }
+
+        while let Some((source_target, times)) = self.reaction_times.pop_lru() {
+            older.reaction_times.put(source_target, times);
+        }
+
+        while let Some((message_id, deleted)) = self.deleted_messages.pop_lru() {
+            older.deleted_messages.put(message_id, deleted);
+        }
+
+        while let Some((message_id, removed)) = self.removed_reactions.pop_lru() {
+            older.removed_reactions.put(message_id, removed);
+        }
+
+        *self = older;
+        self.trim();
+    }
+
+    /// Encoded for the store, with each list in least recently used order, leaving out early
+    /// removals as they won't matter by the time it's loaded again:
+    /// `{"messages":[<changes>,...],"reactions":[[message,user,[emoji,...],<changes>|null],...],
+    /// "reaction_times":[[source,target,[time,...]],...]}`.
+    fn to_json(&self) -> Value {
+        let messages: Vec<_> = self
+            .messages
+            .iter()
+            .rev()
+            .map(|(_, record)| record.to_json())
+            .collect();
+
+        let reactions: Vec<_> = self
+            .reactions
+            .iter()
+            .rev()
+            .flat_map(|(message_id, users)| {
+                users.iter().map(move |(user_id, user)| {
+                    json!([
+                        message_id,
+                        user_id,
+                        user.emojis,
+                        user.changes.as_ref().map(InteractionChanges::to_json),
+                    ])
+                })
+            })
+            .collect();
+
+        let reaction_times: Vec<_> = self
+            .reaction_times
+            .iter()
+            .rev()
+            .map(|(IdPair(source, target), times)| json!([source, target, times]))
+            .collect();
+
+        json!({
+            "messages": messages,
+            "reactions": reactions,
+            "reaction_times": reaction_times,
+        })
+    }
+
+    fn from_json(value: &Value) -> AnyhowResult<Self> {
+        let mut recent = RecentInteractions::default();
+
+        let list = |key: &str| {
+            value[key]
+                .as_array()
+                .with_context(|| format!("missing {}", key))
+        };
+
+        for record in list("messages")? {
+            let record = InteractionChanges::from_json(record)?;
+            let message_id = record.interaction.message.context("missing message id")?;
+
+            recent.messages.put(message_id, record);
+        }
+
+        for reaction in list("reactions")? {
+            let message_id = Deserialize::deserialize(&reaction[0]).context("invalid message")?;
+            let user_id = Deserialize::deserialize(&reaction[1]).context("invalid user")?;
+
+            let user = UserReactions {
+                emojis: Deserialize::deserialize(&reaction[2]).context("invalid emojis")?,
+                changes: match &reaction[3] {
+                    Value::Null => None,
+                    record => Some(InteractionChanges::from_json(record)?),
+                },
+            };
+
+            recent
+                .reactions
+                .get_or_insert_mut(message_id, HashMap::new)
+                .insert(user_id, user);
+        }
+
+        for times in list("reaction_times")? {
+            let source = Deserialize::deserialize(&times[0]).context("invalid source")?;
+            let target = Deserialize::deserialize(&times[1]).context("invalid target")?;
+
+            recent.reaction_times.put(
+                IdPair(source, target),
+                Deserialize::deserialize(&times[2]).context("invalid times")?,
+            );
+        }
+
+        recent.trim();
+
+        Ok(recent)
+    }
+
+    /// The graph channel a recent message's changes were made in, if any were.
+    fn message_channel(&self, message_id: Id<MessageMarker>) -> Option<Id<ChannelMarker>> {
+        let message = self.messages.peek(&message_id).into_iter();
+
+        let reactions = self
+            .reactions
+            .peek(&message_id)
+            .into_iter()
+            .flat_map(|users| users.values())
+            .filter_map(|user| user.changes.as_ref());
+
+        message
+            .chain(reactions)
+            .map(|record| record.interaction.channel)
+            .next()
+    }
+}
+
+/// A guild being rebuilt from history, from `SocialGraph::begin_rebuild`.
+///
+/// Live changes keep being made to the old graphs in the meantime, so they're made again on top of
+/// the rebuilt graphs unless the events they were rebuilt from already include them. Dropping this
+/// without finishing gives up.
+pub struct GuildRebuild<'a> {
+    social: &'a SocialGraph,
+    guild_id: Id<GuildMarker>,
+}
+
+/// Which of the changes logged during a rebuild are in the events it's rebuilt from, counted while
+/// they're loaded.
+///
+/// Anything logged once loading has started can't be in there yet, as the changes are applied
+/// before they're stored, and the events are a snapshot from when loading started. Whatever was
+/// logged before that may or may not have been stored in time.
+#[derive(Debug)]
+pub struct LoadedChanges {
+    /// How many of the changes logged before loading started came before these.
+    logged: usize,
+    counts: HashMap<HistoryKey, usize>,
+}
+
+impl LoadedChanges {
+    /// Count the next events loaded.
+    pub fn add(&mut self, events: &[StoredEvent]) {
+        for event in events {
+            let key = (
+                event.timestamp,
+                event.channel,
+                IdPair(event.source, event.target),
+                event.reason,
+            );
+
+            if let Some(count) = self.counts.get_mut(&key) {
+                *count += 1;
+            }
+        }
+    }
+}
+
+impl GuildRebuild<'_> {
+    /// Call just before starting to load the events to rebuild from, to count which of the changes
+    /// logged so far they include.
+    pub fn start_loading(&self) -> LoadedChanges {
+        let guild = self.social.guild(self.guild_id);
+        let guild = guild.lock();
+        let log = guild.rebuild_log.as_deref().unwrap_or_default();
+
+        LoadedChanges {
+            logged: log.len(),
+            counts: log
+                .iter()
+                .flat_map(LoggedChanges::history_keys)
+                .map(|key| (key, 0))
+                .collect(),
+        }
+    }
+
+    /// Swap in the rebuilt graphs, with anything that happened since the events they were rebuilt
+    /// from were loaded done again on top of them.
+    ///
+    /// Any channels missing from `graphs` are cleared, including their stored data.
+    pub fn finish(
+        self,
+        mut graphs: HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+        mut activity: GuildActivity,
+        loaded: LoadedChanges,
+    ) {
+        let LoadedChanges {
+            logged,
+            counts: mut in_history,
+        } = loaded;
+
+        let guild = self.social.guild(self.guild_id);
+        let mut guild = guild.lock();
+
+        let base_half_life = guild.half_life();
+        let log = guild.rebuild_log.take().unwrap_or_default();
+
+        for (index, logged_changes) in log.into_iter().enumerate() {
+            let maybe_loaded = index < logged;
+
+            match logged_changes {
+                LoggedChanges::Applied {
+                    interaction,
+                    changes,
+                    timestamp,
+                } => {
+                    let keys: Vec<_> = changes
+                        .iter()
+                        .map(|change| history_key(&interaction, change))
+                        .collect();
+
+                    // An interaction's rows are all inserted together.
+                    if maybe_loaded && keys.iter().any(|key| in_history[key] > 0) {
+                        continue;
+                    }
+
+                    reinforce_guild(
+                        &mut graphs,
+                        &mut activity,
+                        base_half_life,
+                        interaction.channel,
+                        interaction.what == InteractionType::Message && !interaction.source_is_bot,
+                        changes.iter().map(|change| {
+                            (IdPair(change.source, change.target), change.strength())
+                        }),
+                        timestamp,
+                    );
+
+                    if maybe_loaded {
+                        for key in keys {
+                            *in_history.get_mut(&key).unwrap() += 1;
+                        }
+                    }
+                }
+                LoggedChanges::Adjusted {
+                    interaction,
+                    changes,
+                    now,
+                } => {
+                    let half_life = base_half_life.mul_f64(activity.half_life_scale());
+                    let graph = graphs.entry(interaction.channel).or_default();
+
+                    for (change, scale) in changes {
+                        if maybe_loaded {
+                            let count = in_history
+                                .get_mut(&history_key(&interaction, &change))
+                                .unwrap();
+
+                            // Something added is only missing if history doesn't have it yet,
+                            // and something taken away is only still there if history does.
+                            if scale >= 0.0 {
+                                if *count > 0 {
+                                    continue;
+                                }
+
+                                *count += 1;
+                            } else {
+                                if *count == 0 {
+                                    continue;
+                                }
+
+                                *count -= 1;
+                            }
+                        }
+
+                        graph.adjust(
+                            IdPair(change.source, change.target),
+                            change.strength() * scale,
+                            interaction.when,
+                            now,
+                            half_life,
+                        );
+                    }
+
+                    graph.prune(now, half_life);
+                }
+            }
+        }
+
+        guild.replace(&self.social.storage, graphs, activity);
+    }
+}
+
+impl Drop for GuildRebuild<'_> {
+    fn drop(&mut self) {
+        if let Some(guild) = self.social.existing_guild(self.guild_id) {
+            guild.lock().rebuild_log = None;
+        }
+    }
+}
+
+/// All of the guilds' graphs, which can be shared between threads without any outer lock.
+///
+/// Each guild has its own lock, so rendering a huge guild doesn't hold up inference everywhere
+/// else. When more than one lock is needed they're taken in the order store, guild, then engines,
+/// and the guild map is never held while waiting for anything else.
+#[derive(Debug)]
+pub struct SocialGraph {
+    storage: GraphStorage,
+    guilds: RwLock<SnowflakeMap<Id<GuildMarker>, Arc<Mutex<GuildGraph>>>>,
+    engines: RwLock<InferenceEngineRegistry>,
+    /// If any guild's settings have changed since they were last written to the store.
+    settings_changed: AtomicBool,
+}
+
+impl SocialGraph {
+    pub fn new(store: Option<SharedGraphStore>) -> Self {
+        let social = SocialGraph {
+            storage: GraphStorage {
+                store,
+                pending: Mutex::new(Vec::new()),
+            },
+            guilds: RwLock::new(SnowflakeMap::default()),
+            engines: RwLock::new(InferenceEngineRegistry::new()),
+            settings_changed: AtomicBool::new(false),
+        };
+
+        social.load_settings();
+
+        social
+    }
+
+    /// Restore the guilds' settings from the store, which is only done before anything else can
+    /// be looking at them.
+    fn load_settings(&self) {
+        let store = match &self.storage.store {
+            Some(store) => store,
+            None => return,
+        };
+
+        let settings = match store.lock().load_settings() {
+            Ok(Some(settings)) => settings,
+            Ok(None) => return,
+            Err(error) => {
+                error!(?error, "failed to load guild settings");
+                return;
+            }
+        };
+
+        let settings = match settings.as_object() {
+            Some(settings) => settings,
+            None => {
+                error!("guild settings aren't an object, ignoring them");
+                return;
+            }
+        };
+
+        for (guild_id, guild_settings) in settings {
+            let guild_id = match guild_id.parse().ok().and_then(Id::new_checked) {
+                Some(guild_id) => guild_id,
+                None => {
+                    warn!(?guild_id, "ignoring settings for invalid guild");
+                    continue;
+                }
+            };
+
+            if let Some(half_life) = guild_settings["half_life"].as_u64() {
+                self.guild(guild_id).lock().half_life = Some(Duration::from_millis(half_life));
+            }
+
+            let engines = guild_settings["engines"].as_object().into_iter().flatten();
+
+            for (name, enabled) in engines {
+                let enabled = match enabled.as_bool() {
+                    Some(enabled) => enabled,
+                    None => continue,
+                };
+
+                if !self.engines.write().set_enabled(guild_id, name, enabled) {
+                    warn!(
+                        ?guild_id,
+                        ?name,
+                        "ignoring setting for unknown inference engine"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Every guild's settings, as `{"<guild>": {"half_life": <ms>, "engines": {"<name>": <bool>}}}`,
+    /// leaving out anything that is using the defaults.
+    fn settings_to_json(&self) -> Value {
+        let mut settings = JsonMap::new();
+
+        // Collected up front, as the engines can't be locked before a guild.
+        let engine_overrides = self.engines.read().guild_overrides().clone();
+
+        for (guild_id, overrides) in engine_overrides {
+            settings.insert(guild_id.to_string(), json!({ "engines": overrides }));
+        }
+
+        for guild in self.all_guilds() {
+            let guild = guild.lock();
+
+            if let Some(half_life) = guild.half_life {
+                let guild_settings = settings
+                    .entry(guild.guild_id.to_string())
+                    .or_insert_with(|| json!({}));
+
+                guild_settings["half_life"] = json!(half_life.as_millis() as u64);
+            }
+        }
+
+        Value::Object(settings)
+    }
+
+    /// Mark the guilds' settings as needing to be written to the store by the next flush.
+    fn settings_changed(&self) {
+        if self.storage.store.is_some() {
+            self.settings_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The guild's entry, adding an empty one if we haven't seen it before.
+    fn guild(&self, guild_id: Id<GuildMarker>) -> Arc<Mutex<GuildGraph>> {
+        if let Some(guild) = self.guilds.read().get(&guild_id) {
+            return guild.clone();
+        }
+
+        self.guilds
+            .write()
+            .entry(guild_id)
+            .or_insert_with(|| Arc::new(Mutex::new(GuildGraph::new(guild_id))))
+            .clone()
+    }
+
+    fn existing_guild(&self, guild_id: Id<GuildMarker>) -> Option<Arc<Mutex<GuildGraph>>> {
+        self.guilds.read().get(&guild_id).cloned()
+    }
+
+    fn all_guilds(&self) -> Vec<Arc<Mutex<GuildGraph>>> {
+        self.guilds.read().values().cloned().collect()
+    }
+
+    /// Run inference for an interaction and apply the resulting changes to the graph, with the
+    /// guild locked throughout so nothing else in it happens in between.
+    ///
+    /// `timestamp` is the unix timestamp in milliseconds the interaction happened at.
+    pub fn infer_and_apply(
+        &self,
+        interaction: &Interaction,
+        timestamp: u64,
+    ) -> Vec<RelationshipChange> {
+        self.with_channel(interaction.guild, interaction.channel, |guild| {
+            if guild.recent.take_deleted_message(interaction) {
+                return Vec::new();
+            }
+
+            let changes = guild.infer(&self.engines, interaction);
+            let scale = guild.apply(&self.storage, interaction, &changes, timestamp);
+            guild.recent.track_changes(interaction, &changes, scale);
+
+            changes
+        })
+    }
+
+    /// Run `f` with the guild locked, after loading a channel's graph into it if needed.
+    ///
+    /// Loading can mean waiting for a flush to finish with the store, so it's done before the
+    /// guild is locked rather than holding up everything else in the guild.
+    fn with_channel<R>(
+        &self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        f: impl FnOnce(&mut GuildGraph) -> R,
+    ) -> R {
+        let guild = self.guild(guild_id);
+
+        let mut unloads = {
+            let mut guild = guild.lock();
+            if guild.channels.contains_key(&channel_id) {
+                return f(&mut guild);
+            }
+
+            guild.unloads
+        };
+
+        loop {
+            let graph = self.storage.load(guild_id, channel_id);
+
+            let mut locked = guild.lock();
+
+            // If a channel was unloaded while we weren't looking its changes might have been
+            // pending after we checked, so try again. Otherwise something else loading or
+            // replacing the channel in the meantime wins.
+            if locked.unloads == unloads || locked.channels.contains_key(&channel_id) {
+                locked.channels.entry(channel_id).or_insert(graph);
+
+                return f(&mut locked);
+            }
+
+            unloads = locked.unloads;
+        }
+    }
+
+    /// Track which voice channel a user is in, if any, returning the one they were in before.
+    pub fn set_voice_channel(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        channel_id: Option<Id<ChannelMarker>>,
+    ) -> Option<Id<ChannelMarker>> {
+        let guild = self.guild(guild_id);
+        let mut guild = guild.lock();
+
+        match channel_id {
+            Some(channel_id) => guild.voice_channels.insert(user_id, channel_id),
+            None => guild.voice_channels.remove(&user_id),
+        }
+    }
+
+    /// List the registered inference engines and if they're enabled for the guild.
+    pub fn get_engines(&self, guild_id: Id<GuildMarker>) -> Vec<(&'static str, bool)> {
+        let engines = self.engines.read();
+
+        engines
+            .engines()
+            .iter()
+            .map(|descriptor| (descriptor.name, engines.is_enabled(guild_id, descriptor)))
+            .collect()
+    }
+
+    /// Enable or disable an inference engine for a guild.
+    ///
+    /// Returns false if there is no engine registered with that name.
+    pub fn set_engine_enabled(&self, guild_id: Id<GuildMarker>, name: &str, enabled: bool) -> bool {
+        if !self.engines.write().set_enabled(guild_id, name, enabled) {
+            return false;
+        }
+
+        self.settings_changed();
+
+        // Throw away the guild's existing inference state so it is rebuilt with the new engines.
+        if let Some(guild) = self.existing_guild(guild_id) {
+            guild.lock().state.clear();
+        }
+
+        true
+    }
+
+    /// How long it takes for relationships in the guild to lose half their strength.
+    pub fn get_half_life(&self, guild_id: Id<GuildMarker>) -> Duration {
+        self.existing_guild(guild_id)
+            .map_or(RELATIONSHIP_HALF_LIFE, |guild| guild.lock().half_life())
+    }
+
+    /// The half-life after it has been adapted to the size of the guild, this is what is used
+    /// to actually decay the graph.
+    pub fn get_effective_half_life(&self, guild_id: Id<GuildMarker>) -> Duration {
+        self.existing_guild(guild_id)
+            .map_or(RELATIONSHIP_HALF_LIFE, |guild| {
+                guild.lock().effective_half_life()
+            })
+    }
+
+    pub fn get_activity(&self, guild_id: Id<GuildMarker>) -> Option<GuildActivity> {
+        self.existing_guild(guild_id)?.lock().activity.clone()
+    }
+
+    /// Override the decay half-life for a guild, or reset it to the default with `None`.
+    pub fn set_half_life(&self, guild_id: Id<GuildMarker>, half_life: Option<Duration>) {
+        self.guild(guild_id).lock().half_life = half_life;
+        self.settings_changed();
+    }
+
+    /// Undo the changes a recent message made to the graph, for when it has been deleted. A message
+    /// we haven't seen yet won't make any changes if it turns up shortly afterwards.
+    ///
+    /// Returns the message's interaction and the changes that were undone, if there were any.
+    pub fn retract_message(
+        &self,
+        guild_id: Id<GuildMarker>,
+        message_id: Id<MessageMarker>,
+        now: u64,
+    ) -> Option<(Interaction, Vec<RelationshipChange>)> {
+        self.with_message_channel(guild_id, message_id, |guild| {
+            let record = match guild.recent.messages.pop(&message_id) {
+                Some(record) => record,
+                None => {
+                    guild.recent.message_deleted(message_id, now);
+                    return None;
+                }
+            };
+
+            if record.changes.is_empty() {
+                return None;
+            }
+
+            guild.adjust_interaction(
+                &self.storage,
+                &record.interaction,
+                record.changes.iter().map(|change| (change, -record.scale)),
+                now,
+            );
+
+            Some((record.interaction, record.changes))
+        })
+        .flatten()
+    }
+
+    /// Keep track of a new reaction, and if it counts towards the graph run inference for it and
+    /// apply the changes like `infer_and_apply`, all with the guild locked so that a removal can't
+    /// come in between. Returns `None` if it doesn't count.
+    ///
+    /// Only a user's first reaction to a message counts, and only up to `REACTION_RATE_LIMIT` of
+    /// them between the same two users within `REACTION_RATE_WINDOW`.
+    pub fn add_reaction(
+        &self,
+        interaction: &Interaction,
+        emoji: &str,
+        timestamp: u64,
+    ) -> Option<Vec<RelationshipChange>> {
+        self.with_channel(interaction.guild, interaction.channel, |guild| {
+            if !guild.recent.add_reaction(interaction, emoji) {
+                return None;
+            }
+
+            let changes = guild.infer(&self.engines, interaction);
+            let scale = guild.apply(&self.storage, interaction, &changes, timestamp);
+            guild.recent.track_changes(interaction, &changes, scale);
+
+            Some(changes)
+        })
+    }
+
+    /// Forget reactions to a recent message, either from one user or everyone, and either with one
+    /// emoji or all of them. The changes of any users that no longer have any reactions left on the
+    /// message are undone, and reactions we haven't seen yet won't count if they turn up shortly
+    /// afterwards.
+    ///
+    /// Returns each of the undone reaction interactions and their changes.
+    pub fn remove_reactions(
+        &self,
+        guild_id: Id<GuildMarker>,
+        message_id: Id<MessageMarker>,
+        user_id: Option<Id<UserMarker>>,
+        emoji: Option<&str>,
+        now: u64,
+    ) -> Vec<(Interaction, Vec<RelationshipChange>)> {
+        self.with_message_channel(guild_id, message_id, |guild| {
+            let removed = guild
+                .recent
+                .remove_reactions(message_id, user_id, emoji, now);
+
+            removed
+                .into_iter()
+                .map(|record| {
+                    guild.adjust_interaction(
+                        &self.storage,
+                        &record.interaction,
+                        record.changes.iter().map(|change| (change, -record.scale)),
+                        now,
+                    );
+
+                    (record.interaction, record.changes)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Run the stateless inference engines again for a recent message that has been edited, undoing
+    /// any changes it no longer makes and applying any new ones.
+    ///
+    /// Returns `None` if the message isn't one we're keeping track of.
+    pub fn edit_message(
+        &self,
+        guild_id: Id<GuildMarker>,
+        message_id: Id<MessageMarker>,
+        content: &str,
+        mentions: impl Iterator<Item = Id<UserMarker>>,
+        now: u64,
+    ) -> Option<MessageEdit> {
+        self.update_message(
+            guild_id,
+            message_id,
+            |interaction| interaction.with_edit(content, mentions),
+            now,
+        )
+    }
+
+    /// Like `edit_message`, but given the message's interaction as it was after the edit, such as
+    /// from a recording.
+    pub fn apply_edit(&self, edited: &Interaction, now: u64) -> Option<MessageEdit> {
+        self.update_message(edited.guild, edited.message?, |_| edited.clone(), now)
+    }
+
+    fn update_message(
+        &self,
+        guild_id: Id<GuildMarker>,
+        message_id: Id<MessageMarker>,
+        edit: impl FnOnce(&Interaction) -> Interaction,
+        now: u64,
+    ) -> Option<MessageEdit> {
+        self.with_message_channel(guild_id, message_id, |guild| {
+            let record = guild.recent.messages.get_mut(&message_id)?;
+            let interaction = edit(&record.interaction);
+
+            let (retracted, added) = {
+                let engines = self.engines.read();
+                let before = engines.infer_stateless(&record.interaction);
+                let after = engines.infer_stateless(&interaction);
+
+                let retracted: Vec<_> = before
+                    .iter()
+                    .filter(|&change| !after.contains(change) && record.changes.contains(change))
+                    .cloned()
+                    .collect();
+
+                let added: Vec<_> = after
+                    .into_iter()
+                    .filter(|change| !before.contains(change))
+                    .collect();
+
+                (retracted, added)
+            };
+
+            record.interaction = interaction.clone();
+            record.changes.retain(|change| !retracted.contains(change));
+            record.changes.extend(added.iter().cloned());
+
+            let scale = record.scale;
+
+            guild.adjust_interaction(
+                &self.storage,
+                &interaction,
+                (retracted.iter().map(|change| (change, -scale)))
+                    .chain(added.iter().map(|change| (change, scale))),
+                now,
+            );
+
+            Some(MessageEdit {
+                interaction,
+                retracted,
+                added,
+            })
+        })
+        .flatten()
+    }
+
+    /// Run `f` with the guild locked, and the channel a recent message's changes were made in
+    /// loaded if there were any. Returns `None` if we haven't seen the guild.
+    fn with_message_channel<R>(
+        &self,
+        guild_id: Id<GuildMarker>,
+        message_id: Id<MessageMarker>,
+        f: impl FnOnce(&mut GuildGraph) -> R,
+    ) -> Option<R> {
+        let guild = self.existing_guild(guild_id)?;
+        let channel_id = guild.lock().recent.message_channel(message_id);
+
+        Some(match channel_id {
+            Some(channel_id) => self.with_channel(guild_id, channel_id, f),
+            None => f(&mut guild.lock()),
+        })
+    }
+
+    /// Everything that needs writing to the store since the last call, in the order to write it.
+    pub fn take_pending_writes(&self) -> Vec<PendingWrite> {
+        let mut writes = std::mem::take(&mut *self.storage.pending.lock());
+
+        for guild in self.all_guilds() {
+            let mut guild = guild.lock();
+
+            let channel_ids: Vec<_> = guild.dirty.keys().copied().collect();
+            for channel_id in channel_ids {
+                writes.extend(guild.take_dirty_graph(channel_id));
+            }
+        }
+
+        // Cleared before the settings are read, so a change made meanwhile is written next time.
+        if self.settings_changed.swap(false, Ordering::Relaxed) {
+            writes.push(PendingWrite::Settings {
+                settings: self.settings_to_json(),
+            });
+        }
+
+        writes
+    }
+
+    /// Put back writes taken by `take_pending_writes` that couldn't be stored, ahead of anything
+    /// queued since so they're still written in order.
+    pub fn restore_pending_writes(&self, mut writes: Vec<PendingWrite>) {
+        let mut pending = self.storage.pending.lock();
+        writes.append(&mut pending);
+        *pending = writes;
+    }
+
+    /// Start keeping track of everything done to the guild's graphs, so that it isn't lost when
+    /// they're replaced by ones rebuilt from history. Returns `None` if the guild is already being
+    /// rebuilt.
+    pub fn begin_rebuild(&self, guild_id: Id<GuildMarker>) -> Option<GuildRebuild<'_>> {
+        let guild = self.guild(guild_id);
+        let mut guild = guild.lock();
+
+        if guild.rebuild_log.is_some() {
+            return None;
+        }
+
+        guild.rebuild_log = Some(Vec::new());
+
+        Some(GuildRebuild {
+            social: self,
+            guild_id,
+        })
+    }
+
+    /// Combine all the guild's channel graphs, decayed up until `now`.
+    // TODO: Do we want to do this on the client-side instead? Probably.
+    pub fn build_guild_graph(
+        &self,
+        guild_id: Id<GuildMarker>,
+        now: u64,
+    ) -> Option<UserRelationshipGraphMap> {
+        self.build_filtered_graph(guild_id, now, |_| true)
+    }
+
+    /// Like `build_guild_graph`, but only including the listed channels.
+    pub fn build_channels_graph(
+        &self,
+        guild_id: Id<GuildMarker>,
+        channel_ids: &HashSet<Id<ChannelMarker>>,
+        now: u64,
+    ) -> Option<UserRelationshipGraphMap> {
+        self.build_filtered_graph(guild_id, now, |channel_id| {
+            channel_ids.contains(&channel_id)
+        })
+    }
+
+    fn build_filtered_graph(
+        &self,
+        guild_id: Id<GuildMarker>,
+        now: u64,
+        include_channel: impl Fn(Id<ChannelMarker>) -> bool,
+    ) -> Option<UserRelationshipGraphMap> {
+        let guild = self.existing_guild(guild_id)?;
+        let guild = guild.lock();
+        let half_life = guild.effective_half_life();
+
+        let channel_graphs = guild
+            .channels
+            .iter()
+            .filter(|(&channel_id, _)| include_channel(channel_id))
+            .map(|(_, channel_graph)| channel_graph);
+
+        Some(UserRelationshipGraphMap::merge(
+            channel_graphs,
+            now,
+            half_life,
+        ))
+    }
+
+    /// Copies of each of the guild's non-empty channel graphs, with decay applied up until `now`.
+    pub fn snapshot_guild(
+        &self,
+        guild_id: Id<GuildMarker>,
+        now: u64,
+    ) -> Option<HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>> {
+        let guild = self.existing_guild(guild_id)?;
+        let guild = guild.lock();
+        let half_life = guild.effective_half_life();
+
+        let channels: HashMap<_, _> = guild
+            .channels
+            .iter()
+            .filter(|(_, channel_graph)| !channel_graph.is_empty())
+            .map(|(&channel_id, channel_graph)| {
+                let channel_graph =
+                    UserRelationshipGraphMap::merge([channel_graph], now, half_life);
+
+                (channel_id, channel_graph)
+            })
+            .collect();
+
+        if channels.is_empty() {
+            None
+        } else {
+            Some(channels)
+        }
+    }
+
+    // TODO: Temporary hack for debug command.
+    pub fn get_all_guild_ids(&self) -> Vec<(Id<GuildMarker>, usize)> {
+        let mut guilds: Vec<_> = self
+            .all_guilds()
+            .into_iter()
+            .map(|guild| {
+                let guild = guild.lock();
+
+                (
+                    guild.guild_id,
+                    guild.channels.values().map(|graph| graph.len()).sum(),
+                )
+            })
+            .collect();
+
+        guilds.sort_by_key(|(_, len)| *len);
+        guilds.reverse();
+
+        guilds
+    }
+
+    /// Run `f` with a channel's graph, loading it first if needed.
+    pub fn with_graph<R>(
+        &self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        f: impl FnOnce(&mut UserRelationshipGraphMap) -> R,
+    ) -> R {
+        self.with_channel(guild_id, channel_id, |guild| f(guild.graph(channel_id)))
+    }
+
+    /// Bring back the interactions the guild was keeping track of before we last restarted, so they
+    /// can still be taken back. This only does anything the first time it is called for a guild.
+    pub fn load_recent_interactions(&self, guild_id: Id<GuildMarker>) {
+        let guild = self.guild(guild_id);
+        if guild.lock().recent_loaded {
+            return;
+        }
+
+        let recent = self.storage.load_recent_interactions(guild_id);
+
+        let mut guild = guild.lock();
+        if !std::mem::replace(&mut guild.recent_loaded, true) {
+            guild.recent.merge_older(recent);
+        }
+    }
+
+    /// If the guild's messages from before we were last restarted still need to be counted towards
+    /// its activity, which should then be done and passed to `add_counted_activity`. This only
+    /// returns true the first time it is called for a guild.
+    pub fn start_counting_activity(&self, guild_id: Id<GuildMarker>) -> bool {
+        !std::mem::replace(&mut self.guild(guild_id).lock().activity_counted, true)
+    }
+
+    /// Add messages counted from history to the guild's activity, alongside any counted since.
+    pub fn add_counted_activity(&self, guild_id: Id<GuildMarker>, counted: GuildActivity) {
+        self.guild(guild_id)
+            .lock()
+            .activity
+            .get_or_insert_with(GuildActivity::default)
+            .merge_messages(&counted);
+    }
+
+    /// Queue every guild's recent interactions to be written to the store, for when we're about to
+    /// shut down.
+    pub fn save_recent_interactions(&self) {
+        for guild in self.all_guilds() {
+            guild.lock().save_recent_interactions(&self.storage);
+        }
+    }
+
+    /// Load a channel's existing graph into memory.
+    pub fn load_graph(&self, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) {
+        self.with_graph(guild_id, channel_id, |_| ());
+    }
+
+    /// Unload everything for a guild, after saving its recent interactions to be loaded again if
+    /// it comes back. Its entry is only kept if it has settings that need to survive it being
+    /// unavailable, or is being rebuilt.
+    pub fn remove_guild(&self, guild_id: Id<GuildMarker>) {
+        let guild = match self.existing_guild(guild_id) {
+            Some(guild) => guild,
+            None => return,
+        };
+
+        {
+            let mut guild = guild.lock();
+
+            let channel_ids: Vec<_> = guild.channels.keys().copied().collect();
+            for channel_id in channel_ids {
+                guild.unload_channel(&self.storage, channel_id);
+            }
+
+            guild.save_recent_interactions(&self.storage);
+
+            // This includes the state for any threads.
+            *guild = GuildGraph {
+                half_life: guild.half_life,
+                rebuild_log: guild.rebuild_log.take(),
+                unloads: guild.unloads,
+                ..GuildGraph::new(guild_id)
+            };
+        }
+
+        let mut guilds = self.guilds.write();
+
+        // Anything that happened in the guild since it was emptied keeps it around, and as the map
+        // can't be held while waiting for a guild, so does the guild being busy.
+        let unused = guilds.get(&guild_id).is_some_and(|entry| {
+            Arc::ptr_eq(entry, &guild) && entry.try_lock().is_some_and(|guild| guild.is_unused())
+        });
+
+        if unused {
+            guilds.remove(&guild_id);
+        }
+    }
+
+    /// Threads share their parent channel's graph, so only have inference state to remove.
+    pub fn remove_thread(&self, guild_id: Id<GuildMarker>, thread_id: Id<ChannelMarker>) {
+        if let Some(guild) = self.existing_guild(guild_id) {
+            guild.lock().state.remove(&thread_id);
+        }
+    }
+
+    pub fn remove_channel(&self, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) {
+        let guild = match self.existing_guild(guild_id) {
+            Some(guild) => guild,
+            None => return,
+        };
+
+        let mut guild = guild.lock();
+
+        guild.state.remove(&channel_id);
+        guild
+            .voice_channels
+            .retain(|_, &mut voice_channel_id| voice_channel_id != channel_id);
+        guild.unload_channel(&self.storage, channel_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        reaction_emoji_key, DisplayGraph, DisplayNode, Relationship, SocialGraph,
+        UserRelationshipGraphMap, EARLY_REMOVAL_WINDOW, REACTION_RATE_LIMIT, REACTION_RATE_WINDOW,
+    };
+    use crate::avatar::Avatar;
+    use crate::snowflake::IdPair;
+    use crate::social::history::{GuildRebuilder, StoredEvent};
+    use crate::social::inference::{Interaction, InteractionType, RelationshipChange};
+    use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+    use twilight_model::channel::message::ReactionType;
+    use twilight_model::id::Id;
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    #[test]
+    fn test_decayed_strength() {
+        let relationship = Relationship {
+            strength: 4.0,
+            updated: 10 * DAY,
+        };
+
+        let half_life = Duration::from_millis(7 * DAY);
+
+        assert_eq!(relationship.decayed_strength(10 * DAY, half_life), 4.0);
+        assert_eq!(relationship.decayed_strength(17 * DAY, half_life), 2.0);
+        assert_eq!(relationship.decayed_strength(24 * DAY, half_life), 1.0);
+
+        // Clock skew shouldn't make relationships stronger.
+        assert_eq!(relationship.decayed_strength(9 * DAY, half_life), 4.0);
+    }
+
+    #[test]
+    fn test_reinforce_and_prune() {
+        let half_life = Duration::from_millis(DAY);
+        let key = IdPair(Id::new(1), Id::new(2));
+
+        let mut graph = UserRelationshipGraphMap::new();
+        graph.reinforce(key, 2.0, 0, half_life);
+        graph.reinforce(key, 2.0, DAY, half_life);
+        assert_eq!(graph[&key].strength, 3.0);
+        assert_eq!(graph[&key].updated, DAY);
+
+        graph.prune(2 * DAY, half_life);
+        assert_eq!(graph.len(), 1);
+
+        graph.prune(20 * DAY, half_life);
+        assert!(graph.is_empty());
+    }
+
+    #[test]
+    fn test_serialization() {
+        let mut graph = UserRelationshipGraphMap::new();
+        graph.insert(
+            IdPair(Id::new(1), Id::new(2)),
+            Relationship {
+                strength: 1.5,
+                updated: 1234,
+            },
+        );
+
+        let json = serde_json::to_string(&graph).unwrap();
+        assert_eq!(json, r#"{"1:2":[1.5,1234]}"#);
+
+        let graph: UserRelationshipGraphMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(graph.len(), 1);
+        assert_eq!(graph[&IdPair(Id::new(1), Id::new(2))].updated, 1234);
+    }
+
+    #[test]
+    fn test_legacy_deserialization() {
+        let graph: UserRelationshipGraphMap =
+            serde_json::from_str(r#"{"1:2":1.5,"3:4":2}"#).unwrap();
+
+        let relationship = graph[&IdPair(Id::new(1), Id::new(2))];
+        assert_eq!(relationship.strength, 1.5);
+        assert_ne!(relationship.updated, 0);
+
+        assert_eq!(graph[&IdPair(Id::new(3), Id::new(4))].strength, 2.0);
+    }
+
+    #[test]
+    fn test_build_channels_graph() {
+        let guild_id = Id::new(1);
+        let social = SocialGraph::new(None);
+
+        for (channel, user) in [(10, 2), (11, 3), (12, 4)] {
+            social.with_graph(guild_id, Id::new(channel), |graph| {
+                graph.insert(IdPair(Id::new(1), Id::new(user)), Relationship::default())
+            });
+        }
+
+        let graph = social.build_guild_graph(guild_id, 0).unwrap();
+        assert_eq!(graph.len(), 3);
+
+        let channel_ids = HashSet::from([Id::new(10), Id::new(12)]);
+        let graph = social
+            .build_channels_graph(guild_id, &channel_ids, 0)
+            .unwrap();
+        assert_eq!(graph.len(), 2);
+        assert!(graph.contains_key(&IdPair(Id::new(1), Id::new(2))));
+        assert!(graph.contains_key(&IdPair(Id::new(1), Id::new(4))));
+
+        assert!(social.build_guild_graph(Id::new(2), 0).is_none());
+    }
+
+    #[test]
+    fn test_edit_and_delete_message() {
+        let social = SocialGraph::new(None);
+
+        let interaction = Interaction {
+            message: Some(Id::new(3)),
+            ..Interaction::test_message(0, 10, Some(20))
+        };
+
+        social.infer_and_apply(&interaction, interaction.when);
+        assert!(social.with_graph(Id::new(1), Id::new(2), |graph| graph
+            .contains_key(&IdPair(Id::new(10), Id::new(20)))));
+
+        // Editing it to mention someone else moves the relationship over to them.
+        let edit = social
+            .edit_message(
+                Id::new(1),
+                Id::new(3),
+                "<@30> sorry, wrong person",
+                [Id::new(30)].into_iter(),
+                DAY,
+            )
+            .unwrap();
+        assert_eq!(edit.retracted.len(), 1);
+        assert_eq!(edit.added.len(), 1);
+
+        social.with_graph(Id::new(1), Id::new(2), |graph| {
+            assert!(!graph.contains_key(&IdPair(Id::new(10), Id::new(20))));
+            assert!(graph.contains_key(&IdPair(Id::new(10), Id::new(30))));
+        });
+
+        // An edit that doesn't change anything leaves the changes alone.
+        let unchanged = social
+            .edit_message(
+                Id::new(1),
+                Id::new(3),
+                "<@30> sorry!",
+                [Id::new(30)].into_iter(),
+                DAY,
+            )
+            .unwrap();
+        assert!(unchanged.retracted.is_empty() && unchanged.added.is_empty());
+
+        // Messages are tracked separately for each guild.
+        assert!(social
+            .retract_message(Id::new(2), Id::new(3), 2 * DAY)
+            .is_none());
+
+        let (_, retracted) = social
+            .retract_message(Id::new(1), Id::new(3), 2 * DAY)
+            .unwrap();
+        assert_eq!(retracted, edit.added);
+        assert!(social.with_graph(Id::new(1), Id::new(2), |graph| graph.is_empty()));
+
+        assert!(social
+            .retract_message(Id::new(1), Id::new(3), 2 * DAY)
+            .is_none());
+    }
+
+    #[test]
+    fn test_message_deleted_before_created() {
+        let social = SocialGraph::new(None);
+
+        let message = |when, message| Interaction {
+            message: Some(Id::new(message)),
+            ..Interaction::test_message(when, 10, Some(20))
+        };
+
+        // Load the guild, as deletions in guilds we haven't seen are ignored.
+        social.load_graph(Id::new(1), Id::new(2));
+
+        // The deletion overtakes the message while it's looking up what it replied to.
+        assert!(social.retract_message(Id::new(1), Id::new(3), 0).is_none());
+        let interaction = message(1000, 3);
+        assert!(social
+            .infer_and_apply(&interaction, interaction.when)
+            .is_empty());
+        assert!(social.with_graph(Id::new(1), Id::new(2), |graph| graph.is_empty()));
+
+        // Deletions are only remembered for a short while.
+        social.retract_message(Id::new(1), Id::new(4), 0);
+        let interaction = message(EARLY_REMOVAL_WINDOW, 4);
+        assert!(!social
+            .infer_and_apply(&interaction, interaction.when)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_remove_guild() {
+        let social = SocialGraph::new(None);
+
+        let interaction = Interaction {
+            message: Some(Id::new(3)),
+            ..Interaction::test_message(0, 10, Some(20))
+        };
+
+        social.load_recent_interactions(Id::new(1));
+        social.infer_and_apply(&interaction, interaction.when);
+
+        // Nothing is kept for a guild without any settings.
+        social.remove_guild(Id::new(1));
+        assert!(social.existing_guild(Id::new(1)).is_none());
+
+        // A guild with settings keeps them, but nothing else.
+        social.set_half_life(Id::new(1), Some(Duration::from_millis(DAY)));
+        social.infer_and_apply(&interaction, interaction.when);
+        social.remove_guild(Id::new(1));
+
+        let guild = social.existing_guild(Id::new(1)).unwrap();
+        let guild = guild.lock();
+        assert_eq!(guild.half_life, Some(Duration::from_millis(DAY)));
+        assert!(guild.channels.is_empty() && guild.recent.messages.is_empty());
+    }
+
+    #[test]
+    fn test_rebuild_keeps_live_changes() {
+        let social = SocialGraph::new(None);
+
+        let message = |when, message, source, target| Interaction {
+            message: Some(Id::new(message)),
+            ..Interaction::test_message(when, source, Some(target))
+        };
+
+        let stored = |interaction: &Interaction, changes: &[RelationshipChange]| {
+            changes
+                .iter()
+                .map(|change| StoredEvent {
+                    timestamp: interaction.when,
+                    channel: interaction.channel,
+                    source: change.source,
+                    target: change.target,
+                    reason: change.reason,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let deleted = message(0, 3, 10, 20);
+        let mut events = stored(&deleted, &social.infer_and_apply(&deleted, deleted.when));
+
+        let rebuild = social.begin_rebuild(Id::new(1)).unwrap();
+        assert!(social.begin_rebuild(Id::new(1)).is_none());
+
+        // Of the messages from before loading starts, one is stored in time and one isn't.
+        let stored_in_time = message(1000, 4, 20, 40);
+        let changes = social.infer_and_apply(&stored_in_time, stored_in_time.when);
+        events.extend(stored(&stored_in_time, &changes));
+        let stored_late = message(1500, 5, 20, 50);
+        social.infer_and_apply(&stored_late, stored_late.when);
+
+        let mut loaded = rebuild.start_loading();
+
+        // Nothing from after loading starts is in the events, even once it's in the table.
+        let after = message(2000, 6, 30, 40);
+        social.infer_and_apply(&after, after.when);
+        social.retract_message(Id::new(1), Id::new(3), 3000);
+
+        let mut rebuilder = GuildRebuilder::new(Duration::from_secs(60));
+        rebuilder.add(&events);
+        loaded.add(&events);
+        let (graphs, activity) = rebuilder.finish(3000);
+        let stored_strength = graphs[&Id::new(2)][&IdPair(Id::new(20), Id::new(40))].strength;
+        rebuild.finish(graphs, activity, loaded);
+
+        social.with_graph(Id::new(1), Id::new(2), |graph| {
+            assert!(!graph.contains_key(&IdPair(Id::new(10), Id::new(20))));
+            assert_eq!(
+                graph[&IdPair(Id::new(20), Id::new(40))].strength,
+                stored_strength
+            );
+            assert!(graph.contains_key(&IdPair(Id::new(20), Id::new(50))));
+            assert!(graph.contains_key(&IdPair(Id::new(30), Id::new(40))));
+        });
+
+        // Nothing is logged once the rebuild is over.
+        drop(social.begin_rebuild(Id::new(1)).unwrap());
+        assert!(social.begin_rebuild(Id::new(1)).is_some());
+    }
+
+    #[test]
+    fn test_reactions() {
+        let social = SocialGraph::new(None);
+
+        let reaction = |when, message| Interaction {
+            what: InteractionType::Reaction,
+            message: Some(Id::new(message)),
+            ..Interaction::test_message(when, 10, Some(20))
+        };
+
+        let emoji = |name: &str| {
+            reaction_emoji_key(&ReactionType::Unicode {
+                name: name.to_owned(),
+            })
+        };
+
+        let interaction = reaction(0, 3);
+        assert!(social.add_reaction(&interaction, &emoji("👍"), 0).is_some());
+        assert!(!social.with_graph(Id::new(1), Id::new(2), |graph| graph.is_empty()));
+
+        // Only the first reaction to a message counts.
+        assert!(social.add_reaction(&interaction, &emoji("🎉"), 0).is_none());
+
+        // The changes are only undone once all of the user's reactions are removed.
+        let retracted = social.remove_reactions(
+            Id::new(1),
+            Id::new(3),
+            Some(Id::new(10)),
+            Some(&emoji("👍")),
+            0,
+        );
+        assert!(retracted.is_empty());
+        assert!(!social.with_graph(Id::new(1), Id::new(2), |graph| graph.is_empty()));
+
+        let retracted =
+            social.remove_reactions(Id::new(1), Id::new(3), None, Some(&emoji("🎉")), 0);
+        assert_eq!(retracted.len(), 1);
+        assert!(social.with_graph(Id::new(1), Id::new(2), |graph| graph.is_empty()));
+
+        // Toggling a reaction counts towards the rate limit, which resets after the window.
+        for _ in 1..REACTION_RATE_LIMIT {
+            assert!(social.add_reaction(&interaction, &emoji("👍"), 0).is_some());
+            social.remove_reactions(Id::new(1), Id::new(3), None, None, 0);
+        }
+
+        assert!(social
+            .add_reaction(&reaction(1, 4), &emoji("👍"), 1)
+            .is_none());
+
+        let interaction = reaction(REACTION_RATE_WINDOW, 5);
+        assert!(social
+            .add_reaction(&interaction, &emoji("👍"), interaction.when)
+            .is_some());
+    }
+
+    #[test]
+    fn test_reaction_removed_before_added() {
+        let social = SocialGraph::new(None);
+
+        let reaction = |when, message, source| Interaction {
+            what: InteractionType::Reaction,
+            message: Some(Id::new(message)),
+            ..Interaction::test_message(when, source, Some(20))
+        };
+
+        let emoji = |name: &str| {
+            reaction_emoji_key(&ReactionType::Unicode {
+                name: name.to_owned(),
+            })
+        };
+
+        social.load_graph(Id::new(1), Id::new(2));
+
+        // The removal overtakes the reaction while it's looking up the message.
+        let retracted = social.remove_reactions(
+            Id::new(1),
+            Id::new(3),
+            Some(Id::new(10)),
+            Some(&emoji("👍")),
+            0,
+        );
+        assert!(retracted.is_empty());
+
+        let interaction = reaction(1000, 3, 10);
+        assert!(social
+            .add_reaction(&interaction, &emoji("👍"), interaction.when)
+            .is_none());
+        assert!(social.with_graph(Id::new(1), Id::new(2), |graph| graph.is_empty()));
+
+        // That removal only covered the one reaction, so reacting again counts.
+        assert!(social
+            .add_reaction(&interaction, &emoji("👍"), interaction.when)
+            .is_some());
+
+        // Removing every reaction covers everyone's, until the window is over.
+        social.remove_reactions(Id::new(1), Id::new(4), None, None, 0);
+
+        for source in [10, 11] {
+            let interaction = reaction(1000, 4, source);
+            assert!(social
+                .add_reaction(&interaction, &emoji("🎉"), interaction.when)
+                .is_none());
+        }
+
+        let interaction = reaction(EARLY_REMOVAL_WINDOW, 4, 12);
+        assert!(social
+            .add_reaction(&interaction, &emoji("🎉"), interaction.when)
+            .is_some());
+    }
+
+    #[test]
+    fn test_ego_graph() {
+        // A chain of 1 - 2 - 3 - 4, with 5 off to the side of 2.
+        let edges = vec![
+            ([Id::new(1), Id::new(2)], 1.0),
+            ([Id::new(2), Id::new(3)], 2.0),
+            ([Id::new(2), Id::new(5)], 4.0),
+            ([Id::new(3), Id::new(4)], 8.0),
+        ];
+
+        let nodes: HashMap<_, _> = (1..=5)
+            .map(|id| {
+                let node = DisplayNode {
+                    name: id.to_string(),
+                    color: None,
+                    is_member: true,
+                    avatar: Avatar::Default { index: 0 },
+                    weight: 100.0,
+                };
+
+                (Id::new(id), node)
+            })
+            .collect();
+
+        let graph = DisplayGraph { nodes, edges };
+
+        assert!(graph.ego_graph(Id::new(6), 1).is_none());
+
+        let ego = graph.ego_graph(Id::new(3), 1).unwrap();
+        assert_eq!(
+            ego.sorted_user_ids(),
+            vec![Id::new(2), Id::new(3), Id::new(4)]
+        );
+        assert_eq!(ego.edges.len(), 2);
+        assert_eq!(ego.nodes[&Id::new(3)].weight, 10.0);
+
+        let ego = graph.ego_graph(Id::new(1), 2).unwrap();
+        assert_eq!(
+            ego.sorted_user_ids(),
+            vec![Id::new(1), Id::new(2), Id::new(3), Id::new(5)],
+        );
+        assert_eq!(ego.edges.len(), 3);
     }
 }
@@ -0,0 +1,433 @@
+use anyhow::{Context as AnyhowContext, Result};
+use futures::TryStreamExt;
+use sqlx::mysql::MySqlRow;
+use sqlx::{MySqlPool, Row};
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::graph::{self, UserRelationshipGraphMap};
+use super::inference::RelationshipChangeReason;
+use super::weighting::GuildActivity;
+use crate::snowflake::IdPair;
+
+/// A relationship change as stored in the `events` table by `store_interaction`.
+#[derive(Debug, Clone)]
+pub struct StoredEvent {
+    /// Unix timestamp (in milliseconds) the interaction happened at.
+    pub timestamp: u64,
+    pub channel: Id<ChannelMarker>,
+    pub source: Id<UserMarker>,
+    pub target: Id<UserMarker>,
+    pub reason: RelationshipChangeReason,
+}
+
+impl StoredEvent {
+    fn from_row(row: &MySqlRow) -> Result<Self> {
+        let id = |index: usize| -> Result<u64> {
+            let id: u64 = row.try_get(index)?;
+            anyhow::ensure!(id != 0, "invalid id in column {}", index);
+
+            Ok(id)
+        };
+
+        Ok(StoredEvent {
+            timestamp: row.try_get(0)?,
+            channel: Id::new(id(1)?),
+            source: Id::new(id(2)?),
+            target: Id::new(id(3)?),
+            reason: row.try_get::<u8, _>(4)?.try_into()?,
+        })
+    }
+}
+
+/// How many events are loaded before handing them over to be processed.
+const EVENT_BATCH_SIZE: usize = 10_000;
+
+/// Go through a guild's events from `since` (in milliseconds) onwards, oldest first, returning
+/// `state` along with how many events there were.
+///
+/// The events are streamed in, and handed to `process` a batch at a time on a blocking thread, so
+/// the whole history is never loaded at once. They're all read from a single query, so they're a
+/// consistent snapshot of the table as it was when this is called.
+pub async fn process_guild_events<T: Send + 'static>(
+    pool: &MySqlPool,
+    guild_id: Id<GuildMarker>,
+    since: u64,
+    mut state: T,
+    process: impl Fn(&mut T, &[StoredEvent]) + Send + Copy + 'static,
+) -> Result<(T, usize)> {
+    let mut rows = sqlx::query("SELECT timestamp, channel, source, target, reason FROM events WHERE guild = ? AND timestamp >= ? ORDER BY timestamp")
+        .bind(guild_id.get())
+        .bind(since)
+        .fetch(pool);
+
+    let mut batch = Vec::with_capacity(EVENT_BATCH_SIZE);
+    let mut total = 0;
+
+    loop {
+        let row = rows.try_next().await.context("failed to load events")?;
+        if let Some(row) = &row {
+            batch.push(StoredEvent::from_row(row)?);
+        }
+
+        if batch.len() == EVENT_BATCH_SIZE || (row.is_none() && !batch.is_empty()) {
+            total += batch.len();
+
+            (state, batch) = tokio::task::spawn_blocking(move || {
+                process(&mut state, &batch);
+                batch.clear();
+
+                (state, batch)
+            })
+            .await?;
+        }
+
+        if row.is_none() {
+            return Ok((state, total));
+        }
+    }
+}
+
+/// All the guilds that have any stored events.
+pub async fn load_event_guild_ids(pool: &MySqlPool) -> Result<Vec<Id<GuildMarker>>> {
+    let guild_ids = sqlx::query("SELECT DISTINCT guild FROM events")
+        .try_map(|row: MySqlRow| {
+            Id::new_checked(row.get(0)).ok_or(sqlx::Error::ColumnDecode {
+                index: "0".into(),
+                source: "invalid id".into(),
+            })
+        })
+        .fetch_all(pool)
+        .await
+        .context("failed to load guilds")?;
+
+    Ok(guild_ids)
+}
+
+/// Only messages produce these changes, unlike thread participation which joining a thread does
+/// as well, so a message that only linked its author to the thread's starter isn't counted. That's
+/// rare enough not to matter.
+fn is_message(events: &[StoredEvent]) -> bool {
+    events.iter().any(|event| {
+        matches!(
+            event.reason,
+            RelationshipChangeReason::MessageDirectMention
+                | RelationshipChangeReason::MessageIndirectMention
+                | RelationshipChangeReason::MessageAdjacency
+                | RelationshipChangeReason::MessageBinarySequence
+                | RelationshipChangeReason::GroupMention
+        )
+    })
+}
+
+/// Rebuilds a guild's channel graphs from its events, with the same weighting and decay that live
+/// updates get.
+///
+/// The events table only has the interactions that changed something, so the guild's message
+/// rate is estimated by counting each interaction that came from a message.
+#[derive(Debug)]
+pub struct GuildRebuilder {
+    base_half_life: Duration,
+    graphs: HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+    activity: GuildActivity,
+    /// The events of the interaction being added, which might continue in the next batch.
+    interaction: Vec<StoredEvent>,
+}
+
+impl GuildRebuilder {
+    pub fn new(base_half_life: Duration) -> Self {
+        GuildRebuilder {
+            base_half_life,
+            graphs: HashMap::new(),
+            activity: GuildActivity::default(),
+            interaction: Vec::new(),
+        }
+    }
+
+    /// Add the next events, which have to be in order.
+    pub fn add(&mut self, events: &[StoredEvent]) {
+        for event in events {
+            // All the changes from an interaction were stored together with the same timestamp.
+            if let Some(first) = self.interaction.first() {
+                if first.timestamp != event.timestamp || first.channel != event.channel {
+                    self.reinforce();
+                }
+            }
+
+            self.interaction.push(event.clone());
+        }
+    }
+
+    fn reinforce(&mut self) {
+        let first = &self.interaction[0];
+
+        graph::reinforce_guild(
+            &mut self.graphs,
+            &mut self.activity,
+            self.base_half_life,
+            first.channel,
+            is_message(&self.interaction),
+            self.interaction.iter().map(|event| {
+                (
+                    IdPair(event.source, event.target),
+                    event.reason.get_change_strength(),
+                )
+            }),
+            first.timestamp,
+        );
+
+        self.interaction.clear();
+    }
+
+    /// The rebuilt graphs and activity, brought up to date at `now`.
+    pub fn finish(
+        mut self,
+        now: u64,
+    ) -> (
+        HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+        GuildActivity,
+    ) {
+        if !self.interaction.is_empty() {
+            self.reinforce();
+        }
+
+        // Bring everything up to date, so we don't write out anything that has since faded away.
+        let half_life = self.base_half_life.mul_f64(self.activity.half_life_scale());
+        for graph in self.graphs.values_mut() {
+            graph.prune(now, half_life);
+        }
+
+        (self.graphs, self.activity)
+    }
+}
+
+/// Counts the messages in a guild's events from before `until`, to estimate its message rate when
+/// it is loaded without one.
+#[derive(Debug)]
+pub struct ActivityCounter {
+    until: u64,
+    activity: GuildActivity,
+    /// The interaction being counted, and if it has been counted as a message yet.
+    interaction: Option<(u64, Id<ChannelMarker>, bool)>,
+}
+
+impl ActivityCounter {
+    pub fn new(until: u64) -> Self {
+        ActivityCounter {
+            until,
+            activity: GuildActivity::default(),
+            interaction: None,
+        }
+    }
+
+    /// Add the next events, which have to be in order.
+    pub fn add(&mut self, events: &[StoredEvent]) {
+        for event in events
+            .iter()
+            .take_while(|event| event.timestamp < self.until)
+        {
+            // All the changes from an interaction were stored together with the same timestamp.
+            let counted = match self.interaction {
+                Some((timestamp, channel, counted)) => {
+                    counted && timestamp == event.timestamp && channel == event.channel
+                }
+                None => false,
+            };
+
+            let message = is_message(std::slice::from_ref(event));
+            if message && !counted {
+                self.activity.record_message(event.timestamp);
+            }
+
+            self.interaction = Some((event.timestamp, event.channel, counted || message));
+        }
+    }
+
+    pub fn finish(self) -> GuildActivity {
+        self.activity
+    }
+}
+
+/// Replays events into a single graph for the whole guild, taking a copy at each of the (sorted)
+/// frame timestamps with decay applied up until then.
+///
+/// This doesn't apply the guild activity scaling that live updates get, so the strengths are only
+/// meaningful relative to each other.
+#[derive(Debug)]
+pub struct FrameReplay {
+    half_life: Duration,
+    graph: UserRelationshipGraphMap,
+    frames: Vec<u64>,
+    taken: Vec<UserRelationshipGraphMap>,
+}
+
+impl FrameReplay {
+    pub fn new(frames: Vec<u64>, half_life: Duration) -> Self {
+        FrameReplay {
+            half_life,
+            graph: UserRelationshipGraphMap::new(),
+            frames,
+            taken: Vec::new(),
+        }
+    }
+
+    /// Add the next events, which have to be in order.
+    pub fn add(&mut self, events: &[StoredEvent]) {
+        for event in events {
+            // Events landing exactly on a frame are included in it.
+            while self.taken.len() < self.frames.len()
+                && self.frames[self.taken.len()] < event.timestamp
+            {
+                self.take_frame();
+            }
+
+            self.graph.reinforce(
+                IdPair(event.source, event.target),
+                event.reason.get_change_strength(),
+                event.timestamp,
+                self.half_life,
+            );
+        }
+    }
+
+    fn take_frame(&mut self) {
+        let frame = self.frames[self.taken.len()];
+
+        self.taken.push(UserRelationshipGraphMap::merge(
+            [&self.graph],
+            frame,
+            self.half_life,
+        ));
+    }
+
+    /// A graph for each of the frames.
+    pub fn finish(mut self) -> Vec<UserRelationshipGraphMap> {
+        while self.taken.len() < self.frames.len() {
+            self.take_frame();
+        }
+
+        self.taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActivityCounter, FrameReplay, GuildRebuilder, StoredEvent};
+    use crate::snowflake::IdPair;
+    use crate::social::inference::RelationshipChangeReason;
+    use crate::social::weighting::GuildActivity;
+    use std::time::Duration;
+    use twilight_model::id::Id;
+
+    #[test]
+    fn test_replay_frames() {
+        let event = |timestamp, source, target| StoredEvent {
+            timestamp,
+            channel: Id::new(1),
+            source: Id::new(source),
+            target: Id::new(target),
+            reason: RelationshipChangeReason::MessageDirectMention,
+        };
+
+        let events = [event(0, 1, 2), event(10, 1, 2), event(15, 2, 3)];
+        let mut replay = FrameReplay::new(vec![5, 10, 20], Duration::from_millis(10));
+        replay.add(&events[..2]);
+        replay.add(&events[2..]);
+        let frames = replay.finish();
+
+        assert_eq!(frames.len(), 3);
+
+        assert_eq!(frames[0].len(), 1);
+        assert!(frames[0][&IdPair(Id::new(1), Id::new(2))].strength < 2.0);
+
+        // Events landing exactly on a frame are included in it.
+        assert_eq!(frames[1][&IdPair(Id::new(1), Id::new(2))].strength, 3.0);
+        assert_eq!(frames[1].len(), 1);
+
+        assert_eq!(frames[2].len(), 2);
+        assert_eq!(frames[2][&IdPair(Id::new(1), Id::new(2))].strength, 1.5);
+        assert_eq!(frames[2][&IdPair(Id::new(1), Id::new(2))].updated, 20);
+    }
+
+    #[test]
+    fn test_rebuild_guild() {
+        let event = |timestamp, channel, source, target, reason| StoredEvent {
+            timestamp,
+            channel: Id::new(channel),
+            source: Id::new(source),
+            target: Id::new(target),
+            reason,
+        };
+
+        let events = vec![
+            event(0, 1, 1, 2, RelationshipChangeReason::MessageDirectMention),
+            event(0, 1, 1, 3, RelationshipChangeReason::MessageDirectMention),
+            event(5, 2, 2, 3, RelationshipChangeReason::Reaction),
+            event(7, 3, 4, 1, RelationshipChangeReason::ThreadParticipation),
+            event(10, 1, 1, 2, RelationshipChangeReason::MessageDirectMention),
+        ];
+
+        // The first interaction is split between two batches.
+        let mut rebuilder = GuildRebuilder::new(Duration::from_secs(60 * 60));
+        rebuilder.add(&events[..1]);
+        rebuilder.add(&events[1..]);
+        let (graphs, activity) = rebuilder.finish(10);
+
+        assert_eq!(graphs.len(), 3);
+        assert_eq!(graphs[&Id::new(1)].len(), 2);
+        assert_eq!(graphs[&Id::new(2)].len(), 1);
+
+        // Repeated interactions build on each other.
+        let repeated = graphs[&Id::new(1)][&IdPair(Id::new(1), Id::new(2))].strength;
+        let single = graphs[&Id::new(1)][&IdPair(Id::new(1), Id::new(3))].strength;
+        assert!(repeated > single);
+
+        // Each message is counted once, and reactions and thread joins aren't counted at all.
+        let mut expected = GuildActivity::default();
+        expected.record_message(0);
+        expected.record_message(10);
+        assert_eq!(activity.message_rate(10), expected.message_rate(10));
+
+        // Everything fades away if we rebuild far enough in the future.
+        let mut rebuilder = GuildRebuilder::new(Duration::from_millis(1));
+        rebuilder.add(&events);
+        let (graphs, _) = rebuilder.finish(1_000_000);
+        assert!(graphs.values().all(|graph| graph.is_empty()));
+    }
+
+    #[test]
+    fn test_count_activity() {
+        let event = |timestamp, channel, reason| StoredEvent {
+            timestamp,
+            channel: Id::new(channel),
+            source: Id::new(1),
+            target: Id::new(2),
+            reason,
+        };
+
+        let events = [
+            event(0, 1, RelationshipChangeReason::MessageDirectMention),
+            event(0, 1, RelationshipChangeReason::MessageAdjacency),
+            event(0, 2, RelationshipChangeReason::MessageAdjacency),
+            event(5, 1, RelationshipChangeReason::Reaction),
+            event(7, 1, RelationshipChangeReason::ThreadParticipation),
+            event(7, 1, RelationshipChangeReason::MessageBinarySequence),
+            event(10, 1, RelationshipChangeReason::MessageDirectMention),
+        ];
+
+        // The first interaction is split between two batches, and the last is too late to count.
+        let mut counter = ActivityCounter::new(10);
+        counter.add(&events[..1]);
+        counter.add(&events[1..]);
+        let activity = counter.finish();
+
+        let mut expected = GuildActivity::default();
+        expected.record_message(0);
+        expected.record_message(0);
+        expected.record_message(7);
+        assert_eq!(activity.message_rate(10), expected.message_rate(10));
+    }
+}
@@ -2,69 +2,170 @@ use anyhow::{Context as AnyhowContext, Result};
 use futures::future::join_all;
 use twilight_model::channel::{ChannelType, Message};
 use twilight_model::gateway::payload::incoming::ReactionAdd;
-use twilight_model::id::marker::{ChannelMarker, GuildMarker, UserMarker};
+use twilight_model::id::marker::{
+    ChannelMarker, GuildMarker, MessageMarker, RoleMarker, UserMarker,
+};
 use twilight_model::id::Id;
 
-use std::collections::{HashSet, VecDeque};
-use std::fmt::{Display, Formatter};
-use std::time::Instant;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use crate::cache::{Cache, CachedMessage};
+use crate::cache::{Cache, CachedChannel, CachedMessage};
+
+/// Where interactions get their timestamps from.
+///
+/// The inference engines only ever look at the time an interaction happened, so replacing this
+/// lets recorded interactions be replayed with time passing as it originally did.
+pub trait Clock: Debug + Send + Sync {
+    /// The current unix timestamp in milliseconds.
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+/// A clock that only moves when it is set, for tests.
+#[derive(Debug, Default)]
+pub struct FixedClock(AtomicU64);
+
+impl FixedClock {
+    pub fn new(now: u64) -> Self {
+        FixedClock(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum InteractionType {
     Message,
     Reaction,
+    ThreadJoin,
+    VoiceJoin,
+    VoiceLeave,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Thread {
+    pub id: Id<ChannelMarker>,
+    /// The user that started the thread, if we know who it was.
+    pub owner: Option<Id<UserMarker>>,
+}
+
+/// Threads are rolled up into their parent channel's graph, so work out which channel an
+/// interaction in `channel` belongs to, and which thread if any.
+fn graph_channel(channel: &CachedChannel) -> (Id<ChannelMarker>, Option<Thread>) {
+    match channel.parent_id {
+        Some(parent_id) if channel.kind.is_thread() => (
+            parent_id,
+            Some(Thread {
+                id: channel.id,
+                owner: channel.owner_id,
+            }),
+        ),
+        _ => (channel.id, None),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Interaction {
     pub what: InteractionType,
-    pub when: Instant,
+    /// Unix timestamp (in milliseconds) the interaction happened at.
+    pub when: u64,
     pub guild: Id<GuildMarker>,
+    /// The channel whose graph the interaction is part of, for threads this is their parent.
     pub channel: Id<ChannelMarker>,
+    pub thread: Option<Thread>,
+    /// The message that was sent, or reacted to.
+    pub message: Option<Id<MessageMarker>>,
     pub source: Id<UserMarker>,
     pub source_is_bot: bool,
     pub target: Option<Id<UserMarker>>,
     pub other_targets: Vec<Id<UserMarker>>,
+    /// Author of the message that a message was replied to, as that can't be changed by editing.
+    pub reply_to: Option<Id<UserMarker>>,
+    pub source_roles: Vec<Id<RoleMarker>>,
+    pub mention_roles: Vec<Id<RoleMarker>>,
+    /// If the message pinged @everyone or @here.
+    pub mention_everyone: bool,
 }
 
 impl Interaction {
     pub fn new_from_message(
         message: &Message,
+        channel: &CachedChannel,
         referenced_message: Option<&CachedMessage>,
+        clock: &dyn Clock,
     ) -> Result<Self> {
         let guild_id = message
             .guild_id
             .context("tried to create an interaction from a message not sent to a guild")?;
 
-        // We prefer a parsed mention over a reply, as a parsed mention with reply is usually
-        // someone directing a message to someone else.
-        let reply_to = parse_direct_mention(&message.content)
-            .or_else(|| referenced_message.map(|m| m.author_id));
+        let reply_to = referenced_message.map(|m| m.author_id);
+        let (target, other_targets) = message_targets(
+            &message.content,
+            message.mentions.iter().map(|mention| mention.id),
+            reply_to,
+        );
 
-        let user_mentions = message
-            .mentions
-            .iter()
-            .map(|mention| mention.id)
-            .filter(|&u| Some(u) != reply_to)
-            .collect::<Vec<_>>();
+        let (channel, thread) = graph_channel(channel);
 
         Ok(Interaction {
             what: InteractionType::Message,
-            when: Instant::now(),
+            when: clock.now(),
             guild: guild_id,
-            channel: message.channel_id,
+            channel,
+            thread,
+            message: Some(message.id),
             source: message.author.id,
             source_is_bot: message.author.bot,
-            target: reply_to,
-            other_targets: user_mentions,
+            target,
+            other_targets,
+            reply_to,
+            source_roles: message
+                .member
+                .as_ref()
+                .map_or_else(Vec::new, |member| member.roles.clone()),
+            mention_roles: message.mention_roles.clone(),
+            mention_everyone: message.mention_everyone,
         })
     }
 
+    /// A copy of a message interaction with the targets updated for an edit to the message.
+    pub fn with_edit(&self, content: &str, mentions: impl Iterator<Item = Id<UserMarker>>) -> Self {
+        let (target, other_targets) = message_targets(content, mentions, self.reply_to);
+
+        Interaction {
+            target,
+            other_targets,
+            ..self.clone()
+        }
+    }
+
     pub fn new_from_reaction(
         reaction: &ReactionAdd,
+        channel: &CachedChannel,
         target_message: &CachedMessage,
+        clock: &dyn Clock,
     ) -> Result<Self> {
         let guild_id = reaction
             .guild_id
@@ -76,18 +177,113 @@ impl Interaction {
             .context("member info missing from reaction")?
             .user;
 
+        let (channel, thread) = graph_channel(channel);
+
         Ok(Interaction {
             what: InteractionType::Reaction,
-            when: Instant::now(),
+            when: clock.now(),
             guild: guild_id,
-            channel: reaction.channel_id,
+            channel,
+            thread,
+            message: Some(reaction.message_id),
             source: reaction.user_id,
             source_is_bot: user.bot,
             target: Some(target_message.author_id),
             other_targets: Vec::new(),
+            reply_to: None,
+            source_roles: Vec::new(),
+            mention_roles: Vec::new(),
+            mention_everyone: false,
+        })
+    }
+
+    pub fn new_from_thread_join(
+        guild_id: Id<GuildMarker>,
+        thread: &CachedChannel,
+        user_id: Id<UserMarker>,
+        user_is_bot: bool,
+        clock: &dyn Clock,
+    ) -> Result<Self> {
+        let (channel, thread) = match graph_channel(thread) {
+            (channel, Some(thread)) => (channel, thread),
+            (_, None) => anyhow::bail!("tried to create a thread join interaction for a channel"),
+        };
+
+        Ok(Interaction {
+            what: InteractionType::ThreadJoin,
+            when: clock.now(),
+            guild: guild_id,
+            channel,
+            thread: Some(thread),
+            message: None,
+            source: user_id,
+            source_is_bot: user_is_bot,
+            target: thread.owner,
+            other_targets: Vec::new(),
+            reply_to: None,
+            source_roles: Vec::new(),
+            mention_roles: Vec::new(),
+            mention_everyone: false,
         })
     }
 
+    /// A user joining or leaving a voice channel, deafened users count as having left.
+    pub fn new_from_voice_state(
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        user_id: Id<UserMarker>,
+        user_is_bot: bool,
+        joined: bool,
+        clock: &dyn Clock,
+    ) -> Self {
+        Interaction {
+            what: if joined {
+                InteractionType::VoiceJoin
+            } else {
+                InteractionType::VoiceLeave
+            },
+            when: clock.now(),
+            guild: guild_id,
+            channel: channel_id,
+            thread: None,
+            message: None,
+            source: user_id,
+            source_is_bot: user_is_bot,
+            target: None,
+            other_targets: Vec::new(),
+            reply_to: None,
+            source_roles: Vec::new(),
+            mention_roles: Vec::new(),
+            mention_everyone: false,
+        }
+    }
+
+    /// A plain message in channel 2 of guild 1, for tests to build other interactions from.
+    #[cfg(test)]
+    pub(crate) fn test_message(when: u64, source: u64, target: Option<u64>) -> Self {
+        Interaction {
+            what: InteractionType::Message,
+            when,
+            guild: Id::new(1),
+            channel: Id::new(2),
+            thread: None,
+            message: Some(Id::new(when + 1)),
+            source: Id::new(source),
+            source_is_bot: false,
+            target: target.map(Id::new),
+            other_targets: Vec::new(),
+            reply_to: None,
+            source_roles: Vec::new(),
+            mention_roles: Vec::new(),
+            mention_everyone: false,
+        }
+    }
+
+    /// Where the interaction actually happened, the thread if it was in one.
+    pub fn conversation(&self) -> Id<ChannelMarker> {
+        self.thread.map_or(self.channel, |thread| thread.id)
+    }
+
     async fn get_user_display_name(
         cache: &Cache,
         guild_id: Id<GuildMarker>,
@@ -123,10 +319,10 @@ impl Interaction {
             None
         };
 
-        let channel_name = match cache.get_channel(self.guild, self.channel).await {
+        let channel_name = match cache.get_channel(self.guild, self.conversation()).await {
             Ok(channel) if channel.kind == ChannelType::GuildText => format!("#{}", channel.name),
             Ok(channel) => channel.name,
-            Err(_) => format!("<invalid channel {}>", self.channel),
+            Err(_) => format!("<invalid channel {}>", self.conversation()),
         };
 
         let guild_name = match cache.get_guild(self.guild).await {
@@ -150,6 +346,21 @@ impl Interaction {
                 channel_name,
                 guild_name,
             ),
+            InteractionType::ThreadJoin => format!(
+                "{} joined {} @ \"{}\", started by {}",
+                source_name,
+                channel_name,
+                guild_name,
+                primary_target_name.as_deref().unwrap_or("None"),
+            ),
+            InteractionType::VoiceJoin => format!(
+                "{} joined voice in {} @ \"{}\"",
+                source_name, channel_name, guild_name,
+            ),
+            InteractionType::VoiceLeave => format!(
+                "{} left voice in {} @ \"{}\"",
+                source_name, channel_name, guild_name,
+            ),
         }
     }
 }
@@ -157,19 +368,24 @@ impl Interaction {
 pub type RelationshipStrength = f32;
 
 // These values are serialized and can't be modified.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RelationshipChangeReason {
     Reaction = 1,
     MessageDirectMention = 2,
     MessageIndirectMention = 3,
     MessageAdjacency = 4,
     MessageBinarySequence = 5,
+    ThreadParticipation = 6,
+    VoiceCoPresence = 7,
+    GroupMention = 8,
 }
 
-// TODO: I think this needs to be based on the total number of nodes in the graph.
-//       AlliedModders seems to be decaying a bit quick, while Together C & C++ is not quite there.
-pub const RELATIONSHIP_DECAY: RelationshipStrength = -0.02;
-pub const RELATIONSHIP_DECAY_GLOBAL: RelationshipStrength = -0.0002;
+/// How long it takes for a relationship to lose half its strength, unless overridden for a guild.
+/// This is further scaled by the size of the guild, see `GuildActivity`.
+pub const RELATIONSHIP_HALF_LIFE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Relationships that have decayed below this are removed from the graph entirely.
+pub const RELATIONSHIP_PRUNE_THRESHOLD: RelationshipStrength = 0.01;
 
 impl RelationshipChangeReason {
     pub fn get_change_strength(&self) -> RelationshipStrength {
@@ -180,15 +396,47 @@ impl RelationshipChangeReason {
             Self::MessageAdjacency => 0.5,
             // TODO: Increase weight back to 1.0 once implementation is fixed.
             Self::MessageBinarySequence => 0.5,
+            Self::ThreadParticipation => 0.5,
+            // Per `VOICE_PRESENCE_INTERVAL` spent together.
+            Self::VoiceCoPresence => 0.25,
+            Self::GroupMention => 0.1,
         }
     }
 }
 
-#[derive(Debug)]
+impl TryFrom<u8> for RelationshipChangeReason {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => Self::Reaction,
+            2 => Self::MessageDirectMention,
+            3 => Self::MessageIndirectMention,
+            4 => Self::MessageAdjacency,
+            5 => Self::MessageBinarySequence,
+            6 => Self::ThreadParticipation,
+            7 => Self::VoiceCoPresence,
+            8 => Self::GroupMention,
+            _ => anyhow::bail!("unknown relationship change reason {}", value),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct RelationshipChange {
     pub source: Id<UserMarker>,
     pub target: Id<UserMarker>,
     pub reason: RelationshipChangeReason,
+    /// How many times over the change is made, such as for each interval spent in voice together.
+    /// It's stored as that many rows of the `events` table.
+    pub count: u32,
+}
+
+impl RelationshipChange {
+    /// How much the change adds to the relationship, before it's scaled for the guild.
+    pub fn strength(&self) -> RelationshipStrength {
+        self.reason.get_change_strength() * self.count as RelationshipStrength
+    }
 }
 
 impl Display for RelationshipChange {
@@ -196,52 +444,305 @@ impl Display for RelationshipChange {
         f.write_fmt(format_args!(
             "{} --> {} [{:?}]",
             self.source, self.target, self.reason
-        ))
+        ))?;
+
+        if self.count != 1 {
+            f.write_fmt(format_args!(" x{}", self.count))?;
+        }
+
+        Ok(())
     }
 }
 
 const MESSAGE_HISTORY_COUNT: usize = 5;
 
+/// Users in a voice channel together get a `VoiceCoPresence` change counted once for each full
+/// interval they overlapped for.
+const VOICE_PRESENCE_INTERVAL: u64 = 10 * 60 * 1000;
+
+/// Limit on the intervals counted for a single overlap, so that people idling in a channel
+/// together all day don't drown out everything else.
+const VOICE_PRESENCE_MAX_INTERVALS: u64 = 12;
+
+/// Role and @everyone mentions only reach users that have posted in the channel this recently.
+const GROUP_MENTION_ACTIVE_WINDOW: u64 = 60 * 60 * 1000;
+
+/// How many recent authors in each channel are remembered for role and @everyone mentions.
+const GROUP_MENTION_ACTIVE_USERS: usize = 50;
+
+/// Limit on the users a single role or @everyone mention links to, the most recently active first.
+const GROUP_MENTION_MAX_TARGETS: usize = 10;
+
+/// A single heuristic used to infer relationships from interactions.
+///
+/// A fresh instance of each enabled engine is created for every guild channel, so any state the
+/// engine needs (such as recent message history) can simply live on the engine itself.
+pub trait InferenceEngine: Debug + Send {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction);
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct InferenceEngineDescriptor {
+    /// Stable identifier used to enable or disable the engine for a guild.
+    pub name: &'static str,
+    /// Engines that are disabled by default can be enabled for specific guilds to experiment.
+    pub enabled_by_default: bool,
+    /// Stateless engines only look at the interaction itself, so can be run again when a message
+    /// is edited.
+    pub stateless: bool,
+    pub create: fn() -> Box<dyn InferenceEngine>,
+}
+
+/// The set of known inference engines, and which of them are enabled for each guild.
 #[derive(Debug)]
-pub struct InferenceState {
-    /// Recent messages to channel, used to infer temporal proximity.
-    /// Limited to `MESSAGE_HISTORY_COUNT`, latest entries at the front.
-    history: VecDeque<Interaction>,
+pub struct InferenceEngineRegistry {
+    engines: Vec<InferenceEngineDescriptor>,
+    guild_overrides: HashMap<Id<GuildMarker>, HashMap<&'static str, bool>>,
 }
 
-impl InferenceState {
+impl InferenceEngineRegistry {
+    /// Create a registry containing all the built-in engines.
     pub fn new() -> Self {
+        let mut registry = InferenceEngineRegistry {
+            engines: Vec::new(),
+            guild_overrides: HashMap::new(),
+        };
+
+        // Order matters here, engines are run in registration order.
+        registry.register(InferenceEngineDescriptor {
+            name: "reaction",
+            enabled_by_default: true,
+            stateless: true,
+            create: || Box::<ReactionEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "direct_mention",
+            enabled_by_default: true,
+            stateless: true,
+            create: || Box::<DirectMentionEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "indirect_mention",
+            enabled_by_default: true,
+            stateless: true,
+            create: || Box::<IndirectMentionEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "message_adjacency",
+            enabled_by_default: true,
+            stateless: false,
+            create: || Box::<MessageAdjacencyEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "message_binary_sequence",
+            enabled_by_default: true,
+            stateless: false,
+            create: || Box::<MessageBinarySequenceEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "thread_starter",
+            enabled_by_default: true,
+            stateless: false,
+            create: || Box::<ThreadStarterEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "group_mention",
+            enabled_by_default: true,
+            stateless: false,
+            create: || Box::<GroupMentionEngine>::default(),
+        });
+
+        registry.register(InferenceEngineDescriptor {
+            name: "voice_presence",
+            enabled_by_default: true,
+            stateless: false,
+            create: || Box::<VoicePresenceEngine>::default(),
+        });
+
+        registry
+    }
+
+    pub fn register(&mut self, descriptor: InferenceEngineDescriptor) {
+        if self.engines.iter().any(|e| e.name == descriptor.name) {
+            panic!("inference engine {} registered twice", descriptor.name);
+        }
+
+        self.engines.push(descriptor);
+    }
+
+    pub fn engines(&self) -> &[InferenceEngineDescriptor] {
+        &self.engines
+    }
+
+    /// Returns false if there is no engine registered with that name.
+    pub fn set_enabled(&mut self, guild_id: Id<GuildMarker>, name: &str, enabled: bool) -> bool {
+        let descriptor = match self.engines.iter().find(|e| e.name == name) {
+            Some(descriptor) => descriptor,
+            None => return false,
+        };
+
+        let overrides = self.guild_overrides.entry(guild_id).or_default();
+
+        if enabled == descriptor.enabled_by_default {
+            overrides.remove(descriptor.name);
+        } else {
+            overrides.insert(descriptor.name, enabled);
+        }
+
+        if overrides.is_empty() {
+            self.guild_overrides.remove(&guild_id);
+        }
+
+        true
+    }
+
+    /// The guilds that have engines enabled or disabled differently to the defaults, and which.
+    pub fn guild_overrides(&self) -> &HashMap<Id<GuildMarker>, HashMap<&'static str, bool>> {
+        &self.guild_overrides
+    }
+
+    pub fn is_enabled(
+        &self,
+        guild_id: Id<GuildMarker>,
+        descriptor: &InferenceEngineDescriptor,
+    ) -> bool {
+        self.guild_overrides
+            .get(&guild_id)
+            .and_then(|overrides| overrides.get(descriptor.name))
+            .cloned()
+            .unwrap_or(descriptor.enabled_by_default)
+    }
+
+    /// Run just the guild's enabled stateless engines against an interaction, without touching
+    /// any channel's inference state.
+    pub fn infer_stateless(&self, interaction: &Interaction) -> Vec<RelationshipChange> {
+        let mut changes = Vec::new();
+
+        for descriptor in &self.engines {
+            if descriptor.stateless && self.is_enabled(interaction.guild, descriptor) {
+                (descriptor.create)().infer(&mut changes, interaction);
+            }
+        }
+
+        changes
+    }
+
+    pub fn create_state(&self, guild_id: Id<GuildMarker>) -> InferenceState {
         InferenceState {
-            history: VecDeque::new(),
+            engines: self
+                .engines
+                .iter()
+                .filter(|descriptor| self.is_enabled(guild_id, descriptor))
+                .map(|descriptor| (descriptor.create)())
+                .collect(),
         }
     }
+}
 
-    // TODO: Re-write this as a set of inference engines.
+impl Default for InferenceEngineRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-channel inference state, one instance of every engine enabled for the guild.
+#[derive(Debug)]
+pub struct InferenceState {
+    engines: Vec<Box<dyn InferenceEngine>>,
+}
+
+impl InferenceState {
     pub fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
-        let source = interaction.source;
+        for engine in &mut self.engines {
+            engine.infer(changes, interaction);
+        }
+    }
+}
+
+/// Someone reacting to a message is interacting with the author.
+#[derive(Debug, Default)]
+struct ReactionEngine;
+
+impl InferenceEngine for ReactionEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        if interaction.what != InteractionType::Reaction {
+            return;
+        }
 
         if let Some(target) = interaction.target {
             changes.push(RelationshipChange {
-                source,
+                source: interaction.source,
                 target,
-                reason: match interaction.what {
-                    InteractionType::Reaction => RelationshipChangeReason::Reaction,
-                    InteractionType::Message => RelationshipChangeReason::MessageDirectMention,
-                },
+                reason: RelationshipChangeReason::Reaction,
+                count: 1,
             });
         }
+    }
+}
+
+/// A reply, or a message starting with a mention, is directed at that user.
+#[derive(Debug, Default)]
+struct DirectMentionEngine;
 
+impl InferenceEngine for DirectMentionEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        if interaction.what != InteractionType::Message {
+            return;
+        }
+
+        if let Some(target) = interaction.target {
+            changes.push(RelationshipChange {
+                source: interaction.source,
+                target,
+                reason: RelationshipChangeReason::MessageDirectMention,
+                count: 1,
+            });
+        }
+    }
+}
+
+/// Any other users mentioned in the message.
+#[derive(Debug, Default)]
+struct IndirectMentionEngine;
+
+impl InferenceEngine for IndirectMentionEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
         if interaction.what != InteractionType::Message {
             return;
         }
 
         for target in &interaction.other_targets {
             changes.push(RelationshipChange {
-                source,
+                source: interaction.source,
                 target: *target,
                 reason: RelationshipChangeReason::MessageIndirectMention,
+                count: 1,
             });
         }
+    }
+}
+
+/// A quick response to a message that broke a long silence is probably a reply to it.
+#[derive(Debug, Default)]
+struct MessageAdjacencyEngine {
+    /// Recent messages to channel, used to infer temporal proximity.
+    /// Limited to `MESSAGE_HISTORY_COUNT`, latest entries at the front.
+    history: VecDeque<Interaction>,
+}
+
+impl InferenceEngine for MessageAdjacencyEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        if interaction.what != InteractionType::Message {
+            return;
+        }
+
+        let source = interaction.source;
 
         if let Some(last) = self.history.front() {
             // If the last message isn't from the same author, and was less than 2 minutes ago.
@@ -250,8 +751,7 @@ impl InferenceState {
             //       to move a bit quick for these limits. Often in #sourcemod there will be
             //       a reply within 30 seconds or so to a question answered only a couple of
             //       minutes after the previous message.
-            if last.source != source
-                && interaction.when.duration_since(last.when).as_secs() < (60 * 2)
+            if last.source != source && interaction.when.saturating_sub(last.when) < (60 * 2 * 1000)
             {
                 // Find the message before that from a different author.
                 let previous = self
@@ -263,11 +763,12 @@ impl InferenceState {
                 if let Some(previous) = previous {
                     // If there was at least 10 minutes between the last message and the message
                     // before that, and we're messaging within 2 minutes, we're probably replying.
-                    if last.when.duration_since(previous.when).as_secs() > (60 * 10) {
+                    if last.when.saturating_sub(previous.when) > (60 * 10 * 1000) {
                         changes.push(RelationshipChange {
                             source,
                             target: last.source,
                             reason: RelationshipChangeReason::MessageAdjacency,
+                            count: 1,
                         });
                     }
                 }
@@ -275,36 +776,193 @@ impl InferenceState {
         }
 
         self.history.push_front(interaction.clone());
+        self.history.truncate(MESSAGE_HISTORY_COUNT);
+    }
+}
+
+/// Two users talking back and forth with nobody else interjecting are talking to each other.
+#[derive(Debug, Default)]
+struct MessageBinarySequenceEngine {
+    /// Authors of recent messages to channel, latest entries at the front.
+    /// Limited to `MESSAGE_HISTORY_COUNT`.
+    history: VecDeque<Id<UserMarker>>,
+}
 
-        if self.history.len() >= MESSAGE_HISTORY_COUNT {
-            self.history.truncate(MESSAGE_HISTORY_COUNT);
+impl InferenceEngine for MessageBinarySequenceEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        if interaction.what != InteractionType::Message {
+            return;
+        }
+
+        let source = interaction.source;
+
+        self.history.push_front(source);
+
+        if self.history.len() < MESSAGE_HISTORY_COUNT {
+            return;
+        }
 
-            // This is... gross.
-            let unique_sources = self
-                .history
+        self.history.truncate(MESSAGE_HISTORY_COUNT);
+
+        let unique_sources = self.history.iter().cloned().collect::<HashSet<_>>();
+
+        // TODO: The original waits for the history list to be full *and* clears it each
+        //       time it triggers. Now that this has its own history we could do the same,
+        //       but that changes the weighting so needs re-tuning first.
+        if unique_sources.len() == 2 {
+            let target = unique_sources.into_iter().find(|&u| u != source).unwrap();
+
+            changes.push(RelationshipChange {
+                source,
+                target,
+                reason: RelationshipChangeReason::MessageBinarySequence,
+                count: 1,
+            });
+        }
+    }
+}
+
+/// Work out who a message is directed at, and who else it mentions.
+fn message_targets(
+    content: &str,
+    mentions: impl Iterator<Item = Id<UserMarker>>,
+    reply_to: Option<Id<UserMarker>>,
+) -> (Option<Id<UserMarker>>, Vec<Id<UserMarker>>) {
+    // We prefer a parsed mention over a reply, as a parsed mention with reply is usually
+    // someone directing a message to someone else.
+    let target = parse_direct_mention(content).or(reply_to);
+
+    let other_targets = mentions.filter(|&u| Some(u) != target).collect();
+
+    (target, other_targets)
+}
+
+/// Joining or taking part in a thread is interacting with whoever started it.
+#[derive(Debug, Default)]
+struct ThreadStarterEngine {
+    /// Users that have already been linked to the thread's starter, as this engine's state is per
+    /// thread. Only the first time each user joins or posts counts.
+    participants: HashSet<Id<UserMarker>>,
+}
+
+impl InferenceEngine for ThreadStarterEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        if interaction.what == InteractionType::Reaction {
+            return;
+        }
+
+        let owner = match interaction.thread.and_then(|thread| thread.owner) {
+            Some(owner) => owner,
+            None => return,
+        };
+
+        if interaction.source == owner || !self.participants.insert(interaction.source) {
+            return;
+        }
+
+        changes.push(RelationshipChange {
+            source: interaction.source,
+            target: owner,
+            reason: RelationshipChangeReason::ThreadParticipation,
+            count: 1,
+        });
+    }
+}
+
+/// Mentioning a role or @everyone is a weak interaction with whoever has been active in the
+/// channel recently and is part of that group.
+#[derive(Debug, Default)]
+struct GroupMentionEngine {
+    /// Recent authors in the channel with their roles as of their last message, latest first.
+    /// Limited to `GROUP_MENTION_ACTIVE_USERS`.
+    active: VecDeque<(Id<UserMarker>, u64, Vec<Id<RoleMarker>>)>,
+}
+
+impl InferenceEngine for GroupMentionEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        if interaction.what != InteractionType::Message {
+            return;
+        }
+
+        let source = interaction.source;
+
+        if interaction.mention_everyone || !interaction.mention_roles.is_empty() {
+            let targets = self
+                .active
                 .iter()
-                .map(|i| i.source)
-                .collect::<HashSet<Id<UserMarker>>>();
-
-            // TODO: The original waits for the history list to be full *and* clears it each
-            //       time it triggers. It'd be good to do the same here, but I'm not sure if
-            //       we can without losing the single state storage. That said, if we split
-            //       these up like the original, MessageAdjacency will be a lot simpler.
-            if unique_sources.len() == 2 {
-                let mut unique_sources = unique_sources.iter();
-                let first = unique_sources.next().unwrap();
-                let second = unique_sources.next().unwrap();
-                let target = if source == *first { second } else { first };
+                .take_while(|(_, when, _)| {
+                    interaction.when.saturating_sub(*when) < GROUP_MENTION_ACTIVE_WINDOW
+                })
+                .filter(|(user, _, roles)| {
+                    *user != source
+                        && (interaction.mention_everyone
+                            || roles.iter().any(|r| interaction.mention_roles.contains(r)))
+                })
+                // Anyone mentioned directly is already covered by the stronger reasons.
+                .filter(|(user, _, _)| {
+                    interaction.target != Some(*user) && !interaction.other_targets.contains(user)
+                })
+                .take(GROUP_MENTION_MAX_TARGETS);
 
+            for (target, _, _) in targets {
                 changes.push(RelationshipChange {
                     source,
                     target: *target,
-                    reason: RelationshipChangeReason::MessageBinarySequence,
+                    reason: RelationshipChangeReason::GroupMention,
+                    count: 1,
                 });
+            }
+        }
+
+        self.active.retain(|(user, _, _)| *user != source);
+        self.active
+            .push_front((source, interaction.when, interaction.source_roles.clone()));
+        self.active.truncate(GROUP_MENTION_ACTIVE_USERS);
+    }
+}
+
+/// Spending time in a voice channel together, the longer the overlap the stronger the link.
+#[derive(Debug, Default)]
+struct VoicePresenceEngine {
+    /// When each user currently in the voice channel joined it.
+    joined: HashMap<Id<UserMarker>, u64>,
+}
 
-                // We can't do this while MessageAdjacency is sharing the same list.
-                // self.history.clear();
+impl InferenceEngine for VoicePresenceEngine {
+    fn infer(&mut self, changes: &mut Vec<RelationshipChange>, interaction: &Interaction) {
+        match interaction.what {
+            InteractionType::VoiceJoin => {
+                self.joined
+                    .entry(interaction.source)
+                    .or_insert(interaction.when);
             }
+            InteractionType::VoiceLeave => {
+                let joined_at = match self.joined.remove(&interaction.source) {
+                    Some(joined_at) => joined_at,
+                    None => return,
+                };
+
+                let mut others = self.joined.iter().collect::<Vec<_>>();
+                others.sort_unstable();
+
+                for (&target, &other_joined_at) in others {
+                    let overlap = interaction
+                        .when
+                        .saturating_sub(joined_at.max(other_joined_at));
+                    let intervals =
+                        (overlap / VOICE_PRESENCE_INTERVAL).min(VOICE_PRESENCE_MAX_INTERVALS);
+
+                    if intervals > 0 {
+                        changes.push(RelationshipChange {
+                            source: interaction.source,
+                            target,
+                            reason: RelationshipChangeReason::VoiceCoPresence,
+                            count: intervals as u32,
+                        });
+                    }
+                }
+            }
+            _ => (),
         }
     }
 }
@@ -362,3 +1020,245 @@ mod parse_direct_mention_tests {
         assert_eq!(mention, Some(Id::new(766407857851072512)));
     }
 }
+
+#[cfg(test)]
+mod inference_engine_tests {
+    use super::{
+        FixedClock, InferenceEngineRegistry, Interaction, InteractionType, RelationshipChange,
+        RelationshipChangeReason, Thread,
+    };
+    use twilight_model::id::marker::UserMarker;
+    use twilight_model::id::Id;
+
+    fn reasons(changes: &[RelationshipChange]) -> Vec<(Id<UserMarker>, RelationshipChangeReason)> {
+        changes.iter().map(|c| (c.target, c.reason)).collect()
+    }
+
+    #[test]
+    fn test_direct_mention() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let mut changes = Vec::new();
+        state.infer(&mut changes, &Interaction::test_message(0, 10, Some(20)));
+
+        assert!(matches!(
+            reasons(&changes)[..],
+            [(target, RelationshipChangeReason::MessageDirectMention)] if target == Id::new(20)
+        ));
+    }
+
+    #[test]
+    fn test_reaction() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let mut interaction = Interaction::test_message(0, 10, Some(20));
+        interaction.what = InteractionType::Reaction;
+
+        let mut changes = Vec::new();
+        state.infer(&mut changes, &interaction);
+
+        assert!(matches!(
+            reasons(&changes)[..],
+            [(_, RelationshipChangeReason::Reaction)]
+        ));
+    }
+
+    #[test]
+    fn test_binary_sequence() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let mut changes = Vec::new();
+        for (i, source) in [10, 20, 10, 20, 10].into_iter().enumerate() {
+            changes.clear();
+            let when = i as u64 * 1000;
+            state.infer(&mut changes, &Interaction::test_message(when, source, None));
+        }
+
+        assert!(matches!(
+            reasons(&changes)[..],
+            [(target, RelationshipChangeReason::MessageBinarySequence)] if target == Id::new(20)
+        ));
+    }
+
+    #[test]
+    fn test_message_adjacency() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let minute = 60 * 1000;
+        let mut changes = Vec::new();
+        state.infer(&mut changes, &Interaction::test_message(0, 10, None));
+        state.infer(
+            &mut changes,
+            &Interaction::test_message(30 * minute, 20, None),
+        );
+        state.infer(
+            &mut changes,
+            &Interaction::test_message(31 * minute, 30, None),
+        );
+
+        assert!(matches!(
+            reasons(&changes)[..],
+            [(target, RelationshipChangeReason::MessageAdjacency)] if target == Id::new(20)
+        ));
+    }
+
+    #[test]
+    fn test_thread_starter() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let in_thread = |when, source| Interaction {
+            thread: Some(Thread {
+                id: Id::new(3),
+                owner: Some(Id::new(10)),
+            }),
+            ..Interaction::test_message(when, source, None)
+        };
+
+        let mut changes = Vec::new();
+        state.infer(&mut changes, &in_thread(0, 10));
+        state.infer(&mut changes, &in_thread(1, 20));
+        assert!(matches!(
+            reasons(&changes)[..],
+            [(target, RelationshipChangeReason::ThreadParticipation)] if target == Id::new(10)
+        ));
+
+        // Only the first post by each user counts.
+        changes.clear();
+        state.infer(&mut changes, &in_thread(2, 20));
+        assert!(!reasons(&changes)
+            .iter()
+            .any(|(_, reason)| *reason == RelationshipChangeReason::ThreadParticipation));
+    }
+
+    #[test]
+    fn test_group_mention() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let with_role = |when, source, role| Interaction {
+            source_roles: vec![Id::new(role)],
+            ..Interaction::test_message(when, source, None)
+        };
+
+        let minute = 60 * 1000;
+        let mut changes = Vec::new();
+        state.infer(&mut changes, &with_role(0, 10, 100));
+        state.infer(&mut changes, &with_role(90 * minute, 20, 100));
+        state.infer(&mut changes, &with_role(91 * minute, 30, 200));
+        state.infer(&mut changes, &with_role(92 * minute, 40, 100));
+
+        // Only recently active users with the role, and not anyone mentioned directly.
+        changes.clear();
+        state.infer(
+            &mut changes,
+            &Interaction {
+                mention_roles: vec![Id::new(100)],
+                ..Interaction::test_message(93 * minute, 30, Some(40))
+            },
+        );
+        assert_eq!(
+            reasons(&changes),
+            [
+                (Id::new(40), RelationshipChangeReason::MessageDirectMention),
+                (Id::new(20), RelationshipChangeReason::GroupMention),
+            ]
+        );
+
+        // @everyone reaches every recent author, up to a limit.
+        for source in 100..120 {
+            state.infer(
+                &mut changes,
+                &Interaction::test_message(94 * minute, source, None),
+            );
+        }
+
+        changes.clear();
+        state.infer(
+            &mut changes,
+            &Interaction {
+                mention_everyone: true,
+                ..Interaction::test_message(95 * minute, 10, None)
+            },
+        );
+        assert_eq!(changes.len(), 10);
+        assert!(changes.iter().all(
+            |c| c.reason == RelationshipChangeReason::GroupMention && c.target >= Id::new(110)
+        ));
+    }
+
+    #[test]
+    fn test_voice_presence() {
+        let registry = InferenceEngineRegistry::new();
+        let mut state = registry.create_state(Id::new(1));
+
+        let clock = FixedClock::default();
+        let voice = |source, joined| {
+            Interaction::new_from_voice_state(
+                Id::new(1),
+                Id::new(2),
+                Id::new(source),
+                false,
+                joined,
+                &clock,
+            )
+        };
+
+        let minute = 60 * 1000;
+        let mut changes = Vec::new();
+        state.infer(&mut changes, &voice(10, true));
+        clock.set(5 * minute);
+        state.infer(&mut changes, &voice(20, true));
+        clock.set(10 * minute);
+        state.infer(&mut changes, &voice(30, true));
+        assert!(changes.is_empty());
+
+        // Only the time spent together counts, and partial intervals are dropped.
+        clock.set(28 * minute);
+        state.infer(&mut changes, &voice(10, false));
+        assert_eq!(
+            reasons(&changes),
+            [
+                (Id::new(20), RelationshipChangeReason::VoiceCoPresence),
+                (Id::new(30), RelationshipChangeReason::VoiceCoPresence),
+            ]
+        );
+        assert_eq!(changes.iter().map(|c| c.count).collect::<Vec<_>>(), [2, 1]);
+
+        // Long overlaps are capped.
+        changes.clear();
+        clock.set(24 * 60 * minute);
+        state.infer(&mut changes, &voice(20, false));
+        assert_eq!(
+            reasons(&changes),
+            [(Id::new(30), RelationshipChangeReason::VoiceCoPresence)]
+        );
+        assert_eq!(changes[0].count, 12);
+
+        // Leaving again without having joined does nothing.
+        changes.clear();
+        state.infer(&mut changes, &voice(20, false));
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_disabled_engine() {
+        let mut registry = InferenceEngineRegistry::new();
+        assert!(registry.set_enabled(Id::new(1), "direct_mention", false));
+        assert!(!registry.set_enabled(Id::new(1), "not_an_engine", false));
+
+        let mut changes = Vec::new();
+        let mut state = registry.create_state(Id::new(1));
+        state.infer(&mut changes, &Interaction::test_message(0, 10, Some(20)));
+        assert!(changes.is_empty());
+
+        // Other guilds are unaffected.
+        let mut state = registry.create_state(Id::new(3));
+        state.infer(&mut changes, &Interaction::test_message(0, 10, Some(20)));
+        assert_eq!(changes.len(), 1);
+    }
+}
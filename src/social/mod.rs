@@ -1,41 +1,98 @@
+pub mod analysis;
+pub mod export;
 pub mod graph;
+pub mod history;
 pub mod inference;
+pub mod replay;
+pub mod snapshot;
+pub mod store;
+pub mod weighting;
 
-use anyhow::Result;
-use std::collections::HashSet;
-use tracing::{error, info};
-use twilight_model::channel::message::{MessageReference, MessageType};
+use anyhow::{Context as AnyhowContext, Result};
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use tracing::{error, info, warn};
+use twilight_model::channel::message::{MessageReference, MessageType, ReactionType};
 use twilight_model::channel::ChannelType;
 use twilight_model::gateway::event::Event;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
+use twilight_model::id::Id;
+use twilight_model::voice::VoiceState;
 
+use crate::cache::CachedChannel;
 use crate::context::Context;
+use crate::social::graph::reaction_emoji_key;
+use crate::social::history::ActivityCounter;
 use crate::social::inference::{Interaction, RelationshipChange};
+use crate::social::replay::RecordedEvent;
+use crate::social::weighting::MESSAGE_HISTORY;
 use crate::stats;
 
 pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
     match event {
         Event::GuildCreate(guild) => {
-            // Load any existing graphs into memory for the guild's channels.
-            let mut social = context.social.lock();
-            for channel in &guild.channels {
-                social.get_graph(guild.id, channel.id);
+            {
+                // Load any existing graphs into memory for the guild's channels.
+                for channel in &guild.channels {
+                    context.social.load_graph(guild.id, channel.id);
+                }
+
+                context.social.load_recent_interactions(guild.id);
+            }
+
+            // Only sent if voice states are enabled, anyone already in voice has just joined
+            // as far as we're concerned.
+            for voice_state in &guild.voice_states {
+                update_voice_state(context, guild.id, voice_state).await;
             }
+
+            count_activity(context, guild.id).await?;
         }
         Event::GuildDelete(guild) => {
-            let mut social = context.social.lock();
-            social.remove_guild(guild.id);
+            context.social.remove_guild(guild.id);
         }
+        // Threads are part of their parent channel's graph, and anything else is loaded on demand.
         Event::ChannelCreate(channel) if channel.kind == ChannelType::GuildText => {
             if let Some(guild_id) = channel.guild_id {
                 // Load any existing graph into memory for the channel.
-                let mut social = context.social.lock();
-                social.get_graph(guild_id, channel.id);
+                context.social.load_graph(guild_id, channel.id);
             }
         }
         Event::ChannelDelete(channel) => {
             if let Some(guild_id) = channel.guild_id {
-                let mut social = context.social.lock();
-                social.remove_channel(guild_id, channel.id);
+                context.social.remove_channel(guild_id, channel.id);
+            }
+        }
+        Event::ThreadDelete(thread) => {
+            context.social.remove_thread(thread.guild_id, thread.id);
+        }
+        // Only sent for other users with the privileged GUILD_MEMBERS intent, which is enabled by
+        // DISCOGRAPH_SERVER_MEMBERS.
+        Event::ThreadMembersUpdate(update) => {
+            let thread = context
+                .cache
+                .get_channel(update.guild_id, update.id)
+                .await?;
+
+            for member in &update.added_members {
+                let (user_id, user_is_bot) = match (&member.member, member.user_id) {
+                    (Some(member), _) => (member.user.id, member.user.bot),
+                    (None, Some(user_id)) => (user_id, false),
+                    (None, None) => continue,
+                };
+
+                if user_id == context.user.id {
+                    continue;
+                }
+
+                let interaction = Interaction::new_from_thread_join(
+                    update.guild_id,
+                    &thread,
+                    user_id,
+                    user_is_bot,
+                    context.clock.as_ref(),
+                )?;
+                process_interaction(context, interaction).await;
             }
         }
         Event::MessageCreate(message)
@@ -57,7 +114,18 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 _ => None,
             };
 
-            let interaction = Interaction::new_from_message(message, referenced_message.as_ref())?;
+            let guild_id = message
+                .guild_id
+                .context("tried to create an interaction from a message not sent to a guild")?;
+
+            let channel = event_channel(context, guild_id, message.channel_id).await?;
+
+            let interaction = Interaction::new_from_message(
+                message,
+                &channel,
+                referenced_message.as_ref(),
+                context.clock.as_ref(),
+            )?;
             process_interaction(context, interaction).await;
         }
         Event::ReactionAdd(reaction) if reaction.user_id != context.user.id => {
@@ -66,8 +134,117 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 .get_message(reaction.guild_id, reaction.channel_id, reaction.message_id)
                 .await?;
 
-            let interaction = Interaction::new_from_reaction(reaction, &message)?;
-            process_interaction(context, interaction).await;
+            let guild_id = reaction
+                .guild_id
+                .context("tried to create an interaction from a reaction not sent to a guild")?;
+
+            let channel = event_channel(context, guild_id, reaction.channel_id).await?;
+
+            let interaction = Interaction::new_from_reaction(
+                reaction,
+                &channel,
+                &message,
+                context.clock.as_ref(),
+            )?;
+
+            // Every reaction is recorded, whether it counts or not, so a replay can tell.
+            let emoji = reaction_emoji_key(&reaction.emoji);
+            record_event(context, || RecordedEvent::Reaction {
+                interaction: interaction.clone(),
+                emoji: emoji.clone(),
+            })
+            .await;
+
+            let timestamp = interaction.when;
+            if let Some(changes) = context.social.add_reaction(&interaction, &emoji, timestamp) {
+                report_and_store(context, interaction, changes).await;
+            }
+        }
+        Event::ReactionRemove(reaction) => {
+            if let Some(guild_id) = reaction.guild_id {
+                retract_reactions(
+                    context,
+                    guild_id,
+                    reaction.message_id,
+                    Some(reaction.user_id),
+                    Some(&reaction.emoji),
+                )
+                .await?
+            }
+        }
+        Event::ReactionRemoveAll(reactions) => {
+            if let Some(guild_id) = reactions.guild_id {
+                retract_reactions(context, guild_id, reactions.message_id, None, None).await?
+            }
+        }
+        Event::ReactionRemoveEmoji(reactions) => {
+            retract_reactions(
+                context,
+                reactions.guild_id,
+                reactions.message_id,
+                None,
+                Some(&reactions.emoji),
+            )
+            .await?
+        }
+        Event::MessageUpdate(message) => {
+            // Edits that only change embeds don't include the content.
+            if let (Some(guild_id), Some(content), Some(mentions)) =
+                (message.guild_id, &message.content, &message.mentions)
+            {
+                let now = context.clock.now();
+                let edit = context.social.edit_message(
+                    guild_id,
+                    message.id,
+                    content,
+                    mentions.iter().map(|mention| mention.id),
+                    now,
+                );
+
+                // Every edit of a message we're tracking is recorded, as replaying with other
+                // engines enabled might see changes where we didn't.
+                if let Some(edit) = &edit {
+                    record_event(context, || RecordedEvent::MessageEdit {
+                        when: now,
+                        interaction: edit.interaction.clone(),
+                    })
+                    .await;
+                }
+
+                let edit = edit.filter(|edit| !edit.retracted.is_empty() || !edit.added.is_empty());
+
+                if let Some(edit) = edit {
+                    info!("message {} edited", message.id);
+                    for change in &edit.retracted {
+                        info!("| - {}", change);
+                    }
+                    for change in &edit.added {
+                        info!("| + {}", change);
+                    }
+
+                    let timestamp = edit.interaction.when;
+                    context.event_writes.begin(&edit.interaction, timestamp);
+                    remove_stored_changes(context, &edit.interaction, &edit.retracted).await?;
+                    store_interaction(context, edit.interaction, timestamp, edit.added).await?;
+                }
+            }
+        }
+        Event::VoiceStateUpdate(voice_state) if voice_state.user_id != context.user.id => {
+            if let Some(guild_id) = voice_state.guild_id {
+                update_voice_state(context, guild_id, voice_state).await;
+            }
+        }
+        Event::MessageDelete(message) => {
+            if let Some(guild_id) = message.guild_id {
+                retract_message(context, guild_id, message.id).await?
+            }
+        }
+        Event::MessageDeleteBulk(messages) => {
+            if let Some(guild_id) = messages.guild_id {
+                for &message_id in &messages.ids {
+                    retract_message(context, guild_id, message_id).await?;
+                }
+            }
         }
         _ => (),
     }
@@ -75,28 +252,91 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
     Ok(())
 }
 
-async fn process_interaction(context: &Context, interaction: Interaction) {
-    // Calculate this first, before we do anything async.
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
+/// The channel a message or reaction is in. Every one of them goes through here, so the cache should
+/// already have it, but anything missing is fetched rather than the event being dropped.
+async fn event_channel(
+    context: &Context,
+    guild_id: Id<GuildMarker>,
+    channel_id: Id<ChannelMarker>,
+) -> Result<CachedChannel> {
+    if let Some(channel) = context.cache.get_cached_channel(guild_id, channel_id) {
+        return Ok(channel);
+    }
 
-    let interaction_string = interaction.to_string(&context.cache).await;
-    let mut debug_lines = vec![interaction_string];
+    warn!("channel {} is missing from the cache", channel_id);
 
-    let changes = {
-        let mut social = context.social.lock();
+    context.cache.get_channel(guild_id, channel_id).await
+}
 
-        let changes = social.infer(&interaction);
-        for change in &changes {
-            debug_lines.push(format!("| {}", change));
+/// Move a user between voice channels, leaving the one they were in and joining the new one.
+async fn update_voice_state(
+    context: &Context,
+    guild_id: Id<GuildMarker>,
+    voice_state: &VoiceState,
+) {
+    // Music bots and the like sit in voice with everyone, they'd link users to the bot at best.
+    if matches!(&voice_state.member, Some(member) if member.user.bot) {
+        return;
+    }
+
+    // Deafened users can't hear anyone, so count as having left.
+    let channel_id = voice_state
+        .channel_id
+        .filter(|_| !voice_state.deaf && !voice_state.self_deaf);
+
+    let previous_channel_id =
+        context
+            .social
+            .set_voice_channel(guild_id, voice_state.user_id, channel_id);
+
+    if previous_channel_id == channel_id {
+        return;
+    }
+
+    let moves = [(previous_channel_id, false), (channel_id, true)];
+    for (channel_id, joined) in moves {
+        if let Some(channel_id) = channel_id {
+            let interaction = Interaction::new_from_voice_state(
+                guild_id,
+                channel_id,
+                voice_state.user_id,
+                false,
+                joined,
+                context.clock.as_ref(),
+            );
+            process_interaction(context, interaction).await;
         }
+    }
+}
+
+async fn process_interaction(context: &Context, interaction: Interaction) {
+    record_event(context, || RecordedEvent::Interaction(interaction.clone())).await;
 
-        social.apply(&interaction, &changes);
+    let changes = context
+        .social
+        .infer_and_apply(&interaction, interaction.when);
 
-        changes
-    };
+    report_and_store(context, interaction, changes).await;
+}
+
+/// Log the changes that were just applied for an interaction, and store them.
+async fn report_and_store(
+    context: &Context,
+    interaction: Interaction,
+    changes: Vec<RelationshipChange>,
+) {
+    let timestamp = interaction.when;
+
+    // This has to happen before anything is awaited, so that if the message is deleted before its
+    // changes are stored, removing them waits until they are.
+    context.event_writes.begin(&interaction, timestamp);
+
+    let interaction_string = interaction.to_string(&context.cache).await;
+    let mut debug_lines = vec![interaction_string];
+
+    for change in &changes {
+        debug_lines.push(format!("| {}", change));
+    }
 
     for line in &debug_lines {
         info!("{}", line);
@@ -106,13 +346,13 @@ async fn process_interaction(context: &Context, interaction: Interaction) {
         context
             .channels_with_debug_enabled
             .lock()
-            .contains(&interaction.channel)
+            .contains(&interaction.conversation())
     };
 
     if debug_enabled {
         let result = context
             .http
-            .create_message(interaction.channel)
+            .create_message(interaction.conversation())
             .content(&debug_lines.join("\n"))
             .unwrap()
             .await;
@@ -127,11 +367,204 @@ async fn process_interaction(context: &Context, interaction: Interaction) {
     }
 }
 
+/// Add an event to the recording, if we're making one.
+async fn record_event(context: &Context, event: impl FnOnce() -> RecordedEvent) {
+    if let Some(recorder) = &context.recorder {
+        if let Err(error) = recorder.record(&event()).await {
+            error!(?error, "failed to record event");
+        }
+    }
+}
+
+/// Count the guild's recent messages from before we were last restarted towards its activity, so
+/// it doesn't start out as if nobody has said anything.
+async fn count_activity(context: &Context, guild_id: Id<GuildMarker>) -> Result<()> {
+    let pool = match &context.pool {
+        Some(pool) => pool,
+        None => return Ok(()),
+    };
+
+    if !context.social.start_counting_activity(guild_id) {
+        return Ok(());
+    }
+
+    // Anything from now on is counted as it happens.
+    let now = context.clock.now();
+    let since = now.saturating_sub(MESSAGE_HISTORY.as_millis() as u64);
+
+    let (counter, _) = history::process_guild_events(
+        pool,
+        guild_id,
+        since,
+        ActivityCounter::new(now),
+        ActivityCounter::add,
+    )
+    .await
+    .context("failed to count guild activity")?;
+
+    context
+        .social
+        .add_counted_activity(guild_id, counter.finish());
+
+    Ok(())
+}
+
+async fn retract_message(
+    context: &Context,
+    guild_id: Id<GuildMarker>,
+    message_id: Id<MessageMarker>,
+) -> Result<()> {
+    let now = context.clock.now();
+
+    record_event(context, || RecordedEvent::MessageDelete {
+        when: now,
+        guild: guild_id,
+        message: message_id,
+    })
+    .await;
+
+    let retracted = context.social.retract_message(guild_id, message_id, now);
+
+    if let Some((interaction, changes)) = retracted {
+        info!("message {} deleted", message_id);
+        for change in &changes {
+            info!("| - {}", change);
+        }
+
+        remove_stored_changes(context, &interaction, &changes).await?;
+    }
+
+    Ok(())
+}
+
+async fn retract_reactions(
+    context: &Context,
+    guild_id: Id<GuildMarker>,
+    message_id: Id<MessageMarker>,
+    user_id: Option<Id<UserMarker>>,
+    emoji: Option<&ReactionType>,
+) -> Result<()> {
+    let now = context.clock.now();
+    let emoji = emoji.map(reaction_emoji_key);
+
+    record_event(context, || RecordedEvent::ReactionRemove {
+        when: now,
+        guild: guild_id,
+        message: message_id,
+        user: user_id,
+        emoji: emoji.clone(),
+    })
+    .await;
+
+    let retracted =
+        context
+            .social
+            .remove_reactions(guild_id, message_id, user_id, emoji.as_deref(), now);
+
+    for (interaction, changes) in retracted {
+        info!(
+            "reaction by {} to message {} removed",
+            interaction.source, message_id,
+        );
+        for change in &changes {
+            info!("| - {}", change);
+        }
+
+        remove_stored_changes(context, &interaction, &changes).await?;
+    }
+
+    Ok(())
+}
+
+/// An interaction's rows in the `events` table are identified by its guild, channel and timestamp.
+type EventKey = (Id<GuildMarker>, Id<ChannelMarker>, u64);
+
+/// Writes of an interaction's rows that are still in progress, and any of its changes that were
+/// retracted in the meantime.
+#[derive(Debug, Default)]
+struct InFlightEvents {
+    writers: usize,
+    retracted: Vec<RelationshipChange>,
+}
+
+/// Keeps deleting an interaction's changes from the `events` table in order with inserting them.
+///
+/// The insert can take a while, as it first makes sure everyone involved is in the database, so
+/// a message that is deleted quickly would otherwise have its changes deleted before they were
+/// there, and brought back the next time the guild is rebuilt.
+#[derive(Debug, Default)]
+pub struct EventWrites {
+    in_flight: Mutex<HashMap<EventKey, InFlightEvents>>,
+}
+
+impl EventWrites {
+    /// Note that an interaction's changes are about to be stored with `store_interaction`.
+    pub fn begin(&self, interaction: &Interaction, timestamp: u64) {
+        let key = (interaction.guild, interaction.channel, timestamp);
+
+        self.in_flight.lock().entry(key).or_default().writers += 1;
+    }
+
+    /// Returns any changes that were retracted while they were being stored, once the last of the
+    /// interaction's writes has finished.
+    fn finish(&self, interaction: &Interaction, timestamp: u64) -> Vec<RelationshipChange> {
+        let key = (interaction.guild, interaction.channel, timestamp);
+        let mut in_flight = self.in_flight.lock();
+
+        let events = match in_flight.get_mut(&key) {
+            Some(events) => events,
+            None => return Vec::new(),
+        };
+
+        events.writers -= 1;
+        if events.writers > 0 {
+            return Vec::new();
+        }
+
+        in_flight
+            .remove(&key)
+            .map_or_else(Vec::new, |events| events.retracted)
+    }
+
+    /// Hold on to changes to be removed once they've been stored, returning false if they aren't
+    /// being stored and can be removed straight away.
+    fn defer_retraction(&self, interaction: &Interaction, changes: &[RelationshipChange]) -> bool {
+        let key = (interaction.guild, interaction.channel, interaction.when);
+
+        match self.in_flight.lock().get_mut(&key) {
+            Some(events) => {
+                events.retracted.extend_from_slice(changes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Store an interaction's changes in the `events` table, after `EventWrites::begin` was called for
+/// it.
 pub async fn store_interaction(
     context: &Context,
     interaction: Interaction,
     timestamp: u64,
     changes: Vec<RelationshipChange>,
+) -> Result<()> {
+    let result = insert_events(context, &interaction, timestamp, changes).await;
+
+    // Anything retracted while the insert was happening can be removed now that it's there.
+    let retracted = context.event_writes.finish(&interaction, timestamp);
+    if !retracted.is_empty() {
+        delete_events(context, &interaction, &retracted).await?;
+    }
+
+    result
+}
+
+async fn insert_events(
+    context: &Context,
+    interaction: &Interaction,
+    timestamp: u64,
+    changes: Vec<RelationshipChange>,
 ) -> Result<()> {
     if changes.is_empty() {
         return Ok(());
@@ -142,7 +575,11 @@ pub async fn store_interaction(
         None => return Ok(()),
     };
 
-    let mut values = "(?, ?, ?, ?, ?, ?), ".repeat(changes.len());
+    // A change counted more than once is stored as that many rows, so they each add up the same
+    // way when the graphs are rebuilt.
+    let rows: usize = changes.iter().map(|change| change.count as usize).sum();
+
+    let mut values = "(?, ?, ?, ?, ?, ?), ".repeat(rows);
     values.truncate(values.len() - 2);
 
     let sql = format!(
@@ -157,13 +594,15 @@ pub async fn store_interaction(
         user_ids.insert(change.source);
         user_ids.insert(change.target);
 
-        query = query
-            .bind(timestamp)
-            .bind(interaction.guild.get())
-            .bind(interaction.channel.get())
-            .bind(change.source.get())
-            .bind(change.target.get())
-            .bind(change.reason as u8);
+        for _ in 0..change.count {
+            query = query
+                .bind(timestamp)
+                .bind(interaction.guild.get())
+                .bind(interaction.channel.get())
+                .bind(change.source.get())
+                .bind(change.target.get())
+                .bind(change.reason as u8);
+        }
     }
 
     // Ensure the DB contains the details of who was involved in this interaction.
@@ -181,3 +620,76 @@ pub async fn store_interaction(
 
     Ok(())
 }
+
+/// Remove changes previously stored by `store_interaction`, or that are still being stored.
+async fn remove_stored_changes(
+    context: &Context,
+    interaction: &Interaction,
+    changes: &[RelationshipChange],
+) -> Result<()> {
+    if context.event_writes.defer_retraction(interaction, changes) {
+        return Ok(());
+    }
+
+    delete_events(context, interaction, changes).await
+}
+
+async fn delete_events(
+    context: &Context,
+    interaction: &Interaction,
+    changes: &[RelationshipChange],
+) -> Result<()> {
+    let pool = match &context.pool {
+        Some(pool) => pool,
+        None => return Ok(()),
+    };
+
+    for change in changes {
+        sqlx::query("DELETE FROM events WHERE timestamp = ? AND guild = ? AND channel = ? AND source = ? AND target = ? AND reason = ? LIMIT ?")
+            .bind(interaction.when)
+            .bind(interaction.guild.get())
+            .bind(interaction.channel.get())
+            .bind(change.source.get())
+            .bind(change.target.get())
+            .bind(change.reason as u8)
+            .bind(change.count)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventWrites;
+    use crate::social::inference::{Interaction, RelationshipChange, RelationshipChangeReason};
+    use twilight_model::id::Id;
+
+    #[test]
+    fn test_retraction_waits_for_insert() {
+        let interaction = Interaction::test_message(1000, 10, Some(20));
+
+        let changes = [RelationshipChange {
+            source: Id::new(10),
+            target: Id::new(20),
+            reason: RelationshipChangeReason::MessageDirectMention,
+            count: 1,
+        }];
+
+        let writes = EventWrites::default();
+
+        // Nothing is being stored, so it can be removed straight away.
+        assert!(!writes.defer_retraction(&interaction, &changes));
+
+        // The message is deleted while its changes, and then those from an edit, are stored.
+        writes.begin(&interaction, interaction.when);
+        writes.begin(&interaction, interaction.when);
+        assert!(writes.defer_retraction(&interaction, &changes));
+
+        assert!(writes.finish(&interaction, interaction.when).is_empty());
+        assert_eq!(writes.finish(&interaction, interaction.when), changes);
+
+        assert!(!writes.defer_retraction(&interaction, &changes));
+    }
+}
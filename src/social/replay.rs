@@ -0,0 +1,799 @@
+use anyhow::{Context as AnyhowContext, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+use twilight_model::id::marker::{GuildMarker, MessageMarker, UserMarker};
+use twilight_model::id::Id;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::analysis::diff_graphs;
+use super::graph::{SocialGraph, UserRelationshipGraphMap};
+use super::inference::{
+    InferenceEngineRegistry, Interaction, InteractionType, RelationshipChange,
+    RelationshipChangeReason, Thread,
+};
+
+const USAGE: &str = "usage: discograph replay <recording> [--output <dir>] [<config> [<config>]]";
+
+/// Something that changed the graph, as recorded by `InteractionRecorder`.
+#[derive(Debug, Clone)]
+pub enum RecordedEvent {
+    Interaction(Interaction),
+    /// A reaction, which only counts if it is the user's first on the message.
+    Reaction {
+        interaction: Interaction,
+        emoji: String,
+    },
+    /// A message was edited, with its interaction as it was afterwards.
+    MessageEdit {
+        when: u64,
+        interaction: Interaction,
+    },
+    MessageDelete {
+        when: u64,
+        guild: Id<GuildMarker>,
+        message: Id<MessageMarker>,
+    },
+    /// Reactions to a message were removed, either from one user or everyone, and either with one
+    /// emoji or all of them.
+    ReactionRemove {
+        when: u64,
+        guild: Id<GuildMarker>,
+        message: Id<MessageMarker>,
+        user: Option<Id<UserMarker>>,
+        emoji: Option<String>,
+    },
+}
+
+impl RecordedEvent {
+    pub fn when(&self) -> u64 {
+        match self {
+            Self::Interaction(interaction) | Self::Reaction { interaction, .. } => interaction.when,
+            Self::MessageEdit { when, .. }
+            | Self::MessageDelete { when, .. }
+            | Self::ReactionRemove { when, .. } => *when,
+        }
+    }
+
+    /// Interactions are recorded as they are, anything else is tagged with an `event` type.
+    fn to_json(&self) -> Value {
+        match self {
+            Self::Interaction(interaction) => interaction_to_json(interaction),
+            Self::Reaction { interaction, emoji } => json!({
+                "event": "reaction",
+                "emoji": emoji,
+                "interaction": interaction_to_json(interaction),
+            }),
+            Self::MessageEdit { when, interaction } => json!({
+                "event": "message_edit",
+                "when": when,
+                "interaction": interaction_to_json(interaction),
+            }),
+            Self::MessageDelete {
+                when,
+                guild,
+                message,
+            } => json!({
+                "event": "message_delete",
+                "when": when,
+                "guild": guild,
+                "message": message,
+            }),
+            Self::ReactionRemove {
+                when,
+                guild,
+                message,
+                user,
+                emoji,
+            } => json!({
+                "event": "reaction_remove",
+                "when": when,
+                "guild": guild,
+                "message": message,
+                "user": user,
+                "emoji": emoji,
+            }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let when = || value["when"].as_u64().context("invalid timestamp");
+
+        Ok(match value["event"].as_str() {
+            None => Self::Interaction(interaction_from_json(value)?),
+            Some("reaction") => Self::Reaction {
+                interaction: interaction_from_json(&value["interaction"])?,
+                emoji: value["emoji"].as_str().context("invalid emoji")?.to_owned(),
+            },
+            Some("message_edit") => Self::MessageEdit {
+                when: when()?,
+                interaction: interaction_from_json(&value["interaction"])?,
+            },
+            Some("message_delete") => Self::MessageDelete {
+                when: when()?,
+                guild: Deserialize::deserialize(&value["guild"]).context("invalid guild")?,
+                message: Deserialize::deserialize(&value["message"]).context("invalid message")?,
+            },
+            Some("reaction_remove") => Self::ReactionRemove {
+                when: when()?,
+                guild: Deserialize::deserialize(&value["guild"]).context("invalid guild")?,
+                message: Deserialize::deserialize(&value["message"]).context("invalid message")?,
+                user: Deserialize::deserialize(&value["user"]).context("invalid user")?,
+                emoji: Deserialize::deserialize(&value["emoji"]).context("invalid emoji")?,
+            },
+            Some(event) => anyhow::bail!("unknown event type {}", event),
+        })
+    }
+}
+
+/// Appends every interaction, and every edit, deletion or reaction removal that might take one
+/// back, to a file as a line of JSON, so they can be replayed later.
+///
+/// Only the ids involved are recorded, never any message content.
+#[derive(Debug)]
+pub struct InteractionRecorder {
+    file: tokio::sync::Mutex<tokio::fs::File>,
+}
+
+impl InteractionRecorder {
+    pub async fn new(path: &Path) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        Ok(InteractionRecorder {
+            file: tokio::sync::Mutex::new(file),
+        })
+    }
+
+    pub async fn record(&self, event: &RecordedEvent) -> Result<()> {
+        let mut line = event.to_json().to_string();
+        line.push('\n');
+
+        // Flushing waits for the write to actually finish, rather than leaving it in the background.
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+pub(super) fn interaction_to_json(interaction: &Interaction) -> Value {
+    json!({
+        "when": interaction.when,
+        "what": match interaction.what {
+            InteractionType::Message => "message",
+            InteractionType::Reaction => "reaction",
+            InteractionType::ThreadJoin => "thread_join",
+            InteractionType::VoiceJoin => "voice_join",
+            InteractionType::VoiceLeave => "voice_leave",
+        },
+        "guild": interaction.guild,
+        "channel": interaction.channel,
+        "thread": interaction.thread.map(|thread| json!({
+            "id": thread.id,
+            "owner": thread.owner,
+        })),
+        "message": interaction.message,
+        "source": interaction.source,
+        "source_is_bot": interaction.source_is_bot,
+        "target": interaction.target,
+        "other_targets": interaction.other_targets,
+        "reply_to": interaction.reply_to,
+        "source_roles": interaction.source_roles,
+        "mention_roles": interaction.mention_roles,
+        "mention_everyone": interaction.mention_everyone,
+    })
+}
+
+pub(super) fn interaction_from_json(value: &Value) -> Result<Interaction> {
+    let what = match value["what"].as_str() {
+        Some("message") => InteractionType::Message,
+        Some("reaction") => InteractionType::Reaction,
+        Some("thread_join") => InteractionType::ThreadJoin,
+        Some("voice_join") => InteractionType::VoiceJoin,
+        Some("voice_leave") => InteractionType::VoiceLeave,
+        _ => anyhow::bail!("invalid interaction type"),
+    };
+
+    Ok(Interaction {
+        what,
+        when: value["when"].as_u64().context("invalid timestamp")?,
+        guild: Deserialize::deserialize(&value["guild"]).context("invalid guild")?,
+        channel: Deserialize::deserialize(&value["channel"]).context("invalid channel")?,
+        thread: match &value["thread"] {
+            Value::Null => None,
+            thread => Some(Thread {
+                id: Deserialize::deserialize(&thread["id"]).context("invalid thread")?,
+                owner: Deserialize::deserialize(&thread["owner"])
+                    .context("invalid thread owner")?,
+            }),
+        },
+        message: Deserialize::deserialize(&value["message"]).context("invalid message")?,
+        source: Deserialize::deserialize(&value["source"]).context("invalid source")?,
+        source_is_bot: value["source_is_bot"].as_bool().unwrap_or(false),
+        target: Deserialize::deserialize(&value["target"]).context("invalid target")?,
+        other_targets: match &value["other_targets"] {
+            Value::Null => Vec::new(),
+            other_targets => {
+                Deserialize::deserialize(other_targets).context("invalid other targets")?
+            }
+        },
+        reply_to: Deserialize::deserialize(&value["reply_to"]).context("invalid reply")?,
+        source_roles: match &value["source_roles"] {
+            Value::Null => Vec::new(),
+            roles => Deserialize::deserialize(roles).context("invalid source roles")?,
+        },
+        mention_roles: match &value["mention_roles"] {
+            Value::Null => Vec::new(),
+            roles => Deserialize::deserialize(roles).context("invalid mentioned roles")?,
+        },
+        mention_everyone: value["mention_everyone"].as_bool().unwrap_or(false),
+    })
+}
+
+/// Load a recording made by `InteractionRecorder`, oldest event first.
+pub fn load_recording(path: &Path) -> Result<Vec<RecordedEvent>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut events = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event = serde_json::from_str(&line)
+            .map_err(anyhow::Error::from)
+            .and_then(|value| RecordedEvent::from_json(&value))
+            .with_context(|| format!("line {} of {}", index + 1, path.display()))?;
+
+        events.push(event);
+    }
+
+    // Events are handled concurrently, so they can be recorded slightly out of order.
+    events.sort_by_key(RecordedEvent::when);
+
+    Ok(events)
+}
+
+/// The inference settings to replay a recording with, applied to every guild in it.
+///
+/// Written as a comma-separated list of `<engine>=on|off` and `halflife=<days>`, or `default`.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayConfig {
+    name: String,
+    engines: Vec<(&'static str, bool)>,
+    half_life: Option<Duration>,
+}
+
+impl FromStr for ReplayConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut config = ReplayConfig {
+            name: value.to_owned(),
+            ..Default::default()
+        };
+
+        if value == "default" {
+            return Ok(config);
+        }
+
+        let registry = InferenceEngineRegistry::new();
+
+        for setting in value.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .with_context(|| format!("{} is not a valid setting", setting))?;
+
+            if key == "halflife" {
+                let days: f64 = value.parse()?;
+                if !days.is_finite() || days <= 0.0 {
+                    anyhow::bail!("half-life must be greater than 0 days");
+                }
+
+                config.half_life = Some(Duration::from_secs_f64(days * 24.0 * 60.0 * 60.0));
+                continue;
+            }
+
+            let name = registry
+                .engines()
+                .iter()
+                .find(|descriptor| descriptor.name == key)
+                .with_context(|| format!("{} is not a recognized inference engine", key))?
+                .name;
+
+            let enabled = match value {
+                "on" => true,
+                "off" => false,
+                _ => anyhow::bail!(
+                    "{} is not a recognized engine state, expected \"on\" or \"off\"",
+                    value,
+                ),
+            };
+
+            config.engines.push((name, enabled));
+        }
+
+        Ok(config)
+    }
+}
+
+impl ReplayConfig {
+    fn apply(&self, social: &SocialGraph, guild_id: Id<GuildMarker>) {
+        for &(name, enabled) in &self.engines {
+            social.set_engine_enabled(guild_id, name, enabled);
+        }
+
+        social.set_half_life(guild_id, self.half_life);
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplayResult {
+    pub changes: BTreeMap<RelationshipChangeReason, usize>,
+    /// Each guild's whole graph as of the last interaction in the recording.
+    pub graphs: HashMap<Id<GuildMarker>, UserRelationshipGraphMap>,
+}
+
+/// Feed `events` through inference and into a fresh graph, exactly like live ones.
+///
+/// The counts of changes by reason are of those still in the graph at the end, after any that
+/// were taken back by edits and deletions.
+pub fn replay(events: &[RecordedEvent], config: &ReplayConfig) -> ReplayResult {
+    let social = SocialGraph::new(None);
+    let mut configured_guilds = HashSet::new();
+    let mut changes_by_reason = BTreeMap::new();
+
+    let mut count = |changes: &[RelationshipChange], delta: isize| {
+        for change in changes {
+            let count: &mut usize = changes_by_reason.entry(change.reason).or_default();
+            *count = count.saturating_add_signed(delta * change.count as isize);
+        }
+    };
+
+    for event in events {
+        match event {
+            RecordedEvent::Interaction(interaction) => {
+                if configured_guilds.insert(interaction.guild) {
+                    config.apply(&social, interaction.guild);
+                }
+
+                count(&social.infer_and_apply(interaction, interaction.when), 1);
+            }
+            RecordedEvent::Reaction { interaction, emoji } => {
+                if configured_guilds.insert(interaction.guild) {
+                    config.apply(&social, interaction.guild);
+                }
+
+                if let Some(changes) = social.add_reaction(interaction, emoji, interaction.when) {
+                    count(&changes, 1);
+                }
+            }
+            RecordedEvent::MessageEdit { when, interaction } => {
+                if let Some(edit) = social.apply_edit(interaction, *when) {
+                    count(&edit.retracted, -1);
+                    count(&edit.added, 1);
+                }
+            }
+            RecordedEvent::MessageDelete {
+                when,
+                guild,
+                message,
+            } => {
+                if let Some((_, retracted)) = social.retract_message(*guild, *message, *when) {
+                    count(&retracted, -1);
+                }
+            }
+            RecordedEvent::ReactionRemove {
+                when,
+                guild,
+                message,
+                user,
+                emoji,
+            } => {
+                let removed =
+                    social.remove_reactions(*guild, *message, *user, emoji.as_deref(), *when);
+
+                for (_, retracted) in removed {
+                    count(&retracted, -1);
+                }
+            }
+        }
+    }
+
+    let end = events.last().map_or(0, RecordedEvent::when);
+
+    let graphs = configured_guilds
+        .into_iter()
+        .filter_map(|guild_id| {
+            let channels = social.snapshot_guild(guild_id, end)?;
+            let half_life = social.get_effective_half_life(guild_id);
+
+            Some((
+                guild_id,
+                UserRelationshipGraphMap::merge(channels.values(), end, half_life),
+            ))
+        })
+        .collect();
+
+    ReplayResult {
+        changes: changes_by_reason,
+        graphs,
+    }
+}
+
+fn format_result(config: &ReplayConfig, result: &ReplayResult) -> String {
+    let mut report = format!("{}:\n", config.name);
+
+    for (reason, count) in &result.changes {
+        writeln!(report, "  {:<24} {:>8}", format!("{:?}", reason), count).unwrap();
+    }
+
+    let mut guild_ids: Vec<_> = result.graphs.keys().collect();
+    guild_ids.sort_unstable();
+
+    for guild_id in guild_ids {
+        let edges = result.graphs[guild_id].visible_edges();
+        let users: HashSet<_> = edges.iter().flat_map(|(key, _)| *key).collect();
+
+        writeln!(
+            report,
+            "  guild {}: {} users, {} relationships",
+            guild_id,
+            users.len(),
+            edges.len(),
+        )
+        .unwrap();
+    }
+
+    report
+}
+
+fn format_comparison(configs: [&ReplayConfig; 2], results: [&ReplayResult; 2]) -> String {
+    let mut report = format!("{} vs {}:\n", configs[0].name, configs[1].name);
+
+    let reasons: HashSet<_> = results
+        .iter()
+        .flat_map(|result| result.changes.keys())
+        .collect();
+
+    let mut reasons: Vec<_> = reasons.into_iter().collect();
+    reasons.sort_unstable();
+
+    for reason in reasons {
+        let [a, b] = results.map(|result| result.changes.get(reason).cloned().unwrap_or(0));
+
+        writeln!(
+            report,
+            "  {:<24} {:>8} {:>8} {:>+8}",
+            format!("{:?}", reason),
+            a,
+            b,
+            b as i64 - a as i64,
+        )
+        .unwrap();
+    }
+
+    let guild_ids: HashSet<_> = results
+        .iter()
+        .flat_map(|result| result.graphs.keys())
+        .collect();
+
+    let mut guild_ids: Vec<_> = guild_ids.into_iter().collect();
+    guild_ids.sort_unstable();
+
+    for guild_id in guild_ids {
+        let [before, after] = results.map(|result| {
+            result
+                .graphs
+                .get(guild_id)
+                .map(|graph| graph.visible_edges())
+                .unwrap_or_default()
+        });
+
+        let diff = diff_graphs(&before, &after);
+
+        writeln!(
+            report,
+            "  guild {}: {} added, {} removed, {} stronger, {} weaker",
+            guild_id,
+            diff.added.len(),
+            diff.removed.len(),
+            diff.strengthened.len(),
+            diff.weakened.len(),
+        )
+        .unwrap();
+    }
+
+    report
+}
+
+/// Entry point for `discograph replay`, which replays a recording with one or two configs and
+/// writes a report of the changes inferred by each to `report`, comparing them if there are two.
+pub fn run(mut args: impl Iterator<Item = String>, report: &mut impl Write) -> Result<()> {
+    let mut recording = None;
+    let mut output = None;
+    let mut configs = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--output" {
+            output = Some(PathBuf::from(args.next().context(USAGE)?));
+        } else if recording.is_none() {
+            recording = Some(PathBuf::from(arg));
+        } else {
+            configs.push(arg.parse::<ReplayConfig>()?);
+        }
+    }
+
+    let recording = recording.context(USAGE)?;
+
+    if configs.is_empty() {
+        configs.push("default".parse()?);
+    }
+
+    if configs.len() > 2 {
+        anyhow::bail!(USAGE);
+    }
+
+    let events = load_recording(&recording)?;
+    info!("replaying {} events", events.len());
+
+    let results: Vec<_> = configs
+        .iter()
+        .map(|config| replay(&events, config))
+        .collect();
+
+    for (config, result) in configs.iter().zip(&results) {
+        report.write_all(format_result(config, result).as_bytes())?;
+    }
+
+    if let [a, b] = &results[..] {
+        report.write_all(format_comparison([&configs[0], &configs[1]], [a, b]).as_bytes())?;
+    }
+
+    if let Some(output) = output {
+        std::fs::create_dir_all(&output)?;
+
+        for (index, result) in results.iter().enumerate() {
+            for (guild_id, graph) in &result.graphs {
+                let file_name = if results.len() > 1 {
+                    format!("{}.{}.json", guild_id, index + 1)
+                } else {
+                    format!("{}.json", guild_id)
+                };
+
+                graph.save_to_path(&output.join(file_name))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        interaction_from_json, interaction_to_json, load_recording, replay, run,
+        InteractionRecorder, RecordedEvent, ReplayConfig,
+    };
+    use crate::snowflake::IdPair;
+    use crate::social::inference::{
+        Interaction, InteractionType, RelationshipChangeReason, Thread,
+    };
+    use twilight_model::id::Id;
+
+    fn message(when: u64, source: u64, target: Option<u64>) -> Interaction {
+        Interaction {
+            other_targets: vec![Id::new(30)],
+            ..Interaction::test_message(when, source, target)
+        }
+    }
+
+    #[test]
+    fn test_recording_round_trip() {
+        let interaction = Interaction {
+            thread: Some(Thread {
+                id: Id::new(4),
+                owner: Some(Id::new(20)),
+            }),
+            ..message(1000, 10, Some(20))
+        };
+        let parsed = interaction_from_json(&interaction_to_json(&interaction)).unwrap();
+
+        assert_eq!(parsed.what, interaction.what);
+        assert_eq!(parsed.when, interaction.when);
+        assert_eq!(parsed.source, interaction.source);
+        assert_eq!(parsed.target, interaction.target);
+        assert_eq!(parsed.other_targets, interaction.other_targets);
+        assert_eq!(parsed.thread, interaction.thread);
+    }
+
+    #[tokio::test]
+    async fn test_recorder() {
+        let path =
+            std::env::temp_dir().join(format!("discograph-recording-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recorder = InteractionRecorder::new(&path).await.unwrap();
+        recorder
+            .record(&RecordedEvent::Interaction(message(2000, 10, Some(20))))
+            .await
+            .unwrap();
+        recorder
+            .record(&RecordedEvent::MessageDelete {
+                when: 1000,
+                guild: Id::new(1),
+                message: Id::new(2),
+            })
+            .await
+            .unwrap();
+        drop(recorder);
+
+        // Events are sorted back into order when they're loaded.
+        let events = load_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            events[..],
+            [
+                RecordedEvent::MessageDelete { when: 1000, .. },
+                RecordedEvent::Interaction(Interaction { when: 2000, .. }),
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let path = std::env::temp_dir().join(format!("discograph-replay-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recorder = InteractionRecorder::new(&path).await.unwrap();
+        recorder
+            .record(&RecordedEvent::Interaction(message(1000, 10, Some(20))))
+            .await
+            .unwrap();
+        drop(recorder);
+
+        let args = [path.to_str().unwrap(), "default", "direct_mention=off"];
+        let mut report = Vec::new();
+        run(args.into_iter().map(str::to_owned), &mut report).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("default:\n"));
+        assert!(report.contains("MessageDirectMention"));
+        assert!(report.contains("default vs direct_mention=off:\n"));
+    }
+
+    #[test]
+    fn test_replay_configs() {
+        assert!("not_an_engine=off".parse::<ReplayConfig>().is_err());
+        assert!("direct_mention=maybe".parse::<ReplayConfig>().is_err());
+        assert!("halflife=0".parse::<ReplayConfig>().is_err());
+
+        let interactions = vec![
+            RecordedEvent::Interaction(message(0, 10, Some(20))),
+            RecordedEvent::Interaction(message(60_000, 20, Some(10))),
+            RecordedEvent::Interaction(message(120_000, 10, None)),
+        ];
+
+        let default = replay(&interactions, &"default".parse().unwrap());
+        let config = "direct_mention=off,halflife=7".parse().unwrap();
+        let without_mentions = replay(&interactions, &config);
+
+        assert_eq!(
+            default.changes[&RelationshipChangeReason::MessageDirectMention],
+            2
+        );
+        assert!(!without_mentions
+            .changes
+            .contains_key(&RelationshipChangeReason::MessageDirectMention));
+        assert_eq!(
+            without_mentions.changes[&RelationshipChangeReason::MessageIndirectMention],
+            default.changes[&RelationshipChangeReason::MessageIndirectMention],
+        );
+
+        let graph = &default.graphs[&Id::new(1)];
+        assert!(graph.contains_key(&IdPair(Id::new(10), Id::new(20))));
+    }
+
+    #[test]
+    fn test_replay_voice_counts() {
+        let voice = |when, source, what| {
+            RecordedEvent::Interaction(Interaction {
+                what,
+                message: None,
+                target: None,
+                other_targets: Vec::new(),
+                ..message(when, source, None)
+            })
+        };
+
+        let minute = 60 * 1000;
+        let events = [
+            voice(0, 10, InteractionType::VoiceJoin),
+            voice(0, 20, InteractionType::VoiceJoin),
+            voice(35 * minute, 10, InteractionType::VoiceLeave),
+        ];
+
+        // Each interval spent together is counted, not just the one change made for them.
+        let result = replay(&events, &"default".parse().unwrap());
+        assert_eq!(
+            result.changes[&RelationshipChangeReason::VoiceCoPresence],
+            3
+        );
+    }
+
+    #[test]
+    fn test_replay_retractions() {
+        let mentioned = message(0, 10, Some(20));
+        let edited = Interaction {
+            target: Some(Id::new(40)),
+            other_targets: Vec::new(),
+            ..mentioned.clone()
+        };
+
+        let events = [
+            RecordedEvent::Interaction(mentioned),
+            RecordedEvent::MessageEdit {
+                when: 1000,
+                interaction: edited,
+            },
+            RecordedEvent::Interaction(message(2000, 20, Some(10))),
+            RecordedEvent::MessageDelete {
+                when: 3000,
+                guild: Id::new(1),
+                message: Id::new(2001),
+            },
+            RecordedEvent::Reaction {
+                interaction: Interaction {
+                    what: InteractionType::Reaction,
+                    ..message(4000, 50, Some(60))
+                },
+                emoji: "👍".into(),
+            },
+            RecordedEvent::ReactionRemove {
+                when: 5000,
+                guild: Id::new(1),
+                message: Id::new(4001),
+                user: Some(Id::new(50)),
+                emoji: Some("👍".into()),
+            },
+        ];
+
+        // Each event survives being recorded and loaded again.
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| RecordedEvent::from_json(&event.to_json()).unwrap())
+            .collect();
+
+        let result = replay(&events, &"default".parse().unwrap());
+
+        // Only the edited message's mention is left.
+        assert_eq!(
+            result.changes[&RelationshipChangeReason::MessageDirectMention],
+            1
+        );
+
+        let graph = &result.graphs[&Id::new(1)];
+        assert!(graph.contains_key(&IdPair(Id::new(10), Id::new(40))));
+        assert!(!graph.contains_key(&IdPair(Id::new(10), Id::new(20))));
+        assert!(!graph.contains_key(&IdPair(Id::new(20), Id::new(10))));
+        assert!(!graph.contains_key(&IdPair(Id::new(50), Id::new(60))));
+        assert_eq!(result.changes[&RelationshipChangeReason::Reaction], 0);
+    }
+}
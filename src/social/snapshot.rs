@@ -0,0 +1,375 @@
+use anyhow::{Context as AnyhowContext, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tracing::{debug, info, warn};
+use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+use twilight_model::id::Id;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind as IoErrorKind, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::graph::{SocialGraph, UserRelationshipGraphMap};
+use super::inference::{Clock, RELATIONSHIP_HALF_LIFE};
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// How often each guild gets a new snapshot.
+const SNAPSHOT_INTERVAL: u64 = DAY;
+
+/// How often to check for guilds that are due a snapshot.
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SNAPSHOT_FILE_EXTENSION: &str = ".json.gz";
+
+/// How many snapshots to keep at each granularity.
+///
+/// Time is split into fixed periods and the oldest snapshot in each period is kept, so the set of
+/// kept snapshots doesn't change as new ones are taken. The most recent snapshot is always kept.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub daily: u64,
+    pub weekly: u64,
+    pub monthly: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            daily: 30,
+            weekly: 26,
+            monthly: 36,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Which of the (sorted) snapshot `timestamps` are no longer needed at `now`.
+    fn expired(&self, timestamps: &[u64], now: u64) -> Vec<u64> {
+        let mut keep = HashSet::new();
+
+        if let Some(&latest) = timestamps.last() {
+            keep.insert(latest);
+        }
+
+        for (period, count) in [
+            (DAY, self.daily),
+            (7 * DAY, self.weekly),
+            (30 * DAY, self.monthly),
+        ] {
+            if count == 0 {
+                continue;
+            }
+
+            let oldest_period = (now / period).saturating_sub(count - 1);
+
+            let mut last_period = None;
+            for &timestamp in timestamps {
+                let period = timestamp / period;
+                if period >= oldest_period && last_period != Some(period) {
+                    keep.insert(timestamp);
+                    last_period = Some(period);
+                }
+            }
+        }
+
+        timestamps
+            .iter()
+            .filter(|timestamp| !keep.contains(timestamp))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A copy of each of a guild's channel graphs, with decay applied up until it was taken.
+pub struct Snapshot {
+    /// Unix timestamp (in milliseconds) the snapshot was taken at.
+    pub timestamp: u64,
+    pub channels: HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+}
+
+impl Snapshot {
+    /// Combine the channel graphs like `SocialGraph::build_guild_graph`, optionally only
+    /// including the listed channels.
+    pub fn build_graph(
+        &self,
+        channel_ids: Option<&HashSet<Id<ChannelMarker>>>,
+    ) -> UserRelationshipGraphMap {
+        let graphs = self
+            .channels
+            .iter()
+            .filter(|(channel_id, _)| channel_ids.is_none_or(|ids| ids.contains(channel_id)))
+            .map(|(_, graph)| graph);
+
+        // Everything is already decayed to the snapshot time, so the half-life doesn't matter.
+        UserRelationshipGraphMap::merge(graphs, self.timestamp, RELATIONSHIP_HALF_LIFE)
+    }
+}
+
+/// Gzipped JSON snapshots, stored as `<directory>/<guild id>/<timestamp>.json.gz`.
+pub struct SnapshotStore {
+    directory: PathBuf,
+    retention: RetentionPolicy,
+}
+
+impl SnapshotStore {
+    pub fn new(directory: PathBuf, retention: RetentionPolicy) -> Self {
+        SnapshotStore {
+            directory,
+            retention,
+        }
+    }
+
+    fn guild_directory(&self, guild_id: Id<GuildMarker>) -> PathBuf {
+        self.directory.join(guild_id.to_string())
+    }
+
+    fn file_name(&self, guild_id: Id<GuildMarker>, timestamp: u64) -> PathBuf {
+        self.guild_directory(guild_id)
+            .join(format!("{}{}", timestamp, SNAPSHOT_FILE_EXTENSION))
+    }
+
+    pub fn save(&self, guild_id: Id<GuildMarker>, snapshot: &Snapshot) -> Result<()> {
+        std::fs::create_dir_all(self.guild_directory(guild_id))?;
+
+        let path = self.file_name(guild_id, snapshot.timestamp);
+
+        // Write to a temporary file first so we never leave a partial snapshot behind.
+        let temp_path = path.with_extension("tmp");
+
+        let file = File::create(&temp_path)
+            .with_context(|| format!("failed to create {}", temp_path.display()))?;
+
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        serde_json::to_writer(&mut encoder, &snapshot.channels)?;
+        encoder.finish()?.into_inner()?.sync_all()?;
+
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("failed to rename {}", temp_path.display()))?;
+
+        Ok(())
+    }
+
+    pub fn load(&self, guild_id: Id<GuildMarker>, timestamp: u64) -> Result<Snapshot> {
+        let path = self.file_name(guild_id, timestamp);
+
+        let file =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+
+        // The graph deserializer borrows its keys, so this can't be streamed with `from_reader`.
+        let mut contents = String::new();
+        GzDecoder::new(BufReader::new(file))
+            .read_to_string(&mut contents)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+
+        let channels = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+
+        Ok(Snapshot {
+            timestamp,
+            channels,
+        })
+    }
+
+    /// The timestamps of all the guild's snapshots, oldest first.
+    pub fn list(&self, guild_id: Id<GuildMarker>) -> Result<Vec<u64>> {
+        let entries = match std::fs::read_dir(self.guild_directory(guild_id)) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut timestamps = vec![];
+        for entry in entries {
+            let file_name = entry?.file_name();
+
+            let timestamp = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(SNAPSHOT_FILE_EXTENSION))
+                .and_then(|timestamp| timestamp.parse().ok());
+
+            if let Some(timestamp) = timestamp {
+                timestamps.push(timestamp);
+            }
+        }
+
+        timestamps.sort_unstable();
+
+        Ok(timestamps)
+    }
+
+    /// Find the most recent snapshot taken at or before `at`.
+    pub fn find(&self, guild_id: Id<GuildMarker>, at: u64) -> Result<Option<u64>> {
+        let timestamps = self.list(guild_id)?;
+
+        Ok(timestamps
+            .into_iter()
+            .take_while(|&timestamp| timestamp <= at)
+            .last())
+    }
+
+    /// Delete any snapshots the retention policy no longer needs, returning how many were removed.
+    pub fn apply_retention(&self, guild_id: Id<GuildMarker>, now: u64) -> Result<usize> {
+        let timestamps = self.list(guild_id)?;
+        let expired = self.retention.expired(&timestamps, now);
+
+        for &timestamp in &expired {
+            std::fs::remove_file(self.file_name(guild_id, timestamp))?;
+        }
+
+        Ok(expired.len())
+    }
+
+    fn is_due(&self, guild_id: Id<GuildMarker>, now: u64) -> Result<bool> {
+        let latest = self.list(guild_id)?.last().cloned();
+
+        Ok(latest.is_none_or(|latest| now.saturating_sub(latest) >= SNAPSHOT_INTERVAL))
+    }
+}
+
+pub async fn start_taking_snapshots(
+    social: Arc<SocialGraph>,
+    store: Arc<SnapshotStore>,
+    clock: Arc<dyn Clock>,
+) {
+    info!("starting taking graph snapshots");
+
+    // Wait 5 minutes for most guilds to have connected and loaded their graphs.
+    tokio::time::sleep(Duration::from_secs(5 * 60)).await;
+
+    loop {
+        let guild_ids: Vec<_> = social
+            .get_all_guild_ids()
+            .into_iter()
+            .map(|(guild_id, _)| guild_id)
+            .collect();
+
+        for guild_id in guild_ids {
+            if let Err(error) = take_snapshot(&social, store.clone(), guild_id, clock.now()).await {
+                warn!(?guild_id, ?error, "failed to take graph snapshot");
+            }
+        }
+
+        tokio::time::sleep(SNAPSHOT_CHECK_INTERVAL).await;
+    }
+}
+
+async fn take_snapshot(
+    social: &SocialGraph,
+    store: Arc<SnapshotStore>,
+    guild_id: Id<GuildMarker>,
+    now: u64,
+) -> Result<()> {
+    let is_due = {
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.is_due(guild_id, now)).await??
+    };
+
+    if !is_due {
+        return Ok(());
+    }
+
+    let channels = match social.snapshot_guild(guild_id, now) {
+        Some(channels) => channels,
+        None => return Ok(()),
+    };
+
+    let snapshot = Snapshot {
+        timestamp: now,
+        channels,
+    };
+
+    let removed = tokio::task::spawn_blocking(move || {
+        store.save(guild_id, &snapshot)?;
+        store.apply_retention(guild_id, now)
+    })
+    .await??;
+
+    debug!(?guild_id, ?removed, "took graph snapshot");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetentionPolicy, Snapshot, SnapshotStore, DAY};
+    use crate::snowflake::IdPair;
+    use crate::social::graph::SocialGraph;
+    use twilight_model::id::Id;
+
+    #[test]
+    fn test_retention() {
+        let policy = RetentionPolicy {
+            daily: 7,
+            weekly: 4,
+            monthly: 0,
+        };
+
+        // A snapshot every 12 hours for 60 days.
+        let timestamps: Vec<_> = (0..120).map(|i| i * DAY / 2).collect();
+        let now = 60 * DAY;
+
+        let expired = policy.expired(&timestamps, now);
+        let kept: Vec<_> = timestamps
+            .iter()
+            .filter(|timestamp| !expired.contains(timestamp))
+            .map(|timestamp| timestamp / DAY)
+            .collect();
+
+        // One a day for the last week (the first of which is also the newest weekly), one a week
+        // for the rest of the last 4 weeks, and always the latest.
+        assert_eq!(kept, vec![35, 42, 49, 54, 55, 56, 57, 58, 59, 59]);
+
+        // Taking another snapshot doesn't change what was kept before.
+        let mut timestamps = timestamps;
+        timestamps.push(now);
+        let expired_later = policy.expired(&timestamps, now);
+        assert!(expired
+            .iter()
+            .all(|timestamp| expired_later.contains(timestamp)));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let directory =
+            std::env::temp_dir().join(format!("discograph-test-{}", std::process::id()));
+        let store = SnapshotStore::new(directory.clone(), RetentionPolicy::default());
+
+        let guild_id = Id::new(1);
+        let social = SocialGraph::new(None);
+        social.load_graph(guild_id, Id::new(10));
+
+        assert!(store.list(guild_id).unwrap().is_empty());
+        assert!(social.snapshot_guild(guild_id, DAY).is_none());
+
+        social.with_graph(guild_id, Id::new(11), |graph| {
+            *graph = serde_json::from_str(r#"{"1:2":[3.0,1],"2:3":[1.0,1]}"#).unwrap();
+        });
+
+        for timestamp in [DAY, 3 * DAY] {
+            let snapshot = Snapshot {
+                timestamp,
+                channels: social.snapshot_guild(guild_id, timestamp).unwrap(),
+            };
+
+            store.save(guild_id, &snapshot).unwrap();
+        }
+
+        assert_eq!(store.list(guild_id).unwrap(), vec![DAY, 3 * DAY]);
+        assert_eq!(store.find(guild_id, 0).unwrap(), None);
+        assert_eq!(store.find(guild_id, 2 * DAY).unwrap(), Some(DAY));
+
+        let snapshot = store.load(guild_id, DAY).unwrap();
+        assert_eq!(snapshot.channels.len(), 1);
+
+        let graph = snapshot.build_graph(None);
+        assert_eq!(graph.len(), 2);
+        assert!(graph[&IdPair(Id::new(1), Id::new(2))].strength < 3.0);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
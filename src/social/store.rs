@@ -0,0 +1,906 @@
+use anyhow::{Context as AnyhowContext, Result};
+use parking_lot::Mutex;
+use redb::{Database, TableDefinition};
+use serde_json::Value;
+use tracing::{debug, error, info};
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use std::fmt::Debug;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::graph::{Relationship, SocialGraph, UserPair, UserRelationshipGraphMap};
+use super::inference::RelationshipStrength;
+use crate::snowflake::IdPair;
+
+/// Where channel graphs are kept between runs.
+pub trait GraphStore: Debug + Send {
+    /// Load a channel's graph, or `None` if nothing has been stored for it.
+    fn load(
+        &mut self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<UserRelationshipGraphMap>>;
+
+    /// Load the settings of every guild, or `None` if none have been stored.
+    fn load_settings(&mut self) -> Result<Option<Value>>;
+
+    /// Load the interactions a guild was keeping track of so they could be taken back, or `None`
+    /// if none have been stored.
+    fn load_recent_interactions(&mut self, guild_id: Id<GuildMarker>) -> Result<Option<Value>>;
+
+    /// Store a batch of writes in order. Either all of them are stored or none of them are.
+    fn write(&mut self, writes: &[PendingWrite]) -> Result<()>;
+}
+
+/// A store shared between the graph, which loads from it, and the flusher, which writes to it.
+pub type SharedGraphStore = Arc<Mutex<dyn GraphStore>>;
+
+/// A change to a channel's stored graph, or to the guilds' settings, that hasn't been written out
+/// yet.
+#[derive(Debug)]
+pub enum PendingWrite {
+    Update {
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        edges: Vec<(UserPair, Option<Relationship>)>,
+    },
+    Replace {
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        graph: UserRelationshipGraphMap,
+    },
+    Settings {
+        settings: Value,
+    },
+    RecentInteractions {
+        guild_id: Id<GuildMarker>,
+        recent: Value,
+    },
+}
+
+impl PendingWrite {
+    /// The channel whose graph this writes, if it is one.
+    pub fn channel(&self) -> Option<(Id<GuildMarker>, Id<ChannelMarker>)> {
+        match self {
+            Self::Update {
+                guild_id,
+                channel_id,
+                ..
+            }
+            | Self::Replace {
+                guild_id,
+                channel_id,
+                ..
+            } => Some((*guild_id, *channel_id)),
+            Self::Settings { .. } | Self::RecentInteractions { .. } => None,
+        }
+    }
+
+    /// Bring a graph that was just loaded from the store up to date with this write.
+    pub fn apply_to(&self, graph: &mut UserRelationshipGraphMap) {
+        match self {
+            Self::Update { edges, .. } => apply_edges(graph, edges),
+            Self::Replace {
+                graph: replacement, ..
+            } => *graph = replacement.clone(),
+            Self::Settings { .. } | Self::RecentInteractions { .. } => (),
+        }
+    }
+}
+
+/// How often changed graphs are written out, unless overridden by `GRAPH_FLUSH_INTERVAL`.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Writes changed graphs out to the store in the background, so that handling events never has
+/// to wait for the disk while holding the graph lock.
+#[derive(Debug)]
+pub struct GraphFlusher {
+    social: Arc<SocialGraph>,
+    store: SharedGraphStore,
+}
+
+impl GraphFlusher {
+    pub fn new(social: Arc<SocialGraph>, store: SharedGraphStore) -> Self {
+        GraphFlusher { social, store }
+    }
+
+    /// Write out everything that has changed since the last flush in a single transaction,
+    /// returning how many writes there were. If it fails they're kept for the next flush to try
+    /// again. This blocks on the disk, so shouldn't be called from async code directly.
+    pub fn flush(&self) -> usize {
+        // The store is locked before the writes are taken and until they've all been written, so
+        // a channel being loaded meanwhile waits for its unloaded changes to be stored rather than
+        // missing them. This also stops one flush's writes overtaking an earlier one's. Each guild
+        // is only locked long enough to collect its changes.
+        let mut store = self.store.lock();
+        let writes = self.social.take_pending_writes();
+
+        if writes.is_empty() {
+            return 0;
+        }
+
+        if let Err(error) = store.write(&writes) {
+            error!(
+                ?error,
+                "failed to store {} writes, will retry",
+                writes.len()
+            );
+            self.social.restore_pending_writes(writes);
+
+            return 0;
+        }
+
+        writes.len()
+    }
+}
+
+pub async fn start_flushing(flusher: Arc<GraphFlusher>, interval: Duration) {
+    info!("writing changed graphs every {:?}", interval);
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let flusher = flusher.clone();
+        match tokio::task::spawn_blocking(move || flusher.flush()).await {
+            Ok(written) => debug!(?written, "flushed graphs"),
+            Err(error) => error!(?error, "failed to flush graphs"),
+        }
+    }
+}
+
+const DATABASE_FILE_NAME: &str = "graphs.redb";
+
+/// Every channel's edges, keyed by `(guild, channel, source, target)` so that each channel's are
+/// together, with the value being `(strength, updated)`.
+const GRAPHS: TableDefinition<(u64, u64, u64, u64), (RelationshipStrength, u64)> =
+    TableDefinition::new("graphs");
+
+/// The guilds' settings under `SETTINGS_KEY`, as JSON.
+const SETTINGS: TableDefinition<&str, &str> = TableDefinition::new("settings");
+const SETTINGS_KEY: &str = "guilds";
+
+/// Each guild's recent interactions, as JSON.
+const RECENT_INTERACTIONS: TableDefinition<u64, &str> = TableDefinition::new("recent_interactions");
+
+/// Stores the graphs in an embedded redb database, with every flush written in a single
+/// transaction that is synced to disk before it's committed.
+///
+/// The guilds' settings and recent interactions are kept in the same database as JSON, as they're
+/// small and only ever read or written in full.
+#[derive(Debug)]
+pub struct RedbGraphStore {
+    database: Database,
+    /// If the settings failed to load, in which case they aren't overwritten.
+    settings_unreadable: bool,
+}
+
+impl RedbGraphStore {
+    pub fn new(path: &Path) -> Result<Self> {
+        let database =
+            Database::create(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+        // Reads fail on tables that haven't been created yet.
+        let transaction = database.begin_write()?;
+        transaction.open_table(GRAPHS)?;
+        transaction.open_table(SETTINGS)?;
+        transaction.open_table(RECENT_INTERACTIONS)?;
+        transaction.commit()?;
+
+        Ok(RedbGraphStore {
+            database,
+            settings_unreadable: false,
+        })
+    }
+}
+
+/// The keys of all a channel's edges in `GRAPHS`.
+fn channel_range(
+    guild_id: Id<GuildMarker>,
+    channel_id: Id<ChannelMarker>,
+) -> RangeInclusive<(u64, u64, u64, u64)> {
+    let (guild_id, channel_id) = (guild_id.get(), channel_id.get());
+
+    (guild_id, channel_id, 0, 0)..=(guild_id, channel_id, u64::MAX, u64::MAX)
+}
+
+fn edge_key(
+    guild_id: Id<GuildMarker>,
+    channel_id: Id<ChannelMarker>,
+    IdPair(source, target): UserPair,
+) -> (u64, u64, u64, u64) {
+    (guild_id.get(), channel_id.get(), source.get(), target.get())
+}
+
+impl GraphStore for RedbGraphStore {
+    fn load(
+        &mut self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<UserRelationshipGraphMap>> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(GRAPHS)?;
+
+        let id =
+            |id: u64| -> Result<Id<UserMarker>> { Id::new_checked(id).context("invalid user id") };
+
+        let mut graph = UserRelationshipGraphMap::new();
+
+        for edge in table.range(channel_range(guild_id, channel_id))? {
+            let (key, value) = edge?;
+            let (_, _, source, target) = key.value();
+            let (strength, updated) = value.value();
+
+            graph.insert(
+                IdPair(id(source)?, id(target)?),
+                Relationship { strength, updated },
+            );
+        }
+
+        // A channel's graph is removed from the store when it's emptied.
+        Ok(Some(graph).filter(|graph| !graph.is_empty()))
+    }
+
+    fn load_settings(&mut self) -> Result<Option<Value>> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(SETTINGS)?;
+
+        let settings = match table.get(SETTINGS_KEY)? {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+
+        let settings = serde_json::from_str(settings.value()).context("failed to parse settings");
+
+        self.settings_unreadable = settings.is_err();
+
+        settings.map(Some)
+    }
+
+    fn load_recent_interactions(&mut self, guild_id: Id<GuildMarker>) -> Result<Option<Value>> {
+        let transaction = self.database.begin_read()?;
+        let table = transaction.open_table(RECENT_INTERACTIONS)?;
+
+        match table.get(guild_id.get())? {
+            Some(recent) => serde_json::from_str(recent.value())
+                .map(Some)
+                .context("failed to parse recent interactions"),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, writes: &[PendingWrite]) -> Result<()> {
+        let transaction = self.database.begin_write()?;
+
+        {
+            let mut graphs = transaction.open_table(GRAPHS)?;
+            let mut settings_table = transaction.open_table(SETTINGS)?;
+            let mut recent_table = transaction.open_table(RECENT_INTERACTIONS)?;
+
+            for write in writes {
+                match write {
+                    PendingWrite::Update {
+                        guild_id,
+                        channel_id,
+                        edges,
+                    } => {
+                        for &(source_target, relationship) in edges {
+                            let key = edge_key(*guild_id, *channel_id, source_target);
+
+                            match relationship {
+                                Some(Relationship { strength, updated }) => {
+                                    graphs.insert(key, (strength, updated))?;
+                                }
+                                None => {
+                                    graphs.remove(key)?;
+                                }
+                            }
+                        }
+                    }
+                    PendingWrite::Replace {
+                        guild_id,
+                        channel_id,
+                        graph,
+                    } => {
+                        graphs.retain_in(channel_range(*guild_id, *channel_id), |_, _| false)?;
+
+                        for (&source_target, relationship) in graph.iter() {
+                            graphs.insert(
+                                edge_key(*guild_id, *channel_id, source_target),
+                                (relationship.strength, relationship.updated),
+                            )?;
+                        }
+                    }
+                    PendingWrite::Settings { settings } => {
+                        // Leaving them alone doesn't need to hold up everything else.
+                        if self.settings_unreadable {
+                            error!("not replacing settings as they couldn't be loaded, they need fixing or removing");
+                            continue;
+                        }
+
+                        settings_table.insert(SETTINGS_KEY, settings.to_string().as_str())?;
+                    }
+                    PendingWrite::RecentInteractions { guild_id, recent } => {
+                        recent_table.insert(guild_id.get(), recent.to_string().as_str())?;
+                    }
+                }
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+fn apply_edges(graph: &mut UserRelationshipGraphMap, edges: &[(UserPair, Option<Relationship>)]) {
+    for &(source_target, relationship) in edges {
+        match relationship {
+            Some(relationship) => graph.insert(source_target, relationship),
+            None => graph.remove(&source_target),
+        };
+    }
+}
+
+/// Parse the guild and channel out of the name of an old `{guild}_{channel}.json` graph file.
+fn parse_json_file_name(path: &Path) -> Option<(Id<GuildMarker>, Id<ChannelMarker>)> {
+    if path.extension()? != "json" {
+        return None;
+    }
+
+    let (guild_id, channel_id) = path.file_stem()?.to_str()?.split_once('_')?;
+
+    Some((
+        Id::new_checked(guild_id.parse().ok()?)?,
+        Id::new_checked(channel_id.parse().ok()?)?,
+    ))
+}
+
+/// Import the channel graphs from the JSON files that used to be kept directly in `data_dir`.
+///
+/// Each imported file is renamed to `.json.migrated` so that it isn't imported again. If a channel
+/// already has something in the store the two are merged, keeping whichever copy of each edge was
+/// updated most recently, so nothing from before the store existed is lost.
+///
+/// Returns how many graphs were imported and how many were skipped.
+pub fn migrate_json_files(data_dir: &Path, store: &mut dyn GraphStore) -> Result<(usize, usize)> {
+    let mut imported = 0;
+    let mut skipped = 0;
+
+    let entries = std::fs::read_dir(data_dir)
+        .with_context(|| format!("failed to read {}", data_dir.display()))?;
+
+    for entry in entries {
+        let path = entry?.path();
+
+        let (guild_id, channel_id) = match parse_json_file_name(&path) {
+            Some(ids) => ids,
+            None => continue,
+        };
+
+        let json_graph = match UserRelationshipGraphMap::new_from_path(&path) {
+            Ok(graph) => graph,
+            Err(error) => {
+                error!(?error, "failed to load {}, skipping", path.display());
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let graph = match store.load(guild_id, channel_id)? {
+            Some(mut graph) => {
+                debug!(?guild_id, ?channel_id, "merging graph into existing one");
+
+                for (&source_target, &relationship) in json_graph.iter() {
+                    let stored = graph.entry(source_target).or_insert(relationship);
+                    if stored.updated < relationship.updated {
+                        *stored = relationship;
+                    }
+                }
+
+                graph
+            }
+            None => json_graph,
+        };
+
+        store.write(&[PendingWrite::Replace {
+            guild_id,
+            channel_id,
+            graph,
+        }])?;
+
+        let mut migrated_path = path.clone().into_os_string();
+        migrated_path.push(".migrated");
+        std::fs::rename(&path, &migrated_path)
+            .with_context(|| format!("failed to rename {}", path.display()))?;
+
+        imported += 1;
+    }
+
+    Ok((imported, skipped))
+}
+
+/// Open the graph store in `data_dir`, first importing any old JSON graph files into it.
+pub fn open_store(data_dir: &Path) -> Result<RedbGraphStore> {
+    let mut store = RedbGraphStore::new(&data_dir.join(DATABASE_FILE_NAME))?;
+
+    let (imported, skipped) = migrate_json_files(data_dir, &mut store)?;
+
+    if imported > 0 || skipped > 0 {
+        info!("imported {} channel graphs, skipped {}", imported, skipped);
+    }
+
+    Ok(store)
+}
+
+/// Entry point for `discograph migrate`, importing the old JSON graph files without starting.
+pub fn run_migration(data_dir: &Path) -> Result<()> {
+    let mut store = RedbGraphStore::new(&data_dir.join(DATABASE_FILE_NAME))?;
+
+    let (imported, skipped) = migrate_json_files(data_dir, &mut store)?;
+
+    info!("imported {} channel graphs, skipped {}", imported, skipped);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        migrate_json_files, GraphFlusher, GraphStore, PendingWrite, RedbGraphStore,
+        SharedGraphStore, DATABASE_FILE_NAME, SETTINGS, SETTINGS_KEY,
+    };
+    use crate::snowflake::IdPair;
+    use crate::social::graph::{Relationship, SocialGraph, UserPair, UserRelationshipGraphMap};
+    use crate::social::inference::{Interaction, InteractionType};
+    use anyhow::{anyhow, Result};
+    use parking_lot::Mutex;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+    use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+    use twilight_model::id::Id;
+
+    fn relationship(strength: f32, updated: u64) -> Option<Relationship> {
+        Some(Relationship { strength, updated })
+    }
+
+    /// Swap in a complete new set of graphs for the guild, as a rebuild from history would.
+    fn replace_guild(
+        social: &SocialGraph,
+        guild_id: Id<GuildMarker>,
+        graphs: HashMap<Id<ChannelMarker>, UserRelationshipGraphMap>,
+    ) {
+        let rebuild = social.begin_rebuild(guild_id).unwrap();
+        let loaded = rebuild.start_loading();
+        rebuild.finish(graphs, Default::default(), loaded);
+    }
+
+    fn temp_directory(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("discograph-store-{}-{}", name, std::process::id()))
+    }
+
+    fn open_store(directory: &Path) -> RedbGraphStore {
+        std::fs::create_dir_all(directory).unwrap();
+        RedbGraphStore::new(&directory.join(DATABASE_FILE_NAME)).unwrap()
+    }
+
+    fn update(
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        edges: &[(UserPair, Option<Relationship>)],
+    ) -> PendingWrite {
+        PendingWrite::Update {
+            guild_id,
+            channel_id,
+            edges: edges.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_incremental_updates() {
+        let directory = temp_directory("updates");
+        let mut store = open_store(&directory);
+
+        let (guild_id, channel_id, other_channel_id) = (Id::new(1), Id::new(10), Id::new(11));
+        assert!(store.load(guild_id, channel_id).unwrap().is_none());
+
+        store
+            .write(&[
+                update(
+                    guild_id,
+                    channel_id,
+                    &[
+                        (IdPair(Id::new(1), Id::new(2)), relationship(1.0, 1)),
+                        (IdPair(Id::new(2), Id::new(3)), relationship(2.0, 1)),
+                    ],
+                ),
+                update(
+                    guild_id,
+                    channel_id,
+                    &[
+                        (IdPair(Id::new(1), Id::new(2)), None),
+                        (IdPair(Id::new(2), Id::new(3)), relationship(5.0, 2)),
+                    ],
+                ),
+                update(
+                    guild_id,
+                    other_channel_id,
+                    &[(IdPair(Id::new(1), Id::new(2)), relationship(1.0, 1))],
+                ),
+            ])
+            .unwrap();
+
+        // Everything is still there once the database is opened again.
+        drop(store);
+        let mut store = open_store(&directory);
+
+        let loaded = store.load(guild_id, channel_id).unwrap().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[&IdPair(Id::new(2), Id::new(3))].strength, 5.0);
+        assert_eq!(loaded[&IdPair(Id::new(2), Id::new(3))].updated, 2);
+
+        // Replacing a channel's graph doesn't touch any other channel's.
+        store
+            .write(&[PendingWrite::Replace {
+                guild_id,
+                channel_id,
+                graph: UserRelationshipGraphMap::new(),
+            }])
+            .unwrap();
+        assert!(store.load(guild_id, channel_id).unwrap().is_none());
+        assert_eq!(
+            store
+                .load(guild_id, other_channel_id)
+                .unwrap()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        drop(store);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Fails the first write it's given, then passes the rest on.
+    #[derive(Debug)]
+    struct FailingStore {
+        store: RedbGraphStore,
+        failed: bool,
+    }
+
+    impl GraphStore for FailingStore {
+        fn load(
+            &mut self,
+            guild_id: Id<GuildMarker>,
+            channel_id: Id<ChannelMarker>,
+        ) -> Result<Option<UserRelationshipGraphMap>> {
+            self.store.load(guild_id, channel_id)
+        }
+
+        fn load_settings(&mut self) -> Result<Option<Value>> {
+            self.store.load_settings()
+        }
+
+        fn load_recent_interactions(&mut self, guild_id: Id<GuildMarker>) -> Result<Option<Value>> {
+            self.store.load_recent_interactions(guild_id)
+        }
+
+        fn write(&mut self, writes: &[PendingWrite]) -> Result<()> {
+            if !self.failed {
+                self.failed = true;
+                return Err(anyhow!("disk full"));
+            }
+
+            self.store.write(writes)
+        }
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        let directory = temp_directory("failed");
+        let store: SharedGraphStore = Arc::new(Mutex::new(FailingStore {
+            store: open_store(&directory),
+            failed: false,
+        }));
+        let social = Arc::new(SocialGraph::new(Some(store.clone())));
+        let flusher = GraphFlusher::new(social.clone(), store.clone());
+
+        let (guild_id, channel_id) = (Id::new(1), Id::new(10));
+
+        let mut graph = UserRelationshipGraphMap::new();
+        graph.reinforce(
+            IdPair(Id::new(1), Id::new(2)),
+            1.0,
+            1,
+            std::time::Duration::MAX,
+        );
+        replace_guild(
+            &social,
+            guild_id,
+            HashMap::from([(channel_id, graph.clone())]),
+        );
+
+        // Nothing is lost when a flush fails, it's written by the next one along with anything
+        // changed meanwhile.
+        assert_eq!(flusher.flush(), 0);
+        assert!(store.lock().load(guild_id, channel_id).unwrap().is_none());
+
+        social.set_half_life(guild_id, Some(std::time::Duration::from_secs(60)));
+        assert_eq!(flusher.flush(), 2);
+        let stored = store.lock().load(guild_id, channel_id).unwrap().unwrap();
+        assert_eq!(*stored, *graph);
+        assert!(store.lock().load_settings().unwrap().is_some());
+
+        drop((flusher, social, store));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_json_files() {
+        let directory = temp_directory("migrate");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        std::fs::write(directory.join("1_10.json"), r#"{"1:2":[3.0,1],"2:3":1.0}"#).unwrap();
+        std::fs::write(
+            directory.join("1_11.json"),
+            r#"{"1:2":[3.0,1],"7:8":[1.0,1]}"#,
+        )
+        .unwrap();
+        std::fs::write(directory.join("notes.json"), "{}").unwrap();
+
+        let mut store = open_store(&directory);
+
+        // The bot has already stored something for the second channel, including a newer copy of
+        // one of the edges.
+        let mut newer = UserRelationshipGraphMap::new();
+        for (source, target) in [(5, 6), (1, 2)] {
+            newer.reinforce(
+                IdPair(Id::new(source), Id::new(target)),
+                1.0,
+                2,
+                std::time::Duration::MAX,
+            );
+        }
+        store
+            .write(&[PendingWrite::Replace {
+                guild_id: Id::new(1),
+                channel_id: Id::new(11),
+                graph: newer,
+            }])
+            .unwrap();
+
+        assert_eq!(migrate_json_files(&directory, &mut store).unwrap(), (2, 0));
+        assert!(directory.join("1_10.json.migrated").exists());
+        assert!(directory.join("1_11.json.migrated").exists());
+
+        let graph = store.load(Id::new(1), Id::new(10)).unwrap().unwrap();
+        assert_eq!(graph.len(), 2);
+        assert_eq!(graph[&IdPair(Id::new(2), Id::new(3))].strength, 1.0);
+
+        let graph = store.load(Id::new(1), Id::new(11)).unwrap().unwrap();
+        assert!(graph.contains_key(&IdPair(Id::new(5), Id::new(6))));
+        assert!(graph.contains_key(&IdPair(Id::new(7), Id::new(8))));
+        assert_eq!(graph[&IdPair(Id::new(1), Id::new(2))].updated, 2);
+
+        // Running it again doesn't find anything new.
+        assert_eq!(migrate_json_files(&directory, &mut store).unwrap(), (0, 0));
+
+        drop(store);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let directory = temp_directory("flush");
+        let store: SharedGraphStore = Arc::new(Mutex::new(open_store(&directory)));
+        let social = Arc::new(SocialGraph::new(Some(store.clone())));
+        let flusher = GraphFlusher::new(social.clone(), store.clone());
+
+        let (guild_id, channel_id, other_channel_id) = (Id::new(1), Id::new(10), Id::new(11));
+
+        let mut graph = UserRelationshipGraphMap::new();
+        graph.reinforce(
+            IdPair(Id::new(1), Id::new(2)),
+            1.0,
+            1,
+            std::time::Duration::MAX,
+        );
+        replace_guild(
+            &social,
+            guild_id,
+            HashMap::from([(channel_id, graph.clone())]),
+        );
+
+        // Nothing is written until the flush.
+        assert!(store.lock().load(guild_id, channel_id).unwrap().is_none());
+        assert_eq!(flusher.flush(), 1);
+        let stored = store.lock().load(guild_id, channel_id).unwrap().unwrap();
+        assert_eq!(*stored, *graph);
+        assert_eq!(flusher.flush(), 0);
+
+        // Changes to a channel that's unloaded before the flush are still there when it's reloaded.
+        let mut graph = UserRelationshipGraphMap::new();
+        graph.reinforce(
+            IdPair(Id::new(3), Id::new(4)),
+            2.0,
+            1,
+            std::time::Duration::MAX,
+        );
+        replace_guild(
+            &social,
+            guild_id,
+            HashMap::from([(other_channel_id, graph.clone())]),
+        );
+        social.remove_channel(guild_id, other_channel_id);
+        assert_eq!(
+            *social.with_graph(guild_id, other_channel_id, |graph| graph.clone()),
+            *graph
+        );
+
+        // The channel missing from the replacement is cleared too.
+        assert_eq!(flusher.flush(), 2);
+        assert!(store.lock().load(guild_id, channel_id).unwrap().is_none());
+        let stored = store
+            .lock()
+            .load(guild_id, other_channel_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(*stored, *graph);
+
+        drop((flusher, social, store));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_during_flush() {
+        let directory = temp_directory("load");
+        let store: SharedGraphStore = Arc::new(Mutex::new(open_store(&directory)));
+        let social = Arc::new(SocialGraph::new(Some(store.clone())));
+        let flusher = GraphFlusher::new(social.clone(), store.clone());
+
+        let (guild_id, channel_id, other_channel_id) = (Id::new(1), Id::new(10), Id::new(11));
+        let edge = IdPair(Id::new(1), Id::new(2));
+
+        let mut graph = UserRelationshipGraphMap::new();
+        graph.reinforce(edge, 1.0, 1, std::time::Duration::MAX);
+        replace_guild(
+            &social,
+            guild_id,
+            HashMap::from([(channel_id, graph.clone()), (other_channel_id, graph)]),
+        );
+
+        // Unloaded changes that are in the middle of being written when the channel is loaded
+        // again are still there, and loading doesn't hold up the rest of the guild meanwhile.
+        social.remove_channel(guild_id, other_channel_id);
+
+        let flushing = store.lock();
+
+        let loader = {
+            let social = social.clone();
+            std::thread::spawn(move || {
+                social.with_graph(guild_id, other_channel_id, |graph| graph.clone())
+            })
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(social.with_graph(guild_id, channel_id, |graph| graph.contains_key(&edge)));
+
+        drop(flushing);
+        assert_eq!(flusher.flush(), 2);
+        assert!(loader.join().unwrap().contains_key(&edge));
+
+        drop((flusher, social, store));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_settings() {
+        let directory = temp_directory("settings");
+        let open = || -> SharedGraphStore { Arc::new(Mutex::new(open_store(&directory))) };
+
+        let half_life = std::time::Duration::from_secs(60 * 60);
+
+        let store = open();
+        let social = Arc::new(SocialGraph::new(Some(store.clone())));
+        let flusher = GraphFlusher::new(social.clone(), store);
+
+        social.set_half_life(Id::new(1), Some(half_life));
+        assert!(social.set_engine_enabled(Id::new(3), "reaction", false));
+        assert_eq!(flusher.flush(), 1);
+        assert_eq!(flusher.flush(), 0);
+        drop((flusher, social));
+
+        let social = SocialGraph::new(Some(open()));
+        assert_eq!(social.get_half_life(Id::new(1)), half_life);
+        assert!(social
+            .get_engines(Id::new(3))
+            .contains(&("reaction", false)));
+        assert!(social.get_engines(Id::new(1)).contains(&("reaction", true)));
+        assert_eq!(
+            social.get_half_life(Id::new(2)),
+            SocialGraph::new(None).get_half_life(Id::new(2))
+        );
+
+        // Settings that can't be read aren't replaced by whatever is set next.
+        drop(social);
+        let store = open_store(&directory);
+        let transaction = store.database.begin_write().unwrap();
+        transaction
+            .open_table(SETTINGS)
+            .unwrap()
+            .insert(SETTINGS_KEY, "{")
+            .unwrap();
+        transaction.commit().unwrap();
+
+        let store = Arc::new(Mutex::new(store));
+        let social = Arc::new(SocialGraph::new(Some(store.clone())));
+        let flusher = GraphFlusher::new(social.clone(), store.clone());
+
+        social.set_half_life(Id::new(2), Some(half_life));
+        flusher.flush();
+
+        let transaction = store.lock().database.begin_read().unwrap();
+        let settings = transaction.open_table(SETTINGS).unwrap();
+        assert_eq!(settings.get(SETTINGS_KEY).unwrap().unwrap().value(), "{");
+
+        drop((settings, transaction, flusher, social, store));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_recent_interactions() {
+        let directory = temp_directory("recent");
+        let open = || -> SharedGraphStore { Arc::new(Mutex::new(open_store(&directory))) };
+
+        let guild_id = Id::new(1);
+        let message = Interaction {
+            message: Some(Id::new(3)),
+            ..Interaction::test_message(0, 10, Some(20))
+        };
+        let reaction = Interaction {
+            what: InteractionType::Reaction,
+            message: Some(Id::new(4)),
+            source: Id::new(20),
+            target: Some(Id::new(10)),
+            ..message.clone()
+        };
+        let emoji = "👍";
+
+        let store = open();
+        let social = Arc::new(SocialGraph::new(Some(store.clone())));
+        let flusher = GraphFlusher::new(social.clone(), store);
+
+        social.load_recent_interactions(guild_id);
+        social.infer_and_apply(&message, message.when);
+        assert!(social
+            .add_reaction(&reaction, emoji, reaction.when)
+            .is_some());
+
+        social.save_recent_interactions();
+        flusher.flush();
+        drop((flusher, social));
+
+        // Both can still be taken back after a restart, once the guild has been loaded.
+        let social = SocialGraph::new(Some(open()));
+        assert!(social.retract_message(guild_id, Id::new(3), 0).is_none());
+
+        social.load_recent_interactions(guild_id);
+        let (interaction, changes) = social.retract_message(guild_id, Id::new(3), 0).unwrap();
+        assert_eq!(interaction.source, message.source);
+        assert!(!changes.is_empty());
+
+        assert!(social.add_reaction(&reaction, emoji, 0).is_none());
+        let removed = social.remove_reactions(guild_id, Id::new(4), Some(Id::new(20)), None, 0);
+        assert_eq!(removed.len(), 1);
+
+        assert!(social.with_graph(guild_id, Id::new(2), |graph| graph.is_empty()));
+
+        drop(social);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+
+use std::time::Duration;
+
+use super::graph::{UserPair, UserRelationshipGraphMap};
+use super::inference::RelationshipStrength;
+use crate::snowflake::SnowflakeSet;
+
+/// Guild size that the base reinforcement strengths and half-life were tuned against.
+const REFERENCE_NODES: f64 = 50.0;
+
+/// Rate of human messages per active edge (per hour) the base strengths were tuned against.
+const REFERENCE_MESSAGES_PER_EDGE: f64 = 0.05;
+
+/// Keep the adaptive scales within reason, so a brand new or enormous guild doesn't go wild.
+const MIN_SCALE: f64 = 0.25;
+const MAX_SCALE: f64 = 4.0;
+
+/// Half-life of the recent message counter used to estimate the message rate.
+const MESSAGE_RATE_HALF_LIFE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How far back messages are counted from when seeding a guild's activity from history, by then
+/// they have decayed to almost nothing.
+pub const MESSAGE_HISTORY: Duration = Duration::from_secs(4 * 24 * 60 * 60);
+
+/// How often we re-count the active nodes and edges in the guild graph.
+const RECOUNT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Tracks how big and busy a guild is, to normalise relationship weights between guilds.
+///
+/// Without this a 10-person guild never builds up enough weight to draw a good graph, while a
+/// 10,000-person guild ends up with an unreadable hairball. Reinforcement is scaled by how many
+/// messages there are per active edge, and decay is scaled by how many active users there are.
+#[derive(Debug, Default, Clone)]
+pub struct GuildActivity {
+    /// Exponentially-decayed count of recent human messages.
+    messages: f64,
+    messages_updated: u64,
+    nodes: usize,
+    edges: usize,
+    counted: Option<u64>,
+}
+
+impl GuildActivity {
+    pub fn record_message(&mut self, now: u64) {
+        self.messages = self.decayed_messages(now) + 1.0;
+        self.messages_updated = now;
+    }
+
+    /// Add in the messages counted by `other`, such as ones counted from history.
+    pub fn merge_messages(&mut self, other: &GuildActivity) {
+        let updated = self.messages_updated.max(other.messages_updated);
+
+        self.messages = self.decayed_messages(updated) + other.decayed_messages(updated);
+        self.messages_updated = updated;
+    }
+
+    fn decayed_messages(&self, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.messages_updated) as f64;
+        let half_lives = elapsed / MESSAGE_RATE_HALF_LIFE.as_millis() as f64;
+
+        self.messages * 0.5f64.powf(half_lives)
+    }
+
+    /// Estimated number of human messages per hour.
+    pub fn message_rate(&self, now: u64) -> f64 {
+        // The steady-state value of the decayed counter is rate * half-life / ln(2).
+        let half_life_hours = MESSAGE_RATE_HALF_LIFE.as_secs_f64() / (60.0 * 60.0);
+
+        self.decayed_messages(now) * std::f64::consts::LN_2 / half_life_hours
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    pub fn edges(&self) -> usize {
+        self.edges
+    }
+
+    pub fn needs_recount(&self, now: u64) -> bool {
+        match self.counted {
+            Some(counted) => now.saturating_sub(counted) >= RECOUNT_INTERVAL.as_millis() as u64,
+            None => true,
+        }
+    }
+
+    pub fn recount<'a>(
+        &mut self,
+        graphs: impl Iterator<Item = &'a UserRelationshipGraphMap>,
+        now: u64,
+    ) {
+        let mut nodes: SnowflakeSet<Id<UserMarker>> = SnowflakeSet::default();
+        let mut edges: SnowflakeSet<UserPair> = SnowflakeSet::default();
+
+        for graph in graphs {
+            for &source_target in graph.keys() {
+                nodes.insert(source_target.0);
+                nodes.insert(source_target.1);
+                edges.insert(source_target);
+            }
+        }
+
+        self.nodes = nodes.len();
+        self.edges = edges.len();
+        self.counted = Some(now);
+    }
+
+    /// Multiplier for the strength of new relationship changes.
+    pub fn reinforcement_scale(&self, now: u64) -> RelationshipStrength {
+        let rate = self.message_rate(now);
+        if self.edges == 0 || rate <= 0.0 {
+            return 1.0;
+        }
+
+        let messages_per_edge = rate / self.edges as f64;
+
+        // Square root to dampen the adjustment, we only want to nudge guilds towards the middle.
+        let scale = (REFERENCE_MESSAGES_PER_EDGE / messages_per_edge).sqrt();
+
+        scale.clamp(MIN_SCALE, MAX_SCALE) as RelationshipStrength
+    }
+
+    /// Multiplier for the guild's decay half-life, larger guilds forget faster.
+    pub fn half_life_scale(&self) -> f64 {
+        if self.nodes == 0 {
+            return 1.0;
+        }
+
+        let scale = (REFERENCE_NODES / self.nodes as f64).sqrt();
+
+        scale.clamp(MIN_SCALE, MAX_SCALE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GuildActivity, MAX_SCALE, MIN_SCALE};
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    #[test]
+    fn test_neutral_without_data() {
+        let activity = GuildActivity::default();
+
+        assert_eq!(activity.reinforcement_scale(0), 1.0);
+        assert_eq!(activity.half_life_scale(), 1.0);
+    }
+
+    #[test]
+    fn test_message_rate() {
+        let mut activity = GuildActivity::default();
+
+        // 10 messages an hour for a week should have settled close to the real rate.
+        for i in 0..(10 * 24 * 7) {
+            activity.record_message(i * HOUR / 10);
+        }
+
+        let rate = activity.message_rate(24 * 7 * HOUR);
+        assert!((rate - 10.0).abs() < 0.5, "rate was {}", rate);
+    }
+
+    #[test]
+    fn test_merge_messages() {
+        let mut history = GuildActivity::default();
+        let mut live = GuildActivity::default();
+
+        for i in 0..(10 * 24) {
+            if i < 5 * 24 {
+                history.record_message(i * HOUR / 10);
+            } else {
+                live.record_message(i * HOUR / 10);
+            }
+        }
+
+        let mut expected = history.clone();
+        expected.merge_messages(&live);
+
+        let mut all = GuildActivity::default();
+        for i in 0..(10 * 24) {
+            all.record_message(i * HOUR / 10);
+        }
+
+        let (merged, all) = (
+            expected.message_rate(24 * HOUR),
+            all.message_rate(24 * HOUR),
+        );
+        assert!((merged - all).abs() < 1e-6, "{} != {}", merged, all);
+    }
+
+    #[test]
+    fn test_scales_are_clamped() {
+        let mut activity = GuildActivity {
+            nodes: 1_000_000,
+            edges: 1,
+            ..Default::default()
+        };
+
+        for i in 0..1000 {
+            activity.record_message(i);
+        }
+
+        assert_eq!(activity.half_life_scale(), MIN_SCALE);
+        assert_eq!(activity.reinforcement_scale(1000), MIN_SCALE as f32);
+
+        activity.nodes = 1;
+        activity.edges = 1_000_000;
+
+        assert_eq!(activity.half_life_scale(), MAX_SCALE);
+        assert_eq!(activity.reinforcement_scale(1000), MAX_SCALE as f32);
+    }
+}
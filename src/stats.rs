@@ -53,7 +53,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 .bind(guild.id.get())
                 .bind(&guild.name)
                 .bind(guild.icon.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(guild.icon.map_or(false, |image| image.is_animated()))
+                .bind(guild.icon.is_some_and(|image| image.is_animated()))
                 .bind(flags)
                 .bind(joined_at)
                 .execute(pool)
@@ -109,7 +109,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 .bind(guild.id.get())
                 .bind(&guild.name)
                 .bind(guild.icon.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(guild.icon.map_or(false, |image| image.is_animated()))
+                .bind(guild.icon.is_some_and(|image| image.is_animated()))
                 .bind(flags)
                 .bind(timestamp)
                 .execute(pool)
@@ -138,7 +138,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 .bind(member.user.discriminator)
                 .bind(member.user.bot)
                 .bind(member.user.avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(member.user.avatar.map_or(false, |image| image.is_animated()))
+                .bind(member.user.avatar.is_some_and(|image| image.is_animated()))
                 .bind(member.user.id.get())
                 .execute(pool)
                 .await?;
@@ -149,7 +149,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                     .bind(member.user.id.get())
                     .bind(&member.nick)
                     .bind(member.avatar.map(|image| image.bytes().as_slice().to_owned()))
-                    .bind(member.avatar.map_or(false, |image| image.is_animated()))
+                    .bind(member.avatar.is_some_and(|image| image.is_animated()))
                     .execute(pool)
                     .await?;
             }
@@ -163,7 +163,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 .bind(member.user.discriminator)
                 .bind(member.user.bot)
                 .bind(member.user.avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(member.user.avatar.map_or(false, |image| image.is_animated()))
+                .bind(member.user.avatar.is_some_and(|image| image.is_animated()))
                 .bind(member.user.id.get())
                 .execute(pool)
                 .await?;
@@ -174,7 +174,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                     .bind(member.user.id.get())
                     .bind(&member.nick)
                     .bind(member.avatar.map(|image| image.bytes().as_slice().to_owned()))
-                    .bind(member.avatar.map_or(false, |image| image.is_animated()))
+                    .bind(member.avatar.is_some_and(|image| image.is_animated()))
                     .execute(pool)
                     .await?;
             }
@@ -185,7 +185,7 @@ pub async fn handle_event(context: &Context, event: &Event) -> Result<()> {
                 .bind(member.user.discriminator)
                 .bind(member.user.bot)
                 .bind(member.user.avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(member.user.avatar.map_or(false, |image| image.is_animated()))
+                .bind(member.user.avatar.is_some_and(|image| image.is_animated()))
                 .bind(member.user.id.get())
                 .execute(pool)
                 .await?;
@@ -250,7 +250,7 @@ async fn store_members(
                 .bind(member.user.discriminator)
                 .bind(member.user.bot)
                 .bind(avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(avatar.map_or(false, |image| image.is_animated()));
+                .bind(avatar.is_some_and(|image| image.is_animated()));
         }
 
         query.execute(pool).await?;
@@ -270,7 +270,7 @@ async fn store_members(
                 .bind(member.user.id.get())
                 .bind(&member.nick)
                 .bind(avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(avatar.map_or(false, |image| image.is_animated()));
+                .bind(avatar.is_some_and(|image| image.is_animated()));
         }
 
         query.execute(pool).await?;
@@ -379,7 +379,7 @@ pub async fn ensure_users_saved_in_db(
     let (users, members): (Vec<_>, Vec<_>) = join_all(already_loaded)
         .await
         .into_iter()
-        .chain(join_all(not_found).await.into_iter())
+        .chain(join_all(not_found).await)
         .unzip();
 
     let users: Vec<_> = users.into_iter().flatten().collect();
@@ -398,7 +398,7 @@ pub async fn ensure_users_saved_in_db(
                 .bind(user.discriminator)
                 .bind(user.bot)
                 .bind(avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(avatar.map_or(false, |image| image.is_animated()));
+                .bind(avatar.is_some_and(|image| image.is_animated()));
         }
 
         query.execute(pool).await?;
@@ -419,7 +419,7 @@ pub async fn ensure_users_saved_in_db(
                 .bind(user_id.get())
                 .bind(&member.nick)
                 .bind(avatar.map(|image| image.bytes().as_slice().to_owned()))
-                .bind(avatar.map_or(false, |image| image.is_animated()));
+                .bind(avatar.is_some_and(|image| image.is_animated()));
         }
 
         query.execute(pool).await?;